@@ -0,0 +1,197 @@
+// Copyright 2017 CoreOS, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! audit keeps track of what a run changed on the host: every file written
+//! and every ssh key installed, so that it can be accounted for afterwards.
+//! the record is kept per thread; the threads that write ssh keys keep a
+//! record of their own and hand it back.
+//!
+//! files are only hashed when the manifest is made, so a file that is
+//! written more than once (the stamp file, say) is listed once, as it ended
+//! up.
+
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::Read;
+use std::mem;
+use std::path::{Path, PathBuf};
+
+use openssh_keys::PublicKey;
+use serde_json;
+use sha2::{Digest, Sha256};
+
+use errors::*;
+use providers::write_file_with_mode;
+use util::ssh_cert::SshCertificate;
+
+thread_local! {
+    static RECORD: RefCell<Record> = RefCell::new(Record::default());
+}
+
+/// Record is what has been changed so far, without the hashes
+#[derive(Debug, Default)]
+pub(crate) struct Record {
+    files: Vec<PathBuf>,
+    ssh_keys: Vec<InstalledKey>,
+}
+
+impl Record {
+    pub(crate) fn wrote(&mut self, path: &Path) {
+        if !self.files.iter().any(|p| p == path) {
+            self.files.push(path.to_owned());
+        }
+    }
+
+    pub(crate) fn installed_key(&mut self, user: &str, key: &PublicKey) {
+        self.ssh_keys.push(InstalledKey {
+            user: user.to_owned(),
+            key_type: key.keytype().to_owned(),
+            fingerprint: Some(format!("SHA256:{}", key.fingerprint())),
+            key_id: None,
+        });
+    }
+
+    pub(crate) fn installed_certificate(&mut self, user: &str, certificate: &SshCertificate) {
+        self.ssh_keys.push(InstalledKey {
+            user: user.to_owned(),
+            key_type: certificate.key_type.clone(),
+            fingerprint: None,
+            key_id: Some(certificate.key_id.clone()),
+        });
+    }
+}
+
+/// WrittenFile is a file the run wrote, and what it holds now
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct WrittenFile {
+    pub path: String,
+    /// the sha256 of the file's contents, hex encoded, or none if it can't
+    /// be read anymore
+    pub sha256: Option<String>,
+}
+
+/// InstalledKey is an ssh key (or certificate) added to a user's authorized
+/// keys
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct InstalledKey {
+    /// the local user
+    pub user: String,
+    pub key_type: String,
+    /// as `ssh-keygen -l` prints it; certificates are listed by key id
+    pub fingerprint: Option<String>,
+    pub key_id: Option<String>,
+}
+
+/// Manifest is everything the run changed on the host
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub files: Vec<WrittenFile>,
+    pub ssh_keys: Vec<InstalledKey>,
+}
+
+impl Manifest {
+    /// log logs every entry of the manifest
+    pub fn log(&self) {
+        for file in &self.files {
+            info!("audit: wrote {} sha256:{}", file.path, file.sha256.as_ref().map_or("(unreadable)", String::as_str));
+        }
+        for key in &self.ssh_keys {
+            match key.fingerprint {
+                Some(ref fingerprint) => info!("audit: installed ssh key {} {} for user {}", key.key_type, fingerprint, key.user),
+                None => info!("audit: installed ssh certificate {} {:?} for user {}",
+                              key.key_type, key.key_id.as_ref().map_or("", String::as_str), key.user),
+            }
+        }
+    }
+
+    pub fn write_to(&self, path: &str) -> Result<()> {
+        let contents = serde_json::to_string_pretty(self)
+            .chain_err(|| "failed to serialize audit manifest")?;
+        write_file_with_mode(Path::new(path), 0o644, &contents)
+    }
+}
+
+/// wrote records that the file was written
+pub(crate) fn wrote<P: AsRef<Path>>(path: P) {
+    RECORD.with(|record| record.borrow_mut().wrote(path.as_ref()));
+}
+
+/// merge adds what another thread recorded
+pub(crate) fn merge(other: Record) {
+    RECORD.with(|record| {
+        let mut record = record.borrow_mut();
+        for path in &other.files {
+            record.wrote(path);
+        }
+        record.ssh_keys.extend(other.ssh_keys);
+    });
+}
+
+/// manifest hashes the files written so far, and starts a new record
+pub fn manifest() -> Manifest {
+    let record = RECORD.with(|record| mem::replace(&mut *record.borrow_mut(), Record::default()));
+    Manifest {
+        files: record.files.iter()
+            .map(|path| WrittenFile {
+                path: path.display().to_string(),
+                sha256: hash_file(path),
+            })
+            .collect(),
+        ssh_keys: record.ssh_keys,
+    }
+}
+
+fn hash_file(path: &Path) -> Option<String> {
+    let mut contents = Vec::new();
+    match File::open(path).and_then(|mut f| f.read_to_end(&mut contents)) {
+        Ok(_) => Some(format!("{:x}", Sha256::digest(&contents))),
+        Err(e) => {
+            warn!("failed to hash {:?} for the audit manifest: {}", path, e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use tempdir::TempDir;
+    use super::*;
+
+    #[test]
+    fn manifest_test() {
+        let dir = TempDir::new("audit").unwrap();
+        let path = dir.path().join("hostname");
+        fs::write(&path, "abc").unwrap();
+        wrote(&path);
+        let mut other = Record::default();
+        other.wrote(&path);
+        other.wrote(&dir.path().join("gone"));
+        merge(other);
+
+        let manifest = manifest();
+        assert_eq!(manifest.files, vec![
+            WrittenFile {
+                path: path.display().to_string(),
+                sha256: Some("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad".to_owned()),
+            },
+            WrittenFile {
+                path: dir.path().join("gone").display().to_string(),
+                sha256: None,
+            },
+        ]);
+        // the record starts over
+        assert_eq!(super::manifest(), Manifest::default());
+    }
+}
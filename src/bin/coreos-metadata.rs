@@ -15,8 +15,6 @@
 #[macro_use]
 extern crate clap;
 #[macro_use]
-extern crate error_chain;
-#[macro_use]
 extern crate slog;
 extern crate slog_term;
 extern crate slog_async;
@@ -25,30 +23,153 @@ extern crate slog_scope;
 
 extern crate coreos_metadata;
 
+use std::collections::HashMap;
 use std::env;
 use std::fs::File;
+use std::io;
 use std::io::prelude::*;
-use clap::{Arg, App};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use clap::{Arg, ArgMatches, App, AppSettings, Shell, SubCommand};
 use slog::Drain;
 
-use coreos_metadata::fetch_metadata;
+use coreos_metadata::{detect_provider, fetch_document, fetch_metadata, provider_for_platform, providers, rooted, set_fetch_observer, wait_for_network, AttributeNaming, Capabilities, FirewallFormat, FirewallOptions, MetadataProvider, NetworkUnitOptions, NetworkUnitScope, NewUser, ProviderConfig, Snapshot, SshKeysOptions};
 use coreos_metadata::errors::*;
+use coreos_metadata::audit;
+use coreos_metadata::cancel;
+use coreos_metadata::cloudinit;
+use coreos_metadata::doctor;
+use coreos_metadata::instance;
+use coreos_metadata::maintenance;
+use coreos_metadata::privileges;
+use coreos_metadata::redact;
+use coreos_metadata::sandbox;
+use coreos_metadata::stamp::Stamp;
+use coreos_metadata::termination;
+use coreos_metadata::timing;
 
 const CMDLINE_PATH: &str = "/proc/cmdline";
 const CMDLINE_OEM_FLAG: &str = "coreos.oem.id";
+const CMDLINE_PLATFORM_FLAG: &str = "ignition.platform.id";
+const DEFAULT_SSH_HOST_KEYS_DIR: &str = "/etc/ssh";
+
+/// set when an optional output fails, for --report-partial
+static OPTIONAL_OUTPUT_FAILED: AtomicBool = AtomicBool::new(false);
+
+/// the outputs, by the names they're recorded under in the stamp file
+const OUTPUTS: [&str; 15] = [
+    "attributes",
+    "custom-data",
+    "env-d",
+    "hostname",
+    "hostnamed",
+    "metadata-firewall",
+    "network-json",
+    "network-units",
+    "node-labels",
+    "password",
+    "publish-ssh-host-keys",
+    "resolved-d",
+    "ssh-host-keys",
+    "ssh-keys",
+    "templates",
+];
+
+/// Command is what the invocation should do. Run is the original behaviour
+/// of fetching and writing in one go, and is used when no subcommand is given.
+#[derive(Debug)]
+enum Command {
+    Run,
+    Fetch { out: String },
+    Apply { from: String },
+    CloudConfigUrl { out: String },
+    Doctor,
+    Completions { shell: Shell },
+    Watch { notice_file: String, exec: Option<String>, interval: Duration },
+    ScheduledEvents { out: String, start: Vec<String> },
+    Raw { path: String },
+    GenerateMan,
+    ListProviders,
+}
+
+/// NetworkBackend is the format network configuration is written in
+#[derive(Clone, Copy, Debug)]
+enum NetworkBackend {
+    Networkd,
+    Ifcfg,
+}
 
 #[derive(Debug)]
 struct Config {
-    provider: String,
+    command: Command,
+    provider: Option<String>,
+    provider_config: ProviderConfig,
     attributes_file: Option<String>,
-    ssh_keys_user: Option<String>,
+    attribute_naming: AttributeNaming,
+    /// the attributes that have to be fetched, if not all of them
+    required_attributes: Option<Vec<String>>,
+    environment_d_dir: Option<String>,
+    resolved_dropin_dir: Option<String>,
+    ssh_keys: SshKeysOptions,
+    password_user: Option<String>,
+    ssh_host_keys_dir: Option<String>,
+    publish_ssh_host_keys: bool,
     hostname_file: Option<String>,
+    hostnamed: bool,
     network_units_dir: Option<String>,
+    network_backend: NetworkBackend,
+    network_unit_options: NetworkUnitOptions,
+    network_json_file: Option<String>,
+    custom_data_file: Option<String>,
+    node_labels_file: Option<String>,
+    metadata_firewall_file: Option<String>,
+    metadata_firewall: FirewallOptions,
+    /// template files to the files they are rendered to
+    templates: Vec<(String, String)>,
+    network_wait_timeout: Option<Duration>,
+    network_wait_interface: Option<String>,
+    stamp_file: Option<String>,
+    instance_id_file: Option<String>,
+    /// the staged root filesystem outputs are written to, if not /
+    root: Option<String>,
+    force: bool,
+    optional_outputs: Vec<String>,
+    /// exit with PARTIAL_SUCCESS_EXIT_CODE if some outputs weren't written
+    report_partial: bool,
+    /// fail rather than warn about outputs the provider can't supply
+    strict: bool,
+    /// outputs this process lacks the privileges for, and why
+    unprivileged_outputs: Vec<(&'static str, String)>,
+    /// the user the metadata is fetched as in a sandbox, if it is
+    sandbox_user: Option<String>,
+    audit_manifest_file: Option<String>,
+    /// log where the time went, and keep the unit's status up to date
+    timing: bool,
 }
 
-quick_main!(run);
+fn main() {
+    match run() {
+        Ok(0) => {}
+        Ok(code) => process::exit(code),
+        Err(ref e) => {
+            // this is quick_main!, except that the error chain can contain
+            // response bodies and urls, so it's redacted before it's printed
+            let stderr = &mut io::stderr();
+            let _ = writeln!(stderr, "Error: {}", redact::redact_text(&e.to_string()));
+            for e in e.iter().skip(1) {
+                let _ = writeln!(stderr, "Caused by: {}", redact::redact_text(&e.to_string()));
+            }
+            process::exit(e.class().exit_code());
+        }
+    }
+}
 
-fn run() -> Result<()> {
+/// run returns the exit status: 0, or PARTIAL_SUCCESS_EXIT_CODE with
+/// --report-partial if some outputs weren't written. errors exit with the
+/// code for their class.
+fn run() -> Result<i32> {
     // setup logging
     let decorator = slog_term::TermDecorator::new().build();
     let drain = slog_term::FullFormat::new(decorator).build().fuse();
@@ -58,38 +179,935 @@ fn run() -> Result<()> {
 
     debug!("Logging initialized");
 
+    // stop cleanly on SIGTERM and SIGINT
+    cancel::install_handlers()?;
+
     // initialize program
     let config = init()
         .chain_err(|| "initialization")?;
 
     trace!("cli configuration - {:?}", config);
 
+    if config.timing {
+        set_fetch_observer(Some(Box::new(timing::FetchTimer::default())));
+    }
+
+    match config.command {
+        Command::ListProviders => {
+            for p in providers() {
+                println!("{}\t{}", p.name, p.description);
+            }
+            return Ok(0);
+        }
+        Command::Completions { shell } => {
+            app().gen_completions_to("coreos-metadata", shell, &mut io::stdout());
+            return Ok(0);
+        }
+        Command::GenerateMan => {
+            write_man(&mut io::stdout())
+                .chain_err(|| "writing man page")?;
+            return Ok(0);
+        }
+        Command::CloudConfigUrl { ref out } => {
+            if !cloudinit::fetch_config_url(CMDLINE_PATH, out).chain_err(|| "fetching cloud-config")? {
+                info!("no cloud-config url on the kernel command line");
+            }
+        }
+        Command::Doctor => {
+            let mut findings = doctor::check_provider(config.provider.as_ref().map(String::as_str), &config.provider_config);
+            findings.extend(doctor::check_outputs(&output_paths(&config)));
+            findings.extend(doctor::check_privileges(&outputs(&config), &config.ssh_keys));
+            for finding in &findings {
+                println!("{}", finding);
+            }
+            let problems = findings.iter().filter(|f| f.status == doctor::Status::Problem).count();
+            if problems > 0 {
+                return Err(format!("found {} problem(s)", problems).into());
+            }
+        }
+        Command::Fetch { ref out } => {
+            let metadata = fetch(&config)?;
+            let provider = config.provider.as_ref().map_or("", String::as_str);
+            Snapshot::from_provider(provider, &*metadata).write_to(out)
+                .chain_err(|| "writing metadata snapshot")?;
+        }
+        Command::Raw { ref path } => {
+            let provider = configured_provider(&config)?;
+            wait_for_configured_network(&config)?;
+            let body = fetch_document(provider, &config.provider_config, path)
+                .chain_err(|| format!("fetching {}", path))?
+                .ok_or_else(|| format!("{} not found", path))?;
+            let stdout = io::stdout();
+            let mut stdout = stdout.lock();
+            stdout.write_all(&body)
+                .and_then(|()| stdout.flush())
+                .chain_err(|| "writing document")?;
+        }
+        Command::ScheduledEvents { ref out, ref start } => {
+            let metadata = fetch(&config)?;
+            let events = metadata.maintenance_events()
+                .chain_err(|| "fetching scheduled events")?;
+            // a typo in --start shouldn't leave the events file written
+            // and nothing started
+            let unknown: Vec<&str> = start.iter()
+                .filter(|id| !events.iter().any(|e| &e.id == *id))
+                .map(|id| id.as_str())
+                .collect();
+            if !unknown.is_empty() {
+                return Err(format!("no scheduled event with id {}", unknown.join(", ")).into());
+            }
+            maintenance::write_events(out, &events)?;
+            if !start.is_empty() {
+                metadata.start_maintenance(start)
+                    .chain_err(|| "starting scheduled events")?;
+            }
+        }
+        Command::Watch { ref notice_file, ref exec, interval } => {
+            let metadata = fetch(&config)?;
+            let notice = termination::watch(&*metadata, interval)
+                .chain_err(|| "watching for a termination notice")?;
+            warn!("the instance is going away: {}", notice.reason);
+            termination::write_notice(notice_file, &notice)?;
+            if let Some(ref exec) = *exec {
+                termination::exec(exec, &notice)
+                    .chain_err(|| "running --exec-on-termination")?;
+            }
+        }
+        Command::Apply { ref from } => {
+            warn_unprivileged(&config);
+            let mut stamp = load_stamp(&config)?;
+            if nothing_to_do(&config, &stamp) {
+                info!("all outputs already written by a previous run, nothing to do");
+                return Ok(0);
+            }
+            let metadata = Snapshot::read_from(from)
+                .chain_err(|| "reading metadata snapshot")?;
+            let res = write_outputs(&config, &metadata, &mut stamp);
+            let audited = write_audit(&config);
+            log_timing(&config);
+            res?;
+            audited?;
+        }
+        Command::Run => {
+            warn_unprivileged(&config);
+            // bail out early if a previous run already did everything
+            let mut stamp = load_stamp(&config)?;
+            if nothing_to_do(&config, &stamp) {
+                info!("all outputs already written by a previous run, nothing to do");
+                return Ok(0);
+            }
+            let res = match config.sandbox_user {
+                Some(ref user) => {
+                    let metadata = fetch_sandboxed(&config, user)?;
+                    write_outputs(&config, &metadata, &mut stamp)
+                }
+                None => {
+                    let metadata = fetch(&config)?;
+                    write_outputs(&config, &*metadata, &mut stamp)
+                }
+            };
+            let audited = write_audit(&config);
+            log_timing(&config);
+            res?;
+            audited?;
+        }
+    }
+
+    debug!("Done!");
+
+    let partial = !config.unprivileged_outputs.is_empty() || OPTIONAL_OUTPUT_FAILED.load(Ordering::SeqCst);
+    match config.command {
+        Command::Apply { .. } | Command::Run if config.report_partial && partial => Ok(PARTIAL_SUCCESS_EXIT_CODE),
+        _ => Ok(0),
+    }
+}
+
+fn load_stamp(config: &Config) -> Result<Stamp> {
+    Stamp::load(config.stamp_file.as_ref().map(String::as_str), config.force)
+        .chain_err(|| "loading stamp file")
+}
+
+/// nothing_to_do is whether a previous run already wrote every output. when
+/// the instance id is tracked, the metadata has to be fetched anyway to see
+/// whether it's still the same instance.
+fn nothing_to_do(config: &Config, stamp: &Stamp) -> bool {
+    let outputs: Vec<(&str, String)> = outputs(config).into_iter()
+        .map(|o| (o, output_target(config, o)))
+        .collect();
+    config.instance_id_file.is_none() && stamp.all_done(&outputs)
+}
+
+/// outputs lists the names of the outputs the configuration asks for, as
+/// recorded in the stamp file
+fn outputs(config: &Config) -> Vec<&'static str> {
+    let mut outputs = Vec::new();
+    if config.attributes_file.is_some() { outputs.push("attributes"); }
+    if config.environment_d_dir.is_some() { outputs.push("env-d"); }
+    if config.ssh_keys.user.is_some() || !config.ssh_keys.users_map.is_empty() { outputs.push("ssh-keys"); }
+    if config.password_user.is_some() { outputs.push("password"); }
+    if config.ssh_host_keys_dir.is_some() { outputs.push("ssh-host-keys"); }
+    if config.publish_ssh_host_keys { outputs.push("publish-ssh-host-keys"); }
+    if config.hostname_file.is_some() { outputs.push("hostname"); }
+    if config.hostnamed { outputs.push("hostnamed"); }
+    if config.network_units_dir.is_some() { outputs.push("network-units"); }
+    if config.network_json_file.is_some() { outputs.push("network-json"); }
+    if config.resolved_dropin_dir.is_some() { outputs.push("resolved-d"); }
+    if config.custom_data_file.is_some() { outputs.push("custom-data"); }
+    if config.node_labels_file.is_some() { outputs.push("node-labels"); }
+    if config.metadata_firewall_file.is_some() { outputs.push("metadata-firewall"); }
+    if !config.templates.is_empty() { outputs.push("templates"); }
+    outputs
+}
+
+/// output_target is what the output is written to, or for, as recorded in
+/// the stamp file, so that pointing an output elsewhere writes it again
+fn output_target(config: &Config, output: &str) -> String {
+    let root = config.root.clone().unwrap_or_else(|| "/".to_owned());
+    let path = |path: &Option<String>| path.clone().unwrap_or_default();
+    match output {
+        "attributes" => path(&config.attributes_file),
+        "env-d" => path(&config.environment_d_dir),
+        "ssh-keys" => {
+            let mut users: Vec<String> = config.ssh_keys.users_map.iter()
+                .map(|(from, to)| format!("{}={}", from, to))
+                .collect();
+            users.sort();
+            users.extend(config.ssh_keys.user.clone());
+            format!("{} in {}", users.join(","), root)
+        }
+        "password" => format!("{} in {}", path(&config.password_user), root),
+        "ssh-host-keys" => path(&config.ssh_host_keys_dir),
+        "publish-ssh-host-keys" => config.ssh_host_keys_dir.clone()
+            .unwrap_or_else(|| rooted_str(config.root.as_ref().map(String::as_str), DEFAULT_SSH_HOST_KEYS_DIR)),
+        "hostname" => path(&config.hostname_file),
+        "hostnamed" => root,
+        "network-units" => path(&config.network_units_dir),
+        "network-json" => path(&config.network_json_file),
+        "resolved-d" => path(&config.resolved_dropin_dir),
+        "custom-data" => path(&config.custom_data_file),
+        "node-labels" => path(&config.node_labels_file),
+        "metadata-firewall" => path(&config.metadata_firewall_file),
+        "templates" => config.templates.iter()
+            .map(|&(ref template, ref out)| format!("{}:{}", template, out))
+            .collect::<Vec<_>>()
+            .join(","),
+        _ => String::new(),
+    }
+}
+
+/// output_paths lists the files and directories the configured outputs are
+/// written to
+fn output_paths(config: &Config) -> Vec<(&'static str, String)> {
+    let mut paths = Vec::new();
+    {
+        let mut push = |name, path: &Option<String>| if let Some(ref path) = *path {
+            paths.push((name, path.clone()));
+        };
+        push("attributes", &config.attributes_file);
+        push("env-d", &config.environment_d_dir);
+        push("ssh-host-keys", &config.ssh_host_keys_dir);
+        push("hostname", &config.hostname_file);
+        push("network-units", &config.network_units_dir);
+        push("network-json", &config.network_json_file);
+        push("resolved-d", &config.resolved_dropin_dir);
+        push("custom-data", &config.custom_data_file);
+        push("node-labels", &config.node_labels_file);
+        push("metadata-firewall", &config.metadata_firewall_file);
+        push("stamp-file", &config.stamp_file);
+        push("instance-id-file", &config.instance_id_file);
+        push("audit-manifest", &config.audit_manifest_file);
+    }
+    for &(_, ref out) in &config.templates {
+        paths.push(("templates", out.clone()));
+    }
+    paths
+}
+
+/// warn_unprivileged logs the outputs that will be skipped because the
+/// process isn't privileged enough to write them
+fn warn_unprivileged(config: &Config) {
+    for &(output, ref reason) in &config.unprivileged_outputs {
+        warn!("not running as root, skipping {}: {}", output, reason);
+    }
+}
+
+fn fetch(config: &Config) -> Result<Box<MetadataProvider>> {
+    let provider = configured_provider(config)?;
+
+    // wait for the network if configured to do so
+    timing::phase("network", || wait_for_configured_network(config))?;
+
     // fetch the metadata from the configured provider
-    let metadata = fetch_metadata(&config.provider)
-        .chain_err(|| "fetching metadata from provider")?;
+    timing::phase("fetch", || fetch_metadata(provider, &config.provider_config))
+        .chain_err(|| "fetching metadata from provider")
+}
+
+/// fetch_sandboxed fetches the metadata with this program's `fetch`, run in
+/// a sandbox as the user, and returns the snapshot it took
+fn fetch_sandboxed(config: &Config, user: &str) -> Result<Snapshot> {
+    let provider = configured_provider(config)?;
+    timing::phase("network", || wait_for_configured_network(config))?;
+
+    let exe = env::current_exe()
+        .chain_err(|| "failed to find the path of this program")?;
+    let mut command = process::Command::new(exe);
+    command.arg("fetch").arg("--provider").arg(provider);
+    let provider_config = &config.provider_config;
+    if let Some(ref endpoint) = provider_config.endpoint {
+        command.arg("--metadata-endpoint").arg(endpoint);
+    }
+    if let Some(ref config_drive) = provider_config.config_drive {
+        command.arg("--config-drive").arg(config_drive);
+    }
+    if let Some(ref mapping) = provider_config.custom_http_mapping {
+        command.arg("--custom-http-mapping").arg(mapping);
+    }
+    if let Some(timeout) = provider_config.cloudstack_router_timeout {
+        command.arg("--cloudstack-router-timeout").arg(timeout.as_secs().to_string());
+    }
+    if provider_config.azure_policy_routing {
+        command.arg("--azure-policy-routing");
+    }
+    if provider_config.gce_static_network {
+        command.arg("--gce-static-network");
+    }
+    if let Some(timeout) = provider_config.dns_timeout {
+        command.arg("--dns-timeout").arg(timeout.as_secs().to_string());
+    }
+    if let Some(rate) = provider_config.max_requests_per_second {
+        command.arg("--max-requests-per-second").arg(rate.to_string());
+    }
+    timing::phase("fetch", || sandbox::fetch(command, user))
+        .chain_err(|| "fetching metadata from provider")
+}
+
+fn configured_provider(config: &Config) -> Result<&str> {
+    match config.provider {
+        Some(ref provider) => Ok(provider.as_str()),
+        None => Err("Must set either --provider or --cmdline".into()),
+    }
+}
+
+fn wait_for_configured_network(config: &Config) -> Result<()> {
+    config.network_wait_timeout
+        .map_or(Ok(()), |x| wait_for_network(config.network_wait_interface.as_ref().map(String::as_str), x))
+        .chain_err(|| "waiting for network")
+}
+
+fn write_outputs(config: &Config, metadata: &MetadataProvider, stamp: &mut Stamp) -> Result<()> {
+    check_capabilities(config, metadata)?;
+
+    // check whether this is still the instance the outputs were written for.
+    // if it isn't, everything written so far is stale.
+    let mut extra_attributes = HashMap::new();
+    let mut instance_id = None;
+    if let Some(ref x) = config.instance_id_file {
+        let changed = match metadata.instance_id().chain_err(|| "fetching instance id")? {
+            Some(id) => {
+                let changed = instance::changed(x, &id).chain_err(|| "checking instance id")?;
+                instance_id = Some((x, id));
+                changed
+            }
+            None => {
+                warn!("the provider doesn't supply an instance id, can't detect instance changes");
+                false
+            }
+        };
+        if changed {
+            warn!("booted as a new instance, rewriting all outputs");
+            stamp.reset().chain_err(|| "resetting stamp file")?;
+        }
+        extra_attributes.insert("INSTANCE_CHANGED".to_owned(), if changed { "1" } else { "0" }.to_owned());
+    }
+
+    let required = config.required_attributes.as_ref().map(Vec::as_slice);
 
     // write attributes if configured to do so
-    config.attributes_file
-        .map_or(Ok(()), |x| metadata.write_attributes(x))
-        .chain_err(|| "writing metadata attributes")?;
+    if let Some(ref x) = config.attributes_file {
+        run_output(config, stamp, "attributes", "writing metadata attributes",
+                   || metadata.write_attributes_with(x.clone(), &extra_attributes, &config.attribute_naming, required))?;
+    }
+
+    // write attributes for environment.d if configured to do so
+    if let Some(ref x) = config.environment_d_dir {
+        run_output(config, stamp, "env-d", "writing environment.d attributes",
+                   || metadata.write_environment_d(x.clone(), &extra_attributes, &config.attribute_naming, required))?;
+    }
 
     // write ssh keys if configured to do so
-    config.ssh_keys_user
-        .map_or(Ok(()), |x| metadata.write_ssh_keys(x))
-        .chain_err(|| "writing ssh keys")?;
+    #[cfg(feature = "ssh-keys")]
+    {
+        if config.ssh_keys.user.is_some() || !config.ssh_keys.users_map.is_empty() {
+            run_output(config, stamp, "ssh-keys", "writing ssh keys",
+                       || metadata.write_ssh_keys(&config.ssh_keys))?;
+        }
+    }
+
+    // set the password if configured to do so. this happens after the ssh
+    // keys are written, which may create the user.
+    if let Some(ref x) = config.password_user {
+        run_output(config, stamp, "password", "setting password",
+                   || metadata.set_password(x.clone(), config.ssh_keys.root.as_ref().map(PathBuf::as_path)))?;
+    }
+
+    // write ssh host keys if configured to do so
+    if let Some(ref x) = config.ssh_host_keys_dir {
+        run_output(config, stamp, "ssh-host-keys", "writing ssh host keys",
+                   || metadata.write_ssh_host_keys(x.clone()))?;
+    }
+
+    // publish ssh host keys if configured to do so. this happens after they
+    // are written so that provider-supplied keys are the ones published.
+    if config.publish_ssh_host_keys {
+        let dir = config.ssh_host_keys_dir.clone()
+            .unwrap_or_else(|| rooted_str(config.root.as_ref().map(String::as_str), DEFAULT_SSH_HOST_KEYS_DIR));
+        run_output(config, stamp, "publish-ssh-host-keys", "publishing ssh host keys",
+                   || metadata.publish_ssh_host_keys_from(dir))?;
+    }
 
     // write hostname if configured to do so
-    config.hostname_file
-        .map_or(Ok(()), |x| metadata.write_hostname(x))
-        .chain_err(|| "writing hostname")?;
+    if let Some(ref x) = config.hostname_file {
+        run_output(config, stamp, "hostname", "writing hostname",
+                   || metadata.write_hostname(x.clone()))?;
+    }
+
+    // set the hostname through hostnamed if configured to do so
+    if config.hostnamed {
+        run_output(config, stamp, "hostnamed", "setting hostname",
+                   || metadata.set_hostnamed(config.root.as_ref().map(Path::new)))?;
+    }
 
     // write network units if configured to do so
-    config.network_units_dir
-        .map_or(Ok(()), |x| metadata.write_network_units(x))
-        .chain_err(|| "writing network units")?;
+    if let Some(ref x) = config.network_units_dir {
+        run_output(config, stamp, "network-units", "writing network units",
+                   || match config.network_backend {
+                       NetworkBackend::Networkd => metadata.write_network_units_with(x.clone(), &config.network_unit_options),
+                       NetworkBackend::Ifcfg => metadata.write_ifcfg(x.clone()),
+                   })?;
+    }
 
-    debug!("Done!");
+    // write network state as json if configured to do so
+    if let Some(ref x) = config.network_json_file {
+        run_output(config, stamp, "network-json", "writing network json",
+                   || metadata.write_network_json(x.clone()))?;
+    }
+
+    // write the search domains for systemd-resolved if configured to do so
+    if let Some(ref x) = config.resolved_dropin_dir {
+        run_output(config, stamp, "resolved-d", "writing resolved drop-in",
+                   || metadata.write_resolved_dropin(x.clone()))?;
+    }
+
+    // write custom data if configured to do so
+    if let Some(ref x) = config.custom_data_file {
+        run_output(config, stamp, "custom-data", "writing custom data",
+                   || metadata.write_custom_data(x.clone()))?;
+    }
+
+    // write node labels for kubelet if configured to do so
+    if let Some(ref x) = config.node_labels_file {
+        run_output(config, stamp, "node-labels", "writing node labels",
+                   || metadata.write_node_labels(x.clone()))?;
+    }
 
+    // write firewall rules for the metadata service if configured to do so
+    if let Some(ref x) = config.metadata_firewall_file {
+        run_output(config, stamp, "metadata-firewall", "writing metadata firewall rules",
+                   || metadata.write_metadata_firewall(x.clone(), &config.metadata_firewall))?;
+    }
+
+    // render templates with the attributes if configured to do so
+    if !config.templates.is_empty() {
+        run_output(config, stamp, "templates", "rendering templates",
+                   || metadata.render_templates(&config.templates, &extra_attributes, &config.attribute_naming, required))?;
+    }
+
+    // the new instance id is only recorded once everything is written for it
+    if let Some((path, id)) = instance_id {
+        instance::record(path, &id).chain_err(|| "recording instance id")?;
+    }
+
+    Ok(())
+}
+
+/// write_audit logs what the outputs changed on the host, and writes it to
+/// the audit manifest if there is one. it's done even when an output failed,
+/// since the ones before it were still written.
+fn write_audit(config: &Config) -> Result<()> {
+    let manifest = audit::manifest();
+    manifest.log();
+    match config.audit_manifest_file {
+        Some(ref path) => manifest.write_to(path).chain_err(|| "writing audit manifest"),
+        None => Ok(()),
+    }
+}
+
+/// check_capabilities warns about the outputs asked for that the provider
+/// can't supply anything for, which would otherwise quietly come out empty.
+/// with --strict, they're an error.
+fn check_capabilities(config: &Config, metadata: &MetadataProvider) -> Result<()> {
+    let capabilities = metadata.capabilities();
+    let unsupported: Vec<&str> = outputs(config).into_iter()
+        .filter(|&output| !supports(capabilities, output))
+        .collect();
+    if unsupported.is_empty() {
+        return Ok(());
+    }
+    let message = format!("the {} provider can't supply {}",
+                          config.provider.as_ref().map_or("configured", String::as_str), unsupported.join(", "));
+    if config.strict {
+        return Err(message.into());
+    }
+    warn!("{}", message);
+    Ok(())
+}
+
+/// supports is whether the provider can supply what the output is written
+/// from. outputs that aren't mentioned are either always supported or only
+/// written when there's something to write.
+fn supports(capabilities: Capabilities, output: &str) -> bool {
+    match output {
+        "attributes" | "env-d" | "templates" => capabilities.attributes,
+        "ssh-keys" => capabilities.ssh_keys,
+        "hostname" | "hostnamed" => capabilities.hostname,
+        "network-units" | "network-json" => capabilities.network,
+        _ => true,
+    }
+}
+
+/// run_output writes one output, unless the stamp file says it already was.
+/// a failure to write an output that was marked optional is only a warning,
+/// so that it doesn't hold up the rest.
+fn run_output<F>(config: &Config, stamp: &mut Stamp, output: &str, context: &str, f: F) -> Result<()>
+    where F: FnOnce() -> Result<()>
+{
+    // skipped outputs aren't recorded, so a later run as root writes them
+    if config.unprivileged_outputs.iter().any(|&(o, _)| o == output) {
+        return Ok(());
+    }
+    // a signal stops the run between outputs, even optional ones
+    cancel::check()?;
+    let target = output_target(config, output);
+    let optional = config.optional_outputs.iter().any(|o| o == output);
+    match timing::phase(output, || stamp.run(output, &target, f)).chain_err(|| context.to_owned()) {
+        Err(ref e) if optional && !cancel::is_cancelled() => {
+            OPTIONAL_OUTPUT_FAILED.store(true, Ordering::SeqCst);
+            let causes: Vec<String> = e.iter().map(|c| c.to_string()).collect();
+            warn!("{} is optional, ignoring the error: {}", output, redact::redact_text(&causes.join(": ")));
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// log_timing logs where the time of the run went, and leaves it as the
+/// unit's status, if asked to
+fn log_timing(config: &Config) {
+    if config.timing {
+        let summary = timing::summary();
+        info!("timing: {}", summary);
+        timing::status(&summary);
+    }
+}
+
+/// provider_args are the arguments that select and configure the provider,
+/// used by `fetch` and the legacy flat invocation
+fn provider_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("azure-policy-routing")
+            .long("azure-policy-routing")
+            .help("Route traffic from secondary Azure NICs' addresses through those NICs"),
+        Arg::with_name("cloudstack-router-timeout")
+            .long("cloudstack-router-timeout")
+            .help("Look for the CloudStack virtual router for up to this many seconds")
+            .takes_value(true),
+        Arg::with_name("cmdline")
+            .long("cmdline")
+            .help("Read the cloud provider from the kernel cmdline"),
+        Arg::with_name("cmdline-key")
+            .long("cmdline-key")
+            .help("The kernel cmdline parameter to read the provider from with --cmdline, instead of coreos.oem.id and ignition.platform.id")
+            .requires("cmdline")
+            .takes_value(true),
+        Arg::with_name("config-drive")
+            .long("config-drive")
+            .help("The config drive to read, as a device path, LABEL=<label>, or UUID=<uuid>")
+            .takes_value(true),
+        Arg::with_name("custom-http-mapping")
+            .long("custom-http-mapping")
+            .help("The mapping file for the custom-http provider")
+            .takes_value(true),
+        Arg::with_name("gce-static-network")
+            .long("gce-static-network")
+            .help("Write network units with static addresses and alias IP ranges for the GCE instance's NICs, instead of leaving them to DHCP"),
+        Arg::with_name("dns-timeout")
+            .long("dns-timeout")
+            .help("Give up resolving the metadata service's host name after this many seconds")
+            .takes_value(true),
+        Arg::with_name("max-requests-per-second")
+            .long("max-requests-per-second")
+            .help("Space out requests to the metadata service to at most this many a second")
+            .takes_value(true),
+        Arg::with_name("metadata-endpoint")
+            .long("metadata-endpoint")
+            .env("COREOS_METADATA_ENDPOINT")
+            .help("Override the base URL of the provider's metadata service")
+            .takes_value(true),
+        Arg::with_name("provider")
+            .long("provider")
+            .help("The name of the cloud provider")
+            .takes_value(true),
+        Arg::with_name("wait-for-network-interface")
+            .long("wait-for-network-interface")
+            .help("The interface to wait for an address on (default: any)")
+            .takes_value(true),
+        Arg::with_name("wait-for-network-timeout")
+            .long("wait-for-network-timeout")
+            .help("Wait up to this many seconds for an address before fetching metadata")
+            .takes_value(true),
+    ]
+}
+
+/// output_args are the arguments that select what gets written, used by
+/// `apply` and the legacy flat invocation
+fn output_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    let mut args = vec![
+        Arg::with_name("afterburn-attributes")
+            .long("afterburn-attributes")
+            .help("Also write every attribute with its AFTERBURN_ name"),
+        Arg::with_name("attribute-renames")
+            .long("attribute-renames")
+            .help("A file of <name>=<new name> lines naming the attributes to write under other names")
+            .takes_value(true),
+        Arg::with_name("attributes")
+            .long("attributes")
+            .help("The file into which the metadata attributes are written")
+            .takes_value(true),
+        Arg::with_name("audit-manifest")
+            .long("audit-manifest")
+            .help("The file into which a record of every file written and ssh key installed is written")
+            .takes_value(true),
+        Arg::with_name("custom-data")
+            .long("custom-data")
+            .help("The file into which the provider custom data is written")
+            .takes_value(true),
+        Arg::with_name("env-d")
+            .long("env-d")
+            .help("The environment.d directory into which the metadata attributes are written")
+            .takes_value(true),
+        Arg::with_name("force")
+            .long("force")
+            .help("Write all outputs, even if the stamp file says they were already written"),
+        Arg::with_name("hostname")
+            .long("hostname")
+            .help("The file into which the hostname should be written")
+            .takes_value(true),
+        Arg::with_name("hostnamed")
+            .long("hostnamed")
+            .help("Set the transient and pretty hostname through systemd-hostnamed"),
+        Arg::with_name("instance-id-file")
+            .long("instance-id-file")
+            .help("Record the instance id in this file, and rewrite all outputs when it changes")
+            .takes_value(true),
+        Arg::with_name("metadata-firewall")
+            .long("metadata-firewall")
+            .help("The file into which firewall rules that keep the metadata service to root are written")
+            .takes_value(true),
+        Arg::with_name("metadata-firewall-format")
+            .long("metadata-firewall-format")
+            .help("The format of the rules written to --metadata-firewall")
+            .possible_values(&["nftables", "iptables"])
+            .default_value("nftables")
+            .takes_value(true),
+        Arg::with_name("metadata-firewall-group")
+            .long("metadata-firewall-group")
+            .help("A group whose processes may reach the metadata service as well as root's")
+            .multiple(true)
+            .number_of_values(1)
+            .takes_value(true),
+        Arg::with_name("network-backend")
+            .long("network-backend")
+            .help("The format of the files written to --network-units")
+            .possible_values(&["networkd", "ifcfg"])
+            .default_value("networkd")
+            .takes_value(true),
+        Arg::with_name("network-json")
+            .long("network-json")
+            .help("The file into which the network configuration is written as JSON")
+            .takes_value(true),
+        Arg::with_name("network-dropins")
+            .long("network-dropins")
+            .help("Extend existing network units that match an interface with drop-ins instead of overriding them"),
+        Arg::with_name("network-link-timeout")
+            .long("network-link-timeout")
+            .help("Wait this many seconds for the interfaces the network units are for to show up, and skip the ones that don't")
+            .takes_value(true),
+        Arg::with_name("network-match-vfs")
+            .long("network-match-vfs")
+            .help("Match SR-IOV virtual functions on their permanent MAC address (needs systemd 245)"),
+        Arg::with_name("network-unit-prefix")
+            .long("network-unit-prefix")
+            .help("The numeric prefix of the generated network units")
+            .takes_value(true),
+        Arg::with_name("network-units")
+            .long("network-units")
+            .help("The directory into which network units are written")
+            .takes_value(true),
+        Arg::with_name("network-units-scope")
+            .long("network-units-scope")
+            .help("Whether network units are runtime (/run/systemd/network) or persistent (/etc/systemd/network)")
+            .possible_values(&["runtime", "persistent"])
+            .takes_value(true),
+        Arg::with_name("node-labels-file")
+            .long("node-labels-file")
+            .help("The file into which the provider's tags are written as Kubernetes node labels")
+            .takes_value(true),
+        Arg::with_name("optional")
+            .long("optional")
+            .help("Only warn if writing the given outputs fails, instead of failing")
+            .possible_values(&OUTPUTS)
+            .multiple(true)
+            .use_delimiter(true)
+            .number_of_values(1)
+            .takes_value(true),
+        Arg::with_name("password")
+            .long("password")
+            .help("Set the password supplied by the provider for the given user")
+            .takes_value(true),
+        Arg::with_name("publish-ssh-host-keys")
+            .long("publish-ssh-host-keys")
+            .help("Publish the SSH host key fingerprints to the provider"),
+        Arg::with_name("report-partial")
+            .long("report-partial")
+            .help("Exit with 7 if optional outputs failed or outputs were skipped for lack of privileges"),
+        Arg::with_name("require")
+            .long("require")
+            .help("Only fail the attribute outputs if this attribute (e.g. COREOS_EC2_IPV4_LOCAL) can't be fetched")
+            .multiple(true)
+            .number_of_values(1)
+            .takes_value(true),
+        Arg::with_name("resolved-d")
+            .long("resolved-d")
+            .help("The resolved.conf.d directory into which the provider's DNS search domains are written")
+            .takes_value(true),
+        Arg::with_name("root")
+            .long("root")
+            .help("Write every output under this directory, e.g. a root filesystem being staged")
+            .takes_value(true),
+        Arg::with_name("sandbox-fetch")
+            .long("sandbox-fetch")
+            .help("Fetch the metadata in a child process that runs as an unprivileged user, with dangerous syscalls filtered"),
+        Arg::with_name("sandbox-user")
+            .long("sandbox-user")
+            .help("The user the sandboxed fetch runs as (default: nobody)")
+            .requires("sandbox-fetch")
+            .takes_value(true),
+        Arg::with_name("ssh-host-keys-dir")
+            .long("ssh-host-keys-dir")
+            .help("The directory into which provider-supplied SSH host keys are written")
+            .takes_value(true),
+        Arg::with_name("stamp-file")
+            .long("stamp-file")
+            .help("Record successfully written outputs in this file, and skip them on later runs")
+            .takes_value(true),
+        Arg::with_name("strict")
+            .long("strict")
+            .help("Fail if the provider can't supply an output that was asked for, instead of warning"),
+        Arg::with_name("template")
+            .long("template")
+            .help("Render a template file with the attributes, as <template>:<output>")
+            .multiple(true)
+            .number_of_values(1)
+            .takes_value(true),
+        Arg::with_name("timing")
+            .long("timing")
+            .help("Log how long each phase and the slowest requests took, and report the running phase as the unit's status"),
+        Arg::with_name("validate-network-units")
+            .long("validate-network-units")
+            .help("Refuse to write network units that match the same interface, assign an address twice, or use an undefined bond"),
+    ];
+    // builds without ssh-keys are meant for the initramfs, where there is no
+    // user database to write authorized keys for
+    if cfg!(feature = "ssh-keys") {
+        args.push(Arg::with_name("ssh-keys")
+                  .long("ssh-keys")
+                  .help("Update SSH keys for the given user")
+                  .takes_value(true));
+        args.push(Arg::with_name("create-user")
+                  .long("create-user")
+                  .help("Create users that SSH keys are written for if they don't exist"));
+        args.push(Arg::with_name("create-user-groups")
+                  .long("create-user-groups")
+                  .help("Supplementary groups of users created with --create-user")
+                  .requires("create-user")
+                  .use_delimiter(true)
+                  .takes_value(true));
+        args.push(Arg::with_name("create-user-shell")
+                  .long("create-user-shell")
+                  .help("Login shell of users created with --create-user")
+                  .requires("create-user")
+                  .takes_value(true));
+        args.push(Arg::with_name("create-user-uid-range")
+                  .long("create-user-uid-range")
+                  .help("Range to pick the UIDs of users created with --create-user from, as <min>-<max>")
+                  .requires("create-user")
+                  .takes_value(true));
+        args.push(Arg::with_name("ssh-keys-lock-timeout")
+                  .long("ssh-keys-lock-timeout")
+                  .help("Give up writing a user's SSH keys after waiting this many seconds for another update of them to finish")
+                  .takes_value(true));
+        args.push(Arg::with_name("ssh-keys-map")
+                  .long("ssh-keys-map")
+                  .help("Write SSH keys for a platform user to a local user, as <platform-user>=<local-user>")
+                  .multiple(true)
+                  .number_of_values(1)
+                  .takes_value(true));
+        args.push(Arg::with_name("ssh-keys-sync")
+                  .long("ssh-keys-sync")
+                  .help("Remove the SSH keys written before for users the provider no longer has any keys for"));
+    }
+    args
+}
+
+/// subcommands are the subcommands of `app`
+fn subcommands<'a, 'b>() -> Vec<App<'a, 'b>> {
+    vec![
+        SubCommand::with_name("apply")
+            .about("Write outputs from a snapshot saved by `fetch`")
+            .arg(Arg::with_name("from")
+                 .long("from")
+                 .help("The snapshot file to read")
+                 .takes_value(true)
+                 .required(true))
+            .args(&output_args()),
+        SubCommand::with_name("cloud-config-url")
+            .about("Fetch the cloud-config named by cloud-config-url= or url= on the kernel command line")
+            .arg(Arg::with_name("out")
+                 .long("out")
+                 .help("The file into which the cloud-config is written")
+                 .takes_value(true)
+                 .required(true)),
+        SubCommand::with_name("completions")
+            .about("Print shell completions for coreos-metadata")
+            .arg(Arg::with_name("shell")
+                 .help("The shell to complete in")
+                 .possible_values(&["bash", "fish", "zsh"])
+                 .required(true)),
+        SubCommand::with_name("doctor")
+            .about("Check what fetching the metadata and writing the outputs needs, and print what's missing")
+            .args(&provider_args())
+            .args(&output_args()),
+        SubCommand::with_name("fetch")
+            .about("Fetch metadata from the provider and save a snapshot of it")
+            .args(&provider_args())
+            .arg(Arg::with_name("out")
+                 .long("out")
+                 .help("The file into which the snapshot is written")
+                 .takes_value(true)
+                 .required(true)),
+        SubCommand::with_name("raw")
+            .about("Fetch one document from the provider's metadata service, as the provider would, and print it")
+            .args(&provider_args())
+            .arg(Arg::with_name("path")
+                 .long("path")
+                 .help("The path of the document from the root of the metadata service, e.g. /latest/meta-data/instance-id")
+                 .takes_value(true)
+                 .required(true)),
+        SubCommand::with_name("scheduled-events")
+            .about("Write the maintenance the platform has scheduled for the instance, and start it early")
+            .args(&provider_args())
+            .arg(Arg::with_name("out")
+                 .long("out")
+                 .help("The file into which the events are written as attributes")
+                 .takes_value(true)
+                 .required(true))
+            .arg(Arg::with_name("start")
+                 .long("start")
+                 .help("Start the event with this id now, e.g. once the node is drained")
+                 .takes_value(true)
+                 .multiple(true)
+                 .number_of_values(1)),
+        SubCommand::with_name("watch")
+            .about("Wait for the provider's notice that the instance is being preempted or terminated")
+            .args(&provider_args())
+            .arg(Arg::with_name("exec-on-termination")
+                 .long("exec-on-termination")
+                 .help("A shell command to run once notice is given, with the notice in its environment")
+                 .takes_value(true))
+            .arg(Arg::with_name("poll-interval")
+                 .long("poll-interval")
+                 .help("Check for a notice every this many seconds")
+                 .takes_value(true))
+            .arg(Arg::with_name("termination-notice-file")
+                 .long("termination-notice-file")
+                 .help("The file into which the notice is written as attributes")
+                 .takes_value(true)
+                 .required(true)),
+    ]
+}
+
+// WARNING: if additional arguments are added, one of two things needs to
+// happen:
+//   1. don't add a shortflag
+//   2. modify the preprocessing logic in init to be smarter about where it
+//      prepends the hyphens
+// the preprocessing will probably convert any short flags it finds into
+// long ones
+fn app<'a, 'b>() -> App<'a, 'b> {
+    App::new("coreos-metadata")
+        .version(crate_version!())
+        .about("Fetch metadata from the cloud provider and write it out")
+        // the flat invocation's flags are defined here as well as on the
+        // subcommands, and would be ignored here if given before one
+        .setting(AppSettings::ArgsNegateSubcommands)
+        .arg(Arg::with_name("generate-man")
+             .long("generate-man")
+             .help("Print the man page and exit"))
+        .arg(Arg::with_name("list-providers")
+             .long("list-providers")
+             .help("List the providers compiled into this build and exit"))
+        .args(&provider_args())
+        .args(&output_args())
+        .subcommands(subcommands())
+}
+
+/// roff_escape escapes help text for a man page, where it's shown as is
+fn roff_escape(text: &str) -> String {
+    text.lines()
+        .map(|line| {
+            let line = line.replace('\\', "\\e");
+            if line.starts_with('.') || line.starts_with('\'') {
+                format!("\\&{}", line)
+            } else {
+                line
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// write_man writes a man page made of the help of the command and each of
+/// its subcommands
+fn write_man<W: Write>(out: &mut W) -> Result<()> {
+    let mut sections = Vec::new();
+    let mut help = Vec::new();
+    app().write_help(&mut help)
+        .chain_err(|| "failed to render help")?;
+    sections.push(("DESCRIPTION".to_owned(), help));
+    for sub in subcommands() {
+        let name = sub.get_name().to_owned();
+        let mut help = Vec::new();
+        sub.bin_name(format!("coreos-metadata {}", name))
+            .write_help(&mut help)
+            .chain_err(|| format!("failed to render help for {}", name))?;
+        sections.push((format!("COREOS-METADATA {}", name.to_uppercase()), help));
+    }
+
+    writeln!(out, ".TH COREOS-METADATA 1 \"\" \"coreos-metadata {}\"", crate_version!())?;
+    writeln!(out, ".SH NAME\ncoreos-metadata \\- fetch metadata from the cloud provider and write it out")?;
+    for (title, help) in sections {
+        writeln!(out, ".SH \"{}\"\n.nf\n{}\n.fi", title, roff_escape(&String::from_utf8_lossy(&help)))?;
+    }
     Ok(())
 }
 
@@ -107,58 +1125,260 @@ fn init() -> Result<Config> {
     });
 
     // setup cli
-    // WARNING: if additional arguments are added, one of two things needs to
-    // happen:
-    //   1. don't add a shortflag
-    //   2. modify the preprocessing logic above to be smarter about where it
-    //      prepends the hyphens
-    // the preprocessing will probably convert any short flags it finds into
-    // long ones
-    let matches = App::new("coreos-metadata")
-        .version(crate_version!())
-        .arg(Arg::with_name("attributes")
-             .long("attributes")
-             .help("The file into which the metadata attributes are written")
-             .takes_value(true))
-        .arg(Arg::with_name("cmdline")
-             .long("cmdline")
-             .help("Read the cloud provider from the kernel cmdline"))
-        .arg(Arg::with_name("hostname")
-             .long("hostname")
-             .help("The file into which the hostname should be written")
-             .takes_value(true))
-        .arg(Arg::with_name("network-units")
-             .long("network-units")
-             .help("The directory into which network units are written")
-             .takes_value(true))
-        .arg(Arg::with_name("provider")
-             .long("provider")
-             .help("The name of the cloud provider")
-             .takes_value(true))
-        .arg(Arg::with_name("ssh-keys")
-             .long("ssh-keys")
-             .help("Update SSH keys for the given user")
-             .takes_value(true))
+    let matches = app()
         .get_matches_from(args);
 
-    // return configuration
-    Ok(Config {
-        provider: match matches.value_of("provider") {
-            Some(provider) => String::from(provider),
-            None => if matches.is_present("cmdline") {
-                get_oem()?
-            } else {
-                return Err("Must set either --provider or --cmdline".into());
+    let (command, matches) = match matches.subcommand() {
+        ("apply", Some(sub)) => (Command::Apply { from: sub.value_of("from").unwrap().to_owned() }, sub),
+        ("cloud-config-url", Some(sub)) => (Command::CloudConfigUrl { out: sub.value_of("out").unwrap().to_owned() }, sub),
+        ("completions", Some(sub)) => (Command::Completions { shell: sub.value_of("shell").unwrap().parse().unwrap() }, sub),
+        ("doctor", Some(sub)) => (Command::Doctor, sub),
+        ("fetch", Some(sub)) => (Command::Fetch { out: sub.value_of("out").unwrap().to_owned() }, sub),
+        ("raw", Some(sub)) => (Command::Raw { path: sub.value_of("path").unwrap().to_owned() }, sub),
+        ("scheduled-events", Some(sub)) => (Command::ScheduledEvents {
+            out: sub.value_of("out").unwrap().to_owned(),
+            start: sub.values_of("start").map_or_else(Vec::new, |v| v.map(String::from).collect()),
+        }, sub),
+        ("watch", Some(sub)) => {
+            let interval = match sub.value_of("poll-interval") {
+                Some(secs) => Duration::from_secs(secs.parse()
+                    .chain_err(|| format!("invalid poll interval: {}", secs))?),
+                None => Duration::from_secs(termination::DEFAULT_POLL_INTERVAL_SECS),
+            };
+            (Command::Watch {
+                notice_file: sub.value_of("termination-notice-file").unwrap().to_owned(),
+                exec: sub.value_of("exec-on-termination").map(String::from),
+                interval,
+            }, sub)
+        }
+        _ if matches.is_present("generate-man") => (Command::GenerateMan, &matches),
+        _ if matches.is_present("list-providers") => (Command::ListProviders, &matches),
+        _ => (Command::Run, &matches),
+    };
+
+    // detection happens before the config is put together
+    timing::set_status_updates(matches.is_present("timing"));
+
+    // the password is acknowledged, and host keys published, to the provider
+    // itself, which isn't there when writing from a snapshot
+    let from_snapshot = match command {
+        Command::Apply { .. } => true,
+        _ => matches.is_present("sandbox-fetch"),
+    };
+    if from_snapshot {
+        for arg in &["password", "publish-ssh-host-keys"] {
+            if matches.is_present(arg) {
+                return Err(format!("--{} can't be used with apply or --sandbox-fetch", arg).into());
+            }
+        }
+    }
+
+    let network_wait_timeout = secs_arg(matches, "wait-for-network-timeout", "network wait timeout")?;
+
+    let cloudstack_router_timeout = secs_arg(matches, "cloudstack-router-timeout", "virtual router timeout")?;
+
+    let dns_timeout = secs_arg(matches, "dns-timeout", "dns timeout")?;
+
+    let max_requests_per_second = match matches.value_of("max-requests-per-second") {
+        Some(rate) => Some(rate.parse()
+            .chain_err(|| format!("invalid request rate: {}", rate))?),
+        None => None,
+    };
+
+    let network_link_timeout = secs_arg(matches, "network-link-timeout", "network link timeout")?;
+
+    let network_unit_prefix = match matches.value_of("network-unit-prefix") {
+        Some(prefix) => Some(prefix.parse()
+            .chain_err(|| format!("invalid network unit prefix: {}", prefix))?),
+        None => None,
+    };
+
+    let attribute_naming = AttributeNaming {
+        afterburn: matches.is_present("afterburn-attributes"),
+        renames: match matches.value_of("attribute-renames") {
+            Some(path) => AttributeNaming::read_renames(path)?,
+            None => HashMap::new(),
+        },
+    };
+
+    // with --root, every output is written under it
+    let root = matches.value_of("root");
+    let path = |name| matches.value_of(name).map(|p| rooted_str(root, p));
+
+    let mut templates = Vec::new();
+    if let Some(values) = matches.values_of("template") {
+        for value in values {
+            let mut parts = value.splitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some(template), Some(out)) if !template.is_empty() && !out.is_empty() =>
+                    templates.push((template.to_owned(), rooted_str(root, out))),
+                _ => return Err(format!("invalid template, expected <template>:<output>: {}", value).into()),
             }
+        }
+    }
+
+    let mut ssh_keys_map = HashMap::new();
+    for mapping in matches.values_of("ssh-keys-map").into_iter().flat_map(|v| v) {
+        let mut parts = mapping.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(platform_user), Some(local_user)) if !platform_user.is_empty() && !local_user.is_empty() => {
+                ssh_keys_map.insert(platform_user.to_owned(), local_user.to_owned());
+            }
+            _ => return Err(format!("invalid ssh keys mapping: {}", mapping).into()),
+        }
+    }
+
+    let lock_timeout = secs_arg(matches, "ssh-keys-lock-timeout", "ssh keys lock timeout")?;
+
+    let create_user = if matches.is_present("create-user") {
+        let uid_range = match matches.value_of("create-user-uid-range") {
+            Some(range) => Some(parse_uid_range(range)
+                .chain_err(|| format!("invalid uid range: {}", range))?),
+            None => None,
+        };
+        Some(NewUser {
+            groups: matches.values_of("create-user-groups")
+                .map_or_else(Vec::new, |values| values.map(String::from).collect()),
+            shell: matches.value_of("create-user-shell").map(String::from),
+            uid_range,
+        })
+    } else {
+        None
+    };
+
+    let provider = match matches.value_of("provider") {
+        Some(provider) => Some(String::from(provider)),
+        None => if matches.is_present("cmdline") {
+            Some(timing::phase("detection", || get_oem(matches.value_of("cmdline-key")))?)
+        } else {
+            None
+        }
+    };
+
+    let network_units_scope = match matches.value_of("network-units-scope") {
+        Some("persistent") => Some(NetworkUnitScope::Persistent),
+        Some(_) => Some(NetworkUnitScope::Runtime),
+        None => None,
+    };
+    // the scope's directory is used unless the units are written elsewhere
+    let network_units_dir = path("network-units")
+        .or_else(|| network_units_scope.map(|scope| rooted_str(root, scope.dir())));
+
+    // return configuration
+    let mut config = Config {
+        command,
+        provider,
+        provider_config: ProviderConfig {
+            endpoint: matches.value_of("metadata-endpoint").map(String::from),
+            config_drive: matches.value_of("config-drive").map(String::from),
+            custom_http_mapping: matches.value_of("custom-http-mapping").map(String::from),
+            cloudstack_router_timeout,
+            azure_policy_routing: matches.is_present("azure-policy-routing"),
+            gce_static_network: matches.is_present("gce-static-network"),
+            dns_timeout,
+            max_requests_per_second,
+        },
+        attributes_file: path("attributes"),
+        attribute_naming,
+        required_attributes: matches.values_of("require")
+            .map(|values| values.map(String::from).collect()),
+        environment_d_dir: path("env-d"),
+        resolved_dropin_dir: path("resolved-d"),
+        ssh_keys: SshKeysOptions {
+            user: matches.value_of("ssh-keys").map(String::from),
+            users_map: ssh_keys_map,
+            create_user,
+            root: root.map(PathBuf::from),
+            lock_timeout,
+            sync: matches.is_present("ssh-keys-sync"),
+        },
+        password_user: matches.value_of("password").map(String::from),
+        ssh_host_keys_dir: path("ssh-host-keys-dir"),
+        publish_ssh_host_keys: matches.is_present("publish-ssh-host-keys"),
+        hostname_file: path("hostname"),
+        hostnamed: matches.is_present("hostnamed"),
+        network_units_dir,
+        network_backend: match matches.value_of("network-backend") {
+            Some("ifcfg") => NetworkBackend::Ifcfg,
+            _ => NetworkBackend::Networkd,
         },
-        attributes_file: matches.value_of("attributes").map(String::from),
-        ssh_keys_user: matches.value_of("ssh-keys").map(String::from),
-        hostname_file: matches.value_of("hostname").map(String::from),
-        network_units_dir: matches.value_of("network-units").map(String::from),
-    })
+        network_unit_options: NetworkUnitOptions {
+            prefix: network_unit_prefix,
+            dropins: matches.is_present("network-dropins"),
+            root: root.map(PathBuf::from),
+            validate: matches.is_present("validate-network-units"),
+            scope: network_units_scope.unwrap_or_default(),
+            link_timeout: network_link_timeout,
+            match_virtual_functions: matches.is_present("network-match-vfs"),
+        },
+        network_json_file: path("network-json"),
+        custom_data_file: path("custom-data"),
+        node_labels_file: path("node-labels-file"),
+        metadata_firewall_file: path("metadata-firewall"),
+        metadata_firewall: FirewallOptions {
+            format: match matches.value_of("metadata-firewall-format") {
+                Some("iptables") => FirewallFormat::Iptables,
+                _ => FirewallFormat::Nftables,
+            },
+            groups: matches.values_of("metadata-firewall-group")
+                .map_or_else(Vec::new, |values| values.map(String::from).collect()),
+        },
+        templates,
+        network_wait_timeout,
+        network_wait_interface: matches.value_of("wait-for-network-interface").map(String::from),
+        stamp_file: path("stamp-file"),
+        instance_id_file: path("instance-id-file"),
+        root: root.map(String::from),
+        force: matches.is_present("force"),
+        optional_outputs: matches.values_of("optional")
+            .map_or_else(Vec::new, |values| values.map(String::from).collect()),
+        report_partial: matches.is_present("report-partial"),
+        strict: matches.is_present("strict"),
+        unprivileged_outputs: vec![],
+        sandbox_user: if matches.is_present("sandbox-fetch") {
+            Some(matches.value_of("sandbox-user").unwrap_or(sandbox::DEFAULT_USER).to_owned())
+        } else {
+            None
+        },
+        audit_manifest_file: path("audit-manifest"),
+        timing: matches.is_present("timing"),
+    };
+    config.unprivileged_outputs = outputs(&config).into_iter()
+        .filter_map(|o| privileges::missing_privileges(o, &config.ssh_keys).map(|reason| (o, reason)))
+        .collect();
+    Ok(config)
+}
+
+/// rooted_str is rooted for the paths in the config, which are strings
+fn rooted_str(root: Option<&str>, path: &str) -> String {
+    rooted(root.map(Path::new), path).to_string_lossy().into_owned()
 }
 
-fn get_oem() -> Result<String> {
+/// secs_arg parses the value of an argument that is a number of seconds
+fn secs_arg(matches: &ArgMatches, name: &str, what: &str) -> Result<Option<Duration>> {
+    match matches.value_of(name) {
+        Some(secs) => Ok(Some(Duration::from_secs(secs.parse()
+            .chain_err(|| format!("invalid {}: {}", what, secs))?))),
+        None => Ok(None),
+    }
+}
+
+/// parse_uid_range parses a `<min>-<max>` range of uids
+fn parse_uid_range(range: &str) -> Result<(u32, u32)> {
+    let mut parts = range.splitn(2, '-');
+    let min: u32 = parts.next().unwrap_or("").trim().parse()
+        .chain_err(|| "invalid minimum uid")?;
+    let max: u32 = parts.next().ok_or("missing maximum uid")?.trim().parse()
+        .chain_err(|| "invalid maximum uid")?;
+    if min > max {
+        return Err("minimum uid is greater than the maximum".into());
+    }
+    Ok((min, max))
+}
+
+/// get_oem identifies the provider: from the given kernel cmdline key, or
+/// coreos.oem.id and then ignition.platform.id, and failing those from DMI
+fn get_oem(cmdline_key: Option<&str>) -> Result<String> {
     // open the cmdline file
     let mut file = File::open(CMDLINE_PATH)
         .chain_err(|| format!("Failed to open cmdline file ({})", CMDLINE_PATH))?;
@@ -168,17 +1388,62 @@ fn get_oem() -> Result<String> {
     file.read_to_string(&mut contents)
         .chain_err(|| format!("Failed to read cmdline file ({})", CMDLINE_PATH))?;
 
-    // split the contents into elements
-    let params: Vec<Vec<&str>> = contents.split(' ')
-        .map(|s| s.split('=').collect())
-        .collect();
-
-    // find the oem flag
-    for p in params {
-        if p.len() > 1 && p[0] == CMDLINE_OEM_FLAG {
-            return Ok(String::from(p[1]));
+    let keys = match cmdline_key {
+        Some(key) => vec![key],
+        None => vec![CMDLINE_OEM_FLAG, CMDLINE_PLATFORM_FLAG],
+    };
+    for key in &keys {
+        let value = match cmdline_value(&contents, key) {
+            Some(value) => value,
+            None => continue,
+        };
+        // coreos.oem.id names the provider as it is, even one that this
+        // build doesn't know
+        if *key == CMDLINE_OEM_FLAG {
+            return Ok(value.to_owned());
+        }
+        match provider_for_platform(value) {
+            Some(provider) => return Ok(provider.to_owned()),
+            None => warn!("there is no provider for platform '{}' from '{}'", value, key),
         }
     }
 
-    Err(format!("Couldn't find '{}' flag in cmdline file ({})", CMDLINE_OEM_FLAG, CMDLINE_PATH).into())
+    if let Some(provider) = detect_provider()? {
+        info!("no provider on the kernel cmdline, detected {} from DMI", provider);
+        return Ok(provider.to_owned());
+    }
+
+    Err(format!("Couldn't find {} in cmdline file ({}), or detect the provider from DMI",
+                keys.join(" or "), CMDLINE_PATH).into())
+}
+
+/// cmdline_value returns the value of the first `key=value` parameter with
+/// the given key
+fn cmdline_value<'a>(cmdline: &'a str, key: &str) -> Option<&'a str> {
+    cmdline.split_whitespace()
+        .filter_map(|p| {
+            let mut parts = p.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(k), Some(v)) if k == key => Some(v),
+                _ => None,
+            }
+        })
+        .next()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_before_subcommand_test() {
+        assert!(app().get_matches_from_safe(vec!["coreos-metadata", "--provider", "ec2", "fetch", "--out", "x"]).is_err());
+
+        let matches = app().get_matches_from_safe(vec!["coreos-metadata", "fetch", "--provider", "ec2", "--out", "x"]).unwrap();
+        assert_eq!(matches.subcommand_matches("fetch").unwrap().value_of("provider"), Some("ec2"));
+
+        let matches = app().get_matches_from_safe(vec!["coreos-metadata", "--provider", "ec2", "--hostname", "h"]).unwrap();
+        assert_eq!(matches.subcommand_name(), None);
+        assert_eq!(matches.value_of("provider"), Some("ec2"));
+    }
 }
@@ -0,0 +1,80 @@
+// Copyright 2017 CoreOS, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! cancellation on SIGTERM and SIGINT
+//!
+//! the signal handler only sets a flag. everything that waits (the retry
+//! loop, waiting for the network, looking for the virtual router) checks it
+//! and fails with `ErrorKind::Cancelled`, which unwinds like any other
+//! error, so config drives are unmounted and temporary directories removed
+//! on the way out. a request in flight isn't interrupted (the http client
+//! does its i/o on a thread of its own, and std retries reads on EINTR), so
+//! it's waited out, for up to its timeout. the handler only runs once: a
+//! second signal kills the process, for when something doesn't check.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use nix::libc::c_int;
+use nix::sys::signal::{sigaction, SigAction, SigHandler, SigSet, Signal, SA_RESETHAND};
+
+use errors::*;
+
+/// how often sleeps check whether they were cancelled
+const POLL_INTERVAL_MILLIS: u64 = 100;
+
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_signal(_: c_int) {
+    CANCELLED.store(true, Ordering::SeqCst);
+}
+
+/// install_handlers makes SIGTERM and SIGINT cancel whatever is in progress,
+/// and a second one kill the process
+pub fn install_handlers() -> Result<()> {
+    let action = SigAction::new(SigHandler::Handler(handle_signal), SA_RESETHAND, SigSet::empty());
+    for &signal in &[Signal::SIGTERM, Signal::SIGINT] {
+        unsafe { sigaction(signal, &action) }
+            .chain_err(|| format!("failed to install a handler for {:?}", signal))?;
+    }
+    Ok(())
+}
+
+/// is_cancelled returns true once a signal asked us to stop
+pub fn is_cancelled() -> bool {
+    CANCELLED.load(Ordering::SeqCst)
+}
+
+/// check fails if a signal asked us to stop
+pub fn check() -> Result<()> {
+    if is_cancelled() {
+        Err(ErrorKind::Cancelled.into())
+    } else {
+        Ok(())
+    }
+}
+
+/// sleep sleeps for the duration, unless a signal asks us to stop first
+pub fn sleep(duration: Duration) -> Result<()> {
+    let start = Instant::now();
+    loop {
+        check()?;
+        let elapsed = start.elapsed();
+        if elapsed >= duration {
+            return Ok(());
+        }
+        thread::sleep((duration - elapsed).min(Duration::from_millis(POLL_INTERVAL_MILLIS)));
+    }
+}
@@ -0,0 +1,85 @@
+// Copyright 2017 CoreOS, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! cloudinit is compatibility with coreos-cloudinit's kernel command line
+//!
+//! machines that were set up for coreos-cloudinit pass the location of their
+//! cloud-config as `cloud-config-url=<url>` (or the older `url=<url>`) on the
+//! kernel command line. this fetches it, so that it can be handed to whatever
+//! replaces coreos-cloudinit.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use errors::*;
+use providers::write_file_with_mode;
+use retry;
+
+const CMDLINE_URL_FLAGS: [&str; 2] = ["cloud-config-url", "url"];
+
+/// config_url finds the cloud-config url on the kernel command line,
+/// preferring `cloud-config-url=` over `url=`
+pub fn config_url(cmdline: &str) -> Option<String> {
+    CMDLINE_URL_FLAGS.iter()
+        .filter_map(|flag| {
+            cmdline.split_whitespace()
+                .filter_map(|p| {
+                    // urls can have '=' in them, so only split on the first
+                    let mut kv = p.splitn(2, '=');
+                    match (kv.next(), kv.next()) {
+                        (Some(k), Some(v)) if k == *flag && !v.is_empty() => Some(v.to_owned()),
+                        _ => None,
+                    }
+                })
+                .next()
+        })
+        .next()
+}
+
+/// fetch_config_url fetches the cloud-config named on the kernel command line
+/// (read from cmdline_path) into out. it returns false if the command line
+/// doesn't name one. the cloud-config can contain secrets, so the file is
+/// only readable by its owner.
+pub fn fetch_config_url(cmdline_path: &str, out: &str) -> Result<bool> {
+    let mut cmdline = String::new();
+    File::open(cmdline_path)
+        .and_then(|mut f| f.read_to_string(&mut cmdline))
+        .chain_err(|| format!("failed to read cmdline file ({})", cmdline_path))?;
+
+    let url = match config_url(&cmdline) {
+        Some(url) => url,
+        None => return Ok(false),
+    };
+
+    let config: String = retry::Client::new()?
+        .get(retry::Raw, url.clone())
+        .send()?
+        .ok_or_else(|| format!("cloud-config {} not found", url))?;
+    write_file_with_mode(Path::new(out), 0o600, &config)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_url_test() {
+        assert_eq!(config_url("root=/dev/sda1 console=ttyS0"), None);
+        assert_eq!(config_url("url=http://a/b?c=d quiet"), Some(String::from("http://a/b?c=d")));
+        assert_eq!(config_url("url=http://a cloud-config-url=http://b"), Some(String::from("http://b")));
+        assert_eq!(config_url("cloud-config-url= url=http://a"), Some(String::from("http://a")));
+    }
+}
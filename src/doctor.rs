@@ -0,0 +1,342 @@
+// Copyright 2017 CoreOS, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! self-test
+//!
+//! `coreos-metadata doctor` checks what fetching the metadata and writing
+//! the outputs depends on, without fetching or writing anything: that the
+//! provider is known, that the machine has an address and a route to the
+//! provider's endpoints, that those endpoints resolve and accept
+//! connections, that config drives and dhcp leases are there, and that the
+//! outputs can be written. every finding comes with what to do about it.
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr, TcpStream};
+use std::path::Path;
+use std::time::Duration;
+
+use pnet;
+use reqwest;
+use tempdir::TempDir;
+
+use network;
+use privileges;
+use providers::{ProviderConfig, SshKeysOptions};
+use util;
+use util::dns;
+
+const CONNECT_TIMEOUT_SECS: u64 = 3;
+const LEASES_DIR: &str = "/run/systemd/netif/leases";
+const ROUTES_PATH: &str = "/proc/net/route";
+
+/// Requirement is something a provider needs to fetch its metadata
+#[derive(Clone, Copy, Debug)]
+enum Requirement {
+    /// a metadata service at the host and port
+    Endpoint(&'static str, u16),
+    /// a config drive with one of the labels
+    ConfigDrive(&'static [&'static str]),
+    /// a dhcp lease, which the provider finds its endpoint or its metadata in
+    DhcpLease,
+}
+
+const LINK_LOCAL_ENDPOINT: Requirement = Requirement::Endpoint("169.254.169.254", 80);
+const CONFIG_2: Requirement = Requirement::ConfigDrive(&["config-2", "CONFIG-2"]);
+
+/// what each provider needs. providers missing from this table (e.g. the
+/// ones that only use --metadata-endpoint) need nothing that can be checked
+/// up front. the openstack config drive fallback isn't a requirement.
+const REQUIREMENTS: [(&str, &[Requirement]); 18] = [
+    ("azure", &[Requirement::DhcpLease, LINK_LOCAL_ENDPOINT]),
+    ("cloudstack-configdrive", &[CONFIG_2]),
+    ("cloudstack-metadata", &[Requirement::DhcpLease]),
+    ("dhcp", &[Requirement::DhcpLease]),
+    ("digitalocean", &[LINK_LOCAL_ENDPOINT]),
+    ("ec2", &[LINK_LOCAL_ENDPOINT]),
+    ("gce", &[Requirement::Endpoint("metadata.google.internal", 80)]),
+    ("ibmcloud-classic", &[Requirement::ConfigDrive(&["METADATA", "metadata"])]),
+    ("kubevirt", &[Requirement::ConfigDrive(&["cidata", "CIDATA", "config-2", "CONFIG-2"])]),
+    ("linode", &[LINK_LOCAL_ENDPOINT]),
+    ("nutanix", &[CONFIG_2]),
+    ("openstack-metadata", &[LINK_LOCAL_ENDPOINT]),
+    ("otc", &[LINK_LOCAL_ENDPOINT]),
+    ("ovhcloud", &[LINK_LOCAL_ENDPOINT]),
+    ("packet", &[Requirement::Endpoint("metadata.packet.net", 80)]),
+    ("rackspace", &[LINK_LOCAL_ENDPOINT]),
+    ("vagrant-virtualbox", &[]),
+    ("dmi", &[]),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    Ok,
+    Warning,
+    Problem,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Status::Ok => write!(f, "ok"),
+            Status::Warning => write!(f, "warning"),
+            Status::Problem => write!(f, "problem"),
+        }
+    }
+}
+
+/// Finding is the result of a single check
+#[derive(Clone, Debug)]
+pub struct Finding {
+    pub status: Status,
+    pub check: String,
+    pub message: String,
+}
+
+impl Finding {
+    fn new<S: Into<String>>(status: Status, check: &str, message: S) -> Finding {
+        Finding {
+            status,
+            check: check.to_owned(),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[{}] {}: {}", self.status, self.check, self.message)
+    }
+}
+
+/// check_provider checks that the provider is known and that what it needs
+/// to fetch the metadata is there
+pub fn check_provider(provider: Option<&str>, config: &ProviderConfig) -> Vec<Finding> {
+    let mut findings = Vec::new();
+
+    let provider = match provider {
+        Some(provider) => {
+            findings.push(Finding::new(Status::Ok, "provider", format!("using {}", provider)));
+            provider.to_owned()
+        }
+        None => match util::dmi::detect_provider() {
+            Ok(Some(detected)) => {
+                findings.push(Finding::new(Status::Warning, "provider",
+                    format!("none given, but the machine looks like {}; pass --provider {} or --cmdline", detected, detected)));
+                detected.to_owned()
+            }
+            _ => {
+                findings.push(Finding::new(Status::Problem, "provider",
+                    "none given, and it can't be detected; pass --provider or --cmdline"));
+                return findings;
+            }
+        },
+    };
+    if let Err(e) = ::check_provider(&provider) {
+        findings.push(Finding::new(Status::Problem, "provider",
+            format!("{}; see --list-providers", e)));
+        return findings;
+    }
+
+    // an endpoint override replaces the provider's own endpoints
+    if let Some(ref endpoint) = config.endpoint {
+        match endpoint_host_port(endpoint) {
+            Some((host, port)) => {
+                findings.push(check_address());
+                findings.extend(check_endpoint(&host, port, config.dns_timeout_or_default()));
+            }
+            None => findings.push(Finding::new(Status::Problem, "endpoint",
+                format!("--metadata-endpoint {} isn't a valid url", endpoint))),
+        }
+        return findings;
+    }
+
+    let requirements = REQUIREMENTS.iter()
+        .find(|&&(name, _)| name == provider)
+        .map_or(&[][..], |&(_, requirements)| requirements);
+    if requirements.iter().any(|r| match *r { Requirement::ConfigDrive(_) => false, _ => true }) {
+        findings.push(check_address());
+    }
+    for &requirement in requirements {
+        match requirement {
+            Requirement::Endpoint(host, port) => findings.extend(check_endpoint(host, port, config.dns_timeout_or_default())),
+            Requirement::ConfigDrive(labels) => findings.push(check_config_drive(config.config_drive.as_ref().map(String::as_str), labels)),
+            Requirement::DhcpLease => findings.push(check_dhcp_lease()),
+        }
+    }
+    findings
+}
+
+/// endpoint_host_port returns the host and port of an endpoint override
+fn endpoint_host_port(endpoint: &str) -> Option<(String, u16)> {
+    let url = reqwest::Url::parse(endpoint).ok()?;
+    let host = url.host_str()?.trim_matches(|c| c == '[' || c == ']').to_owned();
+    let port = url.port_or_known_default()?;
+    Some((host, port))
+}
+
+fn check_address() -> Finding {
+    let addressed: Vec<String> = pnet::datalink::interfaces().into_iter()
+        .filter(|i| !i.is_loopback() && network::has_usable_address(i))
+        .map(|i| i.name)
+        .collect();
+    if addressed.is_empty() {
+        Finding::new(Status::Problem, "network",
+            "no interface has an address; check that networking is up, or use --wait-for-network-timeout")
+    } else {
+        Finding::new(Status::Ok, "network", format!("addresses on {}", addressed.join(", ")))
+    }
+}
+
+/// has_route returns whether the ipv4 routing table has a route to the
+/// address, from /proc/net/route (where addresses and masks are network
+/// byte order words printed as native integers)
+fn has_route(routes: &str, addr: Ipv4Addr) -> bool {
+    let addr = u32::from(addr).to_be();
+    routes.lines().skip(1).any(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 8 {
+            return false;
+        }
+        match (u32::from_str_radix(fields[1], 16), u32::from_str_radix(fields[7], 16)) {
+            (Ok(destination), Ok(mask)) => addr & mask == destination,
+            _ => false,
+        }
+    })
+}
+
+fn check_endpoint(host: &str, port: u16, dns_timeout: Duration) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    let check = format!("endpoint {}:{}", host, port);
+
+    let addrs = match dns::resolve(host, port, dns_timeout) {
+        Ok(addrs) => addrs,
+        Err(e) => {
+            findings.push(Finding::new(Status::Problem, &check,
+                format!("can't resolve {}: {}; check the DNS servers the network configuration hands out", host, e)));
+            return findings;
+        }
+    };
+
+    for addr in &addrs {
+        if let IpAddr::V4(v4) = addr.ip() {
+            let mut routes = String::new();
+            let read = File::open(ROUTES_PATH).and_then(|mut f| f.read_to_string(&mut routes));
+            if read.is_ok() && !has_route(&routes, v4) {
+                let hint = if v4.is_link_local() {
+                    "the link-local metadata address needs a default route or a route to 169.254.0.0/16"
+                } else {
+                    "check the default route"
+                };
+                findings.push(Finding::new(Status::Problem, &check, format!("no route to {}; {}", v4, hint)));
+                return findings;
+            }
+        }
+    }
+
+    let timeout = Duration::from_secs(CONNECT_TIMEOUT_SECS);
+    let mut last_error = None;
+    for addr in &addrs {
+        match TcpStream::connect_timeout(addr, timeout) {
+            Ok(_) => {
+                findings.push(Finding::new(Status::Ok, &check, format!("accepts connections at {}", addr)));
+                return findings;
+            }
+            Err(e) => last_error = Some(format!("{}: {}", addr, e)),
+        }
+    }
+    findings.push(Finding::new(Status::Problem, &check,
+        format!("can't connect ({}); check firewall rules and that the instance is on the provider's network",
+                last_error.unwrap_or_else(|| "no addresses".to_owned()))));
+    findings
+}
+
+#[cfg(any(feature = "cloudstack", feature = "ibmcloud-classic", feature = "kubevirt", feature = "nutanix", feature = "openstack"))]
+fn check_config_drive(spec: Option<&str>, labels: &[&str]) -> Finding {
+    match util::configdrive::find_device(spec, labels) {
+        Ok(device) => Finding::new(Status::Ok, "config drive", format!("found {}", device.display())),
+        Err(e) => Finding::new(Status::Problem, "config drive",
+            format!("{}; check that the drive is attached, or name it with --config-drive", e)),
+    }
+}
+
+// only the providers that use config drives need them
+#[cfg(not(any(feature = "cloudstack", feature = "ibmcloud-classic", feature = "kubevirt", feature = "nutanix", feature = "openstack")))]
+fn check_config_drive(_spec: Option<&str>, _labels: &[&str]) -> Finding {
+    Finding::new(Status::Warning, "config drive", "not checked, no config drive providers are compiled into this build")
+}
+
+fn check_dhcp_lease() -> Finding {
+    let leases = fs::read_dir(LEASES_DIR)
+        .map(|entries| entries.filter_map(|e| e.ok()).count())
+        .unwrap_or(0);
+    if leases == 0 {
+        Finding::new(Status::Problem, "dhcp lease",
+            format!("none in {}; the provider needs networkd to have configured an interface with dhcp", LEASES_DIR))
+    } else {
+        Finding::new(Status::Ok, "dhcp lease", format!("{} in {}", leases, LEASES_DIR))
+    }
+}
+
+/// check_outputs checks that each output path can be written, by creating
+/// (and removing) a directory next to it
+pub fn check_outputs(outputs: &[(&str, String)]) -> Vec<Finding> {
+    outputs.iter().map(|&(name, ref path)| {
+        let check = format!("output {}", name);
+        // the output is created along with its missing parents, so what
+        // matters is the closest directory that already exists
+        let mut dir = Path::new(path);
+        if dir.is_file() {
+            dir = dir.parent().unwrap_or_else(|| Path::new("/"));
+        }
+        while !dir.is_dir() {
+            dir = match dir.parent() {
+                Some(parent) => parent,
+                None => break,
+            };
+        }
+        match TempDir::new_in(dir, ".coreos-metadata-doctor") {
+            Ok(_) => Finding::new(Status::Ok, &check, format!("{} is writable", path)),
+            Err(e) => Finding::new(Status::Problem, &check,
+                format!("can't write {} ({}: {}); check the permissions, or that it isn't on a read-only filesystem", path, dir.display(), e)),
+        }
+    }).collect()
+}
+
+/// check_privileges checks that the process may write the outputs
+pub fn check_privileges(outputs: &[&str], ssh_keys: &SshKeysOptions) -> Vec<Finding> {
+    outputs.iter()
+        .filter_map(|output| privileges::missing_privileges(output, ssh_keys)
+            .map(|reason| Finding::new(Status::Problem, &format!("output {}", output),
+                                       format!("{}; run as root", reason))))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn has_route_test() {
+        let routes = "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT
+eth0\t0000A8C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\t0\t0\t0
+";
+        assert!(has_route(routes, Ipv4Addr::new(192, 168, 0, 10)));
+        assert!(!has_route(routes, Ipv4Addr::new(169, 254, 169, 254)));
+
+        let routes = format!("{}eth0\t00000000\t0100A8C0\t0003\t0\t0\t0\t00000000\t0\t0\t0\n", routes);
+        assert!(has_route(&routes, Ipv4Addr::new(169, 254, 169, 254)));
+    }
+}
@@ -0,0 +1,102 @@
+// Copyright 2017 CoreOS, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! instance remembers the id of the instance the disk was last booted as, to
+//! notice when it's booted as a different one (an image cloned from a running
+//! machine, or a disk attached to a new instance). everything written for
+//! the old instance is stale then.
+
+use std::fs::{self, File};
+use std::io::{ErrorKind as IoErrorKind, Read, Write};
+use std::path::Path;
+
+use audit;
+use errors::*;
+
+/// changed returns true if a different instance id than this one was
+/// recorded. the first boot doesn't count as a change.
+pub fn changed(path: &str, instance_id: &str) -> Result<bool> {
+    match previous(Path::new(path))? {
+        Some(ref previous) if previous == instance_id => Ok(false),
+        Some(ref previous) => {
+            info!("instance id changed from {} to {}", previous, instance_id);
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// record saves the current instance id, if it isn't the one already saved.
+/// it's done once everything was written for the instance, so that a run
+/// that fails part way still sees the change the next time.
+pub fn record(path: &str, instance_id: &str) -> Result<()> {
+    let path = Path::new(path);
+    if previous(path)?.as_ref().map(String::as_str) == Some(instance_id) {
+        return Ok(());
+    }
+    info!("recording instance id {}", instance_id);
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)
+            .chain_err(|| format!("failed to create directory {:?}", dir))?;
+    }
+    let tmp_path = Path::new(&format!("{}.tmp", path.display())).to_owned();
+    {
+        let mut tmp = File::create(&tmp_path)
+            .chain_err(|| format!("failed to create file {:?}", tmp_path))?;
+        writeln!(tmp, "{}", instance_id)
+            .chain_err(|| format!("failed to write file {:?}", tmp_path))?;
+        tmp.sync_all()
+            .chain_err(|| format!("failed to sync file {:?}", tmp_path))?;
+    }
+    fs::rename(&tmp_path, path)
+        .chain_err(|| format!("failed to rename {:?} to {:?}", tmp_path, path))?;
+    audit::wrote(path);
+    Ok(())
+}
+
+fn previous(path: &Path) -> Result<Option<String>> {
+    match File::open(path) {
+        Ok(mut file) => {
+            let mut contents = String::new();
+            file.read_to_string(&mut contents)
+                .chain_err(|| format!("failed to read instance id file {:?}", path))?;
+            Ok(Some(contents.trim().to_owned()))
+        }
+        Err(ref e) if e.kind() == IoErrorKind::NotFound => Ok(None),
+        Err(e) => Err(Error::with_chain(e, format!("failed to open instance id file {:?}", path))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn record_test() {
+        let dir = TempDir::new("coreos-metadata-test").unwrap();
+        let path = dir.path().join("instance-id");
+        let path = path.to_str().unwrap();
+
+        assert!(!changed(path, "i-1").unwrap());
+        record(path, "i-1").unwrap();
+        assert!(!changed(path, "i-1").unwrap());
+        // it's still a change until the new id is recorded
+        assert!(changed(path, "i-2").unwrap());
+        assert!(changed(path, "i-2").unwrap());
+        record(path, "i-2").unwrap();
+        assert!(!changed(path, "i-2").unwrap());
+    }
+}
@@ -12,13 +12,18 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+// error_chain! expands recursively once per error kind
+#![recursion_limit = "256"]
+
 #[macro_use]
 extern crate hyper;
 extern crate reqwest;
+extern crate mime;
 #[cfg(test)]
 extern crate mockito;
 
 extern crate base64;
+extern crate flate2;
 
 #[macro_use]
 extern crate error_chain;
@@ -31,56 +36,388 @@ extern crate slog_scope;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde;
+#[cfg(feature = "azure")]
 extern crate serde_xml_rs;
 extern crate serde_json;
 
 extern crate pnet;
 
+#[cfg(feature = "azure")]
 extern crate openssl;
 extern crate openssh_keys;
+#[cfg(feature = "ssh-keys")]
 extern crate update_ssh_keys;
 
+#[cfg(feature = "ssh-keys")]
 extern crate users;
 extern crate hostname;
+#[macro_use]
+extern crate lazy_static;
 extern crate tempdir;
 extern crate nix;
+extern crate rand;
 
 extern crate ipnetwork;
 
+extern crate sha2;
+
+#[cfg(feature = "kubevirt")]
+extern crate serde_yaml;
+
+#[cfg(feature = "hostnamed")]
+extern crate dbus;
+
 
 mod providers;
 mod network;
 mod retry;
 mod util;
+pub mod audit;
+pub mod cancel;
+pub mod cloudinit;
+pub mod doctor;
+pub mod instance;
+pub mod maintenance;
+pub mod privileges;
+pub mod sandbox;
+pub mod stamp;
+pub mod termination;
+pub mod timing;
+
+pub use providers::{Capabilities, MaintenanceEvent, MetadataEndpoint, MetadataProvider, Password, ProviderConfig, SshKeysOptions, TerminationNotice};
+pub use providers::raw::RawMetadata;
+pub use providers::snapshot::Snapshot;
+pub use network::{NetworkUnitOptions, NetworkUnitScope};
+pub use util::attributes::AttributeNaming;
+pub use util::firewall::{FirewallFormat, FirewallOptions};
+pub use util::redact;
+pub use util::rooted;
+pub use util::secret::Secret;
+pub use util::ssh_cert::SshCertificate;
+pub use util::useradd::NewUser;
+pub use retry::{FetchObserver, set_fetch_observer};
 
 pub mod errors {
+    use std::fmt;
+
     error_chain!{
         links {
             PublicKey(::openssh_keys::errors::Error, ::openssh_keys::errors::ErrorKind);
-            AuthorizedKeys(::update_ssh_keys::errors::Error, ::update_ssh_keys::errors::ErrorKind);
+            AuthorizedKeys(::update_ssh_keys::errors::Error, ::update_ssh_keys::errors::ErrorKind) #[cfg(feature = "ssh-keys")];
         }
         foreign_links {
             Log(::slog::Error);
-            XmlDeserialize(::serde_xml_rs::Error);
+            XmlDeserialize(::serde_xml_rs::Error) #[cfg(feature = "azure")];
             Base64Decode(::base64::DecodeError);
             Io(::std::io::Error);
             Reqwest(::reqwest::Error);
             Hyper(::hyper::error::Error);
-            OpensslStack(::openssl::error::ErrorStack);
+            OpensslStack(::openssl::error::ErrorStack) #[cfg(feature = "azure")];
         }
         errors {
             UnknownProvider(p: String) {
                 description("unknown provider")
                 display("unknown provider '{}'", p)
             }
+            ProviderNotCompiledIn(p: String) {
+                description("provider not compiled in")
+                display("provider '{}' was not compiled into this build", p)
+            }
+            Fetch(e: FetchError) {
+                description("failed to fetch metadata")
+                display("{}", e)
+            }
+            Parse(e: ParseError) {
+                description("failed to parse metadata")
+                display("{}", e)
+            }
+            Write(e: WriteError) {
+                description("failed to write output")
+                display("{}", e)
+            }
+            SandboxUnsupported(arch: String) {
+                description("sandboxing not supported")
+                display("there's no syscall filter for {} to sandbox the fetch with", arch)
+            }
+            Cancelled {
+                description("cancelled")
+                display("cancelled by a signal")
+            }
+        }
+    }
+
+    // these enums, and ErrorClass, are meant to grow. the crate still builds
+    // with rust 1.26, which doesn't have #[non_exhaustive], so each one ends
+    // with a hidden variant that keeps matches on it from being exhaustive.
+
+    /// FetchError is why a metadata service couldn't be fetched from. the
+    /// error it came from, if any, is the next error in the chain.
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub enum FetchError {
+        /// the url couldn't be reached at all
+        Unreachable(String),
+        /// the url refused the connection, e.g. because nothing is listening
+        /// there yet
+        ConnectionRefused(String),
+        /// the response didn't arrive in time
+        TimedOut(String),
+        /// the service answered with a status worth retrying, and maybe a
+        /// Retry-After in seconds
+        RetryableStatus(u16, Option<u64>),
+        /// the service answered with a status that won't change on retry
+        FatalStatus(u16),
+        #[doc(hidden)]
+        __Nonexhaustive,
+    }
+
+    impl fmt::Display for FetchError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match *self {
+                FetchError::Unreachable(ref url) => write!(f, "failed to reach {}", url),
+                FetchError::ConnectionRefused(ref url) => write!(f, "{} refused the connection", url),
+                FetchError::TimedOut(ref url) => write!(f, "timed out reading the response from {}", url),
+                FetchError::RetryableStatus(status, _) => write!(f, "request failed with status {}", status),
+                FetchError::FatalStatus(status) => write!(f, "request failed with non-retryable status {}", status),
+                FetchError::__Nonexhaustive => write!(f, "failed to fetch metadata"),
+            }
+        }
+    }
+
+    /// ParseError is why fetched metadata, or a config drive, couldn't be
+    /// used. the error it came from, if any, is the next error in the chain.
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub enum ParseError {
+        /// the response from the url couldn't be parsed
+        Malformed(String),
+        /// the response from the url was larger than the limit in bytes
+        TooLarge(String, u64),
+        /// the config drive file at the path is corrupt, for the reason given
+        ConfigDriveCorrupt(String, String),
+        #[doc(hidden)]
+        __Nonexhaustive,
+    }
+
+    impl fmt::Display for ParseError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match *self {
+                ParseError::Malformed(ref url) => write!(f, "malformed response from {}", url),
+                ParseError::TooLarge(ref url, limit) => write!(f, "response from {} is larger than {} bytes", url, limit),
+                ParseError::ConfigDriveCorrupt(ref path, ref reason) => write!(f, "config drive file '{}' is corrupt: {}", path, reason),
+                ParseError::__Nonexhaustive => write!(f, "failed to parse metadata"),
+            }
+        }
+    }
+
+    /// WriteError is why an output couldn't be written. the io error is the
+    /// next error in the chain.
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub enum WriteError {
+        /// the file at the path couldn't be written
+        Failed(String),
+        #[doc(hidden)]
+        __Nonexhaustive,
+    }
+
+    impl fmt::Display for WriteError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match *self {
+                WriteError::Failed(ref path) => write!(f, "failed to write {}", path),
+                WriteError::__Nonexhaustive => write!(f, "failed to write output"),
+            }
+        }
+    }
+
+    /// ErrorClass is the broad reason something failed, for callers that want
+    /// to react to it, e.g. by falling back to another provider when the
+    /// metadata service can't be reached.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum ErrorClass {
+        /// the provider isn't known or wasn't compiled in
+        Provider,
+        /// the metadata service couldn't be reached at all
+        Unreachable,
+        /// the metadata service answered with an error status
+        HttpStatus,
+        /// the metadata was fetched (or read from a config drive), but it
+        /// couldn't be parsed
+        Malformed,
+        /// an output couldn't be written
+        Write,
+        /// SIGTERM or SIGINT asked us to stop
+        Cancelled,
+        Other,
+        #[doc(hidden)]
+        __Nonexhaustive,
+    }
+
+    /// the exit status of a run that only wrote some of its outputs, when
+    /// it's asked to report that
+    pub const PARTIAL_SUCCESS_EXIT_CODE: i32 = 7;
+
+    impl ErrorClass {
+        /// exit_code is the exit status of the coreos-metadata command when it
+        /// fails with an error of the class, so that units and scripts can
+        /// tell e.g. an unreachable metadata service (worth retrying) from an
+        /// unknown provider (which isn't)
+        pub fn exit_code(self) -> i32 {
+            match self {
+                ErrorClass::Provider => 2,
+                ErrorClass::Unreachable => 3,
+                ErrorClass::HttpStatus => 4,
+                ErrorClass::Malformed => 5,
+                ErrorClass::Write => 6,
+                ErrorClass::Cancelled => 8,
+                ErrorClass::Other | ErrorClass::__Nonexhaustive => 1,
+            }
+        }
+    }
+
+    impl Error {
+        /// class returns the class of the innermost error in the chain that
+        /// has one
+        pub fn class(&self) -> ErrorClass {
+            self.kinds().into_iter().rev()
+                .filter_map(|k| k.class())
+                .next()
+                .unwrap_or(ErrorClass::Other)
+        }
+
+        /// fetch_error returns the innermost FetchError in the chain, if any
+        pub fn fetch_error(&self) -> Option<&FetchError> {
+            self.kinds().into_iter().rev()
+                .filter_map(|k| match *k { ErrorKind::Fetch(ref e) => Some(e), _ => None })
+                .next()
+        }
+
+        /// parse_error returns the innermost ParseError in the chain, if any
+        pub fn parse_error(&self) -> Option<&ParseError> {
+            self.kinds().into_iter().rev()
+                .filter_map(|k| match *k { ErrorKind::Parse(ref e) => Some(e), _ => None })
+                .next()
+        }
+
+        /// write_error returns the innermost WriteError in the chain, if any
+        pub fn write_error(&self) -> Option<&WriteError> {
+            self.kinds().into_iter().rev()
+                .filter_map(|k| match *k { ErrorKind::Write(ref e) => Some(e), _ => None })
+                .next()
+        }
+
+        /// kinds returns the kind of the error and of each error it was
+        /// chained onto, outermost first. the chain is followed for as long
+        /// as its links are ours; the first foreign error (an io::Error, say)
+        /// ends it.
+        fn kinds(&self) -> Vec<&ErrorKind> {
+            let mut kinds = vec![self.kind()];
+            let mut next = self.1.next_error.as_ref();
+            while let Some(e) = next {
+                let e = match e.downcast_ref::<Error>() {
+                    Some(e) => e,
+                    None => break,
+                };
+                kinds.push(e.kind());
+                next = e.1.next_error.as_ref();
+            }
+            kinds
+        }
+    }
+
+    impl ErrorKind {
+        fn class(&self) -> Option<ErrorClass> {
+            match *self {
+                ErrorKind::UnknownProvider(..) |
+                ErrorKind::ProviderNotCompiledIn(..) => Some(ErrorClass::Provider),
+                ErrorKind::Fetch(FetchError::RetryableStatus(..)) |
+                ErrorKind::Fetch(FetchError::FatalStatus(..)) => Some(ErrorClass::HttpStatus),
+                ErrorKind::Fetch(..) => Some(ErrorClass::Unreachable),
+                ErrorKind::Parse(..) => Some(ErrorClass::Malformed),
+                ErrorKind::Write(..) => Some(ErrorClass::Write),
+                ErrorKind::Cancelled => Some(ErrorClass::Cancelled),
+                _ => None,
+            }
         }
     }
+
 }
 
+use std::time::Duration;
+
 use providers::*;
 
 use errors::*;
 
+/// ProviderInfo describes a provider name that `fetch_metadata` understands
+#[derive(Clone, Copy, Debug)]
+pub struct ProviderInfo {
+    pub name: &'static str,
+    pub description: &'static str,
+    compiled_in: bool,
+}
+
+/// every provider name that `fetch_metadata` understands, whether or not it
+/// was compiled into this build
+const PROVIDERS: [ProviderInfo; 20] = [
+    ProviderInfo { name: "azure", description: "Microsoft Azure (wireserver and OVF environment)", compiled_in: cfg!(feature = "azure") },
+    ProviderInfo { name: "cloudstack-configdrive", description: "CloudStack config drive", compiled_in: cfg!(feature = "cloudstack") },
+    ProviderInfo { name: "cloudstack-metadata", description: "CloudStack metadata service, found via the DHCP server", compiled_in: cfg!(feature = "cloudstack") },
+    ProviderInfo { name: "custom-http", description: "a JSON document at --metadata-endpoint, mapped by --custom-http-mapping", compiled_in: cfg!(feature = "custom-http") },
+    ProviderInfo { name: "dhcp", description: "the systemd-networkd DHCP lease, for platforms without a metadata service", compiled_in: cfg!(feature = "dhcp") },
+    ProviderInfo { name: "digitalocean", description: "DigitalOcean metadata service", compiled_in: cfg!(feature = "digitalocean") },
+    ProviderInfo { name: "dmi", description: "SMBIOS/DMI identity of the machine, for bare metal and generic hypervisors", compiled_in: cfg!(feature = "dmi") },
+    ProviderInfo { name: "ec2", description: "Amazon EC2 instance metadata service", compiled_in: cfg!(feature = "ec2") },
+    ProviderInfo { name: "gce", description: "Google Compute Engine metadata server", compiled_in: cfg!(feature = "gce") },
+    ProviderInfo { name: "ibmcloud-classic", description: "IBM Cloud Classic (SoftLayer) METADATA config disk", compiled_in: cfg!(feature = "ibmcloud-classic") },
+    ProviderInfo { name: "kubevirt", description: "KubeVirt cloud-init NoCloud or config drive volume", compiled_in: cfg!(feature = "kubevirt") },
+    ProviderInfo { name: "linode", description: "Linode (Akamai) metadata service", compiled_in: cfg!(feature = "linode") },
+    ProviderInfo { name: "matchbox", description: "Matchbox-style provisioning server at --metadata-endpoint, selected by MAC address and SMBIOS UUID", compiled_in: cfg!(feature = "matchbox") },
+    ProviderInfo { name: "nutanix", description: "Nutanix AHV guest customization config drive", compiled_in: cfg!(feature = "nutanix") },
+    ProviderInfo { name: "openstack-metadata", description: "OpenStack metadata service (IPv4 or IPv6 link-local) or config drive", compiled_in: cfg!(feature = "openstack") },
+    ProviderInfo { name: "otc", description: "Open Telekom Cloud (OpenStack metadata with OTC extensions)", compiled_in: cfg!(feature = "otc") },
+    ProviderInfo { name: "ovhcloud", description: "OVHcloud Public Cloud (OpenStack metadata with OVHcloud extensions)", compiled_in: cfg!(feature = "ovhcloud") },
+    ProviderInfo { name: "packet", description: "Packet metadata service", compiled_in: cfg!(feature = "packet") },
+    ProviderInfo { name: "rackspace", description: "Rackspace (OpenStack metadata, or xenstore on Rackspace Classic)", compiled_in: cfg!(feature = "rackspace") },
+    ProviderInfo { name: "vagrant-virtualbox", description: "Vagrant with the VirtualBox provider", compiled_in: cfg!(feature = "vagrant-virtualbox") },
+];
+
+/// ignition platform ids that differ from the name of their provider
+const PLATFORM_IDS: [(&str, &str); 4] = [
+    ("aws", "ec2"),
+    ("cloudstack", "cloudstack-metadata"),
+    ("gcp", "gce"),
+    ("openstack", "openstack-metadata"),
+];
+
+/// `provider_for_platform` returns the provider for an ignition platform id
+/// (`ignition.platform.id`), if there is one
+pub fn provider_for_platform(id: &str) -> Option<&'static str> {
+    PLATFORM_IDS.iter()
+        .find(|&&(platform, _)| platform == id)
+        .map(|&(_, provider)| provider)
+        .or_else(|| PROVIDERS.iter().find(|p| p.name == id).map(|p| p.name))
+}
+
+/// `detect_provider` guesses the provider from the machine's SMBIOS/DMI
+/// fields, for images that don't name it on the kernel command line
+pub fn detect_provider() -> Result<Option<&'static str>> {
+    util::dmi::detect_provider()
+}
+
+/// `providers` lists the providers that were compiled into this build, i.e.
+/// the names that `fetch_metadata` accepts
+pub fn providers() -> Vec<ProviderInfo> {
+    PROVIDERS.iter().filter(|p| p.compiled_in).cloned().collect()
+}
+
+/// `check_provider` fails the way `fetch_metadata` would if the provider
+/// can't be used at all: if it's unknown or wasn't compiled in
+pub fn check_provider(provider: &str) -> Result<()> {
+    match PROVIDERS.iter().find(|p| p.name == provider) {
+        None => Err(errors::ErrorKind::UnknownProvider(provider.to_owned()).into()),
+        Some(p) if !p.compiled_in => Err(errors::ErrorKind::ProviderNotCompiledIn(provider.to_owned()).into()),
+        Some(_) => Ok(()),
+    }
+}
+
 macro_rules! box_result {
     ($exp:expr) => (Ok(Box::new($exp?)))
 }
@@ -88,17 +425,209 @@ macro_rules! box_result {
 /// `fetch_metadata` is the generic, top-level function that is used by the main
 /// function to fetch metadata. The configured provider is passed in and this
 /// function dispatches the call to the correct provider-specific fetch function
-pub fn fetch_metadata(provider: &str) -> Result<Box<providers::MetadataProvider>> {
+pub fn fetch_metadata(provider: &str, config: &ProviderConfig) -> Result<Box<providers::MetadataProvider>> {
+    check_provider(provider)?;
+    retry::set_max_requests_per_second(config.max_requests_per_second);
     match provider {
-        "azure" => box_result!(azure::Azure::new()),
-        "cloudstack-metadata" => box_result!(cloudstack::network::CloudstackNetwork::new()),
-        "cloudstack-configdrive" => box_result!(cloudstack::configdrive::ConfigDrive::new()),
-        "digitalocean" => box_result!(digitalocean::DigitalOceanProvider::new()),
-        "ec2" => box_result!(ec2::Ec2Provider::new()),
-        "gce" => box_result!(gce::GceProvider::new()),
-        "openstack-metadata" => box_result!(openstack::network::OpenstackProvider::new()),
-        "packet" => box_result!(packet::PacketProvider::new()),
+        #[cfg(feature = "azure")]
+        "azure" => box_result!(azure::Azure::new(config)),
+        #[cfg(feature = "cloudstack")]
+        "cloudstack-metadata" => box_result!(cloudstack::network::CloudstackNetwork::new(config)),
+        #[cfg(feature = "cloudstack")]
+        "cloudstack-configdrive" => box_result!(cloudstack::configdrive::ConfigDrive::new(config)),
+        #[cfg(feature = "custom-http")]
+        "custom-http" => box_result!(custom_http::CustomHttpProvider::new(config)),
+        #[cfg(feature = "dhcp")]
+        "dhcp" => box_result!(dhcp::DhcpProvider::new(config)),
+        #[cfg(feature = "digitalocean")]
+        "digitalocean" => box_result!(digitalocean::DigitalOceanProvider::new(config)),
+        #[cfg(feature = "dmi")]
+        "dmi" => box_result!(dmi::DmiProvider::new()),
+        #[cfg(feature = "ec2")]
+        "ec2" => box_result!(ec2::Ec2Provider::new(config)),
+        #[cfg(feature = "gce")]
+        "gce" => box_result!(gce::GceProvider::new(config)),
+        #[cfg(feature = "ibmcloud-classic")]
+        "ibmcloud-classic" => box_result!(ibmcloud_classic::IbmCloudClassicProvider::new(config)),
+        #[cfg(feature = "kubevirt")]
+        "kubevirt" => box_result!(kubevirt::KubeVirtProvider::new(config)),
+        #[cfg(feature = "linode")]
+        "linode" => box_result!(linode::LinodeProvider::new(config)),
+        #[cfg(feature = "matchbox")]
+        "matchbox" => box_result!(matchbox::MatchboxProvider::new(config)),
+        #[cfg(feature = "nutanix")]
+        "nutanix" => box_result!(nutanix::NutanixProvider::new(config)),
+        #[cfg(feature = "openstack")]
+        "openstack-metadata" => box_result!(openstack::network::OpenstackProvider::new(config)),
+        #[cfg(feature = "otc")]
+        "otc" => box_result!(openstack::variant::OpenstackVariant::new(config, "OTC")),
+        #[cfg(feature = "ovhcloud")]
+        "ovhcloud" => box_result!(openstack::variant::OpenstackVariant::new(config, "OVHCLOUD")),
+        #[cfg(feature = "packet")]
+        "packet" => box_result!(packet::PacketProvider::new(config)),
+        #[cfg(feature = "rackspace")]
+        "rackspace" => box_result!(rackspace::RackspaceProvider::new(config)),
+        #[cfg(feature = "vagrant-virtualbox")]
         "vagrant-virtualbox" => box_result!(vagrant_virtualbox::VagrantVirtualboxProvider::new()),
-        _ => Err(errors::ErrorKind::UnknownProvider(provider.to_owned()).into()),
+        _ => Err(errors::ErrorKind::ProviderNotCompiledIn(provider.to_owned()).into()),
+    }
+}
+
+/// `fetch_raw_metadata` fetches the documents the provider parses its
+/// metadata from, so that they can be saved and parsed again later with
+/// `parse_raw_metadata`
+pub fn fetch_raw_metadata(provider: &str, config: &ProviderConfig) -> Result<RawMetadata> {
+    check_provider(provider)?;
+    retry::set_max_requests_per_second(config.max_requests_per_second);
+    match provider {
+        #[cfg(feature = "azure")]
+        "azure" => azure::Azure::fetch_raw(config),
+        #[cfg(feature = "cloudstack")]
+        "cloudstack-metadata" => cloudstack::network::CloudstackNetwork::fetch_raw(config),
+        #[cfg(feature = "cloudstack")]
+        "cloudstack-configdrive" => cloudstack::configdrive::ConfigDrive::fetch_raw(config),
+        #[cfg(feature = "custom-http")]
+        "custom-http" => custom_http::CustomHttpProvider::fetch_raw(config),
+        #[cfg(feature = "dhcp")]
+        "dhcp" => dhcp::DhcpProvider::fetch_raw(config),
+        #[cfg(feature = "digitalocean")]
+        "digitalocean" => digitalocean::DigitalOceanProvider::fetch_raw(config),
+        #[cfg(feature = "dmi")]
+        "dmi" => dmi::DmiProvider::fetch_raw(),
+        #[cfg(feature = "ec2")]
+        "ec2" => ec2::Ec2Provider::fetch_raw(config),
+        #[cfg(feature = "gce")]
+        "gce" => gce::GceProvider::fetch_raw(config),
+        #[cfg(feature = "ibmcloud-classic")]
+        "ibmcloud-classic" => ibmcloud_classic::IbmCloudClassicProvider::fetch_raw(config),
+        #[cfg(feature = "kubevirt")]
+        "kubevirt" => kubevirt::KubeVirtProvider::fetch_raw(config),
+        #[cfg(feature = "linode")]
+        "linode" => linode::LinodeProvider::fetch_raw(config),
+        #[cfg(feature = "matchbox")]
+        "matchbox" => matchbox::MatchboxProvider::fetch_raw(config),
+        #[cfg(feature = "nutanix")]
+        "nutanix" => nutanix::NutanixProvider::fetch_raw(config),
+        #[cfg(feature = "openstack")]
+        "openstack-metadata" => openstack::network::OpenstackProvider::fetch_raw(config),
+        #[cfg(feature = "otc")]
+        "otc" => openstack::variant::OpenstackVariant::fetch_raw(config, "otc"),
+        #[cfg(feature = "ovhcloud")]
+        "ovhcloud" => openstack::variant::OpenstackVariant::fetch_raw(config, "ovhcloud"),
+        #[cfg(feature = "packet")]
+        "packet" => packet::PacketProvider::fetch_raw(config),
+        #[cfg(feature = "rackspace")]
+        "rackspace" => rackspace::RackspaceProvider::fetch_raw(config),
+        #[cfg(feature = "vagrant-virtualbox")]
+        "vagrant-virtualbox" => vagrant_virtualbox::VagrantVirtualboxProvider::fetch_raw(),
+        _ => Err(not_compiled_in(provider)),
+    }
+}
+
+/// `fetch_document` fetches a single document from the provider's metadata
+/// service, the way the provider fetches its own (from the endpoint it
+/// picks, with its headers and tokens), so that what the provider sees can
+/// be looked at. the path is from the root of the service, e.g.
+/// `/latest/meta-data/instance-id` on ec2. providers without a metadata
+/// service, and ones whose documents only make sense together, don't
+/// support it.
+pub fn fetch_document(provider: &str, config: &ProviderConfig, path: &str) -> Result<Option<Vec<u8>>> {
+    check_provider(provider)?;
+    retry::set_max_requests_per_second(config.max_requests_per_second);
+    if !path.starts_with('/') {
+        return Err(format!("the document path {:?} doesn't start with /", path).into());
     }
+    let compiled_in = PROVIDERS.iter().any(|p| p.name == provider && p.compiled_in);
+    match provider {
+        #[cfg(feature = "azure")]
+        "azure" => azure::Azure::fetch_document(config, path),
+        #[cfg(feature = "cloudstack")]
+        "cloudstack-metadata" => cloudstack::network::CloudstackNetwork::fetch_document(config, path),
+        #[cfg(feature = "digitalocean")]
+        "digitalocean" => digitalocean::DigitalOceanProvider::fetch_document(config, path),
+        #[cfg(feature = "ec2")]
+        "ec2" => ec2::Ec2Provider::fetch_document(config, path),
+        #[cfg(feature = "gce")]
+        "gce" => gce::GceProvider::fetch_document(config, path),
+        #[cfg(feature = "linode")]
+        "linode" => linode::LinodeProvider::fetch_document(config, path),
+        #[cfg(feature = "openstack")]
+        "openstack-metadata" => openstack::network::OpenstackProvider::fetch_document(config, path),
+        #[cfg(feature = "otc")]
+        "otc" => openstack::network::OpenstackProvider::fetch_document(config, path),
+        #[cfg(feature = "ovhcloud")]
+        "ovhcloud" => openstack::network::OpenstackProvider::fetch_document(config, path),
+        #[cfg(feature = "packet")]
+        "packet" => packet::PacketProvider::fetch_document(config, path),
+        _ if compiled_in => {
+            Err(format!("the {} provider doesn't fetch documents that can be fetched on their own", provider).into())
+        }
+        _ => Err(not_compiled_in(provider)),
+    }
+}
+
+/// `parse_raw_metadata` parses documents saved by `fetch_raw_metadata`,
+/// without network access, with the provider they were fetched by
+pub fn parse_raw_metadata(raw: &RawMetadata) -> Result<Box<providers::MetadataProvider>> {
+    match raw.provider.as_str() {
+        #[cfg(feature = "azure")]
+        "azure" => box_result!(azure::Azure::parse(raw)),
+        #[cfg(feature = "cloudstack")]
+        "cloudstack-metadata" => box_result!(cloudstack::network::CloudstackNetwork::parse(raw)),
+        #[cfg(feature = "cloudstack")]
+        "cloudstack-configdrive" => box_result!(cloudstack::configdrive::ConfigDrive::parse(raw)),
+        #[cfg(feature = "custom-http")]
+        "custom-http" => box_result!(custom_http::CustomHttpProvider::parse(raw)),
+        #[cfg(feature = "dhcp")]
+        "dhcp" => box_result!(dhcp::DhcpProvider::parse(raw)),
+        #[cfg(feature = "digitalocean")]
+        "digitalocean" => box_result!(digitalocean::DigitalOceanProvider::parse(raw)),
+        #[cfg(feature = "dmi")]
+        "dmi" => box_result!(dmi::DmiProvider::parse(raw)),
+        #[cfg(feature = "ec2")]
+        "ec2" => box_result!(ec2::Ec2Provider::parse(raw)),
+        #[cfg(feature = "gce")]
+        "gce" => box_result!(gce::GceProvider::parse(raw)),
+        #[cfg(feature = "ibmcloud-classic")]
+        "ibmcloud-classic" => box_result!(ibmcloud_classic::IbmCloudClassicProvider::parse(raw)),
+        #[cfg(feature = "kubevirt")]
+        "kubevirt" => box_result!(kubevirt::KubeVirtProvider::parse(raw)),
+        #[cfg(feature = "linode")]
+        "linode" => box_result!(linode::LinodeProvider::parse(raw)),
+        #[cfg(feature = "matchbox")]
+        "matchbox" => box_result!(matchbox::MatchboxProvider::parse(raw)),
+        #[cfg(feature = "nutanix")]
+        "nutanix" => box_result!(nutanix::NutanixProvider::parse(raw)),
+        #[cfg(feature = "openstack")]
+        "openstack-metadata" => box_result!(openstack::network::OpenstackProvider::parse(raw)),
+        #[cfg(feature = "otc")]
+        "otc" => box_result!(openstack::variant::OpenstackVariant::parse(raw, "otc", "OTC")),
+        #[cfg(feature = "ovhcloud")]
+        "ovhcloud" => box_result!(openstack::variant::OpenstackVariant::parse(raw, "ovhcloud", "OVHCLOUD")),
+        #[cfg(feature = "packet")]
+        "packet" => box_result!(packet::PacketProvider::parse(raw)),
+        #[cfg(feature = "rackspace")]
+        "rackspace" => box_result!(rackspace::RackspaceProvider::parse(raw)),
+        #[cfg(feature = "vagrant-virtualbox")]
+        "vagrant-virtualbox" => box_result!(vagrant_virtualbox::VagrantVirtualboxProvider::parse(raw)),
+        provider => Err(not_compiled_in(provider)),
+    }
+}
+
+/// not_compiled_in is the error for a provider that a match on the
+/// compiled-in providers didn't find
+fn not_compiled_in(provider: &str) -> Error {
+    if PROVIDERS.iter().any(|p| p.name == provider) {
+        errors::ErrorKind::ProviderNotCompiledIn(provider.to_owned()).into()
+    } else {
+        errors::ErrorKind::UnknownProvider(provider.to_owned()).into()
+    }
+}
+
+/// `wait_for_network` blocks until the given interface (or any interface, if
+/// none is given) has an address that can be used to reach a metadata service.
+/// it should be called before `fetch_metadata` on systems where the network
+/// might not be up yet.
+pub fn wait_for_network(interface: Option<&str>, timeout: Duration) -> Result<()> {
+    network::wait_for_address(interface, timeout)
 }
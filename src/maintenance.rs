@@ -0,0 +1,70 @@
+// Copyright 2017 CoreOS, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! scheduled maintenance
+//!
+//! the events are written out as attributes, numbered in the order the
+//! platform lists them, so that a node can be drained ahead of them and the
+//! maintenance then started early.
+
+use std::path::Path;
+
+use errors::*;
+use providers::{self, MaintenanceEvent};
+
+/// attributes returns the events as attributes
+pub fn attributes(events: &[MaintenanceEvent]) -> Vec<(String, String)> {
+    let mut attributes = vec![("COREOS_MAINTENANCE_EVENTS".to_owned(), events.len().to_string())];
+    for (i, event) in events.iter().enumerate() {
+        let prefix = format!("COREOS_MAINTENANCE_EVENT_{}", i);
+        attributes.push((format!("{}_ID", prefix), event.id.clone()));
+        attributes.push((format!("{}_TYPE", prefix), event.kind.clone()));
+        attributes.push((format!("{}_STATUS", prefix), event.status.clone()));
+        if let Some(ref not_before) = event.not_before {
+            attributes.push((format!("{}_NOT_BEFORE", prefix), not_before.clone()));
+        }
+    }
+    attributes
+}
+
+/// write_events writes the events to the file in the same format as the
+/// attributes file
+pub fn write_events(path: &str, events: &[MaintenanceEvent]) -> Result<()> {
+    providers::write_attributes_file(Path::new(path), &attributes(events))
+        .chain_err(|| format!("failed to write maintenance events to {}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attributes_test() {
+        assert_eq!(attributes(&[]), vec![("COREOS_MAINTENANCE_EVENTS".to_owned(), "0".to_owned())]);
+
+        let events = vec![MaintenanceEvent {
+            id: "602d9444-d2cd-49c7-8624-8643e7171297".to_owned(),
+            kind: "Reboot".to_owned(),
+            status: "Scheduled".to_owned(),
+            not_before: Some("Mon, 19 Sep 2016 18:29:47 GMT".to_owned()),
+        }];
+        assert_eq!(attributes(&events), vec![
+            ("COREOS_MAINTENANCE_EVENTS".to_owned(), "1".to_owned()),
+            ("COREOS_MAINTENANCE_EVENT_0_ID".to_owned(), "602d9444-d2cd-49c7-8624-8643e7171297".to_owned()),
+            ("COREOS_MAINTENANCE_EVENT_0_TYPE".to_owned(), "Reboot".to_owned()),
+            ("COREOS_MAINTENANCE_EVENT_0_STATUS".to_owned(), "Scheduled".to_owned()),
+            ("COREOS_MAINTENANCE_EVENT_0_NOT_BEFORE".to_owned(), "Mon, 19 Sep 2016 18:29:47 GMT".to_owned()),
+        ]);
+    }
+}
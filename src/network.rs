@@ -16,12 +16,18 @@
 //! interface unit files. All that is left is to write the resulting string to
 //! the necessary unit.
 
+use std::collections::{HashMap, HashSet};
+use std::fs;
 use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use pnet;
 use pnet::util::MacAddr;
 use std::string::String;
 use std::string::ToString;
+use std::time::{Duration, Instant};
 
 use ipnetwork::IpNetwork;
+use cancel;
 use errors::*;
 
 pub const BONDING_MODE_BALANCE_RR: u32 = 0;
@@ -51,6 +57,171 @@ pub fn bonding_mode_to_string(mode: u32) -> Result<String> {
     Err(format!("no such bonding mode: {}", mode).into())
 }
 
+/// has_usable_address returns true if the interface has an address that can
+/// be used to reach a metadata service. ipv4 link-local addresses count, since
+/// that's all some platforms hand out, but ipv6 link-local addresses don't,
+/// since every interface gets one as soon as it comes up.
+pub(crate) fn has_usable_address(iface: &pnet::datalink::NetworkInterface) -> bool {
+    iface.ips.iter().any(|ip| match ip.ip() {
+        IpAddr::V4(a) => !a.is_loopback(),
+        IpAddr::V6(a) => !a.is_loopback() && (a.segments()[0] & 0xffc0) != 0xfe80,
+    })
+}
+
+/// wait_for_address blocks until the named interface (or any non-loopback
+/// interface, if no name is given) has a usable address, or until the timeout
+/// expires.
+pub fn wait_for_address(interface: Option<&str>, timeout: Duration) -> Result<()> {
+    let start = Instant::now();
+    let mut logged = false;
+    loop {
+        let ready = pnet::datalink::interfaces().iter()
+            .filter(|i| !i.is_loopback())
+            .filter(|i| interface.map_or(true, |name| i.name == name))
+            .any(has_usable_address);
+        if ready {
+            debug!("network is up after {:?}", start.elapsed());
+            return Ok(());
+        }
+
+        if start.elapsed() >= timeout {
+            return Err(format!("timed out after {:?} waiting for an address on {}",
+                               timeout, interface.unwrap_or("any interface")).into());
+        }
+        if !logged {
+            info!("waiting for an address on {}", interface.unwrap_or("any interface"));
+            logged = true;
+        }
+        cancel::sleep(Duration::from_millis(500))?;
+    }
+}
+
+/// where the kernel shows the state of network links
+const SYSFS_NET: &str = "/sys/class/net";
+
+/// Link is the state of the link of an interface the metadata describes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Link {
+    Up,
+    /// the interface is there, but not plugged in (or not up yet)
+    NoCarrier,
+    /// there's no interface with its name and mac address
+    Absent,
+}
+
+/// link_states finds each interface among the ones present, by its name and
+/// mac address, and reads whether it has a carrier from sysfs. interfaces
+/// matched by neither can't be looked for, and are taken to be up.
+fn link_states(interfaces: &[Interface], present: &[pnet::datalink::NetworkInterface], sysfs: &Path) -> Vec<Link> {
+    interfaces.iter().map(|iface| {
+        if iface.name.is_none() && iface.mac_address.is_none() {
+            return Link::Up;
+        }
+        let found = present.iter().find(|p| {
+            iface.name.as_ref().map_or(true, |name| *name == p.name)
+                && iface.mac_address.map_or(true, |mac| p.mac == Some(mac))
+        });
+        match found {
+            // reading the carrier of a link that's down fails
+            Some(p) => match fs::read_to_string(sysfs.join(&p.name).join("carrier")) {
+                Ok(ref carrier) if carrier.trim() == "1" => Link::Up,
+                _ => Link::NoCarrier,
+            },
+            None => Link::Absent,
+        }
+    }).collect()
+}
+
+/// wait_for_links waits until every interface is present and has a carrier,
+/// or until the timeout expires, and returns the state of each link. nics
+/// can take a while to be probed and to negotiate their links early in
+/// boot.
+pub fn wait_for_links(interfaces: &[Interface], timeout: Duration) -> Result<Vec<Link>> {
+    let start = Instant::now();
+    let mut logged = false;
+    loop {
+        let links = link_states(interfaces, &pnet::datalink::interfaces(), Path::new(SYSFS_NET));
+        if links.iter().all(|&link| link == Link::Up) || start.elapsed() >= timeout {
+            debug!("links after {:?}: {:?}", start.elapsed(), links);
+            return Ok(links);
+        }
+        if !logged {
+            info!("waiting for the links of {} interface(s)", links.iter().filter(|&&link| link != Link::Up).count());
+            logged = true;
+        }
+        cancel::sleep(Duration::from_millis(500))?;
+    }
+}
+
+/// VirtualFunction is the network interface of an sr-iov virtual function
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VirtualFunction {
+    pub name: String,
+    /// the permanent address, if the interface is enslaved to a bond,
+    /// which changes the current one to the bond's
+    pub mac_address: MacAddr,
+    /// e.g. `0000:3b:02.0`
+    pub pci_address: String,
+    /// the pci address of the physical function it belongs to
+    pub physical_function: String,
+}
+
+/// virtual_functions lists the network interfaces of sr-iov virtual
+/// functions, which are the ones with a physical function in sysfs
+pub fn virtual_functions() -> Result<Vec<VirtualFunction>> {
+    virtual_functions_in(Path::new(SYSFS_NET))
+}
+
+fn virtual_functions_in(sysfs: &Path) -> Result<Vec<VirtualFunction>> {
+    let entries = fs::read_dir(sysfs)
+        .chain_err(|| format!("failed to list network interfaces in {:?}", sysfs))?;
+    let mut vfs = Vec::new();
+    for entry in entries {
+        let dir = entry.chain_err(|| format!("failed to list network interfaces in {:?}", sysfs))?.path();
+        let physical_function = match fs::read_link(dir.join("device").join("physfn")) {
+            Ok(link) => file_name(&link),
+            Err(_) => continue,
+        };
+        let pci_address = fs::read_link(dir.join("device"))
+            .map(|link| file_name(&link))
+            .chain_err(|| format!("failed to read the pci device of {:?}", dir))?;
+        let address = fs::read_to_string(dir.join("bonding_slave").join("perm_hwaddr"))
+            .or_else(|_| fs::read_to_string(dir.join("address")))
+            .chain_err(|| format!("failed to read the mac address of {:?}", dir))?;
+        let mac_address = address.trim().parse::<MacAddr>()
+            .map_err(|e| Error::from(format!("{:?}", e)))
+            .chain_err(|| format!("failed to parse mac address: '{}'", address.trim()))?;
+        vfs.push(VirtualFunction {
+            name: file_name(&dir),
+            mac_address,
+            pci_address,
+            physical_function,
+        });
+    }
+    vfs.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(vfs)
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name().map_or_else(String::new, |name| name.to_string_lossy().into_owned())
+}
+
+/// match_virtual_functions makes the interfaces that are virtual functions
+/// match on their permanent mac address
+pub fn match_virtual_functions(interfaces: &mut [Interface], vfs: &[VirtualFunction]) {
+    for iface in interfaces {
+        let vf = match iface.mac_address {
+            Some(mac) => vfs.iter().find(|vf| vf.mac_address == mac),
+            None => None,
+        };
+        if let Some(vf) = vf {
+            info!("{} is virtual function {} of {}, matching its permanent mac address",
+                  iface.unit_name(), vf.pci_address, vf.physical_function);
+            iface.match_permanent_mac = true;
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct NetworkRoute {
     pub destination: IpNetwork,
@@ -63,7 +234,7 @@ pub struct NetworkRoute {
 /// so we just panic! if it's not what we expected.
 /// I guess that there aren't really type systems with inclusive disjunction
 /// so it's not really that big of a deal.
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
 pub struct Interface {
     pub name: Option<String>,
     pub mac_address: Option<MacAddr>,
@@ -73,6 +244,21 @@ pub struct Interface {
     pub routes: Vec<NetworkRoute>,
     pub bond: Option<String>,
     pub unmanaged: bool,
+    /// optional kernel driver (or space-separated list of globs) to match
+    /// in addition to the name and mac address
+    pub driver: Option<String>,
+    pub mtu: Option<u32>,
+    /// ranges that are routed to the machine and delivered locally without
+    /// being assigned to the interface, e.g. gce alias ip ranges
+    pub local_routes: Vec<IpNetwork>,
+    /// optional routing table for the interface's routes, which traffic
+    /// from its addresses is looked up in, so that replies leave through
+    /// the interface they came in on
+    pub route_table: Option<u32>,
+    /// match the mac address against the link's permanent address instead
+    /// of its current one, which the driver of an sr-iov virtual function
+    /// can change
+    pub match_permanent_mac: bool,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -90,10 +276,103 @@ pub struct Device {
     pub sections: Vec<Section>
 }
 
+/// NetworkUnitScope is where generated units are meant to live. runtime
+/// units go away on reboot and are regenerated from the metadata every
+/// boot; persistent ones stay with the machine's own configuration.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NetworkUnitScope {
+    Runtime,
+    Persistent,
+}
+
+impl Default for NetworkUnitScope {
+    fn default() -> NetworkUnitScope {
+        NetworkUnitScope::Runtime
+    }
+}
+
+impl NetworkUnitScope {
+    /// dir is the directory networkd reads the scope's units from
+    pub fn dir(self) -> &'static str {
+        match self {
+            NetworkUnitScope::Runtime => "/run/systemd/network",
+            NetworkUnitScope::Persistent => "/etc/systemd/network",
+        }
+    }
+
+    /// unit_name returns the file name of a generated unit in the scope.
+    /// persistent units share their directory with the administrator's own,
+    /// so they're marked as generated (`10-coreos-metadata-eth0.network`),
+    /// which also keeps them from replacing a unit of the same name.
+    pub fn unit_name(self, unit_name: &str) -> String {
+        match self {
+            NetworkUnitScope::Runtime => unit_name.to_owned(),
+            NetworkUnitScope::Persistent => match unit_name.find('-') {
+                Some(i) => format!("{}-coreos-metadata-{}", &unit_name[..i], &unit_name[i + 1..]),
+                None => format!("coreos-metadata-{}", unit_name),
+            },
+        }
+    }
+}
+
+/// NetworkUnitOptions changes how networkd units are generated
+#[derive(Clone, Debug, Default)]
+pub struct NetworkUnitOptions {
+    /// replaces the numeric prefix of every generated unit (and drop-in)
+    pub prefix: Option<u32>,
+    /// extend existing units that match an interface with drop-ins, instead
+    /// of writing units that take precedence over them
+    pub dropins: bool,
+    /// the root filesystem the existing units are looked for in
+    pub root: Option<PathBuf>,
+    /// refuse to write units that fail validation, instead of warning
+    pub validate: bool,
+    /// where the units are meant to live, which changes their names
+    pub scope: NetworkUnitScope,
+    /// wait this long for the described interfaces to show up and have a
+    /// carrier, and skip the ones that don't show up, if set
+    pub link_timeout: Option<Duration>,
+    /// match sr-iov virtual functions on their permanent mac address
+    pub match_virtual_functions: bool,
+}
+
+/// unit_matches returns true if the [Match] section of the given .network
+/// unit names the interface or its mac address. globs aren't expanded.
+pub fn unit_matches(contents: &str, iface: &Interface) -> bool {
+    let mut in_match = false;
+    for line in contents.lines().map(str::trim) {
+        if line.starts_with('[') {
+            in_match = line == "[Match]";
+            continue;
+        }
+        if !in_match {
+            continue;
+        }
+        let (key, value) = match line.find('=') {
+            Some(i) => (line[..i].trim(), line[i + 1..].trim()),
+            None => continue,
+        };
+        let matched = match key {
+            "Name" => iface.name.as_ref()
+                .map_or(false, |name| value.split_whitespace().any(|v| v == name)),
+            "MACAddress" | "PermanentMACAddress" => iface.mac_address
+                .map_or(false, |mac| value.split_whitespace().any(|v| v.to_lowercase() == mac.to_string())),
+            _ => false,
+        };
+        if matched {
+            return true;
+        }
+    }
+    false
+}
+
 impl Interface {
     pub fn unit_name(&self) -> String {
+        self.unit_name_with_prefix(None)
+    }
+    pub fn unit_name_with_prefix(&self, prefix: Option<u32>) -> String {
         format!("{:02}-{}.network",
-                self.priority.unwrap_or(10),
+                prefix.or(self.priority).unwrap_or(10),
                 self.name.clone().unwrap_or_else(
                     // needs to be a lambda or we panic immediately
                     // yay, manual thunking!
@@ -111,11 +390,27 @@ impl Interface {
             config.push_str(&format!("Name={}\n", name));
         }
         if let Some(mac) = self.mac_address {
-            config.push_str(&format!("MACAddress={}\n", mac));
+            if self.match_permanent_mac {
+                config.push_str(&format!("PermanentMACAddress={}\n", mac));
+            } else {
+                config.push_str(&format!("MACAddress={}\n", mac));
+            }
+        }
+        if let Some(driver) = self.driver.clone() {
+            config.push_str(&format!("Driver={}\n", driver));
         }
 
+        config.push('\n');
+        config.push_str(&self.dropin_config());
+        config
+    }
+    /// dropin_config is the unit without its [Match] section, for extending
+    /// a unit that already matches the interface
+    pub fn dropin_config(&self) -> String {
+        let mut config = String::new();
+
         // [Network] section
-        config.push_str("\n[Network]\n");
+        config.push_str("[Network]\n");
         for ns in &self.nameservers {
             config.push_str(&format!("DNS={}\n", ns))
         }
@@ -124,8 +419,14 @@ impl Interface {
         }
 
         // [Link] section
-        if self.unmanaged {
-            config.push_str("\n[Link]\nUnmanaged=yes\n");
+        if self.unmanaged || self.mtu.is_some() {
+            config.push_str("\n[Link]\n");
+            if self.unmanaged {
+                config.push_str("Unmanaged=yes\n");
+            }
+            if let Some(mtu) = self.mtu {
+                config.push_str(&format!("MTUBytes={}\n", mtu));
+            }
         }
 
         // [Address] sections
@@ -136,6 +437,19 @@ impl Interface {
         // [Route] sections
         for route in &self.routes {
             config.push_str(&format!("\n[Route]\nDestination={}\nGateway={}\n", route.destination, route.gateway));
+            if let Some(table) = self.route_table {
+                config.push_str(&format!("Table={}\n", table));
+            }
+        }
+        for dest in &self.local_routes {
+            config.push_str(&format!("\n[Route]\nDestination={}\nType=local\n", dest));
+        }
+
+        // [RoutingPolicyRule] sections
+        if let Some(table) = self.route_table {
+            for addr in &self.ip_addresses {
+                config.push_str(&format!("\n[RoutingPolicyRule]\nFrom={}\nTable={}\n", addr.ip(), table));
+            }
         }
 
         config
@@ -144,7 +458,10 @@ impl Interface {
 
 impl Device {
     pub fn unit_name(&self) -> String {
-        format!("{:02}-{}.netdev", self.priority.unwrap_or(10), self.name)
+        self.unit_name_with_prefix(None)
+    }
+    pub fn unit_name_with_prefix(&self, prefix: Option<u32>) -> String {
+        format!("{:02}-{}.netdev", prefix.or(self.priority).unwrap_or(10), self.name)
     }
     pub fn config(&self) -> String {
         let mut config = String::new();
@@ -167,12 +484,340 @@ impl Device {
     }
 }
 
+/// validate_units checks the units generated for the interfaces and devices
+/// for the mistakes networkd doesn't complain about: units that match the
+/// same link (only the first one applies), addresses assigned more than
+/// once, and interfaces enslaved to bonds that aren't defined. it returns a
+/// description of every problem found.
+pub fn validate_units(interfaces: &[Interface], devices: &[Device], prefix: Option<u32>) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    let mut unit_names = HashSet::new();
+    let mut names = HashMap::new();
+    let mut macs = HashMap::new();
+    let mut addresses: HashMap<IpAddr, String> = HashMap::new();
+    for iface in interfaces {
+        let unit = iface.unit_name_with_prefix(prefix);
+        if !unit_names.insert(unit.clone()) {
+            problems.push(format!("more than one interface is written to {}", unit));
+        }
+        if let Some(ref name) = iface.name {
+            if let Some(other) = names.insert(name.clone(), unit.clone()) {
+                problems.push(format!("{} and {} both match interface name {}", other, unit, name));
+            }
+        }
+        // units that also match different drivers don't match the same link
+        if let Some(mac) = iface.mac_address {
+            if let Some(other) = macs.insert((mac, iface.driver.clone()), unit.clone()) {
+                problems.push(format!("{} and {} both match mac address {}", other, unit, mac));
+            }
+        }
+        for addr in &iface.ip_addresses {
+            if let Some(other) = addresses.insert(addr.ip(), unit.clone()) {
+                problems.push(format!("address {} is assigned by both {} and {}", addr.ip(), other, unit));
+            }
+        }
+        if let Some(ref bond) = iface.bond {
+            if !devices.iter().any(|d| &d.name == bond && d.kind == "bond") {
+                problems.push(format!("{} is enslaved to bond {}, which isn't defined", unit, bond));
+            }
+        }
+    }
+
+    let mut device_names = HashSet::new();
+    for device in devices {
+        if !device_names.insert(&device.name) {
+            problems.push(format!("more than one device is named {}", device.name));
+        }
+    }
+    problems
+}
+
+/// the networkd [Bond] settings that have an ifcfg BONDING_OPTS equivalent.
+/// the ones that are times are in seconds for networkd and milliseconds for
+/// the bonding driver.
+const IFCFG_BOND_OPTS: [(&str, &str, bool); 6] = [
+    ("Mode", "mode", false),
+    ("TransmitHashPolicy", "xmit_hash_policy", false),
+    ("LACPTransmitRate", "lacp_rate", false),
+    ("MIIMonitorSec", "miimon", true),
+    ("UpDelaySec", "updelay", true),
+    ("DownDelaySec", "downdelay", true),
+];
+
+fn is_ipv4(net: &IpNetwork) -> bool {
+    match *net {
+        IpNetwork::V4(_) => true,
+        IpNetwork::V6(_) => false,
+    }
+}
+
+fn ifcfg_bond_opts(device: &Device) -> String {
+    let mut opts = Vec::new();
+    for section in device.sections.iter().filter(|s| s.name == "Bond") {
+        for &(ref key, ref value) in &section.attributes {
+            match IFCFG_BOND_OPTS.iter().find(|o| o.0 == key.as_str()) {
+                Some(&(_, opt, true)) => match value.parse::<f64>() {
+                    Ok(secs) => opts.push(format!("{}={}", opt, (secs * 1000.0).round() as u64)),
+                    Err(_) => warn!("ignoring invalid bond setting {}={}", key, value),
+                },
+                Some(&(_, opt, false)) => opts.push(format!("{}={}", opt, value)),
+                None => warn!("bond setting {} is not supported by ifcfg, ignoring it", key),
+            }
+        }
+    }
+    opts.join(" ")
+}
+
+/// IfcfgRoutes are the contents of an interface's route and rule files
+#[derive(Default)]
+struct IfcfgRoutes {
+    routes: String,
+    routes6: String,
+    rules: String,
+    rules6: String,
+}
+
+/// ifcfg_routes renders the interface's routes. default routes are set with
+/// the gateway variables in its config, everything else goes into
+/// route-<device>/route6-<device>, and rules for its own routing table into
+/// rule-<device>/rule6-<device>.
+fn ifcfg_routes(iface: &Interface, config: &mut String) -> IfcfgRoutes {
+    let mut files = IfcfgRoutes::default();
+    if let Some(table) = iface.route_table {
+        // routes in a table of their own can't use the gateway variables
+        for route in &iface.routes {
+            let line = format!("{} via {} table {}\n", route.destination, route.gateway, table);
+            push_by_family(&route.destination, &line, &mut files.routes, &mut files.routes6);
+        }
+        for addr in &iface.ip_addresses {
+            let line = format!("from {} table {}\n", addr.ip(), table);
+            push_by_family(addr, &line, &mut files.rules, &mut files.rules6);
+        }
+    }
+    for route in iface.routes.iter().filter(|_| iface.route_table.is_none()) {
+        match (route.destination.prefix(), is_ipv4(&route.destination)) {
+            (0, true) => config.push_str(&format!("GATEWAY={}\n", route.gateway)),
+            (0, false) => config.push_str(&format!("IPV6_DEFAULTGW={}\n", route.gateway)),
+            _ => {
+                let line = format!("{} via {}\n", route.destination, route.gateway);
+                push_by_family(&route.destination, &line, &mut files.routes, &mut files.routes6);
+            }
+        }
+    }
+    if let Some(ref name) = iface.name {
+        for dest in &iface.local_routes {
+            let line = format!("local {} dev {}\n", dest, name);
+            push_by_family(dest, &line, &mut files.routes, &mut files.routes6);
+        }
+    } else if let Some(mac) = iface.mac_address {
+        if !iface.local_routes.is_empty() {
+            warn!("interface {} has no name, skipping its local routes", mac);
+        }
+    }
+    files
+}
+
+/// push_by_family adds the line to the ipv4 or ipv6 file, by the family of
+/// the network it's about
+fn push_by_family(net: &IpNetwork, line: &str, v4: &mut String, v6: &mut String) {
+    if is_ipv4(net) {
+        v4.push_str(line);
+    } else {
+        v6.push_str(line);
+    }
+}
+
+/// ifcfg_files renders the interfaces and devices as sysconfig
+/// network-scripts, returning (file name, contents) pairs. an interface and
+/// a device with the same name (e.g. a bond and its addresses) share a file.
+pub fn ifcfg_files(interfaces: &[Interface], devices: &[Device]) -> Vec<(String, String)> {
+    let mut files = Vec::new();
+
+    let device_config = |name: &str| -> String {
+        let device = match devices.iter().find(|d| d.name == name && d.kind == "bond") {
+            Some(device) => device,
+            None => return String::new(),
+        };
+        let mut config = String::new();
+        config.push_str(&format!("MACADDR={}\n", device.mac_address));
+        config.push_str("TYPE=Bond\nBONDING_MASTER=yes\n");
+        config.push_str(&format!("BONDING_OPTS=\"{}\"\n", ifcfg_bond_opts(device)));
+        config
+    };
+
+    for device in devices {
+        if device.kind != "bond" {
+            warn!("{} devices are not supported by ifcfg, skipping {}", device.kind, device.name);
+            continue;
+        }
+        if interfaces.iter().any(|i| i.name.as_ref() == Some(&device.name)) {
+            continue;
+        }
+        files.push((format!("ifcfg-{}", device.name),
+                    format!("DEVICE={}\nONBOOT=yes\nBOOTPROTO=none\n{}", device.name, device_config(&device.name))));
+    }
+
+    for iface in interfaces {
+        // unmanaged interfaces are left alone entirely
+        if iface.unmanaged {
+            continue;
+        }
+        if iface.driver.is_some() {
+            warn!("ifcfg can't match interfaces by driver, ignoring the driver for {}", iface.unit_name());
+        }
+
+        let mut config = String::new();
+        if let Some(ref name) = iface.name {
+            config.push_str(&format!("DEVICE={}\n", name));
+        }
+        if let Some(mac) = iface.mac_address {
+            config.push_str(&format!("HWADDR={}\n", mac));
+        }
+        config.push_str("ONBOOT=yes\nBOOTPROTO=none\n");
+        if let Some(ref name) = iface.name {
+            config.push_str(&device_config(name));
+        }
+        if let Some(ref bond) = iface.bond {
+            config.push_str(&format!("MASTER={}\nSLAVE=yes\n", bond));
+        }
+        if let Some(mtu) = iface.mtu {
+            config.push_str(&format!("MTU={}\n", mtu));
+        }
+
+        let v4: Vec<_> = iface.ip_addresses.iter().filter(|a| is_ipv4(a)).collect();
+        let v6: Vec<_> = iface.ip_addresses.iter().filter(|a| !is_ipv4(a)).collect();
+        for (i, addr) in v4.iter().enumerate() {
+            config.push_str(&format!("IPADDR{}={}\nPREFIX{}={}\n", i, addr.ip(), i, addr.prefix()));
+        }
+        if let Some((first, rest)) = v6.split_first() {
+            config.push_str(&format!("IPV6INIT=yes\nIPV6ADDR={}\n", first));
+            if !rest.is_empty() {
+                let rest: Vec<String> = rest.iter().map(|a| a.to_string()).collect();
+                config.push_str(&format!("IPV6ADDR_SECONDARIES=\"{}\"\n", rest.join(" ")));
+            }
+        }
+        for (i, ns) in iface.nameservers.iter().enumerate() {
+            config.push_str(&format!("DNS{}={}\n", i + 1, ns));
+        }
+
+        let IfcfgRoutes { mut routes, mut routes6, mut rules, mut rules6 } = ifcfg_routes(iface, &mut config);
+
+        // route files are looked up by device name, so interfaces that are
+        // only matched by mac address can't have them
+        let device = match (iface.name.clone(), iface.mac_address) {
+            (Some(name), _) => name,
+            (None, Some(mac)) => {
+                if !routes.is_empty() || !routes6.is_empty() {
+                    warn!("interface {} has no name, skipping its non-default routes", mac);
+                }
+                if !rules.is_empty() || !rules6.is_empty() {
+                    warn!("interface {} has no name, skipping its routing policy rules", mac);
+                }
+                routes.clear();
+                routes6.clear();
+                rules.clear();
+                rules6.clear();
+                mac.to_string().replace(":", "")
+            }
+            (None, None) => panic!("interface needs either name or mac address (or both)"),
+        };
+        files.push((format!("ifcfg-{}", device), config));
+        if !routes.is_empty() {
+            files.push((format!("route-{}", device), routes));
+        }
+        if !routes6.is_empty() {
+            files.push((format!("route6-{}", device), routes6));
+        }
+        if !rules.is_empty() {
+            files.push((format!("rule-{}", device), rules));
+        }
+        if !rules6.is_empty() {
+            files.push((format!("rule6-{}", device), rules6));
+        }
+    }
+
+    files
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::net::{Ipv4Addr, Ipv6Addr};
     use ipnetwork::{Ipv4Network,Ipv6Network};
 
+    #[test]
+    fn link_states_test() {
+        use tempdir::TempDir;
+
+        let sysfs = TempDir::new("coreos-metadata-sysfs").unwrap();
+        for &(name, carrier) in &[("eth0", "1\n"), ("eth1", "0\n")] {
+            fs::create_dir(sysfs.path().join(name)).unwrap();
+            fs::write(sysfs.path().join(name).join("carrier"), carrier).unwrap();
+        }
+        let mac = MacAddr(0xf4, 0x00, 0x34, 0x09, 0x73, 0xee);
+        let present: Vec<pnet::datalink::NetworkInterface> = ["eth0", "eth1"].iter().enumerate().map(|(i, name)| {
+            pnet::datalink::NetworkInterface {
+                name: name.to_string(),
+                index: i as u32 + 2,
+                mac: if i == 0 { Some(mac) } else { None },
+                ips: vec![],
+                flags: 0,
+            }
+        }).collect();
+        let iface = |name: Option<&str>, mac_address: Option<MacAddr>| Interface {
+            name: name.map(String::from),
+            mac_address,
+            ..Default::default()
+        };
+        let interfaces = vec![
+            iface(None, Some(mac)),
+            iface(Some("eth1"), None),
+            iface(Some("eth1"), Some(mac)),
+            iface(Some("eth2"), None),
+        ];
+        assert_eq!(link_states(&interfaces, &present, sysfs.path()),
+                   vec![Link::Up, Link::NoCarrier, Link::Absent, Link::Absent]);
+    }
+
+    #[test]
+    fn virtual_functions_test() {
+        use std::os::unix::fs::symlink;
+        use tempdir::TempDir;
+
+        let sysfs = TempDir::new("coreos-metadata-sysfs").unwrap();
+        let devices = sysfs.path().join("devices");
+        fs::create_dir_all(devices.join("0000:3b:00.0")).unwrap();
+        fs::create_dir_all(devices.join("0000:3b:02.0")).unwrap();
+        let net = sysfs.path().join("net");
+        for &(name, pci, address) in &[("enp59s0f0", "0000:3b:00.0", "b8:59:9f:00:00:01\n"),
+                                       ("enp59s0f0v0", "0000:3b:02.0", "b8:59:9f:00:00:02\n")] {
+            fs::create_dir_all(net.join(name)).unwrap();
+            symlink(devices.join(pci), net.join(name).join("device")).unwrap();
+            fs::write(net.join(name).join("address"), address).unwrap();
+        }
+        symlink(devices.join("0000:3b:00.0"), devices.join("0000:3b:02.0").join("physfn")).unwrap();
+        // the bond changed the current address to its own
+        fs::create_dir(net.join("enp59s0f0v0").join("bonding_slave")).unwrap();
+        fs::write(net.join("enp59s0f0v0").join("bonding_slave").join("perm_hwaddr"), "b8:59:9f:00:00:03\n").unwrap();
+
+        let vfs = virtual_functions_in(&net).unwrap();
+        assert_eq!(vfs, vec![VirtualFunction {
+            name: "enp59s0f0v0".to_owned(),
+            mac_address: MacAddr(0xb8, 0x59, 0x9f, 0, 0, 3),
+            pci_address: "0000:3b:02.0".to_owned(),
+            physical_function: "0000:3b:00.0".to_owned(),
+        }]);
+
+        let mut interfaces = vec![Interface {
+            mac_address: Some(MacAddr(0xb8, 0x59, 0x9f, 0, 0, 3)),
+            bond: Some("bond0".to_owned()),
+            ..Default::default()
+        }];
+        match_virtual_functions(&mut interfaces, &vfs);
+        assert!(interfaces[0].config().contains("PermanentMACAddress=b8:59:9f:00:00:03\n"));
+    }
+
     #[test]
     fn mac_addr_display() {
         let m = MacAddr(0xf4,0x00,0x34,0x09,0x73,0xee);
@@ -186,41 +831,22 @@ mod tests {
                 name: Some(String::from("lo")),
                 mac_address: Some(MacAddr(0,0,0,0,0,0)),
                 priority: Some(20),
-                nameservers: vec![],
-                ip_addresses: vec![],
-                routes: vec![],
-                bond: None,
-                unmanaged: false,
+                ..Default::default()
             }, "20-lo.network"),
             (Interface {
                 name: Some(String::from("lo")),
                 mac_address: Some(MacAddr(0,0,0,0,0,0)),
-                priority: None,
-                nameservers: vec![],
-                ip_addresses: vec![],
-                routes: vec![],
-                bond: None,
-                unmanaged: false,
+                ..Default::default()
             }, "10-lo.network"),
             (Interface {
-                name: None,
                 mac_address: Some(MacAddr(0,0,0,0,0,0)),
                 priority: Some(20),
-                nameservers: vec![],
-                ip_addresses: vec![],
-                routes: vec![],
-                bond: None,
-                unmanaged: false,
+                ..Default::default()
             }, "20-00:00:00:00:00:00.network"),
             (Interface {
                 name: Some(String::from("lo")),
-                mac_address: None,
                 priority: Some(20),
-                nameservers: vec![],
-                ip_addresses: vec![],
-                routes: vec![],
-                bond: None,
-                unmanaged: false,
+                ..Default::default()
             }, "20-lo.network"),
         ];
 
@@ -236,11 +862,7 @@ mod tests {
             name: None,
             mac_address: None,
             priority: Some(20),
-            nameservers: vec![],
-            ip_addresses: vec![],
-            routes: vec![],
-            bond: None,
-            unmanaged: false,
+            ..Default::default()
         };
         let _name = i.unit_name();
     }
@@ -300,7 +922,7 @@ mod tests {
                     }
                 ],
                 bond: Some(String::from("james")),
-                unmanaged: false,
+                ..Default::default()
             }, "[Match]
 Name=lo
 MACAddress=00:00:00:00:00:00
@@ -324,14 +946,7 @@ Gateway=127.0.0.1
             // the minimum possible configuration for all peices at the same
             // time, so I'll allow it. (sdemos)
             (Interface {
-                name: None,
-                mac_address: None,
-                priority: None,
-                nameservers: vec![],
-                ip_addresses: vec![],
-                routes: vec![],
-                bond: None,
-                unmanaged: false,
+                ..Default::default()
             }, "[Match]
 
 [Network]
@@ -343,6 +958,98 @@ Gateway=127.0.0.1
         }
     }
 
+    #[test]
+    fn interface_config_unmanaged_driver() {
+        let i = Interface {
+            mac_address: Some(MacAddr(0,0,0,0,0,0)),
+            priority: Some(5),
+            unmanaged: true,
+            driver: Some(String::from("mlx4_en mlx5_core")),
+            ..Default::default()
+        };
+        assert_eq!(i.config(), "[Match]
+MACAddress=00:00:00:00:00:00
+Driver=mlx4_en mlx5_core
+
+[Network]
+
+[Link]
+Unmanaged=yes
+");
+    }
+
+    #[test]
+    fn interface_config_mtu_local_routes() {
+        let i = Interface {
+            name: Some(String::from("eth1")),
+            ip_addresses: vec!["10.128.0.2/20".parse().unwrap()],
+            mtu: Some(1460),
+            local_routes: vec!["10.4.0.0/24".parse().unwrap()],
+            ..Default::default()
+        };
+        assert_eq!(i.config(), "[Match]
+Name=eth1
+
+[Network]
+
+[Link]
+MTUBytes=1460
+
+[Address]
+Address=10.128.0.2/20
+
+[Route]
+Destination=10.4.0.0/24
+Type=local
+");
+        let files = ifcfg_files(&[i], &[]);
+        assert!(files[0].1.contains("MTU=1460\n"));
+        assert_eq!(files[1], (String::from("route-eth1"), String::from("local 10.4.0.0/24 dev eth1\n")));
+    }
+
+    #[test]
+    fn interface_config_route_table() {
+        let i = Interface {
+            name: Some(String::from("eth1")),
+            ip_addresses: vec!["10.0.1.4/24".parse().unwrap()],
+            routes: vec![NetworkRoute {
+                destination: "0.0.0.0/0".parse().unwrap(),
+                gateway: "10.0.1.1".parse().unwrap(),
+            }],
+            route_table: Some(101),
+            ..Default::default()
+        };
+        assert_eq!(i.config(), "[Match]
+Name=eth1
+
+[Network]
+
+[Address]
+Address=10.0.1.4/24
+
+[Route]
+Destination=0.0.0.0/0
+Gateway=10.0.1.1
+Table=101
+
+[RoutingPolicyRule]
+From=10.0.1.4
+Table=101
+");
+        let files = ifcfg_files(&[i], &[]);
+        assert!(!files[0].1.contains("GATEWAY="));
+        assert_eq!(files[1], (String::from("route-eth1"), String::from("0.0.0.0/0 via 10.0.1.1 table 101\n")));
+        assert_eq!(files[2], (String::from("rule-eth1"), String::from("from 10.0.1.4 table 101\n")));
+    }
+
+    #[test]
+    fn network_unit_scope_unit_name() {
+        assert_eq!(NetworkUnitScope::Runtime.unit_name("10-eth0.network"), "10-eth0.network");
+        assert_eq!(NetworkUnitScope::Persistent.unit_name("10-eth0.network"), "10-coreos-metadata-eth0.network");
+        assert_eq!(NetworkUnitScope::Persistent.unit_name("05-00:0d:3a:f8:06:ec.network"),
+                   "05-coreos-metadata-00:0d:3a:f8:06:ec.network");
+    }
+
     #[test]
     fn device_config() {
         let ds = vec![
@@ -392,4 +1099,110 @@ MACAddress=00:00:00:00:00:00
             assert_eq!(d.config(), s);
         }
     }
+
+    #[test]
+    fn ifcfg_bond() {
+        let slave = Interface {
+            mac_address: Some(MacAddr(0,0,0,0,0,1)),
+            bond: Some(String::from("bond0")),
+            ..Default::default()
+        };
+        let bond = Interface {
+            name: Some(String::from("bond0")),
+            priority: Some(5),
+            nameservers: vec![IpAddr::V4(Ipv4Addr::new(8,8,8,8))],
+            ip_addresses: vec![IpNetwork::V4(Ipv4Network::new(Ipv4Addr::new(10,1,2,3),31).unwrap())],
+            routes: vec![
+                NetworkRoute {
+                    destination: IpNetwork::V4(Ipv4Network::new(Ipv4Addr::new(0,0,0,0),0).unwrap()),
+                    gateway: IpAddr::V4(Ipv4Addr::new(10,1,2,2)),
+                },
+                NetworkRoute {
+                    destination: IpNetwork::V4(Ipv4Network::new(Ipv4Addr::new(10,0,0,0),8).unwrap()),
+                    gateway: IpAddr::V4(Ipv4Addr::new(10,1,2,2)),
+                },
+            ],
+            ..Default::default()
+        };
+        let device = Device {
+            name: String::from("bond0"),
+            kind: String::from("bond"),
+            mac_address: MacAddr(0,0,0,0,0,1),
+            priority: Some(5),
+            sections: vec![Section {
+                name: String::from("Bond"),
+                attributes: vec![
+                    (String::from("Mode"), String::from("802.3ad")),
+                    (String::from("MIIMonitorSec"), String::from(".1")),
+                ],
+            }],
+        };
+
+        let files = ifcfg_files(&[slave, bond], &[device]);
+        assert_eq!(files, vec![
+            (String::from("ifcfg-000000000001"), String::from("HWADDR=00:00:00:00:00:01
+ONBOOT=yes
+BOOTPROTO=none
+MASTER=bond0
+SLAVE=yes
+")),
+            (String::from("ifcfg-bond0"), String::from("DEVICE=bond0
+ONBOOT=yes
+BOOTPROTO=none
+MACADDR=00:00:00:00:00:01
+TYPE=Bond
+BONDING_MASTER=yes
+BONDING_OPTS=\"mode=802.3ad miimon=100\"
+IPADDR0=10.1.2.3
+PREFIX0=31
+DNS1=8.8.8.8
+GATEWAY=10.1.2.2
+")),
+            (String::from("route-bond0"), String::from("10.0.0.0/8 via 10.1.2.2\n")),
+        ]);
+    }
+
+    #[test]
+    fn unit_matches_test() {
+        let i = Interface {
+            name: Some(String::from("eth0")),
+            mac_address: Some(MacAddr(0xf4,0x00,0x34,0x09,0x73,0xee)),
+            ..Default::default()
+        };
+        assert!(unit_matches("[Match]\nName=eth1 eth0\n\n[Network]\nDHCP=yes\n", &i));
+        assert!(unit_matches("[Match]\nMACAddress=F4:00:34:09:73:EE\n", &i));
+        assert!(!unit_matches("[Match]\nName=eth1\n", &i));
+        assert!(!unit_matches("[Network]\nName=eth0\n", &i));
+    }
+
+    #[test]
+    fn validate_units_test() {
+        let eth0 = Interface {
+            name: Some(String::from("eth0")),
+            mac_address: Some(MacAddr(0,0,0,0,0,1)),
+            ip_addresses: vec![IpNetwork::V4(Ipv4Network::new(Ipv4Addr::new(10,0,0,2), 24).unwrap())],
+            ..Default::default()
+        };
+        let bond0 = Device {
+            name: String::from("bond0"),
+            kind: String::from("bond"),
+            mac_address: MacAddr(0,0,0,0,0,2),
+            priority: None,
+            sections: vec![],
+        };
+        let mut eth1 = eth0.clone();
+        eth1.name = Some(String::from("eth1"));
+        eth1.mac_address = Some(MacAddr(0,0,0,0,0,3));
+        eth1.ip_addresses = vec![];
+        eth1.bond = Some(String::from("bond0"));
+        assert!(validate_units(&[eth0.clone(), eth1.clone()], &[bond0], None).is_empty());
+
+        let mut eth2 = eth0.clone();
+        eth2.name = Some(String::from("eth2"));
+        assert_eq!(validate_units(&[eth0, eth1, eth2], &[], Some(20)), vec![
+            String::from("20-eth1.network is enslaved to bond bond0, which isn't defined"),
+            String::from("20-eth0.network and 20-eth2.network both match mac address 00:00:00:00:00:01"),
+            String::from("address 10.0.0.2 is assigned by both 20-eth0.network and 20-eth2.network"),
+        ]);
+    }
 }
@@ -0,0 +1,99 @@
+// Copyright 2017 CoreOS, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! privilege detection
+//!
+//! some outputs change what only root may change: other users' home
+//! directories, the user database, and the system hostname. runs without
+//! root (in ci, or while debugging) skip those outputs up front instead of
+//! failing halfway through them, and write everything else.
+
+use nix::unistd::Uid;
+
+use providers::SshKeysOptions;
+
+/// is_root returns true if the process runs as root
+pub fn is_root() -> bool {
+    Uid::effective().is_root()
+}
+
+#[cfg(feature = "ssh-keys")]
+fn current_user() -> Option<String> {
+    ::users::get_current_username()
+}
+
+#[cfg(not(feature = "ssh-keys"))]
+fn current_user() -> Option<String> {
+    None
+}
+
+/// missing_privileges returns why the output can't be written by this
+/// process, or nothing if it can be
+pub fn missing_privileges(output: &str, ssh_keys: &SshKeysOptions) -> Option<String> {
+    if is_root() {
+        return None;
+    }
+    needs_root(output, ssh_keys, current_user().as_ref().map(String::as_str))
+}
+
+/// needs_root returns why the output needs root when run as the given user
+fn needs_root(output: &str, ssh_keys: &SshKeysOptions, current_user: Option<&str>) -> Option<String> {
+    match output {
+        // under a staged root, it's only files
+        "hostnamed" if ssh_keys.root.is_some() => None,
+        "hostnamed" => Some("setting the system hostname needs root".to_owned()),
+        "password" => Some("setting passwords needs root".to_owned()),
+        "ssh-keys" => {
+            if ssh_keys.create_user.is_some() {
+                return Some("creating users needs root".to_owned());
+            }
+            // a user can write their own authorized keys
+            let mut others: Vec<&str> = ssh_keys.user.iter()
+                .chain(ssh_keys.users_map.values())
+                .map(String::as_str)
+                .filter(|&u| Some(u) != current_user)
+                .collect();
+            others.sort();
+            others.dedup();
+            if others.is_empty() {
+                None
+            } else {
+                Some(format!("writing ssh keys for {} needs root", others.join(", ")))
+            }
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn needs_root_test() {
+        let mut options = SshKeysOptions {
+            user: Some("core".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(needs_root("ssh-keys", &options, Some("core")), None);
+        assert_eq!(needs_root("attributes", &options, Some("core")), None);
+        assert!(needs_root("hostnamed", &options, Some("core")).is_some());
+
+        options.users_map.insert("alice".to_owned(), "admin".to_owned());
+        assert_eq!(needs_root("ssh-keys", &options, Some("core")),
+                   Some("writing ssh keys for admin needs root".to_owned()));
+        assert_eq!(needs_root("ssh-keys", &options, None),
+                   Some("writing ssh keys for admin, core needs root".to_owned()));
+    }
+}
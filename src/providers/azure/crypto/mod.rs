@@ -16,7 +16,8 @@
 
 pub mod x509;
 
-use openssl::x509::X509;
+use openssl::hash::MessageDigest;
+use openssl::x509::{X509, X509Ref};
 use openssl::pkey::PKey;
 use openssl::cms::CmsContentInfo;
 use openssl::pkcs12::Pkcs12;
@@ -24,6 +25,7 @@ use openssl::pkcs12::Pkcs12;
 use openssh_keys::PublicKey;
 
 use errors::*;
+use util::secret::Secret;
 
 pub fn mangle_pem(x509: &X509) -> Result<String> {
     // get the pem
@@ -38,7 +40,7 @@ pub fn mangle_pem(x509: &X509) -> Result<String> {
         .fold(String::new(), |mut s, l| {s.push_str(l); s}))
 }
 
-pub fn decrypt_cms(smime: &[u8], pkey: &PKey, x509: &X509) -> Result<Vec<u8>> {
+pub fn decrypt_cms(smime: &[u8], pkey: &PKey, x509: &X509) -> Result<Secret> {
     // now we need to read in that mime file
     let cms = CmsContentInfo::smime_read_cms(smime)
         .chain_err(|| "failed to read cms file")?;
@@ -47,10 +49,14 @@ pub fn decrypt_cms(smime: &[u8], pkey: &PKey, x509: &X509) -> Result<Vec<u8>> {
     let p12_der = cms.decrypt(pkey, x509)
         .chain_err(|| "failed to decrypt cms file")?;
 
-    Ok(p12_der)
+    // it has the private keys of the certificates in it
+    Ok(Secret::new(p12_der))
 }
 
-pub fn p12_to_ssh_pubkey(p12_der: &[u8]) -> Result<PublicKey> {
+/// p12_to_ssh_pubkeys returns the ssh public key in each of the certificates
+/// in the pkcs12 blob, along with the certificate's thumbprint, which is what
+/// the provisioning configuration refers to it by
+pub fn p12_to_ssh_pubkeys(p12_der: &[u8]) -> Result<Vec<(String, PublicKey)>> {
     // the contents of that encrypted cms blob we got are actually a different
     // cryptographic structure. we read that in from the contents and parse it.
     // PKCS12 has the ability to have a password, but we don't have one, hence
@@ -62,11 +68,28 @@ pub fn p12_to_ssh_pubkey(p12_der: &[u8]) -> Result<PublicKey> {
 
     // PKCS12 has three parts. A pkey, a main x509 cert, and a list of other
     // x509 certs. The list of other x509 certs is called the chain. there is
-    // only one cert in this chain, and it is the ssh public key.
-    let ssh_pem = p12.chain.get(0)
-        .ok_or_else(|| ErrorKind::from("failed to get cert from pkcs12 chain"))?;
+    // a cert in the chain for each ssh public key; the main cert is only
+    // one too if the blob has a single key.
+    let mut keys = Vec::new();
+    for cert in p12.chain.iter() {
+        keys.push((thumbprint(cert)?, cert_to_ssh_pubkey(cert)?));
+    }
+    if keys.is_empty() {
+        keys.push((thumbprint(&p12.cert)?, cert_to_ssh_pubkey(&p12.cert)?));
+    }
+    Ok(keys)
+}
+
+/// thumbprint is the uppercase hex sha1 fingerprint of the certificate
+fn thumbprint(cert: &X509Ref) -> Result<String> {
+    let digest = cert.fingerprint(MessageDigest::sha1())
+        .chain_err(|| "failed to get certificate fingerprint")?;
+    Ok(digest.iter().map(|b| format!("{:02X}", b)).collect())
+}
+
+fn cert_to_ssh_pubkey(cert: &X509Ref) -> Result<PublicKey> {
     // get the public key from the x509 cert
-    let ssh_pubkey_pem = ssh_pem.public_key()
+    let ssh_pubkey_pem = cert.public_key()
         .chain_err(|| "failed to get public key from cert")?;
     // get the rsa contents from the pkey struct
     let ssh_pubkey_rsa = ssh_pubkey_pem.rsa()
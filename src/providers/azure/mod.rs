@@ -16,27 +16,79 @@
 
 mod crypto;
 
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
-use std::net::{IpAddr, SocketAddr};
+use std::fs::{self, File};
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::str::FromStr;
 
+use ipnetwork::{IpNetwork, Ipv4Network};
 use openssh_keys::PublicKey;
-use update_ssh_keys::AuthorizedKeyEntry;
+use pnet;
+use pnet::util::MacAddr;
+use serde_json;
+use serde_xml_rs;
+use tempdir::TempDir;
 
 use self::crypto::x509;
 use errors::*;
 use network;
-use providers::MetadataProvider;
+use providers::{document_url, endpoints_of, MaintenanceEvent, MetadataEndpoint, MetadataProvider, ProviderConfig, TerminationNotice};
+use providers::raw::RawMetadata;
 use retry;
 use util;
+use util::attributes::AttributeNamer;
+use util::decode;
+use util::json;
+use util::mount;
 
 header! {(MSAgentName, "x-ms-agent-name") => [String]}
 header! {(MSVersion, "x-ms-version") => [String]}
 header! {(MSCipherName, "x-ms-cipher-name") => [String]}
 header! {(MSCert, "x-ms-guest-agent-public-x509-cert") => [String]}
+header! {(Metadata, "Metadata") => [String]}
+
+const PROVIDER: &str = "azure";
 
 const OPTION_245: &str = "OPTION_245";
 const MS_AGENT_NAME: &str = "com.coreos.metadata";
 const MS_VERSION: &str = "2012-11-30";
+/// the header the wireserver and the instance metadata service identify
+/// requests with
+const REQUEST_ID_HEADER: &str = "x-ms-request-id";
+const IMDS_URL: &str = "http://169.254.169.254/metadata/instance";
+// the first version with the scale set and resource id of the vm
+const IMDS_API_VERSION: &str = "2019-03-11";
+const SCHEDULED_EVENTS_URL: &str = "http://169.254.169.254/metadata/scheduledevents";
+// the first version with preemption (spot eviction) events
+const SCHEDULED_EVENTS_API_VERSION: &str = "2017-11-01";
+const OVF_ENV_FILENAME: &str = "ovf-env.xml";
+// the agent copies the provisioning data here if it got to it first
+const OVF_ENV_CACHED_PATH: &str = "/var/lib/waagent/ovf-env.xml";
+const PROVISIONING_DEVICE: &str = "/dev/sr0";
+// these links are created by the azure udev rules, which know which of the
+// attached disks is the temporary one for the current vm size
+const RESOURCE_DISK_LINK: &str = "/dev/disk/azure/resource";
+const RESOURCE_DISK_PARTITION_LINK: &str = "/dev/disk/azure/resource-part1";
+// with accelerated networking, each synthetic nic is paired with a mellanox
+// virtual function that has the same mac address. the vf is enslaved to the
+// synthetic nic by the kernel, so it must never be configured on its own.
+const SYNTHETIC_NIC_DRIVER: &str = "hv_netvsc";
+const VF_DRIVERS: &str = "mlx4_en mlx5_en mlx4_core mlx5_core";
+// the platform's recursive resolver, which is what dhcp hands out too
+const AZURE_DNS: &str = "168.63.129.16";
+// secondary nics get the routing table at this offset from their index
+const ROUTE_TABLE_BASE: u32 = 100;
+/// the names fetch_raw saves documents under, besides the provisioning
+/// configuration, which is saved as OVF_ENV_FILENAME. the wireserver's
+/// documents are named by its goal state, so they get names of their own.
+const SHARED_CONFIG_DOCUMENT: &str = "wireserver/shared-config.xml";
+const CERTIFICATE_KEYS_DOCUMENT: &str = "wireserver/certificate-keys";
+const INSTANCE_METADATA_DOCUMENT: &str = "metadata/instance";
+const RESOURCE_DISK_DOCUMENT: &str = "resource-disk";
+const RESOURCE_DISK_PARTITION_DOCUMENT: &str = "resource-disk-partition";
 const SMIME_HEADER: &str = "\
 MIME-Version:1.0
 Content-Disposition: attachment; filename=/home/core/encrypted-ssh-cert.pem
@@ -47,6 +99,8 @@ Content-Transfer-Encoding: base64
 
 #[derive(Debug, Deserialize, Clone, Default)]
 struct GoalState {
+    #[serde(rename = "Incarnation", default)]
+    pub incarnation: String,
     #[serde(rename = "Container")]
     pub container: Container
 }
@@ -134,6 +188,270 @@ struct Endpoint {
     pub load_balanced_public_address: String,
 }
 
+#[derive(Debug, Deserialize, Clone)]
+struct OvfEnv {
+    #[serde(rename = "ProvisioningSection")]
+    pub provisioning_section: ProvisioningSection,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+struct ProvisioningSection {
+    #[serde(rename = "LinuxProvisioningConfigurationSet")]
+    pub linux_provisioning_configuration_set: LinuxProvisioningConfigurationSet,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+struct LinuxProvisioningConfigurationSet {
+    #[serde(rename = "HostName", default)]
+    pub hostname: String,
+    #[serde(rename = "UserName", default)]
+    pub username: String,
+    #[serde(rename = "CustomData", default)]
+    pub custom_data: String,
+    #[serde(rename = "SSH", default)]
+    pub ssh: SshSection,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+struct SshSection {
+    #[serde(rename = "PublicKeys", default)]
+    pub public_keys: PublicKeys,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+struct PublicKeys {
+    #[serde(rename = "PublicKey", default)]
+    pub keys: Vec<OvfPublicKey>,
+}
+
+/// a key given when the vm was created. keys given as a certificate only
+/// have the certificate's thumbprint here, and are only in the certificates
+/// the fabric hands out.
+#[derive(Debug, Deserialize, Clone)]
+struct OvfPublicKey {
+    #[serde(rename = "Fingerprint", default)]
+    pub fingerprint: String,
+    #[serde(rename = "Value", default)]
+    pub value: String,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+struct InstanceMetadata {
+    #[serde(default)]
+    pub compute: ComputeMetadata,
+    #[serde(default)]
+    pub network: NetworkMetadata,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+struct NetworkMetadata {
+    #[serde(default)]
+    pub interface: Vec<NicMetadata>,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+struct NicMetadata {
+    #[serde(default)]
+    pub ipv4: NicIpv4Metadata,
+    #[serde(default)]
+    pub mac_address: String,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+struct NicIpv4Metadata {
+    #[serde(default)]
+    pub ip_address: Vec<NicAddress>,
+    #[serde(default)]
+    pub subnet: Vec<NicSubnet>,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+struct NicAddress {
+    #[serde(default)]
+    pub private_ip_address: String,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+struct NicSubnet {
+    #[serde(default)]
+    pub address: String,
+    #[serde(default)]
+    pub prefix: String,
+}
+
+impl NicMetadata {
+    /// to_interface converts the nic to an interface matched by mac address
+    /// and the synthetic nic driver, so that its virtual function is left
+    /// alone. only the first nic gets the main table's default route; with
+    /// policy routing, the others get a default route in a table of their
+    /// own, which is used for traffic from their addresses.
+    fn to_interface(&self, index: usize, policy_routing: bool) -> Result<network::Interface> {
+        // the mac address is given as bare hex digits
+        let mac = self.mac_address.as_bytes().chunks(2)
+            .map(|c| String::from_utf8_lossy(c).into_owned())
+            .collect::<Vec<_>>()
+            .join(":");
+        let mac = MacAddr::from_str(&mac)
+            .map_err(|e| Error::from(format!("{:?}", e)))
+            .chain_err(|| format!("failed to parse mac address: '{}'", self.mac_address))?;
+
+        let subnet = self.ipv4.subnet.get(0)
+            .ok_or_else(|| format!("nic {} has no subnet", mac))?;
+        let prefix = subnet.prefix.parse()
+            .chain_err(|| format!("invalid subnet prefix '{}'", subnet.prefix))?;
+        let subnet_address = Ipv4Addr::from_str(&subnet.address)
+            .chain_err(|| format!("invalid subnet address '{}'", subnet.address))?;
+        let subnet = Ipv4Network::new(subnet_address, prefix)
+            .chain_err(|| format!("invalid subnet {}/{}", subnet.address, prefix))?;
+        // the gateway is always the first address in the subnet
+        let gateway = IpAddr::V4(Ipv4Addr::from(u32::from(subnet.network()) + 1));
+
+        let mut ip_addresses = Vec::new();
+        for addr in &self.ipv4.ip_address {
+            let ip = IpAddr::from_str(&addr.private_ip_address)
+                .chain_err(|| format!("invalid address '{}'", addr.private_ip_address))?;
+            ip_addresses.push(IpNetwork::new(ip, prefix)
+                .chain_err(|| format!("invalid address {}/{}", ip, prefix))?);
+        }
+
+        let default_route = network::NetworkRoute {
+            destination: IpNetwork::from_str("0.0.0.0/0").unwrap(),
+            gateway,
+        };
+        let (routes, route_table) = match (index, policy_routing) {
+            (0, _) => (vec![default_route], None),
+            (_, true) => (vec![default_route], Some(ROUTE_TABLE_BASE + index as u32)),
+            (_, false) => (vec![], None),
+        };
+
+        Ok(network::Interface {
+            mac_address: Some(mac),
+            nameservers: vec![IpAddr::from_str(AZURE_DNS).unwrap()],
+            ip_addresses,
+            routes,
+            driver: Some(SYNTHETIC_NIC_DRIVER.to_owned()),
+            route_table,
+            ..Default::default()
+        })
+    }
+}
+
+/// nic_interfaces returns an interface for each nic. vms with a single nic
+/// are left to dhcp, as they always have been.
+fn nic_interfaces(nics: &[NicMetadata], policy_routing: bool) -> Result<Vec<network::Interface>> {
+    if nics.len() < 2 {
+        return Ok(vec![]);
+    }
+    nics.iter().enumerate()
+        .map(|(i, nic)| nic.to_interface(i, policy_routing))
+        .collect()
+}
+
+/// the maintenance the platform has scheduled, from the scheduled events
+/// service
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ScheduledEvents {
+    #[serde(default)]
+    events: Vec<ScheduledEvent>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ScheduledEvent {
+    event_id: String,
+    event_type: String,
+    #[serde(default)]
+    event_status: String,
+    /// the names of the vms the event is for
+    #[serde(default)]
+    resources: Vec<String>,
+    /// empty once the event has started
+    #[serde(default)]
+    not_before: String,
+}
+
+/// termination_event returns the first event that takes the vm away for
+/// good. reboots, redeploys, and freezes leave it (or its disks) in place.
+fn termination_event(events: &ScheduledEvents, vm_name: &str) -> Option<TerminationNotice> {
+    events.events.iter()
+        .filter(|e| e.event_type == "Preempt" || e.event_type == "Terminate")
+        .find(|e| e.resources.iter().any(|r| r == vm_name))
+        .map(|e| TerminationNotice {
+            reason: e.event_type.clone(),
+            not_before: if e.not_before.is_empty() { None } else { Some(e.not_before.clone()) },
+        })
+}
+
+/// maintenance_events returns the events for the vm
+fn maintenance_events(events: &ScheduledEvents, vm_name: &str) -> Vec<MaintenanceEvent> {
+    events.events.iter()
+        .filter(|e| e.resources.iter().any(|r| r == vm_name))
+        .map(|e| MaintenanceEvent {
+            id: e.event_id.clone(),
+            kind: e.event_type.clone(),
+            status: e.event_status.clone(),
+            not_before: if e.not_before.is_empty() { None } else { Some(e.not_before.clone()) },
+        })
+        .collect()
+}
+
+/// the body that starts scheduled events early
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct StartRequests {
+    start_requests: Vec<StartRequest>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct StartRequest {
+    event_id: String,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+struct ComputeMetadata {
+    #[serde(default)]
+    pub vm_size: String,
+    #[serde(default)]
+    pub platform_fault_domain: String,
+    #[serde(default)]
+    pub platform_update_domain: String,
+    #[serde(default)]
+    pub subscription_id: String,
+    #[serde(default)]
+    pub tags: String,
+    #[serde(default)]
+    pub vm_id: String,
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub vm_scale_set_name: String,
+    #[serde(default)]
+    pub resource_id: String,
+}
+
+/// scale_set_ordinal is the instance's ordinal in its scale set, the last
+/// part of its resource id,
+/// `.../virtualMachineScaleSets/<name>/virtualMachines/<ordinal>`. the vms
+/// of flexible scale sets are standalone vms, which don't have one.
+fn scale_set_ordinal(resource_id: &str) -> Option<&str> {
+    let mut parts = resource_id.rsplit('/');
+    let ordinal = parts.next()?;
+    let vms = parts.next()?;
+    let scale_sets = parts.nth(1)?;
+    if vms.eq_ignore_ascii_case("virtualMachines") && scale_sets.eq_ignore_ascii_case("virtualMachineScaleSets")
+        && !ordinal.is_empty() && ordinal.bytes().all(|b| b.is_ascii_digit()) {
+        Some(ordinal)
+    } else {
+        None
+    }
+}
+
 #[derive(Debug, Copy, Clone, Default)]
 struct Attributes {
     pub virtual_ipv4: Option<IpAddr>,
@@ -143,22 +461,44 @@ struct Attributes {
 #[derive(Debug, Clone)]
 pub struct Azure {
     client: retry::Client,
-    endpoint: IpAddr,
-    goal_state: GoalState,
+    endpoint: String,
+    /// the goal state as of the last time it was fetched
+    goal_state: RefCell<GoalState>,
+    /// the provisioning configuration, once ovf_env_read is set
+    ovf_env: RefCell<Option<OvfEnv>>,
+    ovf_env_read: Cell<bool>,
+    policy_routing: bool,
+    /// the documents, when they were saved by fetch_raw rather than fetched
+    /// as they're needed
+    raw: Option<RawMetadata>,
 }
 
 impl Azure {
-    pub fn new() -> Result<Azure> {
-        let addr = Azure::get_fabric_address()
-            .chain_err(|| "failed to get fabric address")?;
+    pub fn new(config: &ProviderConfig) -> Result<Azure> {
+        let endpoint = match config.endpoint_override() {
+            Some(endpoint) => endpoint,
+            None => {
+                let addr = Azure::get_fabric_address()
+                    .chain_err(|| "failed to get fabric address")?;
+                format!("http://{}", addr)
+            }
+        };
         let client = retry::Client::new()?
             .header(MSAgentName(MS_AGENT_NAME.to_owned()))
-            .header(MSVersion(MS_VERSION.to_owned()));
+            .header(MSVersion(MS_VERSION.to_owned()))
+            // the wireserver answers 410 while the goal state is changing
+            .retry_on_status(410)
+            // support needs the request id to look into failed requests
+            .request_id_header(REQUEST_ID_HEADER);
 
         let mut azure = Azure {
             client,
-            endpoint: addr,
-            goal_state: GoalState::default(),
+            endpoint,
+            goal_state: RefCell::new(GoalState::default()),
+            ovf_env: RefCell::new(None),
+            ovf_env_read: Cell::new(false),
+            policy_routing: config.azure_policy_routing,
+            raw: None,
         };
 
         // make sure the metadata service is compatible with our version
@@ -166,16 +506,90 @@ impl Azure {
             .chain_err(|| "failed version compatibility check")?;
 
         // populate goalstate
-        azure.goal_state = azure.get_goal_state()?;
+        azure.goal_state = RefCell::new(azure.get_goal_state()?);
         Ok(azure)
     }
 
+    /// fetch_raw fetches every document the provider reads. the
+    /// certificates are encrypted for a key that's made for the request and
+    /// thrown away, so the ssh keys in them are saved instead, one
+    /// `<thumbprint> <key>` per line. the resource disk is saved as the
+    /// devices its links resolve to.
+    pub fn fetch_raw(config: &ProviderConfig) -> Result<RawMetadata> {
+        let azure = Azure::new(config)?;
+        let mut raw = RawMetadata::new(PROVIDER);
+        if let Some(ovf_env) = Azure::get_ovf_env_document()? {
+            raw.insert(OVF_ENV_FILENAME, ovf_env);
+        }
+        raw.insert(SHARED_CONFIG_DOCUMENT, azure.shared_config_document()?);
+        let keys: Vec<String> = azure.certificate_keys()?.into_iter()
+            .map(|(thumbprint, key)| format!("{} {}", thumbprint, key.to_key_format()))
+            .collect();
+        raw.insert(CERTIFICATE_KEYS_DOCUMENT, keys.join("\n"));
+        // as when it's read, the instance metadata service may be missing
+        match azure.instance_metadata_document() {
+            Ok(metadata) => raw.insert(INSTANCE_METADATA_DOCUMENT, metadata),
+            Err(e) => warn!("failed to fetch instance metadata, skipping: {}", e),
+        }
+        let (disk, partition) = Azure::get_resource_disk()?;
+        if let Some(disk) = disk {
+            raw.insert(RESOURCE_DISK_DOCUMENT, disk);
+        }
+        if let Some(partition) = partition {
+            raw.insert(RESOURCE_DISK_PARTITION_DOCUMENT, partition);
+        }
+        Ok(raw)
+    }
+
+    /// parse makes a provider that reads the documents saved by fetch_raw.
+    /// policy routing is a flag rather than metadata, so it's off.
+    pub fn parse(raw: &RawMetadata) -> Result<Azure> {
+        raw.check(PROVIDER)?;
+        Ok(Azure {
+            client: retry::Client::new()?,
+            endpoint: String::new(),
+            goal_state: RefCell::new(GoalState::default()),
+            ovf_env: RefCell::new(None),
+            ovf_env_read: Cell::new(false),
+            policy_routing: false,
+            raw: Some(raw.clone()),
+        })
+    }
+
     fn get_goal_state(&self) -> Result<GoalState> {
-        self.client.get(retry::Xml, format!("http://{}/machine/?comp=goalstate", self.endpoint)).send()
+        self.client.get_xml(format!("{}/machine/?comp=goalstate", self.endpoint))
             .chain_err(|| "failed to get goal state")?
         .ok_or_else(|| "failed to get goal state: not found response".into())
     }
 
+    /// with_goal_state calls f with the goal state, and once more with a new
+    /// one if it failed because the goal state changed in the meantime. the
+    /// documents a goal state names are only served for its incarnation,
+    /// and the incarnation of a scale set's vms changes whenever the scale
+    /// set's model does, sometimes more than once while a vm boots.
+    fn with_goal_state<T, F>(&self, f: F) -> Result<T>
+        where F: Fn(&GoalState) -> Result<T>
+    {
+        let current = self.goal_state.borrow().clone();
+        let err = match f(&current) {
+            Ok(v) => return Ok(v),
+            Err(e) => e,
+        };
+        let latest = match self.get_goal_state() {
+            Ok(latest) => latest,
+            Err(e) => {
+                warn!("failed to check whether the goal state changed: {}", e);
+                return Err(err);
+            }
+        };
+        if latest.incarnation == current.incarnation {
+            return Err(err);
+        }
+        info!("the goal state changed from incarnation {} to {}, trying again", current.incarnation, latest.incarnation);
+        *self.goal_state.borrow_mut() = latest.clone();
+        f(&latest)
+    }
+
     fn get_fabric_address() -> Result<IpAddr> {
         let v = util::dns_lease_key_lookup(OPTION_245)?;
         // value is an 8 digit hex value. convert it to u32 and
@@ -188,28 +602,32 @@ impl Azure {
     }
 
     fn is_fabric_compatible(&self, version: &str) -> Result<()> {
-        let versions: Versions = self.client.get(retry::Xml, format!("http://{}/?comp=versions", self.endpoint)).send()
+        let versions: Versions = self.client.get_xml(format!("{}/?comp=versions", self.endpoint))
             .chain_err(|| "failed to get versions")?
             .ok_or_else(|| "failed to get versions: not found")?;
 
         if versions.supported.versions.iter().any(|v| v == version) {
             Ok(())
         } else {
-            Err(format!("fabric version {} not compatible with fabric address {}", MS_VERSION, self.endpoint).into())
+            Err(format!("fabric version {} not compatible with fabric endpoint {}", MS_VERSION, self.endpoint).into())
         }
     }
 
-    fn get_certs_endpoint(&self) -> Result<String> {
+    /// role_instance returns the goal state's role instance. a goal state
+    /// without one is an error, which with_goal_state tries again on if the
+    /// goal state has changed since.
+    fn role_instance(goal_state: &GoalState) -> Result<&RoleInstance> {
+        goal_state.container.role_instance_list.role_instances.get(0)
+            .ok_or_else(|| format!("goal state incarnation {} has no role instance", goal_state.incarnation).into())
+    }
+
+    fn get_certs_endpoint(goal_state: &GoalState) -> Result<String> {
         // grab the certificates endpoint from the xml and return it
-        let cert_endpoint: &str = &self.goal_state.container.role_instance_list.role_instances[0].configuration.certificates;
-        Ok(String::from(cert_endpoint))
+        Ok(Azure::role_instance(goal_state)?.configuration.certificates.clone())
     }
 
-    fn get_certs(&self, mangled_pem: String) -> Result<String> {
+    fn get_certs(&self, endpoint: String, mangled_pem: String) -> Result<String> {
         // get the certificates
-        let endpoint = self.get_certs_endpoint()
-            .chain_err(|| "failed to get certs endpoint")?;
-
         let certs: CertificatesFile = self.client.get(retry::Xml, endpoint)
             .header(MSCipherName("DES_EDE3_CBC".to_owned()))
             .header(MSCert(mangled_pem))
@@ -225,8 +643,64 @@ impl Azure {
         Ok(smime)
     }
 
+    /// get_ssh_pubkeys returns the keys in the provisioning configuration,
+    /// and the ones that are only delivered as certificates. the certificates
+    /// are only fetched if the provisioning configuration doesn't have the
+    /// keys itself.
+    fn get_ssh_pubkeys(&self, ovf_env: Option<&OvfEnv>) -> Result<Vec<PublicKey>> {
+        let mut keys = Vec::new();
+        let mut thumbprints = Vec::new();
+        if let Some(ovf_env) = ovf_env {
+            for key in &ovf_env.provisioning_section.linux_provisioning_configuration_set.ssh.public_keys.keys {
+                if !key.value.trim().is_empty() {
+                    keys.push(PublicKey::parse(key.value.trim())?);
+                } else if !key.fingerprint.is_empty() {
+                    thumbprints.push(key.fingerprint.trim().to_uppercase());
+                }
+            }
+        }
+        if !keys.is_empty() && thumbprints.is_empty() {
+            return Ok(keys);
+        }
+        let from_certs = self.certificate_keys()?;
+
+        // without thumbprints to go by, every key in the certificates is used
+        for (thumbprint, key) in from_certs {
+            if thumbprints.is_empty() || thumbprints.contains(&thumbprint) {
+                keys.push(key);
+            } else {
+                debug!("skipping certificate {}, which isn't an ssh key", thumbprint);
+            }
+        }
+        Ok(keys)
+    }
+
+    /// certificate_keys returns the ssh keys in the certificates, with their
+    /// thumbprints
+    fn certificate_keys(&self) -> Result<Vec<(String, PublicKey)>> {
+        if let Some(ref raw) = self.raw {
+            let mut keys = Vec::new();
+            for line in raw.require_text(CERTIFICATE_KEYS_DOCUMENT)?.lines().filter(|l| !l.is_empty()) {
+                let mut fields = line.splitn(2, ' ');
+                match (fields.next(), fields.next()) {
+                    (Some(thumbprint), Some(key)) => keys.push((thumbprint.to_owned(), PublicKey::parse(key)?)),
+                    _ => return Err(format!("invalid certificate key {:?}", line).into()),
+                }
+            }
+            return Ok(keys);
+        }
+        self.with_goal_state(|goal_state| {
+            let endpoint = Azure::get_certs_endpoint(goal_state)?;
+            if endpoint.is_empty() {
+                debug!("the goal state has no certificates");
+                return Ok(vec![]);
+            }
+            self.get_ssh_pubkeys_from_certs(endpoint)
+        })
+    }
+
     // put it all together
-    fn get_ssh_pubkey(&self) -> Result<PublicKey> {
+    fn get_ssh_pubkeys_from_certs(&self, endpoint: String) -> Result<Vec<(String, PublicKey)>> {
         // first we have to get the certificates endoint.
         // we have to generate the rsa public/private keypair and the x509 cert
         // that we use to make the request. this is equivalent to
@@ -239,7 +713,7 @@ impl Azure {
             .chain_err(|| "failed to mangle pem")?;
 
         // fetch the encrypted cms blob from the certs endpoint
-        let smime = self.get_certs(mangled_pem)
+        let smime = self.get_certs(endpoint, mangled_pem)
             .chain_err(|| "failed to get certs")?;
 
         // decrypt the cms blob
@@ -247,18 +721,28 @@ impl Azure {
             .chain_err(|| "failed to decrypt cms blob")?;
 
         // convert that to the OpenSSH public key format
-        let ssh_pubkey = crypto::p12_to_ssh_pubkey(&p12)
-            .chain_err(|| "failed to convert pkcs12 blob to ssh pubkey")?;
+        crypto::p12_to_ssh_pubkeys(p12.expose())
+            .chain_err(|| "failed to convert pkcs12 blob to ssh pubkeys")
+    }
 
-        Ok(ssh_pubkey)
+    /// shared_config_document returns the shared configuration as the
+    /// wireserver served it
+    fn shared_config_document(&self) -> Result<String> {
+        if let Some(ref raw) = self.raw {
+            return Ok(raw.require_text(SHARED_CONFIG_DOCUMENT)?.to_owned());
+        }
+        self.with_goal_state(|goal_state| {
+            let endpoint = &Azure::role_instance(goal_state)?.configuration.shared_config;
+            self.client.get(retry::Raw, endpoint.to_string())
+                .send()
+                .chain_err(|| "failed to get shared configuration")?
+                .ok_or_else(|| "failed to get shared configuration: not found".into())
+        })
     }
 
     fn get_attributes(&self) -> Result<Attributes> {
-        let endpoint = &self.goal_state.container.role_instance_list.role_instances[0].configuration.shared_config;
-
-        let shared_config: SharedConfig = self.client.get(retry::Xml, endpoint.to_string()).send()
-            .chain_err(|| "failed to get shared configuration")?
-            .ok_or_else(|| "failed to get shared configuration: not found")?;
+        let shared_config: SharedConfig = serde_xml_rs::deserialize(self.shared_config_document()?.as_bytes())
+            .chain_err(|| "failed to parse shared configuration")?;
 
         let mut attributes = Attributes::default();
 
@@ -277,6 +761,179 @@ impl Azure {
 
         Ok(attributes)
     }
+
+    /// instance_metadata_document returns the instance metadata as the
+    /// service served it
+    fn instance_metadata_document(&self) -> Result<String> {
+        if let Some(ref raw) = self.raw {
+            return Ok(raw.require_text(INSTANCE_METADATA_DOCUMENT)?.to_owned());
+        }
+        // unlike the wireserver, the instance metadata service is always at
+        // the well-known link-local address
+        self.client.get(retry::Raw, format!("{}?api-version={}", IMDS_URL, IMDS_API_VERSION))
+            .header(Metadata("true".to_owned()))
+            .send()
+            .chain_err(|| "failed to get instance metadata")?
+            .ok_or_else(|| "failed to get instance metadata: not found".into())
+    }
+
+    fn get_instance_metadata(&self) -> Result<InstanceMetadata> {
+        json::from_slice(self.instance_metadata_document()?.as_bytes())
+            .chain_err(|| "failed to parse instance metadata")
+    }
+
+    /// fetch_document fetches a path from the root of the instance metadata
+    /// service, e.g. `/metadata/instance?api-version=2017-08-01`, or from the
+    /// endpoint override if there is one. the wireserver's documents are
+    /// named by its goal state, so they can't be fetched on their own.
+    pub fn fetch_document(config: &ProviderConfig, path: &str) -> Result<Option<Vec<u8>>> {
+        let base_url = config.endpoint_or(IMDS_URL);
+        retry::Client::new()?
+            .return_on_404(true)
+            .request_id_header(REQUEST_ID_HEADER)
+            .get(retry::Raw, document_url(&base_url, path)?)
+            .header(Metadata("true".to_owned()))
+            .send_bytes()
+    }
+
+    fn scheduled_events_url() -> String {
+        format!("{}?api-version={}", SCHEDULED_EVENTS_URL, SCHEDULED_EVENTS_API_VERSION)
+    }
+
+    /// get_scheduled_events fetches the scheduled events. the first request
+    /// turns the service on for the vm, which can take a couple of minutes.
+    fn get_scheduled_events(&self) -> Result<Option<ScheduledEvents>> {
+        // events are only ever fetched as they happen, never saved
+        if self.raw.is_some() {
+            return Ok(None);
+        }
+        self.client.get(retry::Json, Self::scheduled_events_url())
+            .header(Metadata("true".to_owned()))
+            .send()
+            .chain_err(|| "failed to get scheduled events")
+    }
+
+    /// resource_disk returns the resource disk and its first partition, if
+    /// there are any
+    fn resource_disk(&self) -> Result<(Option<String>, Option<String>)> {
+        match self.raw {
+            Some(ref raw) => Ok((raw.text(RESOURCE_DISK_DOCUMENT)?.map(String::from),
+                                 raw.text(RESOURCE_DISK_PARTITION_DOCUMENT)?.map(String::from))),
+            None => Azure::get_resource_disk(),
+        }
+    }
+
+    fn get_resource_disk() -> Result<(Option<String>, Option<String>)> {
+        let resolve = |link: &str| -> Result<Option<String>> {
+            let link = Path::new(link);
+            if !link.exists() {
+                return Ok(None);
+            }
+            let device = fs::canonicalize(link)
+                .chain_err(|| format!("failed to resolve link '{:?}'", link))?;
+            Ok(Some(device.to_string_lossy().into_owned()))
+        };
+
+        Ok((resolve(RESOURCE_DISK_LINK)?, resolve(RESOURCE_DISK_PARTITION_LINK)?))
+    }
+
+    fn get_interface_driver(name: &str) -> Option<String> {
+        let link = Path::new("/sys/class/net").join(name).join("device/driver");
+        fs::read_link(&link).ok()
+            .and_then(|driver| driver.file_name().map(|n| n.to_string_lossy().into_owned()))
+    }
+
+    /// get_synthetic_nic_name returns the name of the synthetic nic with the
+    /// mac address, if it's present
+    fn get_synthetic_nic_name(mac: MacAddr) -> Option<String> {
+        pnet::datalink::interfaces().into_iter()
+            .find(|iface| iface.mac == Some(mac)
+                && Azure::get_interface_driver(&iface.name).as_ref().map(String::as_str) == Some(SYNTHETIC_NIC_DRIVER))
+            .map(|iface| iface.name)
+    }
+
+    fn get_vf_interfaces() -> Vec<network::Interface> {
+        // match on the mac of every synthetic nic rather than on the vfs that
+        // are currently present, since vfs can be hot-added at any time
+        pnet::datalink::interfaces().into_iter()
+            .filter(|iface| Azure::get_interface_driver(&iface.name).as_ref().map(String::as_str) == Some(SYNTHETIC_NIC_DRIVER))
+            .filter_map(|iface| iface.mac)
+            .map(|mac| network::Interface {
+                mac_address: Some(mac),
+                priority: Some(5),
+                unmanaged: true,
+                driver: Some(VF_DRIVERS.to_owned()),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    /// ovf_env is the provisioning configuration, which is only read once.
+    /// it's None if there's no copy of it and the provisioning media can't
+    /// be mounted, e.g. because it's already been ejected.
+    fn ovf_env(&self) -> Result<Option<OvfEnv>> {
+        if self.ovf_env_read.get() {
+            return Ok(self.ovf_env.borrow().clone());
+        }
+        let document = match self.raw {
+            Some(ref raw) => raw.text(OVF_ENV_FILENAME)?.map(String::from),
+            None => Azure::get_ovf_env_document()?,
+        };
+        let ovf_env = match document {
+            Some(document) => Some(Azure::parse_ovf_env(&document)?),
+            None => None,
+        };
+        *self.ovf_env.borrow_mut() = ovf_env.clone();
+        self.ovf_env_read.set(true);
+        Ok(ovf_env)
+    }
+
+    /// ovf_env_for_keys is the provisioning configuration, if it can be
+    /// read. the certificates are enough to find the keys if it can't.
+    fn ovf_env_for_keys(&self) -> Option<OvfEnv> {
+        self.ovf_env().unwrap_or_else(|e| {
+            warn!("failed to read provisioning configuration, using the keys in the certificates: {}", e);
+            None
+        })
+    }
+
+    /// get_ovf_env_document returns the provisioning configuration as the
+    /// fabric wrote it
+    fn get_ovf_env_document() -> Result<Option<String>> {
+        let cached = Path::new(OVF_ENV_CACHED_PATH);
+        if cached.exists() {
+            return Azure::read_ovf_env_document(cached).map(Some);
+        }
+
+        // the provisioning configuration lives on a udf-formatted cdrom that
+        // the fabric attaches to the vm. mount it just long enough to read it.
+        let target = TempDir::new("coreos-metadata")
+            .chain_err(|| "failed to create temporary directory")?;
+        let mounted = mount::mount_ro(Path::new(PROVISIONING_DEVICE), target.path(), "udf")
+            .or_else(|_| mount::mount_ro(Path::new(PROVISIONING_DEVICE), target.path(), "iso9660"));
+        if let Err(e) = mounted {
+            warn!("failed to mount provisioning media, skipping the provisioning configuration: {}", e);
+            return Ok(None);
+        }
+
+        let ovf_env = Azure::read_ovf_env_document(&target.path().join(OVF_ENV_FILENAME));
+        mount::unmount(target.path())?;
+
+        ovf_env.map(Some)
+    }
+
+    fn read_ovf_env_document(path: &Path) -> Result<String> {
+        let mut document = String::new();
+        File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut document))
+            .chain_err(|| format!("failed to read file '{:?}'", path))?;
+        Ok(document)
+    }
+
+    fn parse_ovf_env(document: &str) -> Result<OvfEnv> {
+        serde_xml_rs::deserialize(document.as_bytes())
+            .chain_err(|| "failed to parse provisioning configuration")
+    }
 }
 
 impl MetadataProvider for Azure {
@@ -292,23 +949,316 @@ impl MetadataProvider for Azure {
             out.insert("AZURE_IPV4_DYNAMIC".to_string(), dynamic_ipv4.to_string());
         }
 
+        let (disk, partition) = self.resource_disk()?;
+        out.insert("AZURE_RESOURCE_DISK_PRESENT".to_string(), disk.is_some().to_string());
+        if let Some(disk) = disk {
+            out.insert("AZURE_RESOURCE_DISK".to_string(), disk);
+        }
+        // the first partition is the one that should be mounted, if the vm
+        // size came with a formatted resource disk at all
+        if let Some(partition) = partition {
+            out.insert("AZURE_RESOURCE_DISK_PARTITION".to_string(), partition);
+        }
+
+        // the instance metadata service isn't available everywhere the
+        // wireserver is (e.g. azure stack), so don't fail if it's missing
+        match self.get_instance_metadata() {
+            Ok(metadata) => {
+                let compute = metadata.compute;
+                let add_value = |map: &mut HashMap<_, _>, key: &str, value: String| {
+                    if !value.is_empty() {
+                        map.insert(key.to_string(), value);
+                    }
+                };
+                add_value(&mut out, "AZURE_VM_SIZE", compute.vm_size);
+                add_value(&mut out, "AZURE_FAULT_DOMAIN", compute.platform_fault_domain);
+                add_value(&mut out, "AZURE_UPDATE_DOMAIN", compute.platform_update_domain);
+                add_value(&mut out, "AZURE_SUBSCRIPTION_ID", compute.subscription_id);
+                add_value(&mut out, "AZURE_RESOURCE_ID", compute.resource_id.clone());
+                add_value(&mut out, "AZURE_VMSS_NAME", compute.vm_scale_set_name);
+                if let Some(ordinal) = scale_set_ordinal(&compute.resource_id) {
+                    out.insert("AZURE_VMSS_ORDINAL".to_string(), ordinal.to_owned());
+                }
+                // tags come as a single "key1:value1;key2:value2" string
+                let mut namer = AttributeNamer::new("AZURE_TAG_");
+                for tag in compute.tags.split(';').filter(|t| !t.is_empty()) {
+                    let (key, value) = match tag.find(':') {
+                        Some(index) => (&tag[..index], &tag[index+1..]),
+                        None => (tag, ""),
+                    };
+                    out.insert(namer.name_for(key), value.to_owned());
+                }
+                add_value(&mut out, "AZURE_TAGS", compute.tags);
+            }
+            Err(e) => warn!("failed to fetch instance metadata, skipping: {}", e),
+        }
+
         Ok(out)
     }
 
     fn hostname(&self) -> Result<Option<String>> {
-        Ok(None)
+        let hostname = match self.ovf_env()? {
+            Some(ovf_env) => ovf_env.provisioning_section.linux_provisioning_configuration_set.hostname,
+            None => return Ok(None),
+        };
+        if hostname.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(hostname))
+        }
+    }
+
+    /// the scheduled events service announces spot evictions at least 30
+    /// seconds ahead, and deletions 5 to 15 minutes ahead
+    fn termination_notice(&self) -> Result<Option<TerminationNotice>> {
+        let name = self.get_instance_metadata()?.compute.name;
+        Ok(self.get_scheduled_events()?.and_then(|events| termination_event(&events, &name)))
+    }
+
+    fn maintenance_events(&self) -> Result<Vec<MaintenanceEvent>> {
+        let name = self.get_instance_metadata()?.compute.name;
+        Ok(self.get_scheduled_events()?.map_or_else(Vec::new, |events| maintenance_events(&events, &name)))
+    }
+
+    /// the events start once every vm they're for has asked for it, or when
+    /// they're due
+    fn start_maintenance(&self, ids: &[String]) -> Result<()> {
+        if self.raw.is_some() {
+            return Err("maintenance can't be started from saved documents".into());
+        }
+        let body = StartRequests {
+            start_requests: ids.iter().map(|id| StartRequest { event_id: id.clone() }).collect(),
+        };
+        let body = serde_json::to_vec(&body)
+            .chain_err(|| "failed to serialize start requests")?;
+        self.client.post(retry::Json, Self::scheduled_events_url())
+            .header(Metadata("true".to_owned()))
+            .body(body)
+            .send_bytes()
+            .chain_err(|| "failed to start scheduled events")?;
+        Ok(())
+    }
+
+    fn pretty_hostname(&self) -> Result<Option<String>> {
+        let name = self.get_instance_metadata()?.compute.name;
+        if name.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(name))
+        }
     }
 
-    fn ssh_keys(&self) -> Result<Vec<AuthorizedKeyEntry>> {
-        let key = self.get_ssh_pubkey()?;
-        Ok(vec![AuthorizedKeyEntry::Valid{key}])
+    /// the instance metadata service, and the wireserver, which hands out
+    /// the certificates
+    fn metadata_endpoints(&self) -> Vec<MetadataEndpoint> {
+        endpoints_of(&[IMDS_URL, self.endpoint.as_str()])
     }
 
+    fn instance_id(&self) -> Result<Option<String>> {
+        let vm_id = self.get_instance_metadata()?.compute.vm_id;
+        if vm_id.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(vm_id))
+        }
+    }
+
+    /// the vnet's own dns suffix isn't in the metadata, but names under
+    /// internal.cloudapp.net resolve from any vnet
+    fn search_domains(&self) -> Result<Vec<String>> {
+        Ok(vec!["internal.cloudapp.net".to_owned()])
+    }
+
+    fn ssh_keys(&self) -> Result<Vec<PublicKey>> {
+        let ovf_env = self.ovf_env_for_keys();
+        self.get_ssh_pubkeys(ovf_env.as_ref())
+    }
+
+    /// the keys are the ones given for the admin user the vm was created
+    /// with. without the provisioning configuration, there's no user to give
+    /// them to, and they go to the default one.
+    fn ssh_keys_by_user(&self) -> Result<Vec<(Option<String>, PublicKey)>> {
+        let ovf_env = self.ovf_env_for_keys();
+        let keys = self.get_ssh_pubkeys(ovf_env.as_ref())?;
+        let username = ovf_env.map(|ovf_env| ovf_env.provisioning_section.linux_provisioning_configuration_set.username)
+            .and_then(|username| if username.is_empty() { None } else { Some(username) });
+        Ok(keys.into_iter().map(|key| (username.clone(), key)).collect())
+    }
+
+    /// the nics come from the instance metadata service, which isn't
+    /// everywhere (e.g. azure stack); without it, only the vfs are left
+    /// unmanaged
     fn networks(&self) -> Result<Vec<network::Interface>> {
-        Ok(vec![])
+        let mut interfaces = match self.get_instance_metadata() {
+            Ok(metadata) => nic_interfaces(&metadata.network.interface, self.policy_routing)?,
+            Err(e) => {
+                warn!("failed to fetch instance metadata, skipping the units for the nics: {}", e);
+                vec![]
+            }
+        };
+        // the nics and vfs are looked up on the machine itself, which saved
+        // documents needn't have come from
+        if self.raw.is_some() {
+            return Ok(interfaces);
+        }
+        // naming the units after the nic keeps them apart from the units of
+        // the vfs, which have the same mac address
+        for iface in &mut interfaces {
+            iface.name = iface.mac_address.and_then(Azure::get_synthetic_nic_name);
+        }
+        interfaces.extend(Azure::get_vf_interfaces());
+        Ok(interfaces)
     }
 
     fn network_devices(&self) -> Result<Vec<network::Device>> {
         Ok(vec![])
     }
+
+    fn custom_data(&self) -> Result<Option<Vec<u8>>> {
+        let encoded = match self.ovf_env()? {
+            Some(ovf_env) => ovf_env.provisioning_section.linux_provisioning_configuration_set.custom_data,
+            None => return Ok(None),
+        };
+        if encoded.trim().is_empty() {
+            return Ok(None);
+        }
+        let data = decode::decode_base64(encoded.as_bytes())
+            .and_then(decode::maybe_gunzip)
+            .chain_err(|| "failed to decode custom data")?;
+        Ok(Some(data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nic_interfaces_test() {
+        let metadata: NetworkMetadata = serde_json::from_str(r#"{"interface": [
+            {"ipv4": {"ipAddress": [{"privateIpAddress": "10.0.0.4", "publicIpAddress": "40.1.2.3"}],
+                      "subnet": [{"address": "10.0.0.0", "prefix": "24"}]},
+             "ipv6": {"ipAddress": []}, "macAddress": "000D3AF806EC"},
+            {"ipv4": {"ipAddress": [{"privateIpAddress": "10.0.1.4", "publicIpAddress": ""},
+                                    {"privateIpAddress": "10.0.1.5", "publicIpAddress": ""}],
+                      "subnet": [{"address": "10.0.1.0", "prefix": "24"}]},
+             "ipv6": {"ipAddress": []}, "macAddress": "000D3AF806ED"}
+        ]}"#).unwrap();
+
+        let interfaces = nic_interfaces(&metadata.interface, false).unwrap();
+        assert_eq!(interfaces.len(), 2);
+        assert_eq!(interfaces[0].mac_address, Some(MacAddr(0x00, 0x0d, 0x3a, 0xf8, 0x06, 0xec)));
+        assert_eq!(interfaces[0].routes[0].gateway, IpAddr::from_str("10.0.0.1").unwrap());
+        assert_eq!(interfaces[0].route_table, None);
+        assert_eq!(interfaces[1].ip_addresses, vec![IpNetwork::from_str("10.0.1.4/24").unwrap(),
+                                                    IpNetwork::from_str("10.0.1.5/24").unwrap()]);
+        assert!(interfaces[1].routes.is_empty());
+
+        let interfaces = nic_interfaces(&metadata.interface, true).unwrap();
+        assert_eq!(interfaces[0].route_table, None);
+        assert_eq!(interfaces[1].route_table, Some(101));
+        assert_eq!(interfaces[1].routes[0].gateway, IpAddr::from_str("10.0.1.1").unwrap());
+
+        // a single nic is left to dhcp
+        assert!(nic_interfaces(&metadata.interface[..1], true).unwrap().is_empty());
+    }
+
+    #[test]
+    fn scale_set_ordinal_test() {
+        let compute: ComputeMetadata = serde_json::from_str(r#"{
+            "name": "web_3", "vmScaleSetName": "web",
+            "resourceId": "/subscriptions/8d10da13-8125-4ba9-a717-bf7490507b3d/resourceGroups/rg/providers/Microsoft.Compute/virtualMachineScaleSets/web/virtualMachines/3"
+        }"#).unwrap();
+        assert_eq!(compute.vm_scale_set_name, "web");
+        assert_eq!(scale_set_ordinal(&compute.resource_id), Some("3"));
+        // a vm in a flexible scale set
+        assert_eq!(scale_set_ordinal("/subscriptions/8d10da13-8125-4ba9-a717-bf7490507b3d/resourceGroups/rg/providers/Microsoft.Compute/virtualMachines/web_1a2b3c4d"), None);
+        assert_eq!(scale_set_ordinal(""), None);
+    }
+
+    #[test]
+    fn termination_event_test() {
+        let events: ScheduledEvents = serde_json::from_str(r#"{
+            "DocumentIncarnation": 3,
+            "Events": [
+                {"EventId": "1", "EventType": "Reboot", "ResourceType": "VirtualMachine",
+                 "Resources": ["vm1"], "EventStatus": "Scheduled", "NotBefore": "Mon, 19 Sep 2016 18:29:47 GMT"},
+                {"EventId": "2", "EventType": "Terminate", "ResourceType": "VirtualMachine",
+                 "Resources": ["vm2"], "EventStatus": "Scheduled", "NotBefore": "Mon, 19 Sep 2016 18:29:47 GMT"},
+                {"EventId": "3", "EventType": "Preempt", "ResourceType": "VirtualMachine",
+                 "Resources": ["vm1"], "EventStatus": "Started", "NotBefore": ""}
+            ]
+        }"#).unwrap();
+        assert_eq!(termination_event(&events, "vm1"), Some(TerminationNotice {
+            reason: "Preempt".to_owned(),
+            not_before: None,
+        }));
+        assert_eq!(termination_event(&events, "vm2"), Some(TerminationNotice {
+            reason: "Terminate".to_owned(),
+            not_before: Some("Mon, 19 Sep 2016 18:29:47 GMT".to_owned()),
+        }));
+        assert_eq!(termination_event(&events, "vm3"), None);
+
+        assert_eq!(maintenance_events(&events, "vm1"), vec![
+            MaintenanceEvent {
+                id: "1".to_owned(),
+                kind: "Reboot".to_owned(),
+                status: "Scheduled".to_owned(),
+                not_before: Some("Mon, 19 Sep 2016 18:29:47 GMT".to_owned()),
+            },
+            MaintenanceEvent {
+                id: "3".to_owned(),
+                kind: "Preempt".to_owned(),
+                status: "Started".to_owned(),
+                not_before: None,
+            },
+        ]);
+
+        let body = StartRequests { start_requests: vec![StartRequest { event_id: "1".to_owned() }] };
+        assert_eq!(serde_json::to_string(&body).unwrap(), r#"{"StartRequests":[{"EventId":"1"}]}"#);
+    }
+
+    #[test]
+    fn ovf_env_test() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/azure/ovf-env.xml");
+        let ovf_env = Azure::parse_ovf_env(&Azure::read_ovf_env_document(&path).unwrap()).unwrap();
+        let config = ovf_env.provisioning_section.linux_provisioning_configuration_set;
+        assert_eq!(config.hostname, "coreos-vm");
+        assert_eq!(config.username, "core");
+        assert_eq!(decode::decode_base64(config.custom_data.as_bytes()).unwrap(), b"#cloud-config\n".to_vec());
+
+        // the second key is only in the certificates
+        let keys = config.ssh.public_keys.keys;
+        assert_eq!(keys.len(), 2);
+        PublicKey::parse(keys[0].value.trim()).unwrap();
+        assert_eq!(keys[1].value, "");
+        assert_eq!(keys[1].fingerprint, "0A1B2C3D4E5F60718293A4B5C6D7E8F901234567");
+    }
+
+    #[test]
+    fn parse_test() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/azure/ovf-env.xml");
+        let key = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIGhRWdT6bvpgIX5H4PUTmNM1BVAtbpyCydz6F5XtQdRg core@example";
+        let mut raw = RawMetadata::new(PROVIDER);
+        raw.insert(OVF_ENV_FILENAME, Azure::read_ovf_env_document(&path).unwrap());
+        raw.insert(CERTIFICATE_KEYS_DOCUMENT, format!("0A1B2C3D4E5F60718293A4B5C6D7E8F901234567 {}\n\
+                                                       FFFF2C3D4E5F60718293A4B5C6D7E8F901234567 {}\n", key, key));
+        let azure = Azure::parse(&raw).unwrap();
+        assert_eq!(azure.hostname().unwrap(), Some("coreos-vm".to_owned()));
+        assert_eq!(azure.custom_data().unwrap(), Some(b"#cloud-config\n".to_vec()));
+        // the certificate that isn't in the provisioning configuration is
+        // left out
+        let keys = azure.ssh_keys_by_user().unwrap();
+        assert_eq!(keys.len(), 2);
+        assert!(keys.iter().all(|&(ref user, _)| user.as_ref().map(String::as_str) == Some("core")));
+        assert!(Azure::parse(&RawMetadata::new("ec2")).is_err());
+    }
+
+    #[test]
+    fn role_instance_test() {
+        // a goal state caught while the role is still being set up
+        let goal_state = GoalState { incarnation: "2".to_owned(), ..GoalState::default() };
+        assert!(Azure::role_instance(&goal_state).is_err());
+        assert!(Azure::get_certs_endpoint(&goal_state).is_err());
+    }
 }
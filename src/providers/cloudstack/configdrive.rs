@@ -1,100 +1,111 @@
 //! configdrive metadata fetcher for cloudstack
 
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::Read;
-use std::path::{Path, PathBuf};
+use std::path::Path;
 
-use nix::mount;
 use openssh_keys::PublicKey;
-use tempdir::TempDir;
-use update_ssh_keys::AuthorizedKeyEntry;
 
 use errors::*;
 use network;
-use providers::MetadataProvider;
-
-const CONFIG_DRIVE_LABEL_1: &str = "config-2";
-const CONFIG_DRIVE_LABEL_2: &str = "CONFIG-2";
-
-#[derive(Debug)]
+use providers::{Capabilities, MetadataProvider, ProviderConfig};
+use providers::raw::RawMetadata;
+use util::configdrive::{self, MountedDrive};
+
+const PROVIDER: &str = "cloudstack-configdrive";
+const CONFIG_DRIVE_LABELS: [&str; 2] = ["config-2", "CONFIG-2"];
+const MOUNTED_CONFIG_DRIVE: &str = "/media/ConfigDrive";
+
+/// the values on the drive, each in a `<name>.txt` file
+const VALUES: [&str; 6] = [
+    "availability_zone",
+    "cloud_identifier",
+    "instance_id",
+    "local_hostname",
+    "service_offering",
+    "vm_id",
+];
+const PUBLIC_KEYS_DOCUMENT: &str = "public_keys.txt";
+
+#[derive(Clone, Debug)]
 pub struct ConfigDrive {
-    temp_dir: Option<TempDir>,
-    target: PathBuf,
-    path: PathBuf,
+    values: HashMap<String, String>,
+    public_keys: Option<Vec<u8>>,
 }
 
 impl ConfigDrive {
-    pub fn new() -> Result<Self> {
-        // maybe its already mounted
-        let path = Path::new("/media/ConfigDrive/cloudstack/metadata/");
-        if path.exists() {
-            return Ok(ConfigDrive {
-                temp_dir: None,
-                path: path.to_owned(),
-                target: path.to_owned(),
-            })
+    pub fn new(config: &ProviderConfig) -> Result<Self> {
+        ConfigDrive::parse(&ConfigDrive::fetch_raw(config)?)
+    }
+
+    /// fetch_raw reads the documents in `cloudstack/metadata` on the drive
+    pub fn fetch_raw(config: &ProviderConfig) -> Result<RawMetadata> {
+        // maybe it's already mounted
+        let root = Path::new(MOUNTED_CONFIG_DRIVE);
+        if config.config_drive.is_none() && root.join("cloudstack").join("metadata").exists() {
+            return ConfigDrive::read_raw(root);
         }
 
-        // if not try and mount with each of the labels
-        let target = TempDir::new("coreos-metadata")
-            .chain_err(|| "failed to create temporary directory")?;
-        ConfigDrive::mount_ro(
-            &Path::new("/dev/disk/by-label/").join(CONFIG_DRIVE_LABEL_1),
-            target.path(),
-            "iso9660"
-        )
-        .or_else(|_| ConfigDrive::mount_ro(
-            &Path::new("/dev/disk/by-label/").join(CONFIG_DRIVE_LABEL_2),
-            target.path(),
-            "iso9660")
-        )?;
+        // if not, find the drive by label (or as configured) and mount it
+        // ourselves for as long as we need it
+        let device = configdrive::find_device(config.config_drive.as_ref().map(String::as_str), &CONFIG_DRIVE_LABELS)?;
+        let drive = MountedDrive::mount(&device)?;
+        ConfigDrive::read_raw(drive.path())
+    }
+
+    /// read_raw reads the documents from the root of a mounted config drive
+    pub fn read_raw(root: &Path) -> Result<RawMetadata> {
+        configdrive::verify(root)?;
+        let dir = root.join("cloudstack").join("metadata");
+        let mut raw = RawMetadata::new(PROVIDER);
+        let documents = VALUES.iter()
+            .map(|name| format!("{}.txt", name))
+            .chain(Some(PUBLIC_KEYS_DOCUMENT.to_owned()));
+        for document in documents {
+            let path = dir.join(&document);
+            if path.exists() {
+                raw.insert(&document, configdrive::read_text(&path)?);
+            }
+        }
+        Ok(raw)
+    }
 
+    /// parse parses the documents read from the config drive
+    pub fn parse(raw: &RawMetadata) -> Result<ConfigDrive> {
+        raw.check(PROVIDER)?;
+        let mut values = HashMap::new();
+        for name in VALUES.iter() {
+            if let Some(value) = raw.text(&format!("{}.txt", name))? {
+                values.insert(name.to_string(), value.to_owned());
+            }
+        }
         Ok(ConfigDrive {
-            path: target.path().join("cloudstack").join("metadata"),
-            target: target.path().to_owned(),
-            temp_dir: Some(target),
+            values,
+            public_keys: raw.get(PUBLIC_KEYS_DOCUMENT).map(|d| d.to_vec()),
         })
     }
 
     fn fetch_value(&self, key: &str) -> Result<Option<String>> {
-        let filename = self.path.join(format!("{}.txt", key));
-
-        if !filename.exists() {
-            return Ok(None)
-        }
-
-        let mut file = File::open(&filename)
-            .chain_err(|| format!("failed to open file '{:?}'", filename))?;
-
-        let mut contents = String::new();
-        file.read_to_string(&mut contents)
-            .chain_err(|| format!("failed to read from file '{:?}'", filename))?;
-
-        Ok(Some(contents))
+        Ok(self.values.get(key).cloned())
     }
 
     fn fetch_publickeys(&self) -> Result<Vec<PublicKey>> {
-        let filename = self.path.join("public_keys.txt");
-        let file = File::open(&filename)
-            .chain_err(|| format!("failed to open file '{:?}'", filename))?;
+        let contents = self.public_keys.as_ref()
+            .ok_or_else(|| format!("missing document {}", PUBLIC_KEYS_DOCUMENT))?;
 
-        PublicKey::read_keys(file)
+        PublicKey::read_keys(contents.as_slice())
             .chain_err(|| "failed to read public keys from config drive file")
     }
-
-    fn mount_ro(source: &Path, target: &Path, fstype: &str) -> Result<()> {
-        mount::mount(Some(source), target, Some(fstype), mount::MS_RDONLY, None::<&str>)
-            .chain_err(|| format!("failed to read-only mount source '{:?}' to target '{:?}' with filetype '{}'", source, target, fstype))
-    }
-
-    fn unmount(target: &Path) -> Result<()> {
-        mount::umount(target)
-            .chain_err(|| format!("failed to unmount target '{:?}'", target))
-    }
 }
 
 impl MetadataProvider for ConfigDrive {
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            hostname: false,
+            network: false,
+            ..Capabilities::all()
+        }
+    }
+
     fn attributes(&self) -> Result<HashMap<String, String>> {
         let mut out = HashMap::with_capacity(6);
         let add_value = |map: &mut HashMap<_, _>, key: &str, name| -> Result<()> {
@@ -121,13 +132,12 @@ impl MetadataProvider for ConfigDrive {
         Ok(None)
     }
 
-    fn ssh_keys(&self) -> Result<Vec<AuthorizedKeyEntry>> {
-        let keys = self.fetch_publickeys()?
-            .into_iter()
-            .map(|key| AuthorizedKeyEntry::Valid{key})
-            .collect::<Vec<_>>();
+    fn instance_id(&self) -> Result<Option<String>> {
+        self.fetch_value("instance_id")
+    }
 
-        Ok(keys)
+    fn ssh_keys(&self) -> Result<Vec<PublicKey>> {
+        self.fetch_publickeys()
     }
 
     fn networks(&self) -> Result<Vec<network::Interface>> {
@@ -138,11 +148,3 @@ impl MetadataProvider for ConfigDrive {
         Ok(vec![])
     }
 }
-
-impl ::std::ops::Drop for ConfigDrive {
-    fn drop(&mut self) {
-        if self.temp_dir.is_some() {
-            ConfigDrive::unmount(&self.path).unwrap();
-        }
-    }
-}
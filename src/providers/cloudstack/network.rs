@@ -2,56 +2,249 @@
 
 use std::collections::HashMap;
 use std::net::IpAddr;
-use std::time::Duration;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use openssh_keys::PublicKey;
-use update_ssh_keys::AuthorizedKeyEntry;
+use reqwest;
 
+use cancel;
 use errors::*;
 use network;
-use providers::MetadataProvider;
+use providers::{document_url, endpoints_of, Capabilities, MetadataEndpoint, MetadataProvider, Password, ProviderConfig};
+use providers::raw::RawMetadata;
 use retry;
 use util;
+use util::secret::Secret;
 
-const SERVER_ADDRESS: &str = "SERVER_ADDRESS";
+const PROVIDER: &str = "cloudstack-metadata";
+/// every document the provider reads, by its path under the metadata service
+const DOCUMENTS: [&str; 10] = [
+    "availability-zone",
+    "cloud-identifier",
+    "instance-id",
+    "local-hostname",
+    "local-ipv4",
+    "public-hostname",
+    "public-ipv4",
+    "public-keys",
+    "service-offering",
+    "vm-id",
+];
+
+/// the lease keys the virtual router's address is looked for under: the
+/// dhcp server identifier, and then the gateway, which it usually is too
+const ROUTER_LEASE_KEYS: [&str; 2] = ["SERVER_ADDRESS", "ROUTER"];
+/// how long the virtual router is looked for by default
+const DEFAULT_ROUTER_TIMEOUT_SECS: u64 = 30;
+/// each candidate only gets a few attempts, so that an unreachable one
+/// doesn't hold things up
+const PROBE_ATTEMPTS: u32 = 3;
+/// the virtual router serves passwords on its own port
+const PASSWORD_SERVER_PORT: u16 = 8080;
+/// how often the search for the virtual router checks for a signal
+const ROUTER_POLL_INTERVAL_MILLIS: u64 = 100;
 
 #[derive(Clone, Debug)]
 pub struct CloudstackNetwork {
-    server_address: IpAddr,
+    base_url: String,
+    password_url: Option<String>,
     client: retry::Client,
+    /// the documents, when they were saved by fetch_raw rather than fetched
+    /// as they're needed
+    raw: Option<RawMetadata>,
 }
 
 impl CloudstackNetwork {
-    pub fn new() -> Result<CloudstackNetwork> {
-        let server_address = CloudstackNetwork::get_dhcp_server_address()?;
+    pub fn new(config: &ProviderConfig) -> Result<CloudstackNetwork> {
+        let (base_url, password_url) = match config.endpoint_override() {
+            Some(endpoint) => {
+                // the password server is on the same host as the overridden
+                // metadata service
+                let password_url = reqwest::Url::parse(&endpoint).ok()
+                    .and_then(|u| u.host_str().map(|h| format!("http://{}:{}/", h, PASSWORD_SERVER_PORT)));
+                (endpoint, password_url)
+            }
+            None => {
+                let timeout = config.cloudstack_router_timeout
+                    .unwrap_or_else(|| Duration::from_secs(DEFAULT_ROUTER_TIMEOUT_SECS));
+                let server_address = CloudstackNetwork::find_virtual_router(timeout)?;
+                (format!("http://{}/latest/meta-data", server_address),
+                 Some(format!("http://{}:{}/", server_address, PASSWORD_SERVER_PORT)))
+            }
+        };
+        // the virtual router refuses connections until its metadata service
+        // has started, which can take a while after it's (re)deployed
         let client = retry::Client::new()?
             .initial_backoff(Duration::from_secs(1))
             .max_backoff(Duration::from_secs(5))
-            .max_attempts(10);
+            .max_attempts(10)
+            .refused_timeout(Duration::from_secs(retry::DEFAULT_REFUSED_TIMEOUT_SECS));
 
         Ok(CloudstackNetwork {
-            server_address,
+            base_url,
+            password_url,
             client,
+            raw: None,
+        })
+    }
+
+    /// fetch_raw fetches every document the provider reads
+    pub fn fetch_raw(config: &ProviderConfig) -> Result<RawMetadata> {
+        let provider = CloudstackNetwork::new(config)?;
+        let mut raw = RawMetadata::new(PROVIDER);
+        for document in DOCUMENTS.iter() {
+            if let Some(contents) = provider.get(document)? {
+                raw.insert(document, contents);
+            }
+        }
+        Ok(raw)
+    }
+
+    /// parse makes a provider that reads the documents saved by fetch_raw.
+    /// there's no password server to ask for a password.
+    pub fn parse(raw: &RawMetadata) -> Result<CloudstackNetwork> {
+        raw.check(PROVIDER)?;
+        Ok(CloudstackNetwork {
+            base_url: String::new(),
+            password_url: None,
+            client: retry::Client::new()?,
+            raw: Some(raw.clone()),
         })
     }
 
     fn endpoint_for(&self, key: &str) -> String {
-        format!("http://{}/latest/meta-data/{}", self.server_address, key)
+        format!("{}/{}", self.base_url, key)
+    }
+
+    /// get returns a document under the metadata service, or the saved one
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        match self.raw {
+            Some(ref raw) => Ok(raw.text(key)?.map(String::from)),
+            None => self.client.get(retry::Raw, self.endpoint_for(key)).send(),
+        }
+    }
+
+    /// fetch_document fetches a path from the root of the virtual router's
+    /// metadata service, e.g. `/latest/meta-data/instance-id`
+    pub fn fetch_document(config: &ProviderConfig, path: &str) -> Result<Option<Vec<u8>>> {
+        let provider = CloudstackNetwork::new(config)?;
+        provider.client.get(retry::Raw, document_url(&provider.base_url, path)?).send_bytes()
+    }
+
+    /// password_request makes a request to the password server, which tells
+    /// what it's for with the DomU_Request header
+    fn password_request(&self, request: &str) -> Result<Option<String>> {
+        let url = match self.password_url {
+            Some(ref url) => url.clone(),
+            None => return Ok(None),
+        };
+        self.client.get(retry::Raw, url)
+            .raw_header("DomU_Request", request.to_owned())
+            .send()
+    }
+
+    /// find_virtual_router returns the first candidate from the dhcp leases
+    /// of all the interfaces that answers as a metadata service. guests
+    /// with more than one nic get leases from more than one server, and
+    /// only the virtual router of the guest network is the right one, so
+    /// all the candidates are tried at once.
+    fn find_virtual_router(timeout: Duration) -> Result<IpAddr> {
+        let leases: Vec<String> = util::dhcp_leases()?.into_iter()
+            .map(|(_, lease)| lease)
+            .collect();
+        let candidates = router_candidates(&leases);
+        if candidates.is_empty() {
+            return Err("no virtual router address in the dhcp leases".into());
+        }
+        if candidates.len() == 1 {
+            return Ok(candidates[0]);
+        }
+        debug!("virtual router candidates: {:?}", candidates);
+
+        let client = retry::Client::new()?
+            .timeout(timeout)?
+            .max_attempts(PROBE_ATTEMPTS)
+            .refused_timeout(timeout);
+        let (tx, rx) = mpsc::channel();
+        for candidate in candidates.clone() {
+            let tx = tx.clone();
+            let client = client.clone();
+            thread::spawn(move || {
+                let url = format!("http://{}/latest/meta-data/instance-id", candidate);
+                let res: Result<Option<String>> = client.get(retry::Raw, url).send();
+                match res {
+                    Ok(Some(_)) => { let _ = tx.send(candidate); }
+                    Ok(None) => debug!("{} has no metadata service", candidate),
+                    Err(e) => debug!("{} isn't the virtual router: {}", candidate, e),
+                }
+            });
+        }
+        // once every probe has given up, the channel is disconnected
+        drop(tx);
+        // wait in slices, so that a signal doesn't have to wait out the timeout
+        let start = Instant::now();
+        let result = loop {
+            cancel::check()?;
+            let elapsed = start.elapsed();
+            if elapsed >= timeout {
+                break Err(RecvTimeoutError::Timeout);
+            }
+            match rx.recv_timeout((timeout - elapsed).min(Duration::from_millis(ROUTER_POLL_INTERVAL_MILLIS))) {
+                Err(RecvTimeoutError::Timeout) => continue,
+                result => break result,
+            }
+        };
+        match result {
+            Ok(router) => {
+                info!("found the virtual router at {}", router);
+                Ok(router)
+            }
+            Err(_) => Err(format!("none of the virtual router candidates ({}) answered",
+                                  candidates.iter().map(IpAddr::to_string).collect::<Vec<_>>().join(", ")).into()),
+        }
     }
+}
 
-    fn get_dhcp_server_address() -> Result<IpAddr> {
-        let server = util::dns_lease_key_lookup(SERVER_ADDRESS)?;
-        let ip = server.parse()
-            .chain_err(|| format!("failed to parse server ip address: {}", server))?;
-        Ok(IpAddr::V4(ip))
+/// router_candidates returns the addresses in the leases that could be the
+/// virtual router, with the dhcp server identifiers first
+fn router_candidates(leases: &[String]) -> Vec<IpAddr> {
+    let mut candidates = Vec::new();
+    for key in &ROUTER_LEASE_KEYS {
+        for lease in leases {
+            let values = lease.lines()
+                .filter_map(|l| {
+                    let i = l.find('=')?;
+                    if &l[..i] == *key { Some(&l[i + 1..]) } else { None }
+                })
+                .flat_map(str::split_whitespace);
+            for value in values {
+                match value.parse() {
+                    Ok(ip) => if !candidates.contains(&ip) {
+                        candidates.push(ip);
+                    },
+                    Err(_) => warn!("ignoring invalid address {} in dhcp lease", value),
+                }
+            }
+        }
     }
+    candidates
 }
 
 impl MetadataProvider for CloudstackNetwork {
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            hostname: false,
+            network: false,
+            ..Capabilities::all()
+        }
+    }
+
     fn attributes(&self) -> Result<HashMap<String, String>> {
         let mut out = HashMap::with_capacity(9);
         let add_value = |map: &mut HashMap<_, _>, key: &str, name| -> Result<()> {
-            let value = self.client.get(retry::Raw, self.endpoint_for(name)).send()?;
+            let value = self.get(name)?;
 
             if let Some(value) = value {
                 map.insert(key.to_string(), value);
@@ -77,23 +270,42 @@ impl MetadataProvider for CloudstackNetwork {
         Ok(None)
     }
 
-    fn ssh_keys(&self) -> Result<Vec<AuthorizedKeyEntry>> {
-        let keys: Option<String> = self.client
-            .get(retry::Raw, self.endpoint_for("public-keys"))
-            .send()?;
+    fn instance_id(&self) -> Result<Option<String>> {
+        self.get("instance-id")
+    }
 
-        if let Some(keys) = keys {
-            let keys = PublicKey::read_keys(keys.as_bytes())?
-                .into_iter()
-                .map(|key| AuthorizedKeyEntry::Valid{key})
-                .collect::<Vec<_>>();
+    /// the virtual router, and its password server
+    fn metadata_endpoints(&self) -> Vec<MetadataEndpoint> {
+        if self.raw.is_some() {
+            return vec![];
+        }
+        let mut urls = vec![self.base_url.as_str()];
+        urls.extend(self.password_url.as_ref().map(String::as_str));
+        endpoints_of(&urls)
+    }
+
+    fn ssh_keys(&self) -> Result<Vec<PublicKey>> {
+        let keys = self.get("public-keys")?;
 
-            Ok(keys)
+        if let Some(keys) = keys {
+            Ok(PublicKey::read_keys(keys.as_bytes())?)
         } else {
             Ok(vec![])
         }
     }
 
+    /// the password server hands out the password until it's told that it
+    /// has been saved, and then answers `saved_password`
+    fn password(&self) -> Result<Option<Password>> {
+        let response = Secret::new(self.password_request("send_my_password")?.unwrap_or_default());
+        Ok(parse_password_response(response.expose_str()?))
+    }
+
+    fn acknowledge_password(&self) -> Result<()> {
+        self.password_request("saved_password")?;
+        Ok(())
+    }
+
     fn networks(&self) -> Result<Vec<network::Interface>> {
         Ok(vec![])
     }
@@ -102,3 +314,39 @@ impl MetadataProvider for CloudstackNetwork {
         Ok(vec![])
     }
 }
+
+/// parse_password_response returns the password in a password server
+/// response, which is empty or `saved_password` when there's no new one
+fn parse_password_response(response: &str) -> Option<Password> {
+    match response.trim() {
+        "" | "saved_password" => None,
+        "bad_request" => {
+            warn!("the password server turned down the request");
+            None
+        }
+        password => Some(Password::Plain(Secret::new(password))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_password_response_test() {
+        assert_eq!(parse_password_response("saved_password\n"), None);
+        assert_eq!(parse_password_response("bad_request"), None);
+        assert_eq!(parse_password_response(""), None);
+        assert_eq!(parse_password_response("Wz7pX9kq\n"), Some(Password::Plain(Secret::new("Wz7pX9kq"))));
+    }
+
+    #[test]
+    fn router_candidates_test() {
+        let leases = vec![
+            "# This is private data. Do not parse.\nADDRESS=10.1.1.20\nROUTER=10.1.1.1\nSERVER_ADDRESS=10.1.1.1\n".to_owned(),
+            "ADDRESS=192.168.0.14\nROUTER=192.168.0.1 192.168.0.2\nSERVER_ADDRESS=192.168.0.254\n".to_owned(),
+        ];
+        let candidates: Vec<String> = router_candidates(&leases).iter().map(IpAddr::to_string).collect();
+        assert_eq!(candidates, vec!["10.1.1.1", "192.168.0.254", "192.168.0.1", "192.168.0.2"]);
+    }
+}
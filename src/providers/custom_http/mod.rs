@@ -0,0 +1,206 @@
+// Copyright 2017 CoreOS, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! generic http json metadata fetcher
+//!
+//! for metadata services coreos-metadata doesn't know about, e.g. a lab's
+//! provisioning server. the json document at the `--metadata-endpoint` url
+//! is mapped to metadata by a mapping file of json pointers (RFC 6901):
+//!
+//! ```json
+//! {
+//!     "attributes": {"/region": "REGION", "/net/0/ip": "IPV4"},
+//!     "hostname": "/name",
+//!     "instance_id": "/id",
+//!     "ssh_keys": "/keys"
+//! }
+//! ```
+//!
+//! attributes are prefixed with `CUSTOM_HTTP_`. the ssh keys pointer can
+//! point at a string of newline-separated keys or an array of them.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::Read;
+
+use openssh_keys::PublicKey;
+use serde_json::{self, Value};
+
+use errors::*;
+use network;
+use providers::{Capabilities, MetadataProvider, ProviderConfig};
+use providers::raw::RawMetadata;
+use retry;
+use util::attributes::AttributeNamer;
+
+const PROVIDER: &str = "custom-http";
+/// the mapping is kept with the document, since the document means nothing
+/// without it
+const MAPPING_DOCUMENT: &str = "mapping.json";
+const METADATA_DOCUMENT: &str = "metadata.json";
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Mapping {
+    /// json pointer to attribute name
+    #[serde(default)]
+    attributes: BTreeMap<String, String>,
+    hostname: Option<String>,
+    instance_id: Option<String>,
+    ssh_keys: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct CustomHttpProvider {
+    mapping: Mapping,
+    document: Value,
+}
+
+/// lookup returns the value at the pointer as a string, or nothing if it's
+/// missing or null
+fn lookup(document: &Value, pointer: &str) -> Option<String> {
+    match document.pointer(pointer) {
+        None | Some(&Value::Null) => None,
+        Some(&Value::String(ref s)) => Some(s.clone()),
+        Some(v) => Some(v.to_string()),
+    }
+}
+
+impl CustomHttpProvider {
+    pub fn new(config: &ProviderConfig) -> Result<CustomHttpProvider> {
+        CustomHttpProvider::parse(&CustomHttpProvider::fetch_raw(config)?)
+    }
+
+    /// fetch_raw reads the mapping file and fetches the document
+    pub fn fetch_raw(config: &ProviderConfig) -> Result<RawMetadata> {
+        let url = config.endpoint_override()
+            .ok_or("the custom-http provider needs --metadata-endpoint")?;
+        let path = config.custom_http_mapping.as_ref()
+            .ok_or("the custom-http provider needs --custom-http-mapping")?;
+        let mut mapping = Vec::new();
+        File::open(path)
+            .and_then(|mut f| f.read_to_end(&mut mapping))
+            .chain_err(|| format!("failed to read mapping file {:?}", path))?;
+
+        let document = retry::Client::new()?
+            .get(retry::Json, url.clone())
+            .send_bytes()?
+            .ok_or_else(|| format!("no metadata at {}", url))?;
+
+        let mut raw = RawMetadata::new(PROVIDER);
+        raw.insert(MAPPING_DOCUMENT, mapping);
+        raw.insert(METADATA_DOCUMENT, document);
+        Ok(raw)
+    }
+
+    /// parse parses the document with the mapping
+    pub fn parse(raw: &RawMetadata) -> Result<CustomHttpProvider> {
+        raw.check(PROVIDER)?;
+        let mapping = serde_json::from_str(raw.require_text(MAPPING_DOCUMENT)?)
+            .chain_err(|| "failed to parse mapping file")?;
+        let document = raw.json(METADATA_DOCUMENT)?;
+        Ok(CustomHttpProvider { mapping, document })
+    }
+
+    fn parse_attrs(&self) -> Vec<(String, String)> {
+        let mut namer = AttributeNamer::new("CUSTOM_HTTP_");
+        let mut attrs = Vec::new();
+        for (pointer, name) in &self.mapping.attributes {
+            match lookup(&self.document, pointer) {
+                Some(value) => attrs.push((namer.name_for(name), value)),
+                None => debug!("no value at {:?} for attribute {}", pointer, name),
+            }
+        }
+        attrs
+    }
+}
+
+impl MetadataProvider for CustomHttpProvider {
+    /// the mapping says what there is
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            attributes: !self.mapping.attributes.is_empty(),
+            hostname: self.mapping.hostname.is_some(),
+            ssh_keys: self.mapping.ssh_keys.is_some(),
+            network: false,
+        }
+    }
+
+    fn attributes(&self) -> Result<HashMap<String, String>> {
+        Ok(self.parse_attrs().into_iter().collect())
+    }
+
+    fn hostname(&self) -> Result<Option<String>> {
+        Ok(self.mapping.hostname.as_ref().and_then(|p| lookup(&self.document, p)))
+    }
+
+    fn instance_id(&self) -> Result<Option<String>> {
+        Ok(self.mapping.instance_id.as_ref().and_then(|p| lookup(&self.document, p)))
+    }
+
+    fn ssh_keys(&self) -> Result<Vec<PublicKey>> {
+        let keys: Vec<String> = match self.mapping.ssh_keys.as_ref().and_then(|p| self.document.pointer(p)) {
+            None | Some(&Value::Null) => vec![],
+            Some(&Value::String(ref s)) => s.lines().map(String::from).collect(),
+            Some(&Value::Array(ref a)) => a.iter().filter_map(Value::as_str).map(String::from).collect(),
+            Some(v) => return Err(format!("ssh keys must be a string or an array, not {}", v).into()),
+        };
+        keys.iter()
+            .filter(|k| !k.trim().is_empty())
+            .map(|k| Ok(PublicKey::parse(k)?))
+            .collect()
+    }
+
+    fn networks(&self) -> Result<Vec<network::Interface>> {
+        Ok(vec![])
+    }
+
+    fn network_devices(&self) -> Result<Vec<network::Device>> {
+        Ok(vec![])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mapping_test() {
+        let mapping: Mapping = serde_json::from_str(r#"{
+            "attributes": {"/region": "region", "/net/0/ip": "IPV4", "/missing": "MISSING", "/rack": "RACK"},
+            "hostname": "/name",
+            "ssh_keys": "/keys"
+        }"#).unwrap();
+        let document: Value = serde_json::from_str(r#"{
+            "name": "node-3",
+            "region": "lab-1",
+            "rack": 12,
+            "net": [{"ip": "10.0.0.3"}],
+            "keys": []
+        }"#).unwrap();
+        let provider = CustomHttpProvider { mapping, document };
+
+        let attrs = provider.attributes().unwrap();
+        assert_eq!(attrs.len(), 3);
+        assert_eq!(attrs["CUSTOM_HTTP_REGION"], "lab-1");
+        assert_eq!(attrs["CUSTOM_HTTP_IPV4"], "10.0.0.3");
+        assert_eq!(attrs["CUSTOM_HTTP_RACK"], "12");
+        assert_eq!(provider.hostname().unwrap(), Some("node-3".to_owned()));
+        assert_eq!(provider.instance_id().unwrap(), None);
+        assert!(provider.ssh_keys().unwrap().is_empty());
+        assert_eq!(provider.capabilities(), Capabilities { network: false, ..Capabilities::all() });
+
+        serde_json::from_str::<Mapping>(r#"{"hostnme": "/name"}"#).unwrap_err();
+    }
+}
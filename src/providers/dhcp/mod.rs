@@ -0,0 +1,193 @@
+// Copyright 2017 CoreOS, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! dhcp lease metadata fetcher
+//!
+//! for platforms with no metadata service at all, the dhcp lease is the only
+//! thing that knows anything about the machine. this reads the lease that
+//! systemd-networkd saved for the first interface that has one.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::time::Duration;
+
+use openssh_keys::PublicKey;
+use pnet;
+
+use errors::*;
+use network;
+use providers::{Capabilities, MetadataProvider, ProviderConfig};
+use providers::raw::RawMetadata;
+use retry;
+
+const PROVIDER: &str = "dhcp";
+const LEASE_DIR: &str = "/run/systemd/netif/leases";
+/// the documents are the name of the interface, and its lease file
+const INTERFACE_DOCUMENT: &str = "interface";
+const LEASE_DOCUMENT: &str = "lease";
+
+#[derive(Clone, Debug)]
+pub struct DhcpProvider {
+    interface: String,
+    lease: HashMap<String, String>,
+}
+
+fn parse_lease(contents: &str) -> HashMap<String, String> {
+    contents.lines()
+        .filter(|l| !l.starts_with('#'))
+        .filter_map(|l| {
+            let i = l.find('=')?;
+            Some((l[..i].to_owned(), l[i + 1..].to_owned()))
+        })
+        .collect()
+}
+
+/// read_lease returns the name of the first interface with a lease, and the
+/// lease file
+fn read_lease() -> Result<(String, String)> {
+    let mut interfaces = pnet::datalink::interfaces();
+    interfaces.retain(|i| !i.is_loopback());
+    interfaces.sort_by_key(|i| i.index);
+    for interface in interfaces {
+        let path = Path::new(LEASE_DIR).join(interface.index.to_string());
+        if !path.exists() {
+            continue;
+        }
+        debug!("found lease file {:?} for {}", path, interface.name);
+        let mut contents = String::new();
+        File::open(&path)
+            .and_then(|mut f| f.read_to_string(&mut contents))
+            .chain_err(|| format!("failed to read lease file {:?}", path))?;
+        return Ok((interface.name, contents));
+    }
+    Err("no dhcp lease found".into())
+}
+
+impl DhcpProvider {
+    pub fn new(config: &ProviderConfig) -> Result<DhcpProvider> {
+        DhcpProvider::parse(&DhcpProvider::fetch_raw(config)?)
+    }
+
+    /// fetch_raw reads the lease, and the name of its interface
+    pub fn fetch_raw(_config: &ProviderConfig) -> Result<RawMetadata> {
+        // the lease may still be on its way
+        let (interface, lease) = retry::Retry::new()
+            .initial_backoff(Duration::from_millis(50))
+            .max_backoff(Duration::from_millis(500))
+            .max_attempts(60)
+            .retry(|_| read_lease())?;
+        let mut raw = RawMetadata::new(PROVIDER);
+        raw.insert(INTERFACE_DOCUMENT, interface);
+        raw.insert(LEASE_DOCUMENT, lease);
+        Ok(raw)
+    }
+
+    /// parse parses the lease read by fetch_raw
+    pub fn parse(raw: &RawMetadata) -> Result<DhcpProvider> {
+        raw.check(PROVIDER)?;
+        Ok(DhcpProvider {
+            interface: raw.require_text(INTERFACE_DOCUMENT)?.to_owned(),
+            lease: parse_lease(raw.require_text(LEASE_DOCUMENT)?),
+        })
+    }
+
+    fn parse_attrs(&self) -> Vec<(String, String)> {
+        let mut attrs = vec![("DHCP_INTERFACE".to_owned(), self.interface.clone())];
+        for &(key, name) in &[
+            ("ADDRESS", "IPV4"),
+            ("DOMAINNAME", "DOMAIN"),
+            ("HOSTNAME", "HOSTNAME"),
+            ("SERVER_ADDRESS", "SERVER"),
+        ] {
+            if let Some(value) = self.lease.get(key) {
+                attrs.push((format!("DHCP_{}", name), value.clone()));
+            }
+        }
+
+        // lists are space-separated
+        for &(key, name) in &[("DNS", "DNS"), ("NTP", "NTP"), ("ROUTER", "ROUTER")] {
+            if let Some(values) = self.lease.get(key) {
+                for (i, value) in values.split_whitespace().enumerate() {
+                    attrs.push((format!("DHCP_{}_{}", name, i), value.to_owned()));
+                }
+            }
+        }
+
+        // classless static routes (option 121) are `destination,gateway`
+        if let Some(routes) = self.lease.get("ROUTES") {
+            for (i, route) in routes.split_whitespace().enumerate() {
+                let mut parts = route.splitn(2, ',');
+                if let (Some(destination), Some(gateway)) = (parts.next(), parts.next()) {
+                    attrs.push((format!("DHCP_ROUTE_{}_DESTINATION", i), destination.to_owned()));
+                    attrs.push((format!("DHCP_ROUTE_{}_GATEWAY", i), gateway.to_owned()));
+                }
+            }
+        }
+        attrs
+    }
+}
+
+impl MetadataProvider for DhcpProvider {
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            ssh_keys: false,
+            network: false,
+            ..Capabilities::all()
+        }
+    }
+
+    fn attributes(&self) -> Result<HashMap<String, String>> {
+        Ok(self.parse_attrs().into_iter().collect())
+    }
+
+    fn hostname(&self) -> Result<Option<String>> {
+        Ok(self.lease.get("HOSTNAME").cloned())
+    }
+
+    fn ssh_keys(&self) -> Result<Vec<PublicKey>> {
+        Ok(vec![])
+    }
+
+    fn networks(&self) -> Result<Vec<network::Interface>> {
+        // the interface is already configured by the lease
+        Ok(vec![])
+    }
+
+    fn network_devices(&self) -> Result<Vec<network::Device>> {
+        Ok(vec![])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_attrs_test() {
+        let lease = parse_lease("# This is private data. Do not parse.\n\
+                                 ADDRESS=10.0.2.15\nNETMASK=255.255.255.0\nROUTER=10.0.2.2\n\
+                                 SERVER_ADDRESS=10.0.2.2\nDNS=10.0.2.3 10.0.2.4\nDOMAINNAME=lab.example.com\n\
+                                 HOSTNAME=node7\nROUTES=192.168.0.0/16,10.0.2.1 0.0.0.0/0,10.0.2.2\n");
+        let provider = DhcpProvider { interface: "eth0".to_owned(), lease };
+        let attrs = provider.attributes().unwrap();
+        assert_eq!(attrs["DHCP_DOMAIN"], "lab.example.com");
+        assert_eq!(attrs["DHCP_DNS_1"], "10.0.2.4");
+        assert_eq!(attrs["DHCP_ROUTE_0_DESTINATION"], "192.168.0.0/16");
+        assert_eq!(attrs["DHCP_ROUTE_1_GATEWAY"], "10.0.2.2");
+        assert!(!attrs.contains_key("DHCP_NTP_0"));
+        assert_eq!(provider.hostname().unwrap(), Some("node7".to_owned()));
+    }
+}
@@ -16,18 +16,24 @@
 
 use std::str::FromStr;
 use std::net::{IpAddr,Ipv4Addr,Ipv6Addr};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 use ipnetwork;
 use ipnetwork::{IpNetwork,Ipv4Network,Ipv6Network};
 use openssh_keys::PublicKey;
 use pnet::util::MacAddr;
-use update_ssh_keys::AuthorizedKeyEntry;
+use serde_json;
 
 use errors::*;
 use network;
-use providers::MetadataProvider;
+use providers::{document_url, endpoints_of, MetadataEndpoint, MetadataProvider, ProviderConfig};
+use providers::raw::RawMetadata;
 use retry;
+use util::attributes::AttributeNamer;
+
+const URL: &str = "http://169.254.169.254";
+const PROVIDER: &str = "digitalocean";
+const METADATA_DOCUMENT: &str = "metadata/v1.json";
 
 #[derive(Clone,Deserialize)]
 struct Address {
@@ -47,6 +53,19 @@ struct Interface {
     type_name: String,
 }
 
+/// a reserved (floating) ip, which can be moved between droplets
+#[derive(Clone,Deserialize)]
+struct ReservedAddress {
+    active: bool,
+    ip_address: Option<IpAddr>,
+}
+
+#[derive(Clone,Deserialize)]
+struct ReservedIp {
+    ipv4: Option<ReservedAddress>,
+    ipv6: Option<ReservedAddress>,
+}
+
 #[derive(Clone,Deserialize)]
 struct Interfaces {
     public: Option<Vec<Interface>>,
@@ -60,20 +79,50 @@ struct DNS {
 
 #[derive(Clone,Deserialize)]
 pub struct DigitalOceanProvider {
+    droplet_id: Option<u64>,
     hostname: String,
     interfaces: Interfaces,
     public_keys: Vec<String>,
     region: String,
     dns: DNS,
+    #[serde(default)]
+    tags: Vec<String>,
+    vpc_uuid: Option<String>,
+    reserved_ip: Option<ReservedIp>,
+    #[serde(default)]
+    features: HashMap<String, serde_json::Value>,
 }
 
 impl DigitalOceanProvider {
-    pub fn new() -> Result<DigitalOceanProvider> {
+    pub fn new(config: &ProviderConfig) -> Result<DigitalOceanProvider> {
+        DigitalOceanProvider::parse(&DigitalOceanProvider::fetch_raw(config)?)
+    }
+
+    /// fetch_raw fetches the droplet metadata document
+    pub fn fetch_raw(config: &ProviderConfig) -> Result<RawMetadata> {
         let client = retry::Client::new()?;
-        let data: DigitalOceanProvider = client.get(retry::Json, "http://169.254.169.254/metadata/v1.json".to_owned()).send()?
+        let url = format!("{}/{}", config.endpoint_or(URL), METADATA_DOCUMENT);
+        let body: String = client.get(retry::Raw, url).send()?
             .ok_or("not found")?;
 
-        Ok(data)
+        let mut raw = RawMetadata::new(PROVIDER);
+        raw.insert(METADATA_DOCUMENT, body);
+        Ok(raw)
+    }
+
+    /// fetch_document fetches a path from the root of the metadata service,
+    /// e.g. `/metadata/v1/hostname`
+    pub fn fetch_document(config: &ProviderConfig, path: &str) -> Result<Option<Vec<u8>>> {
+        retry::Client::new()?
+            .return_on_404(true)
+            .get(retry::Raw, document_url(&config.endpoint_or(URL), path)?)
+            .send_bytes()
+    }
+
+    /// parse parses the droplet metadata document
+    pub fn parse(raw: &RawMetadata) -> Result<DigitalOceanProvider> {
+        raw.check(PROVIDER)?;
+        raw.json(METADATA_DOCUMENT)
     }
 
     fn parse_attrs(&self) -> Result<Vec<(String,String)>> {
@@ -82,6 +131,30 @@ impl DigitalOceanProvider {
         attrs.push(("DIGITALOCEAN_HOSTNAME".to_owned(), self.hostname.clone()));
         attrs.push(("DIGITALOCEAN_REGION".to_owned(), self.region.clone()));
 
+        if !self.tags.is_empty() {
+            // tags can only contain letters, numbers, colons, dashes, and
+            // underscores, so a comma is a safe separator
+            attrs.push(("DIGITALOCEAN_TAGS".to_owned(), self.tags.join(",")));
+        }
+        if let Some(ref vpc_uuid) = self.vpc_uuid {
+            attrs.push(("DIGITALOCEAN_VPC_UUID".to_owned(), vpc_uuid.clone()));
+        }
+        if let Some(ref reserved_ip) = self.reserved_ip {
+            for &(name, ref address) in &[("DIGITALOCEAN_RESERVED_IPV4", &reserved_ip.ipv4), ("DIGITALOCEAN_RESERVED_IPV6", &reserved_ip.ipv6)] {
+                if let Some(&ReservedAddress { active: true, ip_address: Some(ip) }) = address.as_ref() {
+                    attrs.push((name.to_owned(), ip.to_string()));
+                }
+            }
+        }
+        let mut namer = AttributeNamer::new("DIGITALOCEAN_FEATURE_");
+        for (name, value) in &self.features {
+            let value = match *value {
+                serde_json::Value::String(ref s) => s.clone(),
+                ref v => v.to_string(),
+            };
+            attrs.push((namer.name_for(name), value));
+        }
+
         if let Some(ref ifaces) = self.interfaces.public {
             for (i, ref a) in ifaces.iter().enumerate() {
                 if let Some(ref v4) = a.ipv4 {
@@ -126,56 +199,67 @@ impl DigitalOceanProvider {
     }
 
     fn parse_network(&self) -> Result<Vec<network::Interface>> {
-        let mut interfaces = Vec::new();
-        if let Some(ifaces) = self.interfaces.public.clone() {
-            interfaces.extend(self.parse_interfaces(ifaces)?);
-        }
-        if let Some(ifaces) = self.interfaces.private.clone() {
-            interfaces.extend(self.parse_interfaces(ifaces)?);
-        }
-        Ok(interfaces)
-    }
-
-    fn parse_interfaces(&self, interfaces: Vec<Interface>) -> Result<Vec<network::Interface>> {
-        let mut iface_config_map: HashMap<MacAddr,network::Interface> = HashMap::new();
-        for iface in interfaces {
+        let mut interfaces: Vec<network::Interface> = Vec::new();
+        let public = self.interfaces.public.iter().flat_map(|i| i.iter());
+        let private = self.interfaces.private.iter().flat_map(|i| i.iter());
+        let mut default_routes = true;
+        for iface in public.chain(private) {
             let mac = MacAddr::from_str(&iface.mac)
                 .map_err(|e| Error::from(format!("{:?}", e)))
                 .chain_err(|| "failed to parse mac address")?;
-            let (mut addrs, mut routes) = DigitalOceanProvider::parse_interface(&iface)?;
+            // only the first public interface gets the default routes, so
+            // that droplets with more than one don't get conflicting ones
+            let is_default = default_routes && iface.type_name == "public";
+            if is_default {
+                default_routes = false;
+            }
+            let (addrs, routes) = DigitalOceanProvider::parse_interface(iface, is_default)?;
 
-            if let Some(existing_iface) = iface_config_map.get_mut(&mac) {
-                addrs.extend(existing_iface.ip_addresses.clone());
-                routes.extend(existing_iface.routes.clone());
+            // the same interface can be listed more than once
+            let index = match interfaces.iter().position(|i| i.mac_address == Some(mac)) {
+                Some(index) => index,
+                None => {
+                    interfaces.push(network::Interface {
+                        mac_address: Some(mac),
+                        nameservers: self.dns.nameservers.clone(),
+                        ..Default::default()
+                    });
+                    interfaces.len() - 1
+                }
+            };
+            interfaces[index].ip_addresses.extend(addrs);
+            interfaces[index].routes.extend(routes);
+
+            // a reserved ipv6 address is routed to the droplet's public
+            // interface, but isn't assigned to it
+            if is_default {
+                if let Some(ip) = self.reserved_ipv6() {
+                    interfaces[index].local_routes.push(IpNetwork::new(ip, 128)
+                        .chain_err(|| "invalid reserved ipv6 address")?);
+                }
             }
-            iface_config_map.insert(mac, network::Interface{
-                mac_address: Some(mac),
-                nameservers: self.dns.nameservers.clone(),
-                ip_addresses: addrs,
-                routes,
-                bond: None,
-                name: None,
-                priority: None,
-                unmanaged: false,
-            });
         }
-        let mut iface_configs = Vec::new();
-        for i in iface_config_map.values() {
-            iface_configs.push(i.clone());
+        Ok(interfaces)
+    }
+
+    /// reserved_ipv6 is the reserved ipv6 address assigned to the droplet
+    fn reserved_ipv6(&self) -> Option<IpAddr> {
+        match self.reserved_ip.as_ref().and_then(|r| r.ipv6.as_ref()) {
+            Some(&ReservedAddress { active: true, ip_address: Some(ip @ IpAddr::V6(_)) }) => Some(ip),
+            _ => None,
         }
-        Ok(iface_configs)
     }
 
-    fn parse_interface(interface: &Interface) -> Result<(Vec<IpNetwork>,Vec<network::NetworkRoute>)> {
+    fn parse_interface(interface: &Interface, default_routes: bool) -> Result<(Vec<IpNetwork>,Vec<network::NetworkRoute>)> {
         let mut addrs = Vec::new();
         let mut routes = Vec::new();
 
-        if interface.ipv4.is_some() {
-            let netmask = interface.clone().ipv4.unwrap().netmask
+        if let Some(ref ipv4) = interface.ipv4 {
+            let netmask = ipv4.netmask
                 .ok_or("missing netmask for ipv4 address")?;
             let prefix = ipnetwork::ip_mask_to_prefix(netmask)
                 .chain_err(|| "invalid network mask")?;
-            let a = match interface.clone().ipv4.unwrap().ip_address {
+            let a = match ipv4.ip_address {
                 IpAddr::V4(a) => Some(a),
                 IpAddr::V6(_) => None,
             }.ok_or("ipv6 address in ipv4 field")?;
@@ -184,21 +268,21 @@ impl DigitalOceanProvider {
             addrs.push(net);
             routes.push(network::NetworkRoute{
                 destination: net,
-                gateway: interface.clone().ipv4.unwrap().gateway,
+                gateway: ipv4.gateway,
             });
 
-            if interface.type_name == "public" {
+            if default_routes {
                 routes.push(network::NetworkRoute{
                     destination: IpNetwork::V4(Ipv4Network::new(Ipv4Addr::new(0,0,0,0),0)
                                         .chain_err(|| "invalid ip address or prefix")?),
-                    gateway: interface.clone().ipv4.unwrap().gateway,
+                    gateway: ipv4.gateway,
                 });
             }
         }
-        if interface.ipv6.is_some() {
-            let cidr = interface.clone().ipv6.unwrap().cidr
+        if let Some(ref ipv6) = interface.ipv6 {
+            let cidr = ipv6.cidr
                 .ok_or("missing cidr for ipv6 address")?;
-            let a = match interface.clone().ipv6.unwrap().ip_address {
+            let a = match ipv6.ip_address {
                 IpAddr::V4(_) => None,
                 IpAddr::V6(a) => Some(a),
             }.ok_or("ipv4 address in ipv6 field")?;
@@ -207,22 +291,22 @@ impl DigitalOceanProvider {
             addrs.push(net);
             routes.push(network::NetworkRoute{
                 destination: net,
-                gateway: interface.clone().ipv6.unwrap().gateway,
+                gateway: ipv6.gateway,
             });
-            if interface.type_name == "public" {
+            if default_routes {
                 routes.push(network::NetworkRoute{
                     destination: IpNetwork::V6(Ipv6Network::new(Ipv6Addr::new(0,0,0,0,0,0,0,0),0)
                                         .chain_err(|| "invalid ip address or prefix")?),
-                    gateway: interface.clone().ipv6.unwrap().gateway,
+                    gateway: ipv6.gateway,
                 });
             }
         }
-        if interface.anchor_ipv4.is_some() {
-            let netmask = interface.clone().anchor_ipv4.unwrap().netmask
+        if let Some(ref anchor_ipv4) = interface.anchor_ipv4 {
+            let netmask = anchor_ipv4.netmask
                 .ok_or("missing netmask for anchor ipv4 address")?;
             let prefix = ipnetwork::ip_mask_to_prefix(netmask)
                 .chain_err(|| "invalid network mask")?;
-            let a = match interface.clone().anchor_ipv4.unwrap().ip_address {
+            let a = match anchor_ipv4.ip_address {
                 IpAddr::V4(a) => Some(a),
                 IpAddr::V6(_) => None,
             }.ok_or("ipv6 address in ipv4 field")?;
@@ -231,7 +315,7 @@ impl DigitalOceanProvider {
             addrs.push(net);
             routes.push(network::NetworkRoute{
                 destination: net,
-                gateway: interface.clone().anchor_ipv4.unwrap().gateway,
+                gateway: anchor_ipv4.gateway,
             });
         }
         Ok((addrs,routes))
@@ -247,12 +331,30 @@ impl MetadataProvider for DigitalOceanProvider {
         Ok(Some(self.hostname.clone()))
     }
 
-    fn ssh_keys(&self) -> Result<Vec<AuthorizedKeyEntry>> {
+    fn instance_id(&self) -> Result<Option<String>> {
+        Ok(self.droplet_id.map(|id| id.to_string()))
+    }
+
+    fn metadata_endpoints(&self) -> Vec<MetadataEndpoint> {
+        endpoints_of(&[URL])
+    }
+
+    /// tags have no values, but are often written as `key:value`
+    fn labels(&self) -> Result<BTreeMap<String, String>> {
+        Ok(self.tags.iter()
+            .map(|t| match t.find(':') {
+                Some(i) => (t[..i].to_owned(), t[i + 1..].to_owned()),
+                None => (t.clone(), String::new()),
+            })
+            .collect())
+    }
+
+    fn ssh_keys(&self) -> Result<Vec<PublicKey>> {
         let mut out = Vec::new();
 
         for key in &self.public_keys {
             let key = PublicKey::parse(&key)?;
-            out.push(AuthorizedKeyEntry::Valid{key});
+            out.push(key);
         }
 
         Ok(out)
@@ -266,3 +368,50 @@ impl MetadataProvider for DigitalOceanProvider {
         Ok(vec![])
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_network_test() {
+        let provider: DigitalOceanProvider = serde_json::from_str(r#"{
+            "hostname": "droplet",
+            "public_keys": [],
+            "region": "ams3",
+            "dns": {"nameservers": ["67.207.67.2"]},
+            "reserved_ip": {
+                "ipv4": {"active": false},
+                "ipv6": {"active": true, "ip_address": "2a03:b0c0:2:f0::1"}
+            },
+            "interfaces": {
+                "public": [
+                    {"ipv4": {"ip_address": "188.166.1.2", "netmask": "255.255.192.0", "gateway": "188.166.0.1"},
+                     "ipv6": {"ip_address": "2a03:b0c0:2:d0::10:1", "cidr": 64, "gateway": "2a03:b0c0:2:d0::1"},
+                     "anchor_ipv4": {"ip_address": "10.18.0.5", "netmask": "255.255.0.0", "gateway": "10.18.0.1"},
+                     "mac": "aa:00:00:00:00:01", "type": "public"},
+                    {"ipv4": {"ip_address": "188.166.9.9", "netmask": "255.255.192.0", "gateway": "188.166.0.1"},
+                     "mac": "aa:00:00:00:00:02", "type": "public"}
+                ],
+                "private": [
+                    {"ipv4": {"ip_address": "10.110.0.2", "netmask": "255.255.240.0", "gateway": "10.110.0.1"},
+                     "mac": "aa:00:00:00:00:03", "type": "private"}
+                ]
+            }
+        }"#).unwrap();
+
+        let interfaces = provider.networks().unwrap();
+        assert_eq!(interfaces.len(), 3);
+        let default_routes: Vec<usize> = interfaces.iter()
+            .map(|i| i.routes.iter().filter(|r| r.destination.prefix() == 0).count())
+            .collect();
+        assert_eq!(default_routes, vec![2, 0, 0]);
+        assert_eq!(interfaces[0].ip_addresses.len(), 3);
+        assert_eq!(interfaces[0].local_routes, vec![IpNetwork::new("2a03:b0c0:2:f0::1".parse().unwrap(), 128).unwrap()]);
+        assert!(interfaces[1].local_routes.is_empty());
+
+        let attrs = provider.attributes().unwrap();
+        assert_eq!(attrs["DIGITALOCEAN_RESERVED_IPV6"], "2a03:b0c0:2:f0::1");
+        assert!(!attrs.contains_key("DIGITALOCEAN_RESERVED_IPV4"));
+    }
+}
@@ -0,0 +1,110 @@
+// Copyright 2017 CoreOS, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! smbios/dmi metadata fetcher, for bare metal without a metadata service
+
+use std::collections::HashMap;
+
+use openssh_keys::PublicKey;
+
+use errors::*;
+use network;
+use providers::{Capabilities, MetadataProvider};
+use providers::raw::RawMetadata;
+use util::dmi;
+
+const PROVIDER: &str = "dmi";
+
+/// the dmi fields that are read, and the attributes they become
+const FIELDS: [(&str, &str); 7] = [
+    ("product_uuid", "DMI_SYSTEM_UUID"),
+    ("sys_vendor", "DMI_SYSTEM_VENDOR"),
+    ("product_name", "DMI_SYSTEM_PRODUCT"),
+    ("product_serial", "DMI_SERIAL_NUMBER"),
+    ("chassis_asset_tag", "DMI_ASSET_TAG"),
+    ("board_vendor", "DMI_BOARD_VENDOR"),
+    ("board_name", "DMI_BOARD_NAME"),
+];
+
+#[derive(Clone, Debug)]
+pub struct DmiProvider {
+    /// the fields that are exported and not empty, by name
+    fields: HashMap<String, String>,
+}
+
+impl DmiProvider {
+    pub fn new() -> Result<DmiProvider> {
+        DmiProvider::parse(&DmiProvider::fetch_raw()?)
+    }
+
+    /// fetch_raw reads the fields, each a document named after it
+    pub fn fetch_raw() -> Result<RawMetadata> {
+        let mut raw = RawMetadata::new(PROVIDER);
+        for &(field, _) in FIELDS.iter() {
+            if let Some(value) = dmi::read_field(field)? {
+                raw.insert(field, value);
+            }
+        }
+        Ok(raw)
+    }
+
+    /// parse parses the fields read by fetch_raw
+    pub fn parse(raw: &RawMetadata) -> Result<DmiProvider> {
+        raw.check(PROVIDER)?;
+        let mut fields = HashMap::new();
+        for &(field, _) in FIELDS.iter() {
+            if let Some(value) = raw.text(field)? {
+                fields.insert(field.to_owned(), value.to_owned());
+            }
+        }
+        Ok(DmiProvider { fields })
+    }
+}
+
+impl MetadataProvider for DmiProvider {
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            hostname: false,
+            ssh_keys: false,
+            network: false,
+            ..Capabilities::all()
+        }
+    }
+
+    fn attributes(&self) -> Result<HashMap<String, String>> {
+        Ok(FIELDS.iter()
+            .filter_map(|&(field, key)| self.fields.get(field).map(|v| (key.to_owned(), v.clone())))
+            .collect())
+    }
+
+    fn hostname(&self) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    fn instance_id(&self) -> Result<Option<String>> {
+        Ok(self.fields.get("product_uuid").cloned())
+    }
+
+    fn ssh_keys(&self) -> Result<Vec<PublicKey>> {
+        Ok(vec![])
+    }
+
+    fn networks(&self) -> Result<Vec<network::Interface>> {
+        Ok(vec![])
+    }
+
+    fn network_devices(&self) -> Result<Vec<network::Device>> {
+        Ok(vec![])
+    }
+}
@@ -1,6 +1,6 @@
 use mockito;
 use errors::*;
-use providers::ec2;
+use providers::{ec2, MetadataProvider, TerminationNotice};
 
 pub(crate) const URL: &'static str = ::mockito::SERVER_URL;
 
@@ -12,7 +12,7 @@ fn test_ec2_basic() {
         .unwrap()
         .max_attempts(1)
         .return_on_404(true);
-    let provider = ec2::Ec2Provider { client };
+    let provider = ec2::Ec2Provider { client, base_url: URL.to_owned(), raw: None };
 
     provider.fetch_ssh_keys().unwrap_err();
 
@@ -33,3 +33,96 @@ fn test_ec2_basic() {
     let v = provider.fetch_ssh_keys().unwrap();
     assert_eq!(v.len(), 0);
 }
+
+#[test]
+fn test_ec2_spot_instance_action() {
+    let ep = "/meta-data/spot/instance-action";
+    let client = ::retry::Client::new()
+        .chain_err(|| "failed to create http client")
+        .unwrap()
+        .max_attempts(1)
+        .return_on_404(true)
+        .remember_404(true);
+    let provider = ec2::Ec2Provider { client, base_url: URL.to_owned(), raw: None };
+
+    let _m = mockito::mock("GET", ep)
+        .with_status(404)
+        .create();
+    assert_eq!(provider.termination_notice().unwrap(), None);
+
+    let _m = mockito::mock("GET", ep)
+        .with_status(200)
+        .with_header("content-type", "application/json")
+        .with_body(r#"{"action": "terminate", "time": "2017-09-18T08:22:00Z"}"#)
+        .create();
+    assert_eq!(provider.termination_notice().unwrap(), Some(TerminationNotice {
+        reason: "terminate".to_owned(),
+        not_before: Some("2017-09-18T08:22:00Z".to_owned()),
+    }));
+}
+
+#[test]
+fn test_ec2_document() {
+    let client = ::retry::Client::new()
+        .chain_err(|| "failed to create http client")
+        .unwrap()
+        .max_attempts(1)
+        .return_on_404(true);
+    // documents are fetched from the root, not the versioned base url
+    let provider = ec2::Ec2Provider { client, base_url: format!("{}/2009-04-04", URL), raw: None };
+
+    let _m = mockito::mock("GET", "/latest/meta-data/instance-id")
+        .with_status(200)
+        .with_body("i-0123456789abcdef0")
+        .create();
+    assert_eq!(provider.document("/latest/meta-data/instance-id").unwrap(), Some(b"i-0123456789abcdef0".to_vec()));
+
+    let _m = mockito::mock("GET", "/latest/meta-data/public-ipv4")
+        .with_status(404)
+        .create();
+    assert_eq!(provider.document("/latest/meta-data/public-ipv4").unwrap(), None);
+}
+
+#[test]
+fn test_ec2_raw() {
+    let client = ::retry::Client::new()
+        .chain_err(|| "failed to create http client")
+        .unwrap()
+        .max_attempts(1)
+        .return_on_404(true);
+    let provider = ec2::Ec2Provider { client, base_url: format!("{}/raw", URL), raw: None };
+
+    let found = [
+        ("meta-data/instance-id", "i-0123456789abcdef0"),
+        ("meta-data/local-ipv4", "10.0.0.5"),
+        ("dynamic/instance-identity/document", r#"{"region": "eu-west-1"}"#),
+        ("meta-data/public-keys", "0=core"),
+        ("meta-data/public-keys/0/openssh-key", "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIGhRWdT6bvpgIX5H4PUTmNM1BVAtbpyCydz6F5XtQdRg core"),
+        ("meta-data/tags/instance", "Name\nteam"),
+        ("meta-data/tags/instance/Name", "web-1"),
+        ("meta-data/tags/instance/team", "infra"),
+    ];
+    let mut mocks = Vec::new();
+    for document in ec2::DOCUMENTS.iter() {
+        if !found.iter().any(|&(path, _)| path == *document) {
+            mocks.push(mockito::mock("GET", format!("/raw/{}", document).as_str()).with_status(404).create());
+        }
+    }
+    for &(path, body) in found.iter() {
+        mocks.push(mockito::mock("GET", format!("/raw/{}", path).as_str()).with_status(200).with_body(body).create());
+    }
+    let raw = provider.fetch_documents().unwrap();
+    assert_eq!(raw.get("meta-data/public-ipv4"), None);
+    drop(mocks);
+
+    // the saved documents are parsed without the metadata service
+    let provider = ec2::Ec2Provider::parse(&raw).unwrap();
+    let attributes = provider.attributes().unwrap();
+    assert_eq!(attributes["EC2_INSTANCE_ID"], "i-0123456789abcdef0");
+    assert_eq!(attributes["EC2_REGION"], "eu-west-1");
+    assert!(!attributes.contains_key("EC2_IPV4_PUBLIC"));
+    assert_eq!(provider.ssh_keys().unwrap().len(), 1);
+    assert_eq!(provider.pretty_hostname().unwrap(), Some("web-1".to_owned()));
+    assert_eq!(provider.labels().unwrap()["team"], "infra");
+    provider.termination_notice().unwrap_err();
+}
@@ -15,64 +15,222 @@
 //! aws ec2 metadata fetcher
 //!
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::net::IpAddr;
+use std::time::Duration;
 
 use openssh_keys::PublicKey;
-use update_ssh_keys::AuthorizedKeyEntry;
+use pnet;
 
 use errors::*;
 use network;
-use providers::MetadataProvider;
+use providers::{document_url, endpoints_of, Capabilities, MetadataEndpoint, MetadataProvider, ProviderConfig, TerminationNotice};
+use providers::raw::RawMetadata;
 use retry;
+use util::attributes::{self, Provenance};
+use util::decode;
 
 #[cfg(test)]
 mod mock_tests;
 #[cfg(test)]
 use self::mock_tests::URL;
 
+const PROVIDER: &str = "ec2";
+
+/// the documents the provider reads, by their path under the metadata
+/// service, besides the keys and tags listed in `meta-data/public-keys` and
+/// `meta-data/tags/instance`
+const DOCUMENTS: [&str; 12] = [
+    "dynamic/instance-identity/document",
+    "meta-data/hostname",
+    "meta-data/instance-id",
+    "meta-data/instance-type",
+    "meta-data/local-ipv4",
+    "meta-data/placement/availability-zone",
+    "meta-data/public-hostname",
+    "meta-data/public-ipv4",
+    "meta-data/public-keys",
+    "meta-data/tags/instance",
+    "meta-data/tags/instance/Name",
+    "user-data",
+];
+
 #[cfg(not(test))]
 const URL: &str = "http://169.254.169.254/2009-04-04";
 
+// the instance metadata service is also reachable over ipv6 on nitro
+// instances, which is the only way to reach it on ipv6-only subnets
+const URL_IPV6: &str = "http://[fd00:ec2::254]/2009-04-04";
+
+// the endpoint that isn't preferred only gets a few attempts when the
+// preferred one can't be reached, so that an unreachable one doesn't hold
+// things up
+const PROBE_ATTEMPTS: u32 = 3;
+
+// the console output is the only thing that ec2 lets users see before they
+// can log in, so the host keys are printed there in the format that other
+// tooling (e.g. cloud-init and ec2 console scrapers) already understands
+const CONSOLE: &str = "/dev/console";
+
 #[allow(non_snake_case)]
 #[derive(Debug, Deserialize)]
 struct InstanceIdDoc {
     region: String,
 }
 
+/// the pending action of a spot instance, from `meta-data/spot/instance-action`
+#[derive(Debug, Deserialize)]
+struct InstanceAction {
+    action: String,
+    time: String,
+}
+
 #[derive(Clone, Debug)]
 pub struct Ec2Provider {
     client: retry::Client,
+    base_url: String,
+    /// the documents, when they were saved by fetch_raw rather than fetched
+    /// as they're needed
+    raw: Option<RawMetadata>,
 }
 
 impl Ec2Provider {
-    pub fn new() -> Result<Ec2Provider> {
+    pub fn new(config: &ProviderConfig) -> Result<Ec2Provider> {
         let client = retry::Client::new()?
-            .return_on_404(true);
+            .return_on_404(true)
+            .remember_404(true);
+
+        let base_url = match config.endpoint_override() {
+            Some(endpoint) => endpoint,
+            None => Ec2Provider::find_endpoint()?,
+        };
+
+        Ok(Ec2Provider {
+            client,
+            base_url,
+            raw: None,
+        })
+    }
+
+    /// fetch_raw fetches every document the provider reads
+    pub fn fetch_raw(config: &ProviderConfig) -> Result<RawMetadata> {
+        Ec2Provider::new(config)?.fetch_documents()
+    }
+
+    fn fetch_documents(&self) -> Result<RawMetadata> {
+        let mut raw = RawMetadata::new(PROVIDER);
+        for document in DOCUMENTS.iter() {
+            if let Some(contents) = self.get_bytes(document)? {
+                raw.insert(document, contents);
+            }
+        }
+        let mut listed = Vec::new();
+        if let Some(keys) = raw.text("meta-data/public-keys")? {
+            for l in keys.lines() {
+                listed.push(format!("meta-data/public-keys/{}/openssh-key", key_index(l)?));
+            }
+        }
+        if let Some(names) = raw.text("meta-data/tags/instance")? {
+            for name in names.lines().filter(|l| !l.is_empty()) {
+                listed.push(format!("meta-data/tags/instance/{}", name));
+            }
+        }
+        for document in listed {
+            if let Some(contents) = self.get_bytes(&document)? {
+                raw.insert(&document, contents);
+            }
+        }
+        Ok(raw)
+    }
+
+    /// parse makes a provider that reads the documents saved by fetch_raw
+    pub fn parse(raw: &RawMetadata) -> Result<Ec2Provider> {
+        raw.check(PROVIDER)?;
+        Ok(Ec2Provider {
+            client: retry::Client::new()?,
+            base_url: String::new(),
+            raw: Some(raw.clone()),
+        })
+    }
+
+    /// get returns a document under the metadata service, or the saved one
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        match self.raw {
+            Some(ref raw) => Ok(raw.text(key)?.map(String::from)),
+            None => self.client.get(retry::Raw, self.endpoint_for(key)).send(),
+        }
+    }
 
-        Ok(Ec2Provider { client })
+    fn get_bytes(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        match self.raw {
+            Some(ref raw) => Ok(raw.get(key).map(|d| d.to_vec())),
+            None => self.client.get(retry::Raw, self.endpoint_for(key)).send_bytes(),
+        }
+    }
+
+    /// find_endpoint returns the first metadata service endpoint that
+    /// answers. the ipv4 endpoint is preferred, unless the instance has no
+    /// ipv4 address to reach it with. the preferred one is waited for as
+    /// long as any other request would be, since at boot the service may
+    /// not be answering yet, and the other one is only tried after that.
+    fn find_endpoint() -> Result<String> {
+        let has_ipv4 = pnet::datalink::interfaces().iter()
+            .filter(|i| !i.is_loopback())
+            .any(|i| i.ips.iter().any(|ip| match ip.ip() {
+                IpAddr::V4(_) => true,
+                IpAddr::V6(_) => false,
+            }));
+        let candidates = if has_ipv4 { [URL, URL_IPV6] } else { [URL_IPV6, URL] };
+
+        let preferred = retry::Client::new()?
+            .refused_timeout(Duration::from_secs(retry::DEFAULT_REFUSED_TIMEOUT_SECS));
+        let other = retry::Client::new()?
+            .max_attempts(PROBE_ATTEMPTS);
+        for (url, client) in candidates.iter().zip(&[preferred, other]) {
+            let res: Result<Option<String>> = client
+                .get(retry::Raw, format!("{}/meta-data/instance-id", url))
+                .send();
+            match res {
+                Ok(_) => return Ok(url.to_string()),
+                Err(e) => warn!("metadata service at {} is unreachable: {}", url, e),
+            }
+        }
+        Err(format!("none of the metadata service endpoints ({}) could be reached", candidates.join(", ")).into())
+    }
+
+    fn region(&self) -> Result<Option<String>> {
+        let key = "dynamic/instance-identity/document";
+        let doc: Option<InstanceIdDoc> = match self.raw {
+            Some(ref raw) => raw.optional_json(key)?,
+            None => self.client.get_json(self.endpoint_for(key))?,
+        };
+        Ok(doc.map(|instance_id_doc| instance_id_doc.region))
     }
 
-    fn endpoint_for(key: &str) -> String {
-        format!("{}/{}", URL, key)
+    fn endpoint_for(&self, key: &str) -> String {
+        format!("{}/{}", self.base_url, key)
+    }
+
+    /// fetch_document fetches a path from the root of the metadata service,
+    /// e.g. `/latest/meta-data/instance-id`, from the endpoint the provider
+    /// would use
+    pub fn fetch_document(config: &ProviderConfig, path: &str) -> Result<Option<Vec<u8>>> {
+        Ec2Provider::new(config)?.document(path)
+    }
+
+    fn document(&self, path: &str) -> Result<Option<Vec<u8>>> {
+        self.client.get(retry::Raw, document_url(&self.base_url, path)?).send_bytes()
     }
 
     fn fetch_ssh_keys(&self) -> Result<Vec<String>> {
-        let keydata: Option<String> = self.client
-            .get(retry::Raw, Ec2Provider::endpoint_for("meta-data/public-keys"))
-            .send()?;
+        let keydata = self.get("meta-data/public-keys")?;
 
         let mut keys = Vec::new();
         if let Some(keys_list) = keydata {
             for l in keys_list.lines() {
-                let tokens: Vec<&str> = l.split('=').collect();
-                if tokens.len() != 2 {
-                    return Err("error parsing keyID".into());
-                }
-                let key: String = self.client
-                    .get(retry::Raw, Ec2Provider::endpoint_for(
-                        &format!("meta-data/public-keys/{}/openssh-key", tokens[0])
-                    ))
-                    .send()?
+                let key = self.get(&format!("meta-data/public-keys/{}/openssh-key", key_index(l)?))?
                     .ok_or("missing ssh key")?;
                 keys.push(key)
             }
@@ -81,53 +239,165 @@ impl Ec2Provider {
     }
 }
 
+/// key_index returns the index of a key in the `meta-data/public-keys`
+/// listing, whose lines are `<index>=<name>`
+fn key_index(line: &str) -> Result<&str> {
+    let tokens: Vec<&str> = line.split('=').collect();
+    if tokens.len() != 2 {
+        return Err("error parsing keyID".into());
+    }
+    Ok(tokens[0])
+}
+
 impl MetadataProvider for Ec2Provider {
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            network: false,
+            ..Capabilities::all()
+        }
+    }
+
     fn attributes(&self) -> Result<HashMap<String, String>> {
-        let mut out = HashMap::with_capacity(6);
+        let (attributes, provenance) = self.attribute_provenance()?;
+        attributes::check_required(&attributes, &provenance, None)?;
+        Ok(attributes)
+    }
+
+    /// every attribute is fetched on its own, so that an error fetching one
+    /// doesn't lose the others
+    fn attribute_provenance(&self) -> Result<(HashMap<String, String>, BTreeMap<String, Provenance>)> {
+        let mut out = HashMap::with_capacity(8);
+        let mut provenance = BTreeMap::new();
 
-        let add_value = |map: &mut HashMap<_, _>, key: &str, name| -> Result<()> {
-            let value = self.client.get(retry::Raw, Ec2Provider::endpoint_for(name)).send()?;
+        {
+            let mut record = |key: &str, value: Result<Option<String>>| {
+                let p = match value {
+                    Ok(Some(value)) => {
+                        out.insert(key.to_string(), value);
+                        Provenance::Fetched
+                    }
+                    Ok(None) => Provenance::Absent,
+                    Err(e) => Provenance::Failed(e.to_string()),
+                };
+                provenance.insert(key.to_string(), p);
+            };
 
-            if let Some(value) = value {
-                map.insert(key.to_string(), value);
+            let fields = [
+                ("EC2_INSTANCE_ID", "meta-data/instance-id"),
+                ("EC2_INSTANCE_TYPE", "meta-data/instance-type"),
+                ("EC2_IPV4_LOCAL", "meta-data/local-ipv4"),
+                ("EC2_IPV4_PUBLIC", "meta-data/public-ipv4"),
+                ("EC2_AVAILABILITY_ZONE", "meta-data/placement/availability-zone"),
+                ("EC2_HOSTNAME", "meta-data/hostname"),
+                ("EC2_PUBLIC_HOSTNAME", "meta-data/public-hostname"),
+            ];
+            for &(key, name) in &fields {
+                record(key, self.get(name));
             }
+            record("EC2_REGION", self.region());
+        }
 
-            Ok(())
-        };
+        Ok((out, provenance))
+    }
 
-        add_value(&mut out, "EC2_INSTANCE_ID", "meta-data/instance-id")?;
-        add_value(&mut out, "EC2_IPV4_LOCAL", "meta-data/local-ipv4")?;
-        add_value(&mut out, "EC2_IPV4_PUBLIC", "meta-data/public-ipv4")?;
-        add_value(&mut out, "EC2_AVAILABILITY_ZONE", "meta-data/placement/availability-zone")?;
-        add_value(&mut out, "EC2_HOSTNAME", "meta-data/hostname")?;
-        add_value(&mut out, "EC2_PUBLIC_HOSTNAME", "meta-data/public-hostname")?;
+    fn hostname(&self) -> Result<Option<String>> {
+        self.get("meta-data/hostname")
+    }
 
-        let region = self.client
-            .get(retry::Json, Ec2Provider::endpoint_for("dynamic/instance-identity/document"))
-            .send()?
-            .map(|instance_id_doc: InstanceIdDoc| instance_id_doc.region);
-        if let Some(region) = region {
-            out.insert("EC2_REGION".to_string(), region);
+    /// the Name tag, which is only there if tags are allowed in the instance
+    /// metadata
+    fn pretty_hostname(&self) -> Result<Option<String>> {
+        self.get("meta-data/tags/instance/Name")
+    }
+
+    fn instance_id(&self) -> Result<Option<String>> {
+        self.get("meta-data/instance-id")
+    }
+
+    /// both endpoints, whichever one is used
+    fn metadata_endpoints(&self) -> Vec<MetadataEndpoint> {
+        if self.raw.is_some() {
+            return vec![];
         }
+        endpoints_of(&[self.base_url.as_str(), URL, URL_IPV6])
+    }
 
-        Ok(out)
+    /// the instance's tags, which are only there if tags are allowed in the
+    /// instance metadata
+    fn labels(&self) -> Result<BTreeMap<String, String>> {
+        let names = self.get("meta-data/tags/instance")?;
+        let mut labels = BTreeMap::new();
+        for name in names.as_ref().map_or("", String::as_str).lines().filter(|l| !l.is_empty()) {
+            let value = self.get(&format!("meta-data/tags/instance/{}", name))?;
+            labels.insert(name.to_owned(), value.unwrap_or_default());
+        }
+        Ok(labels)
     }
 
-    fn hostname(&self) -> Result<Option<String>> {
-        self.client.get(retry::Raw, Ec2Provider::endpoint_for("meta-data/hostname")).send()
+    /// the domain of the instances' private dns names, which is different in
+    /// us-east-1 for historical reasons
+    fn search_domains(&self) -> Result<Vec<String>> {
+        Ok(self.region()?
+            .map(|region| match region.as_str() {
+                "us-east-1" => "ec2.internal".to_owned(),
+                region => format!("{}.compute.internal", region),
+            })
+            .into_iter()
+            .collect())
     }
 
-    fn ssh_keys(&self) -> Result<Vec<AuthorizedKeyEntry>> {
+    fn ssh_keys(&self) -> Result<Vec<PublicKey>> {
         self.fetch_ssh_keys().map(|keys| {
             keys.into_iter()
-                .map(|key| {
-                    let key = PublicKey::parse(&key)?;
-                    Ok(AuthorizedKeyEntry::Valid{key})
-                })
+                .map(|key| Ok(PublicKey::parse(&key)?))
                 .collect::<Result<Vec<_>>>()
         })?
     }
 
+    /// user data can be gzipped to fit in its 16KB
+    fn custom_data(&self) -> Result<Option<Vec<u8>>> {
+        match self.get_bytes("user-data")? {
+            Some(data) => Ok(Some(decode::maybe_gunzip(data)
+                .chain_err(|| "failed to decompress user data")?)),
+            None => Ok(None),
+        }
+    }
+
+    /// spot instances get a two minute warning before they're stopped or
+    /// terminated. the document is only there once they have.
+    fn termination_notice(&self) -> Result<Option<TerminationNotice>> {
+        if self.raw.is_some() {
+            bail!("termination notices can't be watched for in saved documents");
+        }
+        // there's no notice until there is one, so its absence isn't remembered
+        let action: Option<InstanceAction> = self.client.get(retry::Json, self.endpoint_for("meta-data/spot/instance-action"))
+            .remember_404(false)
+            .send()?;
+        Ok(action.map(|action| TerminationNotice {
+            reason: action.action,
+            not_before: Some(action.time),
+        }))
+    }
+
+    fn publish_ssh_host_keys(&self, keys: &[PublicKey]) -> Result<()> {
+        let mut out = String::new();
+        out.push_str("-----BEGIN SSH HOST KEY FINGERPRINTS-----\n");
+        for key in keys {
+            out.push_str(&format!("SHA256:{} ({})\n", key.fingerprint(), key.keytype()));
+        }
+        out.push_str("-----END SSH HOST KEY FINGERPRINTS-----\n");
+        out.push_str("-----BEGIN SSH HOST KEY KEYS-----\n");
+        for key in keys {
+            out.push_str(&format!("{}\n", key.to_key_format()));
+        }
+        out.push_str("-----END SSH HOST KEY KEYS-----\n");
+
+        let mut console = OpenOptions::new().write(true).open(CONSOLE)
+            .chain_err(|| format!("failed to open {}", CONSOLE))?;
+        console.write_all(out.as_bytes())
+            .chain_err(|| format!("failed to write ssh host keys to {}", CONSOLE))
+    }
+
     fn networks(&self) -> Result<Vec<network::Interface>> {
         Ok(vec![])
     }
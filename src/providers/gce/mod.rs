@@ -14,48 +14,302 @@
 
 //! google compute engine metadata fetcher
 
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::Duration;
 
+use ipnetwork;
+use ipnetwork::IpNetwork;
 use openssh_keys::PublicKey;
-use update_ssh_keys::AuthorizedKeyEntry;
+use pnet::util::MacAddr;
+use reqwest::header::{Header, Headers};
 
+use cancel;
 use errors::*;
 use network;
-use providers::MetadataProvider;
+use providers::{document_url, endpoints_of, MetadataEndpoint, MetadataProvider, ProviderConfig, TerminationNotice};
+use providers::raw::RawMetadata;
 use retry;
+use util::dns;
+use util::ssh_cert::{self, SshCertificate};
 
 header! {(MetadataFlavor, "Metadata-Flavor") => [String]}
+
+const PROVIDER: &str = "gce";
+/// the documents the provider reads, by their path under the metadata
+/// service
+const DOCUMENTS: [&str; 18] = [
+    "instance/attributes/block-project-ssh-keys",
+    "instance/attributes/enable-oslogin",
+    "instance/attributes/enable-oslogin-2fa",
+    "instance/attributes/ssh-keys",
+    "instance/attributes/sshKeys",
+    "instance/hostname",
+    "instance/id",
+    "instance/machine-type",
+    "instance/name",
+    "instance/network-interfaces/0/access-configs/0/external-ip",
+    "instance/network-interfaces/0/ip",
+    "instance/network-interfaces/?recursive=true",
+    "instance/tags?alt=json",
+    "instance/zone",
+    "project/attributes/enable-oslogin",
+    "project/attributes/enable-oslogin-2fa",
+    "project/attributes/sshKeys",
+    "project/project-id",
+];
 const GOOGLE: &str = "Google";
+const HOST: &str = "metadata.google.internal";
+const URL: &str = "http://metadata.google.internal/computeMetadata/v1";
+/// the metadata server by the address metadata.google.internal resolves to,
+/// for when dns doesn't work
+const URL_ADDRESS: &str = "http://169.254.169.254/computeMetadata/v1";
+
+// each endpoint only gets a few attempts when picking which one to use, so
+// that an unreachable one doesn't hold things up
+const PROBE_ATTEMPTS: u32 = 3;
+
+/// parse_bool parses a metadata flag the way the guest environment does,
+/// with go's strconv.ParseBool
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.trim() {
+        "1" | "t" | "T" | "true" | "TRUE" | "True" => Some(true),
+        "0" | "f" | "F" | "false" | "FALSE" | "False" => Some(false),
+        _ => None,
+    }
+}
+
+/// flag_enabled is whether a flag that can be set on the instance or the
+/// project is on. the instance's value, if it has a valid one, wins.
+fn flag_enabled(instance: Option<&str>, project: Option<&str>) -> bool {
+    instance.and_then(parse_bool)
+        .or_else(|| project.and_then(parse_bool))
+        .unwrap_or(false)
+}
+
+/// one of the instance's nics, from `instance/network-interfaces/?recursive=true`
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct NetworkInterface {
+    mac: String,
+    ip: IpAddr,
+    subnetmask: IpAddr,
+    gateway: IpAddr,
+    mtu: Option<u32>,
+    #[serde(default)]
+    dns_servers: Vec<IpAddr>,
+    /// alias ip ranges, e.g. the pod range of a gke node
+    #[serde(default)]
+    ip_aliases: Vec<String>,
+}
+
+impl NetworkInterface {
+    /// to_interface converts the nic to an interface matched by mac address.
+    /// only the first nic gets the default route, the others only reach their
+    /// own subnets.
+    fn to_interface(&self, primary: bool) -> Result<network::Interface> {
+        let mac = MacAddr::from_str(&self.mac)
+            .map_err(|e| Error::from(format!("{:?}", e)))
+            .chain_err(|| format!("failed to parse mac address: '{}'", self.mac))?;
+        let prefix = ipnetwork::ip_mask_to_prefix(self.subnetmask)
+            .chain_err(|| format!("invalid network mask {}", self.subnetmask))?;
+        let address = IpNetwork::new(self.ip, prefix)
+            .chain_err(|| format!("invalid address {}/{}", self.ip, prefix))?;
+
+        let mut routes = Vec::new();
+        if primary {
+            routes.push(network::NetworkRoute {
+                destination: IpNetwork::from_str("0.0.0.0/0").unwrap(),
+                gateway: self.gateway,
+            });
+        }
+
+        let mut local_routes = Vec::new();
+        for alias in &self.ip_aliases {
+            // single addresses come without a prefix length
+            let alias = if alias.contains('/') { alias.clone() } else { format!("{}/32", alias) };
+            local_routes.push(IpNetwork::from_str(&alias)
+                .map_err(|e| Error::from(format!("{:?}", e)))
+                .chain_err(|| format!("invalid alias ip range {:?}", alias))?);
+        }
+
+        Ok(network::Interface {
+            mac_address: Some(mac),
+            nameservers: self.dns_servers.clone(),
+            ip_addresses: vec![address],
+            routes,
+            mtu: self.mtu,
+            local_routes,
+            ..Default::default()
+        })
+    }
+}
+
+/// is_google returns true if the response came from the metadata server,
+/// which says so in every response
+fn is_google(headers: &Headers) -> bool {
+    headers.get::<MetadataFlavor>().map_or(false, |flavor| flavor.0 == GOOGLE)
+}
 
 #[derive(Clone, Debug)]
 pub struct GceProvider {
     client: retry::Client,
+    base_url: String,
+    static_network: bool,
+    /// the keys from fetch_all_ssh_keys, which the ssh key methods share
+    ssh_keys: RefCell<Option<Vec<(String, String)>>>,
+    /// the documents, when they were saved by fetch_raw rather than fetched
+    /// as they're needed
+    raw: Option<RawMetadata>,
 }
 
 impl GceProvider {
-    pub fn new() -> Result<GceProvider> {
+    pub fn new(config: &ProviderConfig) -> Result<GceProvider> {
+        let dns_timeout = config.dns_timeout_or_default();
         let client = retry::Client::new()?
             .header(MetadataFlavor(GOOGLE.to_owned()))
-            .return_on_404(true);
+            .return_on_404(true)
+            .remember_404(true)
+            .dns_timeout(dns_timeout);
+
+        let base_url = match config.endpoint_override() {
+            Some(endpoint) => endpoint,
+            None => GceProvider::find_endpoint(&client, dns_timeout)?,
+        };
+
+        Ok(GceProvider {
+            client,
+            base_url,
+            static_network: config.gce_static_network,
+            ssh_keys: RefCell::new(None),
+            raw: None,
+        })
+    }
+
+    /// fetch_raw fetches every document the provider reads
+    pub fn fetch_raw(config: &ProviderConfig) -> Result<RawMetadata> {
+        let provider = GceProvider::new(config)?;
+        let mut raw = RawMetadata::new(PROVIDER);
+        for document in DOCUMENTS.iter() {
+            if let Some(contents) = provider.client.get(retry::Raw, provider.endpoint_for(document)).send_bytes()? {
+                raw.insert(document, contents);
+            }
+        }
+        Ok(raw)
+    }
+
+    /// parse makes a provider that reads the documents saved by fetch_raw,
+    /// with the default settings
+    pub fn parse(raw: &RawMetadata) -> Result<GceProvider> {
+        raw.check(PROVIDER)?;
+        Ok(GceProvider {
+            client: retry::Client::new()?,
+            base_url: String::new(),
+            static_network: false,
+            ssh_keys: RefCell::new(None),
+            raw: Some(raw.clone()),
+        })
+    }
+
+    /// get returns a document under the metadata service, or the saved one
+    fn get(&self, name: &str) -> Result<Option<String>> {
+        match self.raw {
+            Some(ref raw) => Ok(raw.text(name)?.map(String::from)),
+            None => self.client.get(retry::Raw, self.endpoint_for(name)).send(),
+        }
+    }
+
+    fn get_json<T>(&self, name: &str) -> Result<Option<T>>
+        where T: for<'de> ::serde::Deserialize<'de>
+    {
+        match self.raw {
+            Some(ref raw) => raw.optional_json(name),
+            None => self.client.get_json(self.endpoint_for(name)),
+        }
+    }
+
+    /// find_endpoint returns the first of metadata.google.internal and its
+    /// address that answers, which is used for the rest of the run. the host
+    /// name is tried first, unless it doesn't resolve in time.
+    fn find_endpoint(client: &retry::Client, dns_timeout: Duration) -> Result<String> {
+        let candidates = match dns::resolve(HOST, 80, dns_timeout) {
+            Ok(_) => [URL, URL_ADDRESS],
+            Err(e) => {
+                cancel::check()?;
+                warn!("{}, trying the metadata server's address first", e);
+                [URL_ADDRESS, URL]
+            }
+        };
+
+        let client = client.clone()
+            .max_attempts(PROBE_ATTEMPTS);
+        for url in &candidates {
+            let res: Result<(Option<String>, Headers)> = client
+                .get(retry::Raw, format!("{}/instance/id", url))
+                .send_with_headers();
+            match res {
+                // a proxy or captive portal can answer in the metadata
+                // server's place, but doesn't say it's google's
+                Ok((_, ref headers)) if !is_google(headers) => {
+                    warn!("server at {} isn't the metadata server: it answered without {}: {}", url, MetadataFlavor::header_name(), GOOGLE);
+                }
+                Ok(_) => return Ok(url.to_string()),
+                Err(e) => {
+                    cancel::check()?;
+                    warn!("metadata service at {} is unreachable: {}", url, e);
+                }
+            }
+        }
+        Err(format!("none of the metadata service endpoints ({}) could be reached", candidates.join(", ")).into())
+    }
+
+    fn endpoint_for(&self, name: &str) -> String {
+        format!("{}/{}", self.base_url, name)
+    }
 
-        Ok(GceProvider { client })
+    /// fetch_document fetches a path from the root of the metadata server,
+    /// e.g. `/computeMetadata/v1/instance/id`, with the metadata flavor header
+    pub fn fetch_document(config: &ProviderConfig, path: &str) -> Result<Option<Vec<u8>>> {
+        let provider = GceProvider::new(config)?;
+        provider.client.get(retry::Raw, document_url(&provider.base_url, path)?).send_bytes()
     }
 
-    fn endpoint_for(name: &str) -> String {
-        format!("http://metadata.google.internal/computeMetadata/v1/{}", name)
+    /// os_login_flag is whether an os login flag, e.g. `enable-oslogin`, is
+    /// on for the instance
+    fn os_login_flag(&self, name: &str) -> Result<bool> {
+        let instance = self.get(&format!("instance/attributes/{}", name))?;
+        let project = self.get(&format!("project/attributes/{}", name))?;
+        Ok(flag_enabled(instance.as_ref().map(String::as_str), project.as_ref().map(String::as_str)))
     }
 
-    fn fetch_all_ssh_keys(&self) -> Result<Vec<String>> {
+    /// all_ssh_keys returns the keys as (user, key) pairs, fetching them
+    /// the first time
+    fn all_ssh_keys(&self) -> Result<Vec<(String, String)>> {
+        if let Some(ref keys) = *self.ssh_keys.borrow() {
+            return Ok(keys.clone());
+        }
+        let keys = self.fetch_all_ssh_keys()?;
+        *self.ssh_keys.borrow_mut() = Some(keys.clone());
+        Ok(keys)
+    }
+
+    /// fetch_all_ssh_keys returns the keys as (user, key) pairs. with os
+    /// login, access is managed through iam and the keys in the metadata
+    /// don't grant any, so there are none.
+    fn fetch_all_ssh_keys(&self) -> Result<Vec<(String, String)>> {
+        if self.os_login_flag("enable-oslogin")? {
+            info!("os login is enabled, not installing the ssh keys in the metadata");
+            return Ok(vec![]);
+        }
         let keys = self.fetch_ssh_keys("instance/attributes/sshKeys")?;
         if !keys.is_empty() {
             return Ok(keys);
         }
         let mut keys = self.fetch_ssh_keys("instance/attributes/ssh-keys")?;
 
-        let block_project_keys: Option<String> = self.client
-            .clone()
-            .get(retry::Raw, GceProvider::endpoint_for("instance/attributes/block-project-ssh-keys"))
-            .send()?;
+        let block_project_keys = self.get("instance/attributes/block-project-ssh-keys")?;
 
         if block_project_keys == Some("true".to_owned()) {
             return Ok(keys);
@@ -66,18 +320,18 @@ impl GceProvider {
         Ok(keys)
     }
 
-    fn fetch_ssh_keys(&self, key: &str) -> Result<Vec<String>> {
-        let key_data: Option<String> = self.client.get(retry::Raw, GceProvider::endpoint_for(key)).send()?;
+    fn fetch_ssh_keys(&self, key: &str) -> Result<Vec<(String, String)>> {
+        let key_data = self.get(key)?;
         if let Some(key_data) = key_data {
             let mut keys = Vec::new();
             for l in key_data.lines() {
                 if l.is_empty() {
                     continue
                 }
-                let mut l = l.to_owned();
+                // lines are `user:key`
                 let index = l.find(':')
                     .ok_or("character ':' not found in line in key data")?;
-                keys.push(l.split_off(index+1));
+                keys.push((l[..index].to_owned(), l[index+1..].to_owned()));
             }
             Ok(keys)
         } else {
@@ -92,7 +346,7 @@ impl MetadataProvider for GceProvider {
         let mut out = HashMap::with_capacity(3);
 
         let add_value = |map: &mut HashMap<_, _>, key: &str, name| -> Result<()> {
-            let value: Option<String> = self.client.get(retry::Raw, GceProvider::endpoint_for(name)).send()?;
+            let value = self.get(name)?;
 
             if let Some(value) = value {
                 if !value.is_empty() {
@@ -107,29 +361,207 @@ impl MetadataProvider for GceProvider {
         add_value(&mut out, "GCE_IP_EXTERNAL_0", "instance/network-interfaces/0/access-configs/0/external-ip")?;
         add_value(&mut out, "GCE_IP_LOCAL_0", "instance/network-interfaces/0/ip")?;
 
+        // the zone and machine type are paths, e.g.
+        // projects/<number>/zones/<zone>
+        let last_segment = |name| -> Result<Option<String>> {
+            let value = self.get(name)?;
+            Ok(match value.as_ref().and_then(|v| v.rsplit('/').next()) {
+                Some(v) if !v.is_empty() => Some(v.to_owned()),
+                _ => None,
+            })
+        };
+        if let Some(zone) = last_segment("instance/zone")? {
+            // zones are the region with a `-<letter>` suffix
+            if let Some(i) = zone.rfind('-') {
+                out.insert("GCE_REGION".to_string(), zone[..i].to_owned());
+            }
+            out.insert("GCE_ZONE".to_string(), zone);
+        }
+        if let Some(machine_type) = last_segment("instance/machine-type")? {
+            out.insert("GCE_MACHINE_TYPE".to_string(), machine_type);
+        }
+
+        out.insert("GCE_OS_LOGIN".to_string(), self.os_login_flag("enable-oslogin")?.to_string());
+        out.insert("GCE_OS_LOGIN_2FA".to_string(), self.os_login_flag("enable-oslogin-2fa")?.to_string());
+
         Ok(out)
     }
 
     fn hostname(&self) -> Result<Option<String>> {
-        self.client.get(retry::Raw, GceProvider::endpoint_for("instance/hostname")).send()
+        self.get("instance/hostname")
+    }
+
+    fn pretty_hostname(&self) -> Result<Option<String>> {
+        self.get("instance/name")
+    }
+
+    fn instance_id(&self) -> Result<Option<String>> {
+        self.get("instance/id")
+    }
+
+    /// metadata.google.internal is the same address
+    fn metadata_endpoints(&self) -> Vec<MetadataEndpoint> {
+        if self.raw.is_some() {
+            return vec![];
+        }
+        endpoints_of(&[self.base_url.as_str(), URL_ADDRESS])
     }
 
-    fn ssh_keys(&self) -> Result<Vec<AuthorizedKeyEntry>> {
+    /// the metadata server doesn't have the instance's labels, so these are
+    /// its network tags, which have no values
+    fn labels(&self) -> Result<BTreeMap<String, String>> {
+        let tags: Option<Vec<String>> = self.get_json("instance/tags?alt=json")?;
+        Ok(tags.unwrap_or_default().into_iter().map(|t| (t, String::new())).collect())
+    }
+
+    /// the zonal and global internal dns domains of the project, in the same
+    /// order as the guest environment puts them in resolv.conf
+    fn search_domains(&self) -> Result<Vec<String>> {
+        let project = self.get("project/project-id")?;
+        // projects/<number>/zones/<zone>
+        let zone = self.get("instance/zone")?;
+        let mut domains = Vec::new();
+        if let Some(project) = project {
+            if let Some(zone) = zone.as_ref().and_then(|z| z.rsplit('/').next()) {
+                domains.push(format!("{}.c.{}.internal", zone, project));
+            }
+            domains.push(format!("c.{}.internal", project));
+        }
+        domains.push("google.internal".to_owned());
+        Ok(domains)
+    }
+
+    fn ssh_keys(&self) -> Result<Vec<PublicKey>> {
         let mut out = Vec::new();
 
-        for key in &self.fetch_all_ssh_keys()? {
+        for (_, key) in self.all_ssh_keys()? {
+            if ssh_cert::is_certificate(&key) {
+                continue;
+            }
             let key = PublicKey::parse(&key)?;
-            out.push(AuthorizedKeyEntry::Valid{key});
+            out.push(key);
         }
 
         Ok(out)
     }
 
+    /// keys in the metadata are given for a user, which the guest
+    /// environment creates if it doesn't exist
+    fn ssh_keys_by_user(&self) -> Result<Vec<(Option<String>, PublicKey)>> {
+        let mut out = Vec::new();
+        for (user, key) in self.all_ssh_keys()? {
+            if ssh_cert::is_certificate(&key) {
+                continue;
+            }
+            out.push((Some(user), PublicKey::parse(&key)?));
+        }
+        Ok(out)
+    }
+
+    /// certificates can be given in place of keys, the way os login hands
+    /// them out
+    fn ssh_certificates_by_user(&self) -> Result<Vec<(Option<String>, SshCertificate)>> {
+        let mut out = Vec::new();
+        for (user, key) in self.all_ssh_keys()? {
+            if ssh_cert::is_certificate(&key) {
+                out.push((Some(user), SshCertificate::parse(&key)?));
+            }
+        }
+        Ok(out)
+    }
+
+    /// host keys are written to the `hostkeys` guest attribute namespace, one
+    /// attribute per key type, which is where the console and gcloud look for
+    /// them. guest attributes have to be enabled on the instance or project.
+    fn publish_ssh_host_keys(&self, keys: &[PublicKey]) -> Result<()> {
+        if self.raw.is_some() {
+            bail!("ssh host keys can't be published from saved documents");
+        }
+        for key in keys {
+            let url = self.endpoint_for(&format!("instance/guest-attributes/hostkeys/{}", key.keytype()));
+            // the value is the key without its comment
+            let value = key.to_key_format().split_whitespace().take(2).collect::<Vec<_>>().join(" ");
+            let resp: Option<String> = self.client.put(retry::Raw, url)
+                .body(value)
+                .send()?;
+            if resp.is_none() {
+                warn!("guest attributes are not enabled, not publishing ssh host keys");
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// preemptible instances get 30 seconds' notice, after which
+    /// `instance/preempted` is `TRUE`
+    fn termination_notice(&self) -> Result<Option<TerminationNotice>> {
+        if self.raw.is_some() {
+            bail!("termination notices can't be watched for in saved documents");
+        }
+        let preempted: Option<String> = self.client.get(retry::Raw, self.endpoint_for("instance/preempted"))
+            .remember_404(false)
+            .send()?;
+        Ok(match preempted {
+            Some(ref p) if p.trim() == "TRUE" => Some(TerminationNotice {
+                reason: "preempted".to_owned(),
+                not_before: None,
+            }),
+            _ => None,
+        })
+    }
+
+    /// only with --gce-static-network; otherwise dhcp configures the nics
     fn networks(&self) -> Result<Vec<network::Interface>> {
-        Ok(vec![])
+        if !self.static_network {
+            return Ok(vec![]);
+        }
+        let nics: Vec<NetworkInterface> = self
+            .get_json("instance/network-interfaces/?recursive=true")?
+            .unwrap_or_default();
+        nics.iter()
+            .enumerate()
+            .map(|(i, nic)| nic.to_interface(i == 0))
+            .collect()
     }
 
     fn network_devices(&self) -> Result<Vec<network::Device>> {
         Ok(vec![])
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    #[test]
+    fn flag_enabled_test() {
+        assert!(flag_enabled(None, Some("TRUE")));
+        assert!(!flag_enabled(Some("false"), Some("true")));
+        // an invalid instance value doesn't hide the project's
+        assert!(flag_enabled(Some("yes"), Some("1")));
+        assert!(!flag_enabled(None, None));
+    }
+
+    #[test]
+    fn network_interfaces_test() {
+        let nics: Vec<NetworkInterface> = serde_json::from_str(r#"[
+            {"accessConfigs": [], "dnsServers": ["169.254.169.254"], "gateway": "10.128.0.1",
+             "ip": "10.128.0.2", "ipAliases": ["10.4.0.0/24", "10.128.0.9"], "mac": "42:01:0a:80:00:02",
+             "mtu": 1460, "network": "projects/1/networks/default", "subnetmask": "255.255.240.0"},
+            {"dnsServers": ["169.254.169.254"], "gateway": "10.10.0.1", "ip": "10.10.0.5",
+             "mac": "42:01:0a:0a:00:05", "mtu": 8896, "subnetmask": "255.255.255.0"}
+        ]"#).unwrap();
+
+        let primary = nics[0].to_interface(true).unwrap();
+        assert_eq!(primary.ip_addresses, vec![IpNetwork::from_str("10.128.0.2/20").unwrap()]);
+        assert_eq!(primary.routes.len(), 1);
+        assert_eq!(primary.mtu, Some(1460));
+        assert_eq!(primary.local_routes, vec![IpNetwork::from_str("10.4.0.0/24").unwrap(),
+                                              IpNetwork::from_str("10.128.0.9/32").unwrap()]);
+
+        let secondary = nics[1].to_interface(false).unwrap();
+        assert!(secondary.routes.is_empty());
+        assert_eq!(secondary.mtu, Some(8896));
+    }
+}
@@ -0,0 +1,148 @@
+// Copyright 2017 CoreOS, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! golden file tests
+//!
+//! every directory under `tests/fixtures` is named after a provider, and
+//! holds the metadata the provider is given, as the platform serves it, and
+//! under `expected/` what's rendered from it: the attributes file and the
+//! network units. the metadata is parsed without fetching anything. the
+//! ones without `expected/` only hold documents for the provider's own tests.
+//!
+//! when the rendering changes on purpose, run the tests with
+//! `COREOS_METADATA_UPDATE_FIXTURES=1` (or `make update-fixtures`) to
+//! rewrite the expected files, and review the diff.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use providers::MetadataProvider;
+use util::attributes::AttributeNaming;
+
+const UPDATE_VAR: &str = "COREOS_METADATA_UPDATE_FIXTURES";
+
+fn fixture_dir(provider: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("fixtures").join(provider)
+}
+
+fn read_fixture(provider: &str, name: &str) -> String {
+    let path = fixture_dir(provider).join(name);
+    let mut contents = String::new();
+    File::open(&path)
+        .and_then(|mut f| f.read_to_string(&mut contents))
+        .unwrap_or_else(|e| panic!("failed to read {:?}: {}", path, e));
+    contents
+}
+
+/// render returns what's written for the provider, by the path of the file
+/// it's compared with under `expected/`
+fn render(provider: &MetadataProvider) -> BTreeMap<String, String> {
+    let mut outputs = BTreeMap::new();
+
+    let attributes: String = AttributeNaming::default().apply(provider.attributes().unwrap()).into_iter()
+        .map(|(k, v)| format!("{}={}\n", k, v))
+        .collect();
+    outputs.insert("attributes".to_owned(), attributes);
+
+    for interface in provider.networks().unwrap() {
+        outputs.insert(format!("network/{}", interface.unit_name()), interface.config());
+    }
+    for device in provider.network_devices().unwrap() {
+        outputs.insert(format!("network/{}", device.unit_name()), device.config());
+    }
+    outputs
+}
+
+/// read_expected reads every file under the directory, by its path relative
+/// to the directory
+fn read_expected(dir: &Path, prefix: &str, out: &mut BTreeMap<String, String>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries {
+        let path = entry.unwrap().path();
+        let name = format!("{}{}", prefix, path.file_name().unwrap().to_string_lossy());
+        if path.is_dir() {
+            read_expected(&path, &format!("{}/", name), out);
+        } else {
+            let mut contents = String::new();
+            File::open(&path).unwrap().read_to_string(&mut contents).unwrap();
+            out.insert(name, contents);
+        }
+    }
+}
+
+/// check compares what's rendered for the provider with the expected files
+/// of the fixture, or rewrites them when asked to
+fn check(fixture: &str, provider: &MetadataProvider) {
+    let expected_dir = fixture_dir(fixture).join("expected");
+    let outputs = render(provider);
+
+    if env::var_os(UPDATE_VAR).is_some() {
+        let _ = fs::remove_dir_all(&expected_dir);
+        for (name, contents) in &outputs {
+            let path = expected_dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            File::create(&path).unwrap().write_all(contents.as_bytes()).unwrap();
+        }
+        return;
+    }
+
+    let mut expected = BTreeMap::new();
+    read_expected(&expected_dir, "", &mut expected);
+    for (name, contents) in &outputs {
+        match expected.get(name) {
+            Some(e) => assert_eq!(contents, e, "{}: {} differs from the expected file", fixture, name),
+            None => panic!("{}: {} is rendered but not expected; set {} to update the fixture", fixture, name, UPDATE_VAR),
+        }
+    }
+    for name in expected.keys() {
+        assert!(outputs.contains_key(name), "{}: {} is expected but not rendered", fixture, name);
+    }
+}
+
+#[cfg(feature = "digitalocean")]
+#[test]
+fn digitalocean() {
+    use providers::digitalocean::DigitalOceanProvider;
+    use providers::raw::RawMetadata;
+    let mut raw = RawMetadata::new("digitalocean");
+    raw.insert("metadata/v1.json", read_fixture("digitalocean", "metadata.json"));
+    let provider = DigitalOceanProvider::parse(&raw).unwrap();
+    check("digitalocean", &provider);
+}
+
+#[cfg(feature = "matchbox")]
+#[test]
+fn matchbox() {
+    use providers::matchbox::MatchboxProvider;
+    use providers::raw::RawMetadata;
+    let mut raw = RawMetadata::new("matchbox");
+    raw.insert("metadata", read_fixture("matchbox", "metadata"));
+    raw.insert("selectors/mac", "52:54:00:a1:9c:ae");
+    let provider = MatchboxProvider::parse(&raw).unwrap();
+    check("matchbox", &provider);
+}
+
+#[cfg(feature = "nutanix")]
+#[test]
+fn nutanix() {
+    use providers::nutanix::NutanixProvider;
+    let provider = NutanixProvider::read_from(&fixture_dir("nutanix").join("config-drive")).unwrap();
+    check("nutanix", &provider);
+}
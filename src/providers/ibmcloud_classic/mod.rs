@@ -0,0 +1,234 @@
+// Copyright 2017 CoreOS, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! ibm cloud classic (softlayer) metadata fetcher
+//!
+//! servers there, bare metal ones included, get a config disk labelled
+//! `METADATA` in the openstack config drive layout. depending on how the
+//! server was provisioned the metadata is under `openstack/latest` or only
+//! under a dated version directory, so the newest one is used.
+
+use std::collections::HashMap;
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use openssh_keys::PublicKey;
+
+use errors::*;
+use network;
+use providers::{MetadataProvider, ProviderConfig};
+use providers::raw::RawMetadata;
+use util::configdrive::{self, MountedDrive};
+use util::network_data::NetworkData;
+
+const CONFIG_DRIVE_LABELS: [&str; 2] = ["METADATA", "metadata"];
+
+const PROVIDER: &str = "ibmcloud-classic";
+const META_DATA: &str = "meta_data.json";
+const NETWORK_DATA: &str = "network_data.json";
+
+#[derive(Clone, Debug, Deserialize)]
+struct MetaData {
+    hostname: Option<String>,
+    uuid: Option<String>,
+    /// older disks only have the availability zone, which is the datacenter
+    datacenter: Option<String>,
+    availability_zone: Option<String>,
+    #[serde(default)]
+    public_keys: HashMap<String, String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct IbmCloudClassicProvider {
+    meta_data: MetaData,
+    network_data: NetworkData,
+}
+
+/// metadata_dir finds the directory with the metadata: `latest` if there is
+/// one, and otherwise the newest version
+fn metadata_dir(root: &Path) -> Result<PathBuf> {
+    let openstack = root.join("openstack");
+    let latest = openstack.join("latest");
+    if latest.exists() {
+        return Ok(latest);
+    }
+    let mut versions = Vec::new();
+    for entry in fs::read_dir(&openstack).chain_err(|| format!("failed to read {:?}", openstack))? {
+        let entry = entry.chain_err(|| format!("failed to read {:?}", openstack))?;
+        if entry.path().join(META_DATA).exists() {
+            versions.push(entry.path());
+        }
+    }
+    // versions are dates (e.g. 2012-08-10), so they sort by age
+    versions.sort();
+    versions.pop().ok_or_else(|| format!("no metadata found in {:?}", openstack).into())
+}
+
+impl IbmCloudClassicProvider {
+    pub fn new(config: &ProviderConfig) -> Result<IbmCloudClassicProvider> {
+        IbmCloudClassicProvider::parse(&IbmCloudClassicProvider::fetch_raw(config)?)
+    }
+
+    /// fetch_raw reads the documents from the config disk
+    pub fn fetch_raw(config: &ProviderConfig) -> Result<RawMetadata> {
+        let device = configdrive::find_device(config.config_drive.as_ref().map(String::as_str), &CONFIG_DRIVE_LABELS)?;
+        let drive = MountedDrive::mount(&device)?;
+        IbmCloudClassicProvider::read_raw(drive.path())
+    }
+
+    /// read_raw reads the documents from the root of a mounted config disk,
+    /// by their path under it
+    pub fn read_raw(root: &Path) -> Result<RawMetadata> {
+        configdrive::verify(root)?;
+        let dir = metadata_dir(root)?;
+        let mut raw = RawMetadata::new(PROVIDER);
+        for name in &[META_DATA, NETWORK_DATA] {
+            let path = dir.join(name);
+            if path.exists() {
+                let document = path.strip_prefix(root)
+                    .chain_err(|| format!("{:?} isn't under {:?}", path, root))?
+                    .to_string_lossy()
+                    .into_owned();
+                raw.insert(&document, configdrive::read_text(&path)?);
+            }
+        }
+        Ok(raw)
+    }
+
+    #[cfg(test)]
+    fn read_from(root: &Path) -> Result<IbmCloudClassicProvider> {
+        IbmCloudClassicProvider::parse(&IbmCloudClassicProvider::read_raw(root)?)
+    }
+
+    /// parse parses the documents read from the config disk, from whichever
+    /// version directory they were read
+    pub fn parse(raw: &RawMetadata) -> Result<IbmCloudClassicProvider> {
+        raw.check(PROVIDER)?;
+        // `latest` sorts after the dated versions, like metadata_dir prefers
+        let suffix = format!("/{}", META_DATA);
+        let meta_data_document = raw.documents.keys()
+            .filter(|k| k.ends_with(&suffix))
+            .last()
+            .ok_or_else(|| format!("missing document {}", META_DATA))?;
+        let dir = &meta_data_document[..meta_data_document.len() - META_DATA.len()];
+        let meta_data = raw.json(meta_data_document)?;
+        let network_data_document = format!("{}{}", dir, NETWORK_DATA);
+        let network_data = match raw.get(&network_data_document) {
+            Some(_) => raw.json(&network_data_document)?,
+            None => NetworkData::default(),
+        };
+        Ok(IbmCloudClassicProvider { meta_data, network_data })
+    }
+
+    fn parse_attrs(&self) -> Vec<(String, String)> {
+        let mut attrs = Vec::new();
+        {
+            let mut add = |k: &str, v: &Option<String>| {
+                if let Some(ref v) = *v {
+                    attrs.push((format!("IBMCLOUD_CLASSIC_{}", k), v.clone()));
+                }
+            };
+            add("HOSTNAME", &self.meta_data.hostname);
+            add("INSTANCE_ID", &self.meta_data.uuid);
+            add("DATACENTER", &self.meta_data.datacenter.clone().or_else(|| self.meta_data.availability_zone.clone()));
+        }
+
+        // the first address of each kind is the primary one, the rest are
+        // portable or secondary addresses routed to the server
+        let (mut public_v4, mut private_v4, mut public_v6) = (0, 0, 0);
+        for n in &self.network_data.networks {
+            let (kind, count) = match n.ip_address {
+                Some(IpAddr::V4(a)) if a.is_private() => ("IPV4_PRIVATE", &mut private_v4),
+                Some(IpAddr::V4(_)) => ("IPV4_PUBLIC", &mut public_v4),
+                Some(IpAddr::V6(_)) => ("IPV6_PUBLIC", &mut public_v6),
+                None => continue,
+            };
+            attrs.push((format!("IBMCLOUD_CLASSIC_{}_{}", kind, count), n.ip_address.unwrap().to_string()));
+            *count += 1;
+        }
+        attrs
+    }
+}
+
+impl MetadataProvider for IbmCloudClassicProvider {
+    fn attributes(&self) -> Result<HashMap<String, String>> {
+        Ok(self.parse_attrs().into_iter().collect())
+    }
+
+    fn hostname(&self) -> Result<Option<String>> {
+        Ok(self.meta_data.hostname.clone())
+    }
+
+    fn instance_id(&self) -> Result<Option<String>> {
+        Ok(self.meta_data.uuid.clone())
+    }
+
+    fn ssh_keys(&self) -> Result<Vec<PublicKey>> {
+        // sort by key name so the order is stable
+        let mut keys: Vec<(&String, &String)> = self.meta_data.public_keys.iter().collect();
+        keys.sort();
+        keys.into_iter()
+            .map(|(_, key)| Ok(PublicKey::parse(key)?))
+            .collect()
+    }
+
+    fn networks(&self) -> Result<Vec<network::Interface>> {
+        self.network_data.interfaces(false)
+    }
+
+    fn network_devices(&self) -> Result<Vec<network::Device>> {
+        Ok(vec![])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::Write;
+    use tempdir::TempDir;
+
+    #[test]
+    fn read_dated_layout() {
+        let dir = TempDir::new("coreos-metadata-test").unwrap();
+        let version = dir.path().join("openstack").join("2012-08-10");
+        fs::create_dir_all(&version).unwrap();
+        fs::create_dir_all(dir.path().join("openstack").join("content")).unwrap();
+        File::create(version.join("meta_data.json")).unwrap()
+            .write_all(br#"{"hostname": "bm1.example.com", "uuid": "abc", "availability_zone": "dal10"}"#).unwrap();
+        File::create(version.join("network_data.json")).unwrap()
+            .write_all(br#"{
+                "links": [{"id": "interface_0", "ethernet_mac_address": "06:a1:b2:c3:d4:e5"}],
+                "networks": [
+                    {"link": "interface_0", "ip_address": "10.1.2.3", "netmask": "255.255.255.192",
+                     "routes": [{"network": "10.0.0.0", "netmask": "255.0.0.0", "gateway": "10.1.2.1"}]},
+                    {"link": "interface_0", "ip_address": "169.45.1.2", "netmask": "255.255.255.248"},
+                    {"link": "interface_0", "ip_address": "169.45.9.9", "netmask": "255.255.255.255"}
+                ],
+                "services": [{"type": "dns", "address": "10.0.80.11"}]
+            }"#).unwrap();
+
+        let provider = IbmCloudClassicProvider::read_from(dir.path()).unwrap();
+        let attrs = provider.attributes().unwrap();
+        assert_eq!(attrs["IBMCLOUD_CLASSIC_DATACENTER"], "dal10");
+        assert_eq!(attrs["IBMCLOUD_CLASSIC_IPV4_PRIVATE_0"], "10.1.2.3");
+        assert_eq!(attrs["IBMCLOUD_CLASSIC_IPV4_PUBLIC_1"], "169.45.9.9");
+
+        let networks = provider.networks().unwrap();
+        assert_eq!(networks.len(), 1);
+        assert_eq!(networks[0].ip_addresses.len(), 3);
+        assert_eq!(networks[0].routes.len(), 1);
+    }
+}
@@ -0,0 +1,314 @@
+// Copyright 2017 CoreOS, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! kubevirt metadata fetcher
+//!
+//! kubevirt attaches the VMI's cloud-init volume as a disk, either in the
+//! nocloud layout (`cloudInitNoCloud`, labelled `cidata`) or in the openstack
+//! config drive layout (`cloudInitConfigDrive`, labelled `config-2`). the
+//! instance id it writes is `<name>.<namespace>` of the VMI.
+//!
+//! the first interface is on the pod network and gets its address by dhcp,
+//! so network configuration is only generated for the interfaces the network
+//! data gives static addresses, which are the secondary (e.g. multus) ones.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use openssh_keys::PublicKey;
+use serde_yaml;
+
+use errors::*;
+use network;
+use providers::{MetadataProvider, ProviderConfig};
+use providers::raw::RawMetadata;
+use util::configdrive::{self, MountedDrive};
+use util::json;
+use util::network_config;
+use util::network_data::NetworkData;
+
+const PROVIDER: &str = "kubevirt";
+const CONFIG_DRIVE_LABELS: [&str; 4] = ["cidata", "CIDATA", "config-2", "CONFIG-2"];
+
+/// the documents of each layout, by their path on the volume
+const CONFIG_DRIVE_META_DATA: &str = "openstack/latest/meta_data.json";
+const CONFIG_DRIVE_NETWORK_DATA: &str = "openstack/latest/network_data.json";
+const CONFIG_DRIVE_USER_DATA: &str = "openstack/latest/user_data";
+const NOCLOUD_META_DATA: &str = "meta-data";
+const NOCLOUD_NETWORK_CONFIG: &str = "network-config";
+const NOCLOUD_USER_DATA: &str = "user-data";
+
+/// meta_data.json in the config drive layout
+#[derive(Clone, Debug, Deserialize)]
+struct MetaData {
+    instance_id: Option<String>,
+    instance_type: Option<String>,
+    hostname: Option<String>,
+    uuid: Option<String>,
+    #[serde(default)]
+    public_keys: HashMap<String, String>,
+}
+
+/// meta-data in the nocloud layout. kubevirt writes it as json, but it's
+/// yaml as far as cloud-init is concerned.
+#[derive(Clone, Debug, Deserialize)]
+struct NoCloudMetaData {
+    #[serde(rename = "instance-id")]
+    instance_id: Option<String>,
+    #[serde(rename = "instance-type")]
+    instance_type: Option<String>,
+    #[serde(rename = "local-hostname")]
+    local_hostname: Option<String>,
+    #[serde(rename = "public-keys", default)]
+    public_keys: HashMap<String, String>,
+}
+
+impl From<NoCloudMetaData> for MetaData {
+    fn from(m: NoCloudMetaData) -> MetaData {
+        MetaData {
+            instance_id: m.instance_id,
+            instance_type: m.instance_type,
+            hostname: m.local_hostname,
+            uuid: None,
+            public_keys: m.public_keys,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct KubeVirtProvider {
+    meta_data: MetaData,
+    user_data: Option<Vec<u8>>,
+    networks: Vec<network::Interface>,
+}
+
+fn corrupt(document: &str, reason: String) -> Error {
+    ErrorKind::Parse(ParseError::ConfigDriveCorrupt(document.to_owned(), reason)).into()
+}
+
+impl KubeVirtProvider {
+    pub fn new(config: &ProviderConfig) -> Result<KubeVirtProvider> {
+        KubeVirtProvider::parse(&KubeVirtProvider::fetch_raw(config)?)
+    }
+
+    /// fetch_raw reads the documents from the volume
+    pub fn fetch_raw(config: &ProviderConfig) -> Result<RawMetadata> {
+        let device = configdrive::find_device(config.config_drive.as_ref().map(String::as_str), &CONFIG_DRIVE_LABELS)?;
+        let drive = MountedDrive::mount(&device)?;
+        KubeVirtProvider::read_raw(drive.path())
+    }
+
+    /// read_raw reads the documents of either layout from the root of a
+    /// mounted volume
+    pub fn read_raw(root: &Path) -> Result<RawMetadata> {
+        configdrive::verify(root)?;
+        let (documents, user_data) = if root.join("openstack").exists() {
+            ([CONFIG_DRIVE_META_DATA, CONFIG_DRIVE_NETWORK_DATA], CONFIG_DRIVE_USER_DATA)
+        } else {
+            ([NOCLOUD_META_DATA, NOCLOUD_NETWORK_CONFIG], NOCLOUD_USER_DATA)
+        };
+        let mut raw = RawMetadata::new(PROVIDER);
+        for document in &documents {
+            let path = root.join(document);
+            if path.exists() {
+                raw.insert(document, configdrive::read_text(&path)?);
+            }
+        }
+        // user data can be binary
+        let path = root.join(user_data);
+        if path.exists() {
+            raw.insert(user_data, configdrive::read_bytes(&path)?);
+        }
+        Ok(raw)
+    }
+
+    #[cfg(test)]
+    fn read_from(root: &Path) -> Result<KubeVirtProvider> {
+        KubeVirtProvider::parse(&KubeVirtProvider::read_raw(root)?)
+    }
+
+    /// parse parses the documents of whichever layout they're in
+    pub fn parse(raw: &RawMetadata) -> Result<KubeVirtProvider> {
+        raw.check(PROVIDER)?;
+        if raw.documents.keys().any(|k| k.starts_with("openstack/")) {
+            KubeVirtProvider::parse_config_drive(raw)
+        } else {
+            KubeVirtProvider::parse_nocloud(raw)
+        }
+    }
+
+    fn parse_config_drive(raw: &RawMetadata) -> Result<KubeVirtProvider> {
+        let meta_data = json::from_slice(raw.require_text(CONFIG_DRIVE_META_DATA)?.as_bytes())
+            .map_err(|e| corrupt(CONFIG_DRIVE_META_DATA, json::describe(&e)))?;
+
+        let networks = match raw.get(CONFIG_DRIVE_NETWORK_DATA) {
+            Some(contents) => json::from_slice::<NetworkData>(contents)
+                .map_err(|e| corrupt(CONFIG_DRIVE_NETWORK_DATA, json::describe(&e)))?
+                .interfaces(true)?,
+            None => vec![],
+        };
+
+        Ok(KubeVirtProvider {
+            meta_data,
+            user_data: raw.get(CONFIG_DRIVE_USER_DATA).map(|d| d.to_vec()),
+            networks,
+        })
+    }
+
+    fn parse_nocloud(raw: &RawMetadata) -> Result<KubeVirtProvider> {
+        let contents = raw.get(NOCLOUD_META_DATA)
+            .ok_or_else(|| format!("missing document {}", NOCLOUD_META_DATA))?;
+        let meta_data: NoCloudMetaData = serde_yaml::from_slice(contents)
+            .map_err(|e| corrupt(NOCLOUD_META_DATA, e.to_string()))?;
+
+        let networks = match raw.get(NOCLOUD_NETWORK_CONFIG) {
+            Some(contents) => network_config::parse(contents)
+                .map_err(|e| corrupt(NOCLOUD_NETWORK_CONFIG, e.to_string()))?
+                .interfaces()?,
+            None => vec![],
+        };
+
+        Ok(KubeVirtProvider {
+            meta_data: meta_data.into(),
+            user_data: raw.get(NOCLOUD_USER_DATA).map(|d| d.to_vec()),
+            networks,
+        })
+    }
+
+    /// vmi returns the name and namespace of the VMI. namespaces can't
+    /// contain dots, but names can.
+    fn vmi(&self) -> Option<(&str, &str)> {
+        let id = self.meta_data.instance_id.as_ref()?;
+        let i = id.rfind('.')?;
+        Some((&id[..i], &id[i + 1..]))
+    }
+
+    fn parse_attrs(&self) -> Vec<(String, String)> {
+        let mut attrs = Vec::new();
+        {
+            let mut add = |k: &str, v: &Option<String>| {
+                if let Some(ref v) = *v {
+                    attrs.push((format!("KUBEVIRT_{}", k), v.clone()));
+                }
+            };
+            add("HOSTNAME", &self.meta_data.hostname);
+            add("INSTANCE_ID", &self.meta_data.instance_id);
+            add("INSTANCE_TYPE", &self.meta_data.instance_type);
+        }
+        if let Some((name, namespace)) = self.vmi() {
+            attrs.push(("KUBEVIRT_VMI_NAME".to_owned(), name.to_owned()));
+            attrs.push(("KUBEVIRT_VMI_NAMESPACE".to_owned(), namespace.to_owned()));
+        }
+        attrs
+    }
+}
+
+impl MetadataProvider for KubeVirtProvider {
+    fn attributes(&self) -> Result<HashMap<String, String>> {
+        Ok(self.parse_attrs().into_iter().collect())
+    }
+
+    fn hostname(&self) -> Result<Option<String>> {
+        Ok(self.meta_data.hostname.clone())
+    }
+
+    fn instance_id(&self) -> Result<Option<String>> {
+        // the uuid changes when the VMI is recreated under the same name
+        Ok(self.meta_data.uuid.clone().or_else(|| self.meta_data.instance_id.clone()))
+    }
+
+    fn ssh_keys(&self) -> Result<Vec<PublicKey>> {
+        // sort by key name so the order is stable
+        let mut keys: Vec<(&String, &String)> = self.meta_data.public_keys.iter().collect();
+        keys.sort();
+        keys.into_iter()
+            .map(|(_, key)| Ok(PublicKey::parse(key)?))
+            .collect()
+    }
+
+    fn networks(&self) -> Result<Vec<network::Interface>> {
+        Ok(self.networks.clone())
+    }
+
+    fn network_devices(&self) -> Result<Vec<network::Device>> {
+        Ok(vec![])
+    }
+
+    fn custom_data(&self) -> Result<Option<Vec<u8>>> {
+        Ok(self.user_data.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use ipnetwork::IpNetwork;
+    use sha2::{Digest, Sha256};
+    use std::fs::File;
+    use std::io::Write;
+    use std::str::FromStr;
+    use tempdir::TempDir;
+
+    #[test]
+    fn read_nocloud_test() {
+        let dir = TempDir::new("coreos-metadata-test").unwrap();
+        File::create(dir.path().join("meta-data")).unwrap()
+            .write_all(br#"{"instance-id": "web.v1.default", "local-hostname": "web"}"#).unwrap();
+        File::create(dir.path().join("network-config")).unwrap()
+            .write_all(b"version: 2
+ethernets:
+  eth0:
+    dhcp4: true
+  secondary:
+    match:
+      macaddress: '02:00:00:0a:0b:0c'
+    addresses: [192.168.10.5/24]
+    gateway4: 192.168.10.1
+    nameservers:
+      addresses: [192.168.10.1]
+").unwrap();
+
+        let provider = KubeVirtProvider::read_from(dir.path()).unwrap();
+        let attrs = provider.attributes().unwrap();
+        assert_eq!(attrs["KUBEVIRT_VMI_NAME"], "web.v1");
+        assert_eq!(attrs["KUBEVIRT_VMI_NAMESPACE"], "default");
+        assert_eq!(provider.hostname().unwrap(), Some("web".to_owned()));
+
+        let networks = provider.networks().unwrap();
+        assert_eq!(networks.len(), 1);
+        assert_eq!(networks[0].name, None);
+        assert_eq!(networks[0].ip_addresses, vec![IpNetwork::from_str("192.168.10.5/24").unwrap()]);
+        assert_eq!(networks[0].routes[0].destination, IpNetwork::from_str("0.0.0.0/0").unwrap());
+    }
+
+    #[test]
+    fn read_gzipped_user_data_test() {
+        let dir = TempDir::new("coreos-metadata-test").unwrap();
+        File::create(dir.path().join("meta-data")).unwrap()
+            .write_all(br#"{"instance-id": "web.v1.default"}"#).unwrap();
+        // gzip headers have zeroes in them, which text documents can't
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(b"#cloud-config\n").unwrap();
+        let user_data = encoder.finish().unwrap();
+        assert!(user_data.contains(&0));
+        File::create(dir.path().join("user-data")).unwrap().write_all(&user_data).unwrap();
+        File::create(dir.path().join("SHA256SUMS")).unwrap()
+            .write_all(format!("{:x}  user-data\n", Sha256::digest(&user_data)).as_bytes()).unwrap();
+
+        let provider = KubeVirtProvider::read_from(dir.path()).unwrap();
+        assert_eq!(provider.custom_data().unwrap(), Some(user_data));
+    }
+}
@@ -0,0 +1,289 @@
+// Copyright 2017 CoreOS, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! linode (akamai) metadata fetcher
+//!
+//! every request to the metadata service needs a token, which is handed out
+//! in response to a PUT to /v1/token, much like ec2's imdsv2.
+
+use std::collections::HashMap;
+
+use openssh_keys::PublicKey;
+
+use errors::*;
+use network;
+use providers::{document_url, endpoints_of, Capabilities, MetadataEndpoint, MetadataProvider, ProviderConfig};
+use providers::raw::RawMetadata;
+use retry;
+use util::decode;
+use util::secret::Secret;
+
+header! {(MetadataToken, "Metadata-Token") => [String]}
+const URL: &str = "http://169.254.169.254";
+/// the token only has to last for this run
+const TOKEN_EXPIRY_SECS: &str = "300";
+
+const PROVIDER: &str = "linode";
+const INSTANCE_DOCUMENT: &str = "v1/instance";
+const NETWORK_DOCUMENT: &str = "v1/network";
+const SSH_KEYS_DOCUMENT: &str = "v1/ssh-keys";
+const USER_DATA_DOCUMENT: &str = "v1/user-data";
+/// the documents, and whether they're required
+const DOCUMENTS: [(&str, bool); 4] = [
+    (INSTANCE_DOCUMENT, true),
+    (NETWORK_DOCUMENT, true),
+    (SSH_KEYS_DOCUMENT, false),
+    (USER_DATA_DOCUMENT, false),
+];
+
+#[derive(Clone, Debug, Deserialize)]
+struct Instance {
+    id: u64,
+    label: String,
+    region: String,
+    #[serde(rename = "type")]
+    type_name: String,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+struct Ipv4 {
+    #[serde(default)]
+    public: Vec<String>,
+    #[serde(default)]
+    private: Vec<String>,
+    #[serde(default)]
+    shared: Vec<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+struct Ipv6 {
+    slaac: Option<String>,
+    link_local: Option<String>,
+    #[serde(default)]
+    ranges: Vec<String>,
+    #[serde(default)]
+    shared_ranges: Vec<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct Network {
+    #[serde(default)]
+    ipv4: Ipv4,
+    #[serde(default)]
+    ipv6: Ipv6,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct SshKeys {
+    /// authorized keys by user, which are all installed for the one user
+    /// coreos-metadata is told about
+    #[serde(default)]
+    users: HashMap<String, Vec<String>>,
+}
+
+#[derive(Clone, Debug)]
+pub struct LinodeProvider {
+    instance: Instance,
+    network: Network,
+    ssh_keys: Option<SshKeys>,
+    user_data: Option<String>,
+}
+
+impl LinodeProvider {
+    pub fn new(config: &ProviderConfig) -> Result<LinodeProvider> {
+        LinodeProvider::parse(&LinodeProvider::fetch_raw(config)?)
+    }
+
+    /// fetch_raw fetches every document the provider uses. the ssh keys and
+    /// user data may be missing.
+    pub fn fetch_raw(config: &ProviderConfig) -> Result<RawMetadata> {
+        let base_url = config.endpoint_or(URL);
+        let client = LinodeProvider::client(&base_url)?;
+        let mut raw = RawMetadata::new(PROVIDER);
+        for &(document, required) in &DOCUMENTS {
+            let body: Option<String> = client.get(retry::Raw, format!("{}/{}", base_url, document)).send()?;
+            match body {
+                Some(body) => raw.insert(document, body),
+                None if required => bail!("{} not found", document),
+                None => {}
+            }
+        }
+        Ok(raw)
+    }
+
+    /// fetch_document fetches a path from the root of the metadata service,
+    /// e.g. `/v1/instance`, with a token of its own
+    pub fn fetch_document(config: &ProviderConfig, path: &str) -> Result<Option<Vec<u8>>> {
+        let base_url = config.endpoint_or(URL);
+        LinodeProvider::client(&base_url)?
+            .get(retry::Raw, document_url(&base_url, path)?)
+            .send_bytes()
+    }
+
+    /// client gets a metadata token, and returns a client that sends it
+    fn client(base_url: &str) -> Result<retry::Client> {
+        let token: Option<String> = retry::Client::new()?
+            .put(retry::Raw, format!("{}/v1/token", base_url))
+            .raw_header("Metadata-Token-Expiry-Seconds", TOKEN_EXPIRY_SECS.to_owned())
+            .send()
+            .chain_err(|| "failed to get metadata token")?;
+        let token = Secret::new(token.ok_or("failed to get metadata token: not found")?);
+        // the header keeps a copy of its own for as long as the client lasts
+        Ok(retry::Client::new()?
+            .header(MetadataToken(token.expose_str()?.trim().to_owned()))
+            .return_on_404(true))
+    }
+
+    /// parse parses the documents fetched from the metadata service
+    pub fn parse(raw: &RawMetadata) -> Result<LinodeProvider> {
+        raw.check(PROVIDER)?;
+        let ssh_keys = match raw.get(SSH_KEYS_DOCUMENT) {
+            Some(_) => Some(raw.json(SSH_KEYS_DOCUMENT)?),
+            None => None,
+        };
+        Ok(LinodeProvider {
+            instance: raw.json(INSTANCE_DOCUMENT)?,
+            network: raw.json(NETWORK_DOCUMENT)?,
+            ssh_keys,
+            user_data: raw.text(USER_DATA_DOCUMENT)?.map(str::to_owned),
+        })
+    }
+
+    fn parse_attrs(&self) -> Vec<(String, String)> {
+        let mut attrs = vec![
+            ("LINODE_INSTANCE_ID".to_owned(), self.instance.id.to_string()),
+            ("LINODE_LABEL".to_owned(), self.instance.label.clone()),
+            ("LINODE_REGION".to_owned(), self.instance.region.clone()),
+            ("LINODE_TYPE".to_owned(), self.instance.type_name.clone()),
+        ];
+
+        // addresses come with a prefix length, which isn't wanted in the
+        // single-address attributes
+        let add_addrs = |attrs: &mut Vec<_>, name: &str, addrs: &[String], strip: bool| {
+            for (i, a) in addrs.iter().enumerate() {
+                let a = if strip { a.split('/').next().unwrap_or(a) } else { a.as_str() };
+                attrs.push((format!("LINODE_{}_{}", name, i), a.to_owned()));
+            }
+        };
+        let ipv4 = &self.network.ipv4;
+        add_addrs(&mut attrs, "IPV4_PUBLIC", &ipv4.public, true);
+        add_addrs(&mut attrs, "IPV4_PRIVATE", &ipv4.private, true);
+        add_addrs(&mut attrs, "IPV4_SHARED", &ipv4.shared, true);
+
+        let ipv6 = &self.network.ipv6;
+        if let Some(ref slaac) = ipv6.slaac {
+            attrs.push(("LINODE_IPV6_SLAAC".to_owned(), slaac.split('/').next().unwrap_or(slaac).to_owned()));
+        }
+        if let Some(ref link_local) = ipv6.link_local {
+            attrs.push(("LINODE_IPV6_LINK_LOCAL".to_owned(), link_local.split('/').next().unwrap_or(link_local).to_owned()));
+        }
+        // ranges are routed to the instance as a whole, so they keep their
+        // prefix length
+        add_addrs(&mut attrs, "IPV6_RANGE", &ipv6.ranges, false);
+        add_addrs(&mut attrs, "IPV6_SHARED_RANGE", &ipv6.shared_ranges, false);
+
+        attrs
+    }
+}
+
+impl MetadataProvider for LinodeProvider {
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            network: false,
+            ..Capabilities::all()
+        }
+    }
+
+    fn attributes(&self) -> Result<HashMap<String, String>> {
+        Ok(self.parse_attrs().into_iter().collect())
+    }
+
+    fn hostname(&self) -> Result<Option<String>> {
+        Ok(Some(self.instance.label.clone()))
+    }
+
+    fn instance_id(&self) -> Result<Option<String>> {
+        Ok(Some(self.instance.id.to_string()))
+    }
+
+    fn metadata_endpoints(&self) -> Vec<MetadataEndpoint> {
+        endpoints_of(&[URL])
+    }
+
+    fn ssh_keys(&self) -> Result<Vec<PublicKey>> {
+        let mut users: Vec<(String, Vec<String>)> = self.ssh_keys.clone().map(|k| k.users.into_iter().collect())
+            .unwrap_or_default();
+        // sort by user, so the keys are always in the same order
+        users.sort();
+
+        let mut out = Vec::new();
+        for (_, keys) in users {
+            for key in keys {
+                out.push(PublicKey::parse(&key)?);
+            }
+        }
+        Ok(out)
+    }
+
+    fn networks(&self) -> Result<Vec<network::Interface>> {
+        // addresses are configured by dhcp and slaac
+        Ok(vec![])
+    }
+
+    fn network_devices(&self) -> Result<Vec<network::Device>> {
+        Ok(vec![])
+    }
+
+    fn custom_data(&self) -> Result<Option<Vec<u8>>> {
+        match self.user_data {
+            Some(ref data) if !data.trim().is_empty() => Ok(Some(decode::decode_base64(data.as_bytes())
+                .and_then(decode::maybe_gunzip)
+                .chain_err(|| "failed to decode user data")?)),
+            _ => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    #[test]
+    fn parse_attrs_test() {
+        let network: Network = serde_json::from_str(r#"{
+            "interfaces": [],
+            "ipv4": {"public": ["203.0.113.5/32"], "private": ["192.168.1.2/17"], "shared": []},
+            "ipv6": {"slaac": "2600:3c00::f03c:93ff:fe1a:1/128", "ranges": ["2600:3c00:e000:1::/64"],
+                     "link_local": "fe80::f03c:93ff:fe1a:1/128", "shared_ranges": []}
+        }"#).unwrap();
+        let provider = LinodeProvider {
+            instance: Instance {
+                id: 1234,
+                label: "web1".to_owned(),
+                region: "us-east".to_owned(),
+                type_name: "g6-standard-1".to_owned(),
+            },
+            network,
+            ssh_keys: None,
+            user_data: None,
+        };
+        let attrs: HashMap<String, String> = provider.parse_attrs().into_iter().collect();
+        assert_eq!(attrs["LINODE_IPV4_PUBLIC_0"], "203.0.113.5");
+        assert_eq!(attrs["LINODE_IPV4_PRIVATE_0"], "192.168.1.2");
+        assert_eq!(attrs["LINODE_IPV6_SLAAC"], "2600:3c00::f03c:93ff:fe1a:1");
+        assert_eq!(attrs["LINODE_IPV6_RANGE_0"], "2600:3c00:e000:1::/64");
+        assert_eq!(attrs["LINODE_TYPE"], "g6-standard-1");
+    }
+}
@@ -0,0 +1,223 @@
+// Copyright 2017 CoreOS, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! matchbox metadata fetcher
+//!
+//! matchbox (and provisioning servers that work like it) picks a machine's
+//! profile by the selectors in the query string, so the machine identifies
+//! itself the same way it did when it pxe booted: by the mac address of the
+//! interface it booted from and its smbios uuid. the metadata comes back as
+//! `KEY=value` lines, with nested keys joined by underscores.
+
+use std::collections::{BTreeMap, HashMap};
+
+use openssh_keys::PublicKey;
+use pnet;
+
+use errors::*;
+use network;
+use providers::{Capabilities, MetadataProvider, ProviderConfig};
+use providers::raw::RawMetadata;
+use retry;
+use util::attributes::AttributeNamer;
+use util::dmi;
+use util::ssh_cert::{self, SshCertificate};
+
+const SSH_KEYS_KEY: &str = "SSH_AUTHORIZED_KEYS";
+
+const PROVIDER: &str = "matchbox";
+const METADATA_DOCUMENT: &str = "metadata";
+// the selectors aren't served by matchbox, but the metadata can't be
+// attributed to the machine without them
+const MAC_DOCUMENT: &str = "selectors/mac";
+const UUID_DOCUMENT: &str = "selectors/uuid";
+
+#[derive(Clone, Debug)]
+pub struct MatchboxProvider {
+    mac: Option<String>,
+    uuid: Option<String>,
+    metadata: BTreeMap<String, String>,
+}
+
+/// boot_mac guesses the mac address of the interface the machine booted
+/// from, which is the first one that got an address
+fn boot_mac() -> Option<String> {
+    pnet::datalink::interfaces().into_iter()
+        .filter(|i| !i.is_loopback() && network::has_usable_address(i))
+        .filter_map(|i| i.mac)
+        .map(|mac| mac.to_string())
+        .next()
+}
+
+fn parse_metadata(body: &str) -> BTreeMap<String, String> {
+    body.lines()
+        .filter_map(|l| {
+            let i = l.find('=')?;
+            Some((l[..i].trim().to_owned(), l[i + 1..].to_owned()))
+        })
+        .filter(|&(ref k, _)| !k.is_empty())
+        .collect()
+}
+
+impl MatchboxProvider {
+    pub fn new(config: &ProviderConfig) -> Result<MatchboxProvider> {
+        MatchboxProvider::parse(&MatchboxProvider::fetch_raw(config)?)
+    }
+
+    /// fetch_raw fetches the metadata of the machine, along with the mac
+    /// address and uuid it was selected by
+    pub fn fetch_raw(config: &ProviderConfig) -> Result<RawMetadata> {
+        let base_url = config.endpoint_override()
+            .ok_or("the matchbox provider needs --metadata-endpoint")?;
+        let mac = boot_mac();
+        let uuid = dmi::read_field("product_uuid")?;
+
+        let mut selectors = Vec::new();
+        if let Some(ref mac) = mac {
+            selectors.push(format!("mac={}", mac));
+        }
+        if let Some(ref uuid) = uuid {
+            selectors.push(format!("uuid={}", uuid));
+        }
+        if selectors.is_empty() {
+            return Err("found neither a mac address nor a uuid to identify the machine with".into());
+        }
+
+        let url = format!("{}/metadata?{}", base_url, selectors.join("&"));
+        let body: String = retry::Client::new()?
+            .get(retry::Raw, url.clone())
+            .send()?
+            .ok_or_else(|| format!("no metadata at {}", url))?;
+
+        let mut raw = RawMetadata::new(PROVIDER);
+        raw.insert(METADATA_DOCUMENT, body);
+        if let Some(mac) = mac {
+            raw.insert(MAC_DOCUMENT, mac);
+        }
+        if let Some(uuid) = uuid {
+            raw.insert(UUID_DOCUMENT, uuid);
+        }
+        Ok(raw)
+    }
+
+    /// parse reads the metadata of the machine identified by the mac address
+    /// and uuid
+    pub fn parse(raw: &RawMetadata) -> Result<MatchboxProvider> {
+        raw.check(PROVIDER)?;
+        Ok(MatchboxProvider {
+            mac: raw.text(MAC_DOCUMENT)?.map(str::to_owned),
+            uuid: raw.text(UUID_DOCUMENT)?.map(str::to_owned),
+            metadata: parse_metadata(raw.require_text(METADATA_DOCUMENT)?),
+        })
+    }
+
+    /// key_lines returns the ssh keys in the metadata, in order. a list of
+    /// keys is flattened to SSH_AUTHORIZED_KEYS_0, SSH_AUTHORIZED_KEYS_1, ...
+    /// which has to be sorted by index
+    fn key_lines(&self) -> Vec<&String> {
+        let mut keys: Vec<(usize, &String)> = Vec::new();
+        for (k, v) in &self.metadata {
+            let index = match &k[..] {
+                SSH_KEYS_KEY => 0,
+                k if k.starts_with(SSH_KEYS_KEY) => match k[SSH_KEYS_KEY.len()..].trim_left_matches('_').parse() {
+                    Ok(i) => i,
+                    Err(_) => continue,
+                },
+                _ => continue,
+            };
+            keys.push((index, v));
+        }
+        keys.sort();
+        keys.into_iter()
+            .filter(|&(_, k)| !k.trim().is_empty())
+            .map(|(_, k)| k)
+            .collect()
+    }
+
+    fn parse_attrs(&self) -> Vec<(String, String)> {
+        let mut namer = AttributeNamer::new("MATCHBOX_");
+        self.metadata.iter()
+            .filter(|&(k, _)| !k.starts_with(SSH_KEYS_KEY))
+            .map(|(k, v)| (namer.name_for(k), v.clone()))
+            .collect()
+    }
+}
+
+impl MetadataProvider for MatchboxProvider {
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            network: false,
+            ..Capabilities::all()
+        }
+    }
+
+    fn attributes(&self) -> Result<HashMap<String, String>> {
+        Ok(self.parse_attrs().into_iter().collect())
+    }
+
+    fn hostname(&self) -> Result<Option<String>> {
+        Ok(self.metadata.get("HOSTNAME")
+            .or_else(|| self.metadata.get("DOMAIN_NAME"))
+            .cloned())
+    }
+
+    fn instance_id(&self) -> Result<Option<String>> {
+        Ok(self.uuid.clone().or_else(|| self.mac.clone()))
+    }
+
+    fn ssh_keys(&self) -> Result<Vec<PublicKey>> {
+        self.key_lines().into_iter()
+            .filter(|k| !ssh_cert::is_certificate(k))
+            .map(|k| Ok(PublicKey::parse(k)?))
+            .collect()
+    }
+
+    /// certificates can be listed along with the keys
+    fn ssh_certificates_by_user(&self) -> Result<Vec<(Option<String>, SshCertificate)>> {
+        self.key_lines().into_iter()
+            .filter(|k| ssh_cert::is_certificate(k))
+            .map(|k| Ok((None, SshCertificate::parse(k)?)))
+            .collect()
+    }
+
+    fn networks(&self) -> Result<Vec<network::Interface>> {
+        Ok(vec![])
+    }
+
+    fn network_devices(&self) -> Result<Vec<network::Device>> {
+        Ok(vec![])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_metadata_test() {
+        let provider = MatchboxProvider {
+            mac: Some("52:54:00:a1:9c:ae".to_owned()),
+            uuid: None,
+            metadata: parse_metadata("DOMAIN_NAME=node1.example.com\nETCD_NAME=node1\n\
+                                      K8S_POD_CIDR=10.2.0.0/16\nSSH_AUTHORIZED_KEYS_10=\nMAC=52:54:00:a1:9c:ae\n\
+                                      no value\n"),
+        };
+        let attrs = provider.attributes().unwrap();
+        assert_eq!(attrs.len(), 4);
+        assert_eq!(attrs["MATCHBOX_K8S_POD_CIDR"], "10.2.0.0/16");
+        assert_eq!(provider.hostname().unwrap(), Some("node1.example.com".to_owned()));
+        assert_eq!(provider.instance_id().unwrap(), Some("52:54:00:a1:9c:ae".to_owned()));
+        assert!(provider.ssh_keys().unwrap().is_empty());
+    }
+}
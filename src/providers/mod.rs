@@ -19,29 +19,419 @@
 //! services, and externally, they provide a function to fetch that metadata in
 //! a regular format.
 //!
-//! To add a provider, put a `pub mod provider;` line in this file, export a
-//! function to fetch the metadata, and then add a match line in the top-level
-//! `fetch_metadata()` function in metadata.rs.
+//! To add a provider, put a `pub mod provider;` line in this file behind a
+//! cargo feature of the same name, export a function to fetch the metadata,
+//! and then add a match line in the top-level `fetch_metadata()` function in
+//! lib.rs (and its name and description to `PROVIDERS`).
 
+#[cfg(feature = "azure")]
 pub mod azure;
+#[cfg(feature = "dhcp")]
+pub mod dhcp;
+#[cfg(feature = "digitalocean")]
 pub mod digitalocean;
+#[cfg(feature = "cloudstack")]
 pub mod cloudstack;
+#[cfg(feature = "custom-http")]
+pub mod custom_http;
+#[cfg(feature = "dmi")]
+pub mod dmi;
+#[cfg(feature = "ec2")]
 pub mod ec2;
+#[cfg(feature = "gce")]
 pub mod gce;
+#[cfg(test)]
+mod golden_tests;
+#[cfg(feature = "ibmcloud-classic")]
+pub mod ibmcloud_classic;
+#[cfg(feature = "kubevirt")]
+pub mod kubevirt;
+#[cfg(feature = "linode")]
+pub mod linode;
+#[cfg(feature = "matchbox")]
+pub mod matchbox;
+#[cfg(feature = "nutanix")]
+pub mod nutanix;
+#[cfg(feature = "openstack")]
 pub mod openstack;
+#[cfg(feature = "packet")]
 pub mod packet;
+#[cfg(feature = "rackspace")]
+pub mod rackspace;
+pub mod raw;
+pub mod snapshot;
+#[cfg(feature = "vagrant-virtualbox")]
 pub mod vagrant_virtualbox;
 
-use std::collections::HashMap;
-use std::fs::{self, File};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
 use std::io::prelude::*;
-use std::path::Path;
+use std::net::IpAddr;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+#[cfg(feature = "ssh-keys")]
+use std::thread;
+use std::time::Duration;
 
+use openssh_keys::PublicKey;
+use reqwest;
+use serde_json;
+#[cfg(feature = "ssh-keys")]
 use update_ssh_keys::{AuthorizedKeys, AuthorizedKeyEntry};
+#[cfg(feature = "ssh-keys")]
 use users;
+#[cfg(feature = "ssh-keys")]
+use users::os::unix::UserExt;
 
+use audit;
 use errors::*;
 use network;
+use providers::snapshot::{DeviceSnapshot, InterfaceSnapshot};
+use util;
+use util::attributes;
+use util::attributes::{AttributeNaming, Provenance};
+#[cfg(feature = "ssh-keys")]
+use util::authorized_keys;
+use util::dns;
+use util::firewall::{self, FirewallOptions};
+use util::hostnamed;
+use util::labels;
+use util::secret::Secret;
+use util::ssh_cert::SshCertificate;
+use util::template;
+use util::topology;
+use util::useradd::{self, NewUser};
+
+/// directories that administrator- and vendor-supplied networkd units are
+/// read from, in order of precedence
+const NETWORK_UNIT_DIRS: [&str; 3] = [
+    "/etc/systemd/network",
+    "/usr/lib/systemd/network",
+    "/lib/systemd/network",
+];
+
+/// the file written to the --env-d directory. it sorts early so that the
+/// administrator's own files can override it.
+const ENVIRONMENT_D_FILE: &str = "10-coreos-metadata.conf";
+
+/// the file written to the --resolved-d directory
+const RESOLVED_DROPIN_FILE: &str = "10-coreos-metadata.conf";
+
+/// ProviderConfig holds the user-supplied settings that change how providers
+/// talk to their metadata services.
+#[derive(Clone, Debug, Default)]
+pub struct ProviderConfig {
+    /// overrides the base url of the provider's metadata service
+    pub endpoint: Option<String>,
+    /// the config drive to use, as a device path, `LABEL=<label>`, or
+    /// `UUID=<uuid>`, instead of looking for one by its usual labels
+    pub config_drive: Option<String>,
+    /// the mapping file for the custom-http provider
+    pub custom_http_mapping: Option<String>,
+    /// how long the cloudstack provider looks for its virtual router
+    pub cloudstack_router_timeout: Option<Duration>,
+    /// whether the azure provider gives secondary nics routing tables of
+    /// their own
+    pub azure_policy_routing: bool,
+    /// whether the gce provider writes static network units for the
+    /// instance's nics instead of leaving them to dhcp
+    pub gce_static_network: bool,
+    /// how long providers whose metadata services have host names wait for
+    /// them to resolve
+    pub dns_timeout: Option<Duration>,
+    /// the most requests a second made to the metadata service, if they're
+    /// limited
+    pub max_requests_per_second: Option<u32>,
+}
+
+impl ProviderConfig {
+    /// endpoint_override returns the configured endpoint override, if any.
+    /// trailing slashes are stripped so that providers can join paths with a
+    /// single `/`.
+    pub fn endpoint_override(&self) -> Option<String> {
+        self.endpoint.as_ref().map(|endpoint| {
+            info!("using metadata endpoint override {}", endpoint);
+            endpoint.trim_right_matches('/').to_owned()
+        })
+    }
+
+    /// endpoint_or returns the configured endpoint override, or the given
+    /// default if there isn't one.
+    pub fn endpoint_or(&self, default: &str) -> String {
+        self.endpoint_override().unwrap_or_else(|| default.to_owned())
+    }
+
+    /// dns_timeout_or_default returns the configured dns timeout, or the
+    /// default one
+    pub fn dns_timeout_or_default(&self) -> Duration {
+        self.dns_timeout.unwrap_or_else(|| Duration::from_secs(dns::DEFAULT_TIMEOUT_SECS))
+    }
+}
+
+/// SshHostKey is a host keypair (and optionally a host certificate) supplied
+/// by the provider, so that a machine keeps the same identity across
+/// reprovisioning.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SshHostKey {
+    /// the key type as used in the file name, e.g. `rsa` or `ed25519`
+    pub key_type: String,
+    pub private_key: String,
+    pub public_key: Option<String>,
+    pub certificate: Option<String>,
+}
+
+impl ::std::fmt::Debug for SshHostKey {
+    // never print the private key
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        f.debug_struct("SshHostKey")
+            .field("key_type", &self.key_type)
+            .field("public_key", &self.public_key)
+            .field("certificate", &self.certificate)
+            .finish()
+    }
+}
+
+/// MetadataEndpoint is an address and port the provider fetches metadata
+/// from, which the metadata firewall rules protect
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MetadataEndpoint {
+    pub address: IpAddr,
+    pub port: u16,
+}
+
+/// endpoints_of is the endpoints of the urls whose hosts are ip addresses.
+/// urls that name a host can't be turned into rules, and are left out.
+pub(crate) fn endpoints_of<S: AsRef<str>>(urls: &[S]) -> Vec<MetadataEndpoint> {
+    let mut endpoints: Vec<MetadataEndpoint> = vec![];
+    for url in urls {
+        let url = match reqwest::Url::parse(url.as_ref()) {
+            Ok(url) => url,
+            Err(_) => continue,
+        };
+        // ipv6 hosts keep their brackets
+        let address = match url.host_str().map(|h| h.trim_left_matches('[').trim_right_matches(']').parse()) {
+            Some(Ok(address)) => address,
+            _ => continue,
+        };
+        let endpoint = MetadataEndpoint { address, port: url.port_or_known_default().unwrap_or(80) };
+        if !endpoints.contains(&endpoint) {
+            endpoints.push(endpoint);
+        }
+    }
+    endpoints
+}
+
+/// TerminationNotice is the platform's warning that the instance is going
+/// away
+#[derive(Clone, Debug, PartialEq)]
+pub struct TerminationNotice {
+    /// what is going to happen, as the platform calls it, e.g. `preempted`
+    /// on gce, `terminate` on ec2, or `Preempt` on azure
+    pub reason: String,
+    /// when it's going to happen, in the platform's format, if it says
+    pub not_before: Option<String>,
+}
+
+/// MaintenanceEvent is maintenance the platform has scheduled for the
+/// instance
+#[derive(Clone, Debug, PartialEq)]
+pub struct MaintenanceEvent {
+    /// the id the event is started early with
+    pub id: String,
+    /// what is going to happen, as the platform calls it, e.g. `Reboot`
+    pub kind: String,
+    /// e.g. `Scheduled` or `Started`
+    pub status: String,
+    /// when it's going to happen, in the platform's format, if it says
+    pub not_before: Option<String>,
+}
+
+/// Capabilities are the kinds of metadata a provider can supply at all.
+/// a provider that can supply something may still have nothing to give on a
+/// particular instance.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Capabilities {
+    pub attributes: bool,
+    pub ssh_keys: bool,
+    pub hostname: bool,
+    /// network interfaces, beyond what dhcp configures
+    pub network: bool,
+}
+
+impl Capabilities {
+    /// all is every kind of metadata, which providers are assumed to supply
+    /// unless they say otherwise
+    pub fn all() -> Capabilities {
+        Capabilities {
+            attributes: true,
+            ssh_keys: true,
+            hostname: true,
+            network: true,
+        }
+    }
+}
+
+impl Default for Capabilities {
+    fn default() -> Capabilities {
+        Capabilities::all()
+    }
+}
+
+/// SshKeysOptions is who ssh keys are written for, and where
+#[derive(Clone, Debug, Default)]
+pub struct SshKeysOptions {
+    /// the local user that keys without a mapping are written for
+    pub user: Option<String>,
+    /// platform accounts to the local users their keys are written for
+    pub users_map: HashMap<String, String>,
+    /// how local users that don't exist are created, if they are
+    pub create_user: Option<NewUser>,
+    /// the root filesystem the users are in
+    pub root: Option<PathBuf>,
+    /// how long to wait for another update of a user's keys to finish, by
+    /// default `util::authorized_keys::DEFAULT_LOCK_TIMEOUT_SECS`
+    pub lock_timeout: Option<Duration>,
+    /// whether the keys written before are removed when the provider no
+    /// longer has any for a user, instead of being left in place
+    pub sync: bool,
+}
+
+#[cfg(feature = "ssh-keys")]
+impl SshKeysOptions {
+    pub fn lock_timeout_or_default(&self) -> Duration {
+        self.lock_timeout.unwrap_or_else(|| Duration::from_secs(authorized_keys::DEFAULT_LOCK_TIMEOUT_SECS))
+    }
+}
+
+/// Password is a password for a local user supplied by the provider, either
+/// as it was set on the platform or already hashed for the shadow file.
+/// neither is ever printed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Password {
+    Plain(Secret),
+    Hash(Secret),
+}
+
+/// write_file_with_mode creates (or truncates) the file and makes sure it has
+/// the given permissions before anything is written to it
+pub(crate) fn write_file_with_mode(path: &Path, mode: u32, contents: &str) -> Result<()> {
+    let mut file = open_with_mode(path, mode)?;
+    file.write_all(contents.as_bytes())
+        .chain_err(|| ErrorKind::Write(WriteError::Failed(path.display().to_string())))?;
+    if !contents.ends_with('\n') {
+        file.write_all(b"\n")
+            .chain_err(|| ErrorKind::Write(WriteError::Failed(path.display().to_string())))?;
+    }
+    Ok(())
+}
+
+/// write_bytes_with_mode is write_file_with_mode for contents that aren't
+/// necessarily text, which are written as they are
+pub(crate) fn write_bytes_with_mode(path: &Path, mode: u32, contents: &[u8]) -> Result<()> {
+    open_with_mode(path, mode)?
+        .write_all(contents)
+        .chain_err(|| ErrorKind::Write(WriteError::Failed(path.display().to_string())))
+}
+
+fn open_with_mode(path: &Path, mode: u32) -> Result<File> {
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(mode)
+        .open(path)
+        .chain_err(|| ErrorKind::Write(WriteError::Failed(path.display().to_string())))?;
+    audit::wrote(path);
+    // the mode passed to open is only used if the file doesn't exist yet
+    file.set_permissions(fs::Permissions::from_mode(mode))
+        .chain_err(|| format!("failed to set permissions on file {:?}", path))
+        .chain_err(|| ErrorKind::Write(WriteError::Failed(path.display().to_string())))?;
+    Ok(file)
+}
+
+/// document_url is the url of a path on the metadata service that base_url
+/// is on, for `fetch_document`. the path is from the root of the service,
+/// not from the base url, e.g. `/latest/meta-data/instance-id` on ec2.
+pub(crate) fn document_url(base_url: &str, path: &str) -> Result<String> {
+    let base = reqwest::Url::parse(base_url)
+        .chain_err(|| format!("invalid metadata service url {:?}", base_url))?;
+    let url = base.join(path)
+        .chain_err(|| format!("invalid document path {:?}", path))?;
+    Ok(url.to_string())
+}
+
+/// write_attributes_file writes the attributes in the format of the
+/// attributes file, one `KEY=value` per line
+pub(crate) fn write_attributes_file(path: &Path, attributes: &[(String, String)]) -> Result<()> {
+    let contents: String = attributes.iter()
+        .map(|&(ref k, ref v)| format!("{}={}\n", k, v))
+        .collect();
+    write_file_with_mode(path, 0o644, &contents)
+}
+
+/// read_ssh_host_public_keys reads all of the `ssh_host_*_key.pub` files in
+/// the given directory
+fn read_ssh_host_public_keys(dir: &Path) -> Result<Vec<PublicKey>> {
+    let mut keys = Vec::new();
+    let entries = fs::read_dir(dir)
+        .chain_err(|| format!("failed to read directory {:?}", dir))?;
+    for entry in entries {
+        let path = entry
+            .chain_err(|| format!("failed to read directory {:?}", dir))?
+            .path();
+        let is_host_key = path.file_name()
+            .and_then(|n| n.to_str())
+            .map_or(false, |n| n.starts_with("ssh_host_") && n.ends_with("_key.pub"));
+        if !is_host_key {
+            continue;
+        }
+        let file = File::open(&path)
+            .chain_err(|| format!("failed to open file {:?}", path))?;
+        keys.extend(PublicKey::read_keys(file)
+            .chain_err(|| format!("failed to read ssh host key {:?}", path))?);
+    }
+    Ok(keys)
+}
+
+/// find_matching_unit returns the name of the first (in the order networkd
+/// reads them) administrator- or vendor-supplied .network unit that matches
+/// the interface, ignoring a unit with the name we would generate ourselves
+fn find_matching_unit(iface: &network::Interface, own_name: &str, root: Option<&Path>) -> Result<Option<String>> {
+    let mut units: Vec<(String, PathBuf)> = Vec::new();
+    for dir in &NETWORK_UNIT_DIRS {
+        let dir = util::rooted(root, dir);
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries {
+            let path = entry
+                .chain_err(|| format!("failed to read directory {:?}", dir))?
+                .path();
+            let name = match path.file_name().and_then(|n| n.to_str()) {
+                Some(name) if name.ends_with(".network") && name != own_name => name.to_owned(),
+                _ => continue,
+            };
+            // units in earlier directories mask ones with the same name in
+            // later directories
+            if !units.iter().any(|&(ref n, _)| *n == name) {
+                units.push((name, path.clone()));
+            }
+        }
+    }
+    units.sort();
+
+    for (name, path) in units {
+        let mut contents = String::new();
+        File::open(&path)
+            .and_then(|mut f| f.read_to_string(&mut contents))
+            .chain_err(|| format!("failed to read file {:?}", path))?;
+        if network::unit_matches(&contents, iface) {
+            return Ok(Some(name));
+        }
+    }
+    Ok(None)
+}
 
 fn create_file(filename: &str) -> Result<File> {
     let file_path = Path::new(&filename);
@@ -49,51 +439,437 @@ fn create_file(filename: &str) -> Result<File> {
     let folder = file_path.parent()
         .ok_or_else(|| format!("could not get parent directory of {:?}", file_path))?;
     fs::create_dir_all(&folder)
-        .chain_err(|| format!("failed to create directory {:?}", folder))?;
+        .chain_err(|| format!("failed to create directory {:?}", folder))
+        .chain_err(|| ErrorKind::Write(WriteError::Failed(file_path.display().to_string())))?;
     // create (or truncate) the file we want to write to
-    File::create(file_path)
-        .chain_err(|| format!("failed to create file {:?}", file_path))
+    let file = File::create(file_path)
+        .chain_err(|| ErrorKind::Write(WriteError::Failed(file_path.display().to_string())))?;
+    audit::wrote(file_path);
+    Ok(file)
+}
+
+/// local_users_for_keys groups the keys and certificates by the local user
+/// they are written to. ones for platform accounts that aren't mapped go to
+/// the default user, or nowhere if there isn't one.
+#[cfg(feature = "ssh-keys")]
+fn local_users_for_keys(keys: Vec<(Option<String>, PublicKey)>, certificates: Vec<(Option<String>, SshCertificate)>,
+                        default_user: Option<&String>, users_map: &HashMap<String, String>)
+                        -> BTreeMap<String, (Vec<PublicKey>, Vec<SshCertificate>)> {
+    let local_user = |platform_user: &Option<String>| platform_user.as_ref()
+        .and_then(|u| users_map.get(u))
+        .or(default_user)
+        .cloned();
+    let mut keys_by_user: BTreeMap<String, (Vec<PublicKey>, Vec<SshCertificate>)> = BTreeMap::new();
+    for (platform_user, key) in keys {
+        match local_user(&platform_user) {
+            Some(u) => keys_by_user.entry(u).or_insert_with(Default::default).0.push(key),
+            None => warn!("not writing ssh key SHA256:{} for unmapped user {}",
+                          key.fingerprint(), platform_user.as_ref().map_or("(none)", String::as_str)),
+        }
+    }
+    for (platform_user, certificate) in certificates {
+        match local_user(&platform_user) {
+            Some(u) => keys_by_user.entry(u).or_insert_with(Default::default).1.push(certificate),
+            None => warn!("not writing ssh certificate {:?} for unmapped user {}",
+                          certificate.key_id, platform_user.as_ref().map_or("(none)", String::as_str)),
+        }
+    }
+    keys_by_user
+}
+
+/// add_synced_users adds the local users keys can be written for that the
+/// provider has none for, so that the keys written for them before are removed
+#[cfg(feature = "ssh-keys")]
+fn add_synced_users(keys_by_user: &mut BTreeMap<String, (Vec<PublicKey>, Vec<SshCertificate>)>,
+                    default_user: Option<&String>, users_map: &HashMap<String, String>) {
+    for user in default_user.into_iter().chain(users_map.values()) {
+        keys_by_user.entry(user.clone()).or_insert_with(Default::default);
+    }
+}
+
+/// write_authorized_keys adds the keys to the authorized keys directory of
+/// the given local user. with a root, the user is the one in its passwd
+/// file, and the directory is in its copy of their home directory. it runs
+/// on a thread of its own, so it returns what it changed for the audit.
+/// when syncing, the keys replace the ones written before even if there are
+/// none, which removes them.
+#[cfg(feature = "ssh-keys")]
+fn write_authorized_keys(ssh_keys_user: &str, keys: Vec<PublicKey>, certificates: Vec<SshCertificate>,
+                         root: Option<&Path>, lock_timeout: Duration, sync: bool) -> Result<audit::Record> {
+    let mut record = audit::Record::default();
+    for key in &keys {
+        record.installed_key(ssh_keys_user, key);
+    }
+    let mut ssh_keys = keys.into_iter()
+        .map(|key| AuthorizedKeyEntry::Valid{key})
+        .collect::<Vec<_>>();
+    // certificates go after the plain keys. update-ssh-keys can't parse
+    // them, but it writes the lines it can't parse as they are.
+    for certificate in certificates {
+        match certificate.check_valid_now() {
+            Ok(()) => {
+                record.installed_certificate(ssh_keys_user, &certificate);
+                ssh_keys.push(AuthorizedKeyEntry::Invalid{key: certificate.to_key_format()});
+            }
+            Err(e) => warn!("not writing ssh certificate for user '{}': {}", ssh_keys_user, e),
+        }
+    }
+    if ssh_keys.is_empty() && !sync {
+        return Ok(record);
+    }
+
+    // find the ssh keys user and open their ssh authorized keys directory.
+    // a user that doesn't exist has no keys to remove.
+    let (user, ssh_dir) = match root {
+        Some(root) => {
+            let entry = match useradd::lookup_user(root, ssh_keys_user)? {
+                Some(entry) => entry,
+                None if ssh_keys.is_empty() => return Ok(record),
+                None => bail!("could not find user with username {:?} in {:?}", ssh_keys_user, root),
+            };
+            (users::User::new(entry.uid, ssh_keys_user, entry.gid),
+             util::rooted(Some(root), &entry.home).join(".ssh"))
+        }
+        None => {
+            let user = match users::get_user_by_name(ssh_keys_user) {
+                Some(user) => user,
+                None if ssh_keys.is_empty() => return Ok(record),
+                None => bail!("could not find user with username {:?}", ssh_keys_user),
+            };
+            let ssh_dir = user.home_dir().join(".ssh");
+            (user, ssh_dir)
+        }
+    };
+    let fragment = ssh_dir.join("authorized_keys.d").join(authorized_keys::FRAGMENT_NAME);
+    if ssh_keys.is_empty() && !fragment.exists() {
+        return Ok(record);
+    }
+    let uid = user.uid();
+    let mut authorized_keys_dir = authorized_keys::open(user, &ssh_dir, lock_timeout)
+        .chain_err(|| format!("failed to open authorized keys directory for user '{}'", ssh_keys_user))?;
+
+    // back up what the update can touch, so that a failure partway through
+    // can be undone instead of leaving the user without their keys
+    let staged = authorized_keys::Staged::stage(&ssh_dir, uid)
+        .chain_err(|| format!("failed to stage authorized keys for user '{}'", ssh_keys_user))?;
+    let res = update_authorized_keys(&mut authorized_keys_dir, ssh_keys_user, ssh_keys, sync);
+    match res {
+        Ok(()) => {
+            staged.commit()?;
+            record.wrote(&fragment);
+            record.wrote(&ssh_dir.join("authorized_keys"));
+            Ok(record)
+        }
+        Err(e) => {
+            if let Err(rollback) = staged.rollback() {
+                warn!("failed to roll back authorized keys for user '{}': {}", ssh_keys_user, rollback);
+            }
+            Err(e)
+        }
+    }
+}
+
+/// update_authorized_keys replaces our fragment with the keys, writes the
+/// changes, and regenerates authorized_keys. when syncing, the fragment is
+/// removed if there aren't any keys. the other fragments, which hold the
+/// keys users added themselves, are left alone either way.
+#[cfg(feature = "ssh-keys")]
+fn update_authorized_keys(authorized_keys_dir: &mut AuthorizedKeys, ssh_keys_user: &str,
+                          ssh_keys: Vec<AuthorizedKeyEntry>, sync: bool) -> Result<()> {
+    if sync {
+        let current: Vec<String> = ssh_keys.iter().map(entry_line).collect();
+        let removed = authorized_keys_dir.remove_keys(authorized_keys::FRAGMENT_NAME).iter()
+            .filter(|e| !current.contains(&entry_line(e)))
+            .count();
+        if removed > 0 {
+            info!("removing {} ssh keys for user '{}' that the provider no longer has", removed, ssh_keys_user);
+        }
+    }
+    if !ssh_keys.is_empty() {
+        authorized_keys_dir.add_keys(authorized_keys::FRAGMENT_NAME, ssh_keys, true, true)?;
+    }
+    authorized_keys_dir.write()
+        .chain_err(|| "failed to update authorized keys directory")?;
+    authorized_keys_dir.sync()
+        .chain_err(|| "failed to update authorized keys")
+}
+
+/// entry_line is the authorized_keys line of the entry
+#[cfg(feature = "ssh-keys")]
+fn entry_line(entry: &AuthorizedKeyEntry) -> String {
+    match *entry {
+        AuthorizedKeyEntry::Valid{ref key} => key.to_key_format(),
+        AuthorizedKeyEntry::Invalid{ref key} => key.clone(),
+    }
+}
+
+/// NetworkState is the json form of a provider's network configuration.
+/// interfaces reference the bonds they belong to by name, and the bonds
+/// themselves are among the devices.
+#[derive(Debug, Serialize)]
+struct NetworkState {
+    interfaces: Vec<InterfaceSnapshot>,
+    devices: Vec<DeviceSnapshot>,
 }
 
 pub trait MetadataProvider {
     fn attributes(&self) -> Result<HashMap<String, String>>;
     fn hostname(&self) -> Result<Option<String>>;
-    fn ssh_keys(&self) -> Result<Vec<AuthorizedKeyEntry>>;
+    fn ssh_keys(&self) -> Result<Vec<PublicKey>>;
     fn networks(&self) -> Result<Vec<network::Interface>>;
     fn network_devices(&self) -> Result<Vec<network::Device>>;
 
+    /// capabilities is what the provider can supply, so that asking it for
+    /// something it can't isn't mistaken for the instance having none of it
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::all()
+    }
+
+    /// custom data is an opaque, user-supplied blob. most providers don't
+    /// have anything like it.
+    fn custom_data(&self) -> Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+
+    /// pretty_hostname is the name the instance is shown with by the
+    /// platform, if it's different from the hostname
+    fn pretty_hostname(&self) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    /// instance_id is the provider's id for the running instance, where it
+    /// has one
+    fn instance_id(&self) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    /// search_domains are the platform's internal dns domains, which dhcp
+    /// doesn't always hand out
+    fn search_domains(&self) -> Result<Vec<String>> {
+        Ok(vec![])
+    }
+
+    /// ssh_keys_by_user is the ssh keys along with the platform account each
+    /// is meant for, for providers that scope keys to accounts. by default
+    /// none of them are.
+    fn ssh_keys_by_user(&self) -> Result<Vec<(Option<String>, PublicKey)>> {
+        Ok(self.ssh_keys()?.into_iter().map(|key| (None, key)).collect())
+    }
+
+    /// ssh_certificates_by_user is the openssh certificates given along with
+    /// the ssh keys, and the platform account each is meant for. they're
+    /// only found where users can put arbitrary lines among their keys.
+    fn ssh_certificates_by_user(&self) -> Result<Vec<(Option<String>, SshCertificate)>> {
+        Ok(vec![])
+    }
+
+    /// password is the password the platform set for the instance, which
+    /// only a few private clouds supply
+    fn password(&self) -> Result<Option<Password>> {
+        Ok(None)
+    }
+
+    /// acknowledge_password tells the platform that the password has been
+    /// set, for platforms that only hand it out until then
+    fn acknowledge_password(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// labels are the tags or labels the user gave the instance on the
+    /// platform, by name
+    fn labels(&self) -> Result<BTreeMap<String, String>> {
+        Ok(BTreeMap::new())
+    }
+
+    /// metadata_endpoints is where the provider fetches metadata from, for
+    /// the ones it reaches by ip address
+    fn metadata_endpoints(&self) -> Vec<MetadataEndpoint> {
+        vec![]
+    }
+
+    /// ssh host keys are only supplied by a handful of providers
+    fn ssh_host_keys(&self) -> Result<Vec<SshHostKey>> {
+        Ok(vec![])
+    }
+
+    /// publish the public halves of the machine's ssh host keys somewhere the
+    /// platform (and therefore the user) can see them
+    fn publish_ssh_host_keys(&self, _keys: &[PublicKey]) -> Result<()> {
+        Err("publishing ssh host keys is not supported on this platform".into())
+    }
+
+    /// termination_notice is the platform's notice that the instance is about
+    /// to be preempted or terminated, if it has given one. it's polled for
+    /// by `watch`, so it should be cheap.
+    fn termination_notice(&self) -> Result<Option<TerminationNotice>> {
+        Err("termination notices are not supported on this platform".into())
+    }
+
+    /// maintenance_events is the maintenance the platform has scheduled for
+    /// the instance
+    fn maintenance_events(&self) -> Result<Vec<MaintenanceEvent>> {
+        Err("scheduled maintenance events are not supported on this platform".into())
+    }
+
+    /// start_maintenance asks the platform to start the given scheduled
+    /// events now, rather than when they're due
+    fn start_maintenance(&self, _ids: &[String]) -> Result<()> {
+        Err("scheduled maintenance events are not supported on this platform".into())
+    }
+
+    /// attribute_provenance is the attributes that could be fetched, along
+    /// with what became of each one the provider looked for. by default the
+    /// attributes are fetched all at once, so they're all fetched or none.
+    fn attribute_provenance(&self) -> Result<(HashMap<String, String>, BTreeMap<String, Provenance>)> {
+        let attributes = self.attributes()?;
+        let provenance = attributes.keys().map(|k| (k.clone(), Provenance::Fetched)).collect();
+        Ok((attributes, provenance))
+    }
+
+    /// attributes_with is the attributes along with their topology
+    /// attributes, and the given extra ones. with a list of required
+    /// attributes, only those have to be fetched; see
+    /// `attributes::check_required`.
+    fn attributes_with(&self, extra: &HashMap<String, String>, required: Option<&[String]>) -> Result<HashMap<String, String>> {
+        let (mut attributes, provenance) = self.attribute_provenance()?;
+        topology::add_topology(&mut attributes);
+        attributes.extend(extra.clone());
+        attributes::check_required(&attributes, &provenance, required)?;
+        Ok(attributes)
+    }
+
     fn write_attributes(&self, attributes_file_path: String) -> Result<()> {
+        self.write_attributes_with(attributes_file_path, &HashMap::new(), &AttributeNaming::default(), None)
+    }
+
+    /// write_attributes_with also writes the given attributes, which come
+    /// from coreos-metadata itself rather than the provider, with the given
+    /// names
+    fn write_attributes_with(&self, attributes_file_path: String, extra: &HashMap<String, String>,
+                             naming: &AttributeNaming, required: Option<&[String]>) -> Result<()> {
+        let attributes = self.attributes_with(extra, required)?;
         let mut attributes_file = create_file(&attributes_file_path)?;
-        for (k,v) in self.attributes()? {
-            writeln!(&mut attributes_file, "COREOS_{}={}", k, v)
+        for (k,v) in naming.apply(attributes) {
+            writeln!(&mut attributes_file, "{}={}", k, v)
                 .chain_err(|| format!("failed to write attributes to file {:?}", attributes_file))?;
         }
         Ok(())
     }
 
-    fn write_ssh_keys(&self, ssh_keys_user: String) -> Result<()> {
-        let ssh_keys = self.ssh_keys()?;
+    /// write_environment_d writes the attributes (and the given extra ones)
+    /// as a systemd environment.d file, so that user services get them
+    /// without an `EnvironmentFile=` of their own
+    fn write_environment_d(&self, environment_d_dir: String, extra: &HashMap<String, String>,
+                           naming: &AttributeNaming, required: Option<&[String]>) -> Result<()> {
+        let attributes = self.attributes_with(extra, required)?;
+
+        let path = Path::new(&environment_d_dir).join(ENVIRONMENT_D_FILE);
+        let mut file = create_file(&path.to_string_lossy())?;
+        for (k, v) in naming.apply(attributes) {
+            writeln!(&mut file, "{}={}", k, attributes::quote_value(&v))
+                .chain_err(|| format!("failed to write attributes to file {:?}", path))?;
+        }
+        Ok(())
+    }
+
+    /// render_templates renders each template file with the attributes (and
+    /// the given extra ones), under the names they are written as, to its
+    /// output file
+    fn render_templates(&self, templates: &[(String, String)], extra: &HashMap<String, String>,
+                        naming: &AttributeNaming, required: Option<&[String]>) -> Result<()> {
+        let attributes = self.attributes_with(extra, required)?;
+        let attributes: HashMap<String, String> = naming.apply(attributes).into_iter().collect();
+
+        for &(ref template_path, ref out_path) in templates {
+            let mut contents = String::new();
+            File::open(template_path)
+                .and_then(|mut f| f.read_to_string(&mut contents))
+                .chain_err(|| format!("failed to read template {:?}", template_path))?;
+            let rendered = template::render(&contents, &attributes)
+                .chain_err(|| format!("failed to render template {:?}", template_path))?;
+            let mut file = create_file(out_path)?;
+            file.write_all(rendered.as_bytes())
+                .chain_err(|| format!("failed to write rendered template to file {:?}", out_path))?;
+        }
+        Ok(())
+    }
 
-        if !ssh_keys.is_empty() {
-            // find the ssh keys user and open their ssh authorized keys directory
-            let user = users::get_user_by_name(&ssh_keys_user)
-                .ok_or_else(|| format!("could not find user with username {:?}", ssh_keys_user))?;
-            let mut authorized_keys_dir = AuthorizedKeys::open(user, true, None)
-                .chain_err(|| format!("failed to open authorized keys directory for user '{}'", ssh_keys_user))?;
+    /// write_node_labels writes the labels, converted to kubernetes label
+    /// syntax, with a `key=value` line per label for kubelet's
+    /// `--node-labels`
+    fn write_node_labels(&self, node_labels_file_path: String) -> Result<()> {
+        let mut file = create_file(&node_labels_file_path)?;
+        for (k, v) in labels::node_labels(&self.labels()?) {
+            writeln!(&mut file, "{}={}", k, v)
+                .chain_err(|| format!("failed to write node labels to file {:?}", node_labels_file_path))?;
+        }
+        Ok(())
+    }
 
-            // add the ssh keys to the directory
-            authorized_keys_dir.add_keys("coreos-metadata", ssh_keys, true, true)?;
+    /// write_ssh_keys writes the keys (and certificates) meant for the
+    /// platform accounts in the users map to those local users, and all the
+    /// others to the ssh keys user. the users are written in parallel.
+    #[cfg(feature = "ssh-keys")]
+    fn write_ssh_keys(&self, options: &SshKeysOptions) -> Result<()> {
+        let mut keys_by_user = local_users_for_keys(self.ssh_keys_by_user()?, self.ssh_certificates_by_user()?,
+                                                    options.user.as_ref(), &options.users_map);
+        let root = options.root.as_ref().map(PathBuf::as_path);
 
-            // write the changes and sync the directory
-            authorized_keys_dir.write()
-                .chain_err(|| "failed to update authorized keys directory")?;
-            authorized_keys_dir.sync()
-                .chain_err(|| "failed to update authorized keys")?;
+        // useradd locks the user database, so users are created one at a time
+        if let Some(ref new_user) = options.create_user {
+            for user in keys_by_user.keys() {
+                useradd::ensure_user(user, new_user, root)?;
+            }
+        }
+        // users are only created for keys, not to remove them from
+        if options.sync {
+            add_synced_users(&mut keys_by_user, options.user.as_ref(), &options.users_map);
         }
 
+        let handles: Vec<_> = keys_by_user.into_iter()
+            .map(|(user, (keys, certificates))| {
+                let root = options.root.clone();
+                let lock_timeout = options.lock_timeout_or_default();
+                let sync = options.sync;
+                thread::spawn(move || {
+                    let res = write_authorized_keys(&user, keys, certificates, root.as_ref().map(PathBuf::as_path), lock_timeout, sync);
+                    (user, res)
+                })
+            })
+            .collect();
+        let mut failed = Vec::new();
+        for handle in handles {
+            let (user, res) = handle.join()
+                .map_err(|_| Error::from("thread writing ssh keys panicked"))?;
+            match res {
+                Ok(record) => audit::merge(record),
+                Err(e) => {
+                    warn!("failed to write ssh keys for user '{}': {}", user, e);
+                    failed.push(user);
+                }
+            }
+        }
+        if !failed.is_empty() {
+            return Err(format!("failed to write ssh keys for {}", failed.join(", ")).into());
+        }
         Ok(())
     }
 
+    /// write_metadata_firewall writes firewall rules that keep the
+    /// metadata endpoints to root and the given groups
+    fn write_metadata_firewall(&self, metadata_firewall_file_path: String, options: &FirewallOptions) -> Result<()> {
+        let endpoints = self.metadata_endpoints();
+        if endpoints.is_empty() {
+            // no file at all would leave the metadata service open without
+            // anything saying so
+            bail!("the provider doesn't reach its metadata service by address, so there's nothing to write firewall rules for");
+        }
+        let rules = firewall::rules(&endpoints, options)?;
+        let mut file = create_file(&metadata_firewall_file_path)?;
+        file.write_all(rules.as_bytes())
+            .chain_err(|| format!("failed to write metadata firewall rules to file {:?}", metadata_firewall_file_path))
+    }
+
     fn write_hostname(&self, hostname_file_path: String) -> Result<()> {
         match self.hostname()? {
             Some(ref hostname) => {
@@ -105,24 +881,220 @@ pub trait MetadataProvider {
         }
     }
 
+    /// set_hostnamed sets the transient hostname, and the pretty hostname if
+    /// the provider has one, through systemd-hostnamed. under a root that's
+    /// being staged, they're written to the files hostnamed reads at boot.
+    fn set_hostnamed(&self, root: Option<&Path>) -> Result<()> {
+        if let Some(ref hostname) = self.hostname()? {
+            match root {
+                Some(root) => hostnamed::set_static_in(root, hostname),
+                None => hostnamed::set_transient(hostname),
+            }.chain_err(|| format!("failed to set hostname {:?}", hostname))?;
+        }
+        if let Some(ref pretty) = self.pretty_hostname()? {
+            match root {
+                Some(root) => hostnamed::set_pretty_in(root, pretty),
+                None => hostnamed::set_pretty(pretty),
+            }.chain_err(|| format!("failed to set pretty hostname {:?}", pretty))?;
+        }
+        Ok(())
+    }
+
+    /// write_resolved_dropin writes a systemd-resolved drop-in with the
+    /// provider's search domains, if it has any
+    fn write_resolved_dropin(&self, resolved_dropin_dir: String) -> Result<()> {
+        let domains = self.search_domains()?;
+        if domains.is_empty() {
+            info!("the provider has no search domains, not writing a resolved drop-in");
+            return Ok(());
+        }
+        let path = Path::new(&resolved_dropin_dir).join(RESOLVED_DROPIN_FILE);
+        let mut file = create_file(&path.to_string_lossy())?;
+        write!(&mut file, "[Resolve]\nDomains={}\n", domains.join(" "))
+            .chain_err(|| format!("failed to write resolved drop-in {:?}", path))
+    }
+
+    /// custom data often holds bootstrap secrets, so it's only readable by
+    /// the owner
+    fn write_custom_data(&self, custom_data_file_path: String) -> Result<()> {
+        match self.custom_data()? {
+            Some(ref data) => {
+                let path = Path::new(&custom_data_file_path);
+                let folder = path.parent()
+                    .ok_or_else(|| format!("could not get parent directory of {:?}", path))?;
+                fs::create_dir_all(&folder)
+                    .chain_err(|| format!("failed to create directory {:?}", folder))
+                    .chain_err(|| ErrorKind::Write(WriteError::Failed(path.display().to_string())))?;
+                write_bytes_with_mode(path, 0o600, data)
+                    .chain_err(|| format!("failed to write custom data to file {:?}", path))
+            }
+            None => Ok(())
+        }
+    }
+
+    fn write_ssh_host_keys(&self, ssh_host_keys_dir: String) -> Result<()> {
+        let host_keys = self.ssh_host_keys()?;
+        if host_keys.is_empty() {
+            return Ok(());
+        }
+
+        let dir_path = Path::new(&ssh_host_keys_dir);
+        fs::create_dir_all(&dir_path)
+            .chain_err(|| format!("failed to create directory {:?}", dir_path))?;
+
+        for key in host_keys {
+            // the key type ends up in a file name, so be picky about it
+            if key.key_type.is_empty() || !key.key_type.chars().all(|c| c.is_ascii_alphanumeric()) {
+                return Err(format!("invalid ssh host key type {:?}", key.key_type).into());
+            }
+            let base = dir_path.join(format!("ssh_host_{}_key", key.key_type));
+            write_file_with_mode(&base, 0o600, &key.private_key)?;
+            if let Some(ref public_key) = key.public_key {
+                write_file_with_mode(&base.with_file_name(format!("ssh_host_{}_key.pub", key.key_type)), 0o644, public_key)?;
+            }
+            if let Some(ref certificate) = key.certificate {
+                write_file_with_mode(&base.with_file_name(format!("ssh_host_{}_key-cert.pub", key.key_type)), 0o644, certificate)?;
+            }
+            info!("wrote ssh host key {:?}", base);
+        }
+
+        Ok(())
+    }
+
+    /// set_password sets the provider's password, if it has one, for the
+    /// given local user, and acknowledges it
+    fn set_password(&self, user: String, root: Option<&Path>) -> Result<()> {
+        match self.password()? {
+            Some(password) => {
+                useradd::set_password(&user, &password, root)?;
+                info!("set password for user {}", user);
+                self.acknowledge_password()
+            }
+            None => {
+                info!("no password to set");
+                Ok(())
+            }
+        }
+    }
+
+    fn publish_ssh_host_keys_from(&self, ssh_host_keys_dir: String) -> Result<()> {
+        let keys = read_ssh_host_public_keys(Path::new(&ssh_host_keys_dir))?;
+        if keys.is_empty() {
+            warn!("no ssh host keys found in {:?}", ssh_host_keys_dir);
+            return Ok(());
+        }
+        for key in &keys {
+            info!("publishing ssh host key {} SHA256:{}", key.keytype(), key.fingerprint());
+        }
+        self.publish_ssh_host_keys(&keys)
+    }
+
     fn write_network_units(&self, network_units_dir: String) -> Result<()> {
+        self.write_network_units_with(network_units_dir, &network::NetworkUnitOptions::default())
+    }
+
+    fn write_network_units_with(&self, network_units_dir: String, options: &network::NetworkUnitOptions) -> Result<()> {
+        let mut interfaces = self.networks()?;
+        let devices = self.network_devices()?;
+        if options.match_virtual_functions {
+            network::match_virtual_functions(&mut interfaces, &network::virtual_functions()?);
+        }
+        let problems = network::validate_units(&interfaces, &devices, options.prefix);
+        for problem in &problems {
+            warn!("network units: {}", problem);
+        }
+        if options.validate && !problems.is_empty() {
+            return Err(format!("refusing to write invalid network units: {}", problems.join("; ")).into());
+        }
+
+        // a unit for an interface that isn't there does nothing but mislead
+        let interfaces = match options.link_timeout {
+            Some(timeout) => {
+                let links = network::wait_for_links(&interfaces, timeout)?;
+                interfaces.into_iter().zip(links).filter(|&(ref interface, link)| {
+                    let unit_name = interface.unit_name_with_prefix(options.prefix);
+                    match link {
+                        network::Link::Up => true,
+                        network::Link::NoCarrier => {
+                            warn!("network units: the interface matched by {} has no carrier", unit_name);
+                            true
+                        }
+                        network::Link::Absent => {
+                            warn!("network units: no interface matches {}, not writing it", unit_name);
+                            false
+                        }
+                    }
+                }).map(|(interface, _)| interface).collect()
+            }
+            None => interfaces,
+        };
+
         let dir_path = Path::new(&network_units_dir);
         fs::create_dir_all(&dir_path)
             .chain_err(|| format!("failed to create directory {:?}", dir_path))?;
-        for interface in &self.networks()? {
-            let file_path = dir_path.join(interface.unit_name());
+        for interface in &interfaces {
+            let unit_name = options.scope.unit_name(&interface.unit_name_with_prefix(options.prefix));
+            if options.dropins {
+                if let Some(existing) = find_matching_unit(interface, &unit_name, options.root.as_ref().map(PathBuf::as_path))? {
+                    let dropin_dir = dir_path.join(format!("{}.d", existing));
+                    fs::create_dir_all(&dropin_dir)
+                        .chain_err(|| format!("failed to create directory {:?}", dropin_dir))?;
+                    let file_path = dropin_dir.join(format!("{:02}-coreos-metadata.conf", options.prefix.unwrap_or(10)));
+                    let mut dropin_file = File::create(&file_path)
+                        .chain_err(|| format!("failed to create file {:?}", file_path))?;
+                    audit::wrote(&file_path);
+                    write!(&mut dropin_file, "{}", interface.dropin_config())
+                        .chain_err(|| format!("failed to write network interface drop-in {:?}", file_path))?;
+                    continue;
+                }
+            }
+            let file_path = dir_path.join(unit_name);
             let mut unit_file = File::create(&file_path)
                 .chain_err(|| format!("failed to create file {:?}", file_path))?;
+            audit::wrote(&file_path);
             write!(&mut unit_file, "{}", interface.config())
                 .chain_err(|| format!("failed to write network interface unit file {:?}", unit_file))?;
         }
-        for device in &self.network_devices()? {
-            let file_path = dir_path.join(device.unit_name());
+        for device in &devices {
+            let file_path = dir_path.join(options.scope.unit_name(&device.unit_name_with_prefix(options.prefix)));
             let mut unit_file = File::create(&file_path)
                 .chain_err(|| format!("failed to create file {:?}", file_path))?;
+            audit::wrote(&file_path);
             write!(&mut unit_file, "{}", device.config())
                 .chain_err(|| format!("failed to write network device unit file {:?}", unit_file))?;
         }
         Ok(())
     }
+
+    /// write_ifcfg writes the network configuration as sysconfig
+    /// network-scripts, for systems that don't use networkd
+    fn write_ifcfg(&self, network_scripts_dir: String) -> Result<()> {
+        let dir_path = Path::new(&network_scripts_dir);
+        fs::create_dir_all(&dir_path)
+            .chain_err(|| format!("failed to create directory {:?}", dir_path))?;
+        for (name, contents) in network::ifcfg_files(&self.networks()?, &self.network_devices()?) {
+            let file_path = dir_path.join(name);
+            let mut file = File::create(&file_path)
+                .chain_err(|| format!("failed to create file {:?}", file_path))?;
+            audit::wrote(&file_path);
+            write!(&mut file, "{}", contents)
+                .chain_err(|| format!("failed to write network script {:?}", file_path))?;
+        }
+        Ok(())
+    }
+
+    /// write_network_json writes the same network configuration that the
+    /// networkd units are generated from, for tools that want to do their own
+    /// configuration
+    fn write_network_json(&self, network_json_path: String) -> Result<()> {
+        let state = NetworkState {
+            interfaces: self.networks()?.iter().map(InterfaceSnapshot::from).collect(),
+            devices: self.network_devices()?.iter().map(DeviceSnapshot::from).collect(),
+        };
+        let mut file = create_file(&network_json_path)?;
+        serde_json::to_writer_pretty(&mut file, &state)
+            .chain_err(|| format!("failed to write network state to file {:?}", network_json_path))?;
+        writeln!(&mut file)
+            .chain_err(|| format!("failed to write network state to file {:?}", network_json_path))
+    }
 }
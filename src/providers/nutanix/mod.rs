@@ -0,0 +1,215 @@
+// Copyright 2017 CoreOS, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! nutanix ahv metadata fetcher
+//!
+//! ahv guest customization attaches a config drive in the openstack layout,
+//! but its meta_data.json has the VM's uuid, and (for VMs managed by prism
+//! central) its project and categories, rather than openstack's fields.
+//! network_data.json is only there for VMs with static addresses.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+use openssh_keys::PublicKey;
+
+use errors::*;
+use network;
+use providers::{MetadataProvider, ProviderConfig};
+use providers::raw::RawMetadata;
+use util::attributes::AttributeNamer;
+use util::configdrive::{self, MountedDrive};
+use util::network_data::NetworkData;
+
+const CONFIG_DRIVE_LABELS: [&str; 2] = ["config-2", "CONFIG-2"];
+
+const PROVIDER: &str = "nutanix";
+const META_DATA_DOCUMENT: &str = "openstack/latest/meta_data.json";
+const NETWORK_DATA_DOCUMENT: &str = "openstack/latest/network_data.json";
+const USER_DATA_DOCUMENT: &str = "openstack/latest/user_data";
+
+#[derive(Clone, Debug, Deserialize)]
+struct Project {
+    name: Option<String>,
+    uuid: Option<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct MetaData {
+    uuid: Option<String>,
+    hostname: Option<String>,
+    project: Option<Project>,
+    /// category name to value, e.g. `AppType` to `Kubernetes`
+    #[serde(default)]
+    categories: BTreeMap<String, String>,
+    #[serde(default)]
+    public_keys: HashMap<String, String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct NutanixProvider {
+    meta_data: MetaData,
+    network_data: NetworkData,
+    user_data: Option<Vec<u8>>,
+}
+
+impl NutanixProvider {
+    pub fn new(config: &ProviderConfig) -> Result<NutanixProvider> {
+        NutanixProvider::parse(&NutanixProvider::fetch_raw(config)?)
+    }
+
+    /// fetch_raw reads the documents from the config drive
+    pub fn fetch_raw(config: &ProviderConfig) -> Result<RawMetadata> {
+        let device = configdrive::find_device(config.config_drive.as_ref().map(String::as_str), &CONFIG_DRIVE_LABELS)?;
+        let drive = MountedDrive::mount(&device)?;
+        NutanixProvider::read_raw(drive.path())
+    }
+
+    /// read_raw reads the documents from the root of a mounted config drive
+    pub fn read_raw(root: &Path) -> Result<RawMetadata> {
+        configdrive::verify(root)?;
+        let mut raw = RawMetadata::new(PROVIDER);
+        for document in &[META_DATA_DOCUMENT, NETWORK_DATA_DOCUMENT] {
+            let path = root.join(document);
+            if path.exists() {
+                raw.insert(document, configdrive::read_text(&path)?);
+            }
+        }
+        // user data can be binary
+        let path = root.join(USER_DATA_DOCUMENT);
+        if path.exists() {
+            raw.insert(USER_DATA_DOCUMENT, configdrive::read_bytes(&path)?);
+        }
+        Ok(raw)
+    }
+
+    /// read_from reads the metadata from the root of a mounted config drive
+    #[cfg(test)]
+    pub fn read_from(root: &Path) -> Result<NutanixProvider> {
+        NutanixProvider::parse(&NutanixProvider::read_raw(root)?)
+    }
+
+    /// parse parses the documents read from the config drive
+    pub fn parse(raw: &RawMetadata) -> Result<NutanixProvider> {
+        raw.check(PROVIDER)?;
+        let meta_data = raw.json(META_DATA_DOCUMENT)?;
+        let network_data = match raw.get(NETWORK_DATA_DOCUMENT) {
+            Some(_) => raw.json(NETWORK_DATA_DOCUMENT)?,
+            None => NetworkData::default(),
+        };
+        let user_data = raw.get(USER_DATA_DOCUMENT).map(|d| d.to_vec());
+        Ok(NutanixProvider { meta_data, network_data, user_data })
+    }
+
+    fn parse_attrs(&self) -> Vec<(String, String)> {
+        let mut attrs = Vec::new();
+        {
+            let mut add = |k: &str, v: &Option<String>| {
+                if let Some(ref v) = *v {
+                    attrs.push((format!("NUTANIX_{}", k), v.clone()));
+                }
+            };
+            add("HOSTNAME", &self.meta_data.hostname);
+            add("VM_UUID", &self.meta_data.uuid);
+            if let Some(ref project) = self.meta_data.project {
+                add("PROJECT_NAME", &project.name);
+                add("PROJECT_UUID", &project.uuid);
+            }
+        }
+
+        // categories are in a BTreeMap, so colliding names are numbered the
+        // same every time
+        let mut namer = AttributeNamer::new("NUTANIX_CATEGORY_");
+        for (k, v) in &self.meta_data.categories {
+            attrs.push((namer.name_for(k), v.clone()));
+        }
+        attrs
+    }
+}
+
+impl MetadataProvider for NutanixProvider {
+    fn attributes(&self) -> Result<HashMap<String, String>> {
+        Ok(self.parse_attrs().into_iter().collect())
+    }
+
+    fn hostname(&self) -> Result<Option<String>> {
+        Ok(self.meta_data.hostname.clone())
+    }
+
+    fn instance_id(&self) -> Result<Option<String>> {
+        Ok(self.meta_data.uuid.clone())
+    }
+
+    fn ssh_keys(&self) -> Result<Vec<PublicKey>> {
+        // sort by key name so the order is stable
+        let mut keys: Vec<(&String, &String)> = self.meta_data.public_keys.iter().collect();
+        keys.sort();
+        keys.into_iter()
+            .map(|(_, key)| Ok(PublicKey::parse(key)?))
+            .collect()
+    }
+
+    fn networks(&self) -> Result<Vec<network::Interface>> {
+        // interfaces without a static address are left to dhcp
+        self.network_data.interfaces(true)
+    }
+
+    fn network_devices(&self) -> Result<Vec<network::Device>> {
+        Ok(vec![])
+    }
+
+    fn custom_data(&self) -> Result<Option<Vec<u8>>> {
+        Ok(self.user_data.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use tempdir::TempDir;
+
+    #[test]
+    fn read_from_test() {
+        let dir = TempDir::new("coreos-metadata-test").unwrap();
+        let latest = dir.path().join("openstack").join("latest");
+        fs::create_dir_all(&latest).unwrap();
+        File::create(latest.join("meta_data.json")).unwrap()
+            .write_all(br#"{
+                "uuid": "9a4b3f1e-5c2d-4e8f-a1b2-c3d4e5f6a7b8",
+                "hostname": "node1",
+                "project": {"name": "default", "uuid": "1f2e"},
+                "categories": {"AppType": "Kubernetes", "app-type": "other"}
+            }"#).unwrap();
+        File::create(latest.join("network_data.json")).unwrap()
+            .write_all(br#"{
+                "links": [
+                    {"id": "eth0", "ethernet_mac_address": "50:6b:8d:00:00:01"},
+                    {"id": "eth1", "ethernet_mac_address": "50:6b:8d:00:00:02"}
+                ],
+                "networks": [
+                    {"link": "eth0", "type": "ipv4_dhcp"},
+                    {"link": "eth1", "type": "ipv4", "ip_address": "10.10.0.5", "netmask": "255.255.255.0"}
+                ]
+            }"#).unwrap();
+
+        let provider = NutanixProvider::read_from(dir.path()).unwrap();
+        let attrs = provider.attributes().unwrap();
+        assert_eq!(attrs["NUTANIX_PROJECT_NAME"], "default");
+        assert_eq!(attrs["NUTANIX_CATEGORY_APPTYPE"], "Kubernetes");
+        assert_eq!(attrs["NUTANIX_CATEGORY_APP_TYPE"], "other");
+        assert_eq!(provider.networks().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,122 @@
+//! openstack config drive reader
+//!
+//! the config drive is a small iso9660 (or vfat) filesystem labelled
+//! `config-2` that nova attaches when the metadata service isn't (or can't
+//! be) used. it carries the same information as the metadata service, as json.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use serde_json;
+
+use errors::*;
+use providers::openstack::version;
+use util::configdrive::{self, MountedDrive};
+use util::json;
+
+const CONFIG_DRIVE_LABELS: [&str; 2] = ["config-2", "CONFIG-2"];
+
+#[derive(Debug, Deserialize)]
+struct MetaData {
+    hostname: Option<String>,
+    uuid: Option<String>,
+    #[serde(default)]
+    public_keys: HashMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Ec2MetaData {
+    #[serde(rename = "local-ipv4")]
+    local_ipv4: Option<String>,
+    #[serde(rename = "public-ipv4")]
+    public_ipv4: Option<String>,
+}
+
+/// ConfigDriveMetadata is everything the provider needs from the config
+/// drive, read up front so the drive doesn't have to stay mounted
+#[derive(Clone, Debug, Default)]
+pub struct ConfigDriveMetadata {
+    pub hostname: Option<String>,
+    pub instance_id: Option<String>,
+    pub local_ipv4: Option<String>,
+    pub public_ipv4: Option<String>,
+    pub public_keys: Vec<String>,
+    /// the whole of meta_data.json, of the newest version on the drive that
+    /// is known
+    pub meta_data: serde_json::Value,
+}
+
+impl ConfigDriveMetadata {
+    /// get returns the value for the given ec2-style metadata key
+    pub fn get(&self, key: &str) -> Option<String> {
+        match key {
+            "hostname" => self.hostname.clone(),
+            "instance-id" => self.instance_id.clone(),
+            "local-ipv4" => self.local_ipv4.clone(),
+            "public-ipv4" => self.public_ipv4.clone(),
+            _ => None,
+        }
+    }
+}
+
+/// read mounts the config drive, reads the metadata from it, and unmounts it
+/// again
+pub fn read() -> Result<ConfigDriveMetadata> {
+    let device = configdrive::find_device(None, &CONFIG_DRIVE_LABELS)?;
+    let drive = MountedDrive::mount(&device)?;
+    read_from(drive.path())
+}
+
+fn read_json<T>(path: &Path) -> Result<T>
+    where T: for<'de> ::serde::Deserialize<'de>
+{
+    let contents = configdrive::read_text(path)?;
+    json::from_slice(contents.as_bytes())
+        .map_err(|e| ErrorKind::Parse(ParseError::ConfigDriveCorrupt(path.display().to_string(), json::describe(&e))).into())
+}
+
+/// version picks the openstack metadata version to read from the
+/// directories on the drive
+fn version(root: &Path) -> Result<&'static str> {
+    let dir = root.join("openstack");
+    let mut listing = Vec::new();
+    for entry in fs::read_dir(&dir).chain_err(|| format!("failed to list {:?}", dir))? {
+        let entry = entry.chain_err(|| format!("failed to list {:?}", dir))?;
+        listing.push(entry.file_name().to_string_lossy().into_owned());
+    }
+    let version = version::pick(listing.iter().map(String::as_str));
+    info!("using openstack metadata version {} from the config drive", version);
+    Ok(version)
+}
+
+fn read_from(root: &Path) -> Result<ConfigDriveMetadata> {
+    configdrive::verify(root)?;
+
+    let path = root.join("openstack").join(version(root)?).join("meta_data.json");
+    let raw_meta_data: serde_json::Value = read_json(&path)?;
+    let meta_data: MetaData = serde_json::from_value(raw_meta_data.clone())
+        .map_err(|e| Error::from(ErrorKind::Parse(ParseError::ConfigDriveCorrupt(path.display().to_string(), e.to_string()))))?;
+
+    // the addresses are only in the ec2-compatible copy, which older
+    // deployments don't write
+    let path = root.join("ec2").join("latest").join("meta-data.json");
+    let ec2: Option<Ec2MetaData> = if path.exists() {
+        Some(read_json(&path)?)
+    } else {
+        None
+    };
+
+    // sort by key name so the order is the same as from the metadata service
+    let mut public_keys: Vec<(String, String)> = meta_data.public_keys.into_iter().collect();
+    public_keys.sort();
+
+    Ok(ConfigDriveMetadata {
+        hostname: meta_data.hostname,
+        instance_id: meta_data.uuid,
+        local_ipv4: ec2.as_ref().and_then(|e| e.local_ipv4.clone()),
+        public_ipv4: ec2.as_ref().and_then(|e| e.public_ipv4.clone()),
+        public_keys: public_keys.into_iter().map(|(_, key)| key).collect(),
+        meta_data: raw_meta_data,
+    })
+}
@@ -0,0 +1,220 @@
+//! metadata over the ipv6 link-local address
+//!
+//! link-local addresses are only meaningful together with the interface they
+//! are reached through, and urls can't carry that (there's no zone id support
+//! in the url parser), so these requests are made over a plain tcp stream
+//! with a scoped socket address instead of through the retry client. they
+//! still go through its rate limit, 404 memory, and fetch observer, and
+//! their responses are capped the same way.
+
+use std::cell::Cell;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6, TcpStream};
+use std::time::{Duration, Instant};
+
+use pnet;
+
+use errors::*;
+use retry;
+use retry::{limit, missing, observer, status_is_retryable};
+
+const METADATA_PATH: &str = "/latest/meta-data";
+const TIMEOUT_SECS: u64 = 5;
+
+/// fe80::a9fe:a9fe, the link-local counterpart of 169.254.169.254
+pub(crate) fn metadata_address() -> Ipv6Addr {
+    Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0xa9fe, 0xa9fe)
+}
+
+#[derive(Clone, Debug)]
+pub struct LinkLocalEndpoint {
+    interface: String,
+    addr: SocketAddrV6,
+}
+
+impl LinkLocalEndpoint {
+    /// candidates returns the metadata address scoped to each interface that
+    /// has an ipv6 link-local address of its own
+    pub fn candidates() -> Vec<LinkLocalEndpoint> {
+        pnet::datalink::interfaces().into_iter()
+            .filter(|i| !i.is_loopback())
+            .filter(|i| i.ips.iter().any(|ip| match ip.ip() {
+                IpAddr::V6(a) => (a.segments()[0] & 0xffc0) == 0xfe80,
+                IpAddr::V4(_) => false,
+            }))
+            .map(|i| LinkLocalEndpoint {
+                addr: SocketAddrV6::new(metadata_address(), 80, 0, i.index),
+                interface: i.name,
+            })
+            .collect()
+    }
+
+    pub fn interface(&self) -> &str {
+        &self.interface
+    }
+
+    /// get fetches the given metadata key, returning None if it doesn't exist
+    pub fn get(&self, retry: retry::Retry, key: &str) -> Result<Option<String>> {
+        self.get_path(retry, &format!("{}/{}", METADATA_PATH, key))
+    }
+
+    /// url is the path's url, as it's logged and remembered. it can't be
+    /// parsed.
+    fn url(&self, path: &str) -> String {
+        format!("http://[{}%{}]{}", metadata_address(), self.interface, path)
+    }
+
+    /// get_path fetches the given path from the root of the metadata service
+    pub fn get_path(&self, retry: retry::Retry, path: &str) -> Result<Option<String>> {
+        let url = self.url(path);
+        if missing::is_missing(&url) {
+            info!("Fetching {}: not found before, not fetching again", url);
+            return Ok(None);
+        }
+        let host = format!("[{}]", metadata_address());
+        let attempts = Cell::new(0);
+        // like the retry client, only the first refusal is logged
+        let refused = Cell::new(false);
+        let res = retry.retry(|attempt| {
+            limit::wait_for_host(&host)?;
+            if refused.get() {
+                debug!("Fetching {}: Attempt #{}", url, attempt + 1);
+            } else {
+                info!("Fetching {}: Attempt #{}", url, attempt + 1);
+            }
+            attempts.set(attempt + 1);
+            observer::notify(|o| o.attempt_started(&url, attempt + 1));
+            let res = self.request(path);
+            if let Err(ref e) = res {
+                observer::notify(|o| o.attempt_failed(&url, attempt + 1, e));
+            }
+            refused.set(match res {
+                Err(ref e) => match *e.kind() {
+                    ErrorKind::Fetch(FetchError::ConnectionRefused(_)) => true,
+                    _ => false,
+                },
+                Ok(_) => false,
+            });
+            res
+        });
+        if let Ok(ref body) = res {
+            let bytes = body.as_ref().map_or(0, |b| b.len() as u64);
+            observer::notify(|o| o.succeeded(&url, attempts.get(), bytes));
+            if body.is_none() {
+                missing::remember(&url);
+            }
+        }
+        res
+    }
+
+    fn request(&self, path: &str) -> Result<Option<String>> {
+        let timeout = Duration::from_secs(TIMEOUT_SECS);
+        let mut stream = match TcpStream::connect_timeout(&SocketAddr::V6(self.addr), timeout) {
+            Ok(stream) => stream,
+            // the retry waits for the metadata service to start listening
+            Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                return Err(ErrorKind::Fetch(FetchError::ConnectionRefused(format!("http://[{}%{}]", metadata_address(), self.interface))).into());
+            }
+            Err(e) => {
+                return Err(Error::with_chain(e, format!("failed to connect to {} on {}", metadata_address(), self.interface)));
+            }
+        };
+        stream.set_read_timeout(Some(timeout))
+            .chain_err(|| "failed to set read timeout")?;
+        stream.set_write_timeout(Some(timeout))
+            .chain_err(|| "failed to set write timeout")?;
+
+        write!(stream, "GET {} HTTP/1.0\r\nHost: [{}]\r\nConnection: close\r\n\r\n", path, metadata_address())
+            .chain_err(|| "failed to send request")?;
+        let response = read_response(stream, &self.url(path), Instant::now() + Duration::from_secs(retry::DEFAULT_TIMEOUT_SECS))?;
+
+        parse_response(&response)
+    }
+}
+
+/// read_response reads the response, headers and all, as long as it's no
+/// larger than the retry client allows a body to be and arrives before the
+/// deadline
+fn read_response<R: Read>(r: R, url: &str, deadline: Instant) -> Result<String> {
+    let mut response = Vec::new();
+    let mut r = r.take(retry::DEFAULT_MAX_RESPONSE_SIZE + 1);
+    let mut buf = [0; 8192];
+    loop {
+        if Instant::now() > deadline {
+            return Err(ErrorKind::Fetch(FetchError::TimedOut(url.to_owned())).into());
+        }
+        let n = r.read(&mut buf)
+            .chain_err(|| "failed to read response")?;
+        if n == 0 {
+            break;
+        }
+        response.extend_from_slice(&buf[..n]);
+    }
+    if response.len() as u64 > retry::DEFAULT_MAX_RESPONSE_SIZE {
+        return Err(ErrorKind::Parse(ParseError::TooLarge(url.to_owned(), retry::DEFAULT_MAX_RESPONSE_SIZE)).into());
+    }
+    String::from_utf8(response)
+        .chain_err(|| ErrorKind::Parse(ParseError::Malformed(url.to_owned())))
+}
+
+fn parse_response(response: &str) -> Result<Option<String>> {
+    let (head, body) = match response.find("\r\n\r\n") {
+        Some(i) => (&response[..i], &response[i + 4..]),
+        None => return Err("malformed http response".into()),
+    };
+    let status = head.lines().next()
+        .and_then(|l| l.split_whitespace().nth(1))
+        .ok_or("malformed http status line")?;
+    match status {
+        "200" => {
+            info!("Fetch successful");
+            Ok(Some(body.to_owned()))
+        }
+        "404" => {
+            info!("Fetch failed with 404: resource not found");
+            Ok(None)
+        }
+        s => match s.parse::<u16>() {
+            Ok(code) if status_is_retryable(code) => Err(ErrorKind::Fetch(FetchError::RetryableStatus(code, None)).into()),
+            Ok(code) => Err(ErrorKind::Fetch(FetchError::FatalStatus(code)).into()),
+            Err(_) => Err(format!("malformed http status {:?}", s).into()),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_response_test() {
+        let ok = "HTTP/1.0 200 OK\r\nContent-Type: text/plain\r\n\r\nhost.example";
+        assert_eq!(parse_response(ok).unwrap(), Some(String::from("host.example")));
+
+        let missing = "HTTP/1.0 404 Not Found\r\n\r\n";
+        assert_eq!(parse_response(missing).unwrap(), None);
+
+        parse_response("HTTP/1.0 500 Internal Server Error\r\n\r\n").unwrap_err();
+        parse_response("garbage").unwrap_err();
+    }
+
+    #[test]
+    fn read_response_test() {
+        let url = "http://[fe80::a9fe:a9fe%eth0]/latest/meta-data/hostname";
+        let deadline = Instant::now() + Duration::from_secs(60);
+        let ok = "HTTP/1.0 200 OK\r\n\r\nhost.example";
+        assert_eq!(read_response(ok.as_bytes(), url, deadline).unwrap(), ok);
+
+        let large = vec![b'a'; retry::DEFAULT_MAX_RESPONSE_SIZE as usize + 1];
+        match *read_response(&large[..], url, deadline).unwrap_err().kind() {
+            ErrorKind::Parse(ParseError::TooLarge(..)) => {}
+            ref e => panic!("unexpected error {:?}", e),
+        }
+
+        let past = Instant::now() - Duration::from_secs(1);
+        match *read_response(ok.as_bytes(), url, past).unwrap_err().kind() {
+            ErrorKind::Fetch(FetchError::TimedOut(_)) => {}
+            ref e => panic!("unexpected error {:?}", e),
+        }
+    }
+}
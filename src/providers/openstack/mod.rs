@@ -14,4 +14,8 @@
 
 //! openstack metadata fetcher
 
+mod configdrive;
+mod linklocal;
 pub mod network;
+pub mod variant;
+mod version;
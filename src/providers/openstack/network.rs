@@ -1,46 +1,317 @@
 //! openstack metadata fetcher
 
 use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
 
 use openssh_keys::PublicKey;
-use update_ssh_keys::AuthorizedKeyEntry;
+use serde_json;
 
 use errors::*;
 use network;
-use providers::MetadataProvider;
+use providers::{document_url, endpoints_of, Capabilities, MetadataEndpoint, MetadataProvider, ProviderConfig};
+use providers::openstack::configdrive::{self, ConfigDriveMetadata};
+use providers::openstack::linklocal::{self, LinkLocalEndpoint};
+use providers::openstack::version;
+use providers::raw::RawMetadata;
 use retry;
+use util::json;
 
 const URL: &str = "http://169.254.169.254/latest/meta-data";
+/// the ec2-compatible metadata is under this path, and the openstack
+/// metadata next to it
+const EC2_PATH: &str = "/latest/meta-data";
+const OPENSTACK_PATH: &str = "/openstack";
+
+const PROVIDER: &str = "openstack-metadata";
+
+/// the ec2-compatible documents the provider reads, besides the ssh keys
+const DOCUMENTS: [&str; 4] = ["hostname", "instance-id", "local-ipv4", "public-ipv4"];
+/// openstack's own meta_data.json is saved under this name, whichever
+/// version of it was read
+const META_DATA_DOCUMENT: &str = "openstack/meta_data.json";
+
+/// sources are raced with only a few attempts each, so that an unreachable
+/// one doesn't hold things up
+const PROBE_ATTEMPTS: u32 = 3;
+
+/// the metadata service refuses connections for a while after the instance
+/// boots, until the metadata proxy on its network has started
+fn refused_timeout() -> Duration {
+    Duration::from_secs(retry::DEFAULT_REFUSED_TIMEOUT_SECS)
+}
+
+/// Source is one of the places the openstack metadata can come from
+#[derive(Clone, Debug)]
+enum Source {
+    Http(String),
+    LinkLocal(LinkLocalEndpoint),
+    ConfigDrive(ConfigDriveMetadata),
+    /// documents saved by fetch_raw
+    Saved(RawMetadata),
+}
 
 #[derive(Clone, Debug)]
 pub struct OpenstackProvider {
     client: retry::Client,
+    retry: retry::Retry,
+    source: Source,
 }
 
 impl OpenstackProvider {
-    pub fn new() -> Result<OpenstackProvider> {
-        let client = retry::Client::new()?;
-        Ok(OpenstackProvider { client })
+    pub fn new(config: &ProviderConfig) -> Result<OpenstackProvider> {
+        // an explicit endpoint is used as-is
+        let source = match config.endpoint_override() {
+            Some(endpoint) => Source::Http(endpoint),
+            None => OpenstackProvider::race(true)?,
+        };
+        Ok(OpenstackProvider {
+            client: retry::Client::new()?.refused_timeout(refused_timeout()),
+            retry: retry::Retry::new().refused_timeout(refused_timeout()),
+            source,
+        })
+    }
+
+    /// fetch_raw fetches every document the provider reads, from whichever
+    /// source answers first
+    pub fn fetch_raw(config: &ProviderConfig) -> Result<RawMetadata> {
+        OpenstackProvider::fetch_raw_as(config, PROVIDER)
     }
 
-    fn endpoint_for(key: &str) -> String {
-        format!("{}/{}", URL, key)
+    /// fetch_raw_as is fetch_raw for a provider built on this one
+    pub(crate) fn fetch_raw_as(config: &ProviderConfig, provider: &str) -> Result<RawMetadata> {
+        let mut raw = RawMetadata::new(provider);
+        OpenstackProvider::new(config)?.fetch_documents(&mut raw)?;
+        Ok(raw)
+    }
+
+    /// fetch_documents adds the documents the provider reads to raw. the
+    /// documents are named after the ec2-compatible metadata, whatever the
+    /// source; a config drive doesn't list its keys, so they're saved the
+    /// way the metadata service would list them.
+    pub(crate) fn fetch_documents(&self, raw: &mut RawMetadata) -> Result<()> {
+        for document in DOCUMENTS.iter() {
+            if let Some(contents) = self.get(document)? {
+                raw.insert(document, contents);
+            }
+        }
+        match self.source {
+            Source::ConfigDrive(ref metadata) => {
+                let listing: Vec<String> = (0..metadata.public_keys.len()).map(|i| format!("{}=", i)).collect();
+                raw.insert("public-keys", listing.join("\n"));
+                for (i, key) in metadata.public_keys.iter().enumerate() {
+                    raw.insert(&format!("public-keys/{}/openssh-key", i), key.as_str());
+                }
+            }
+            _ => {
+                if let Some(listing) = self.get("public-keys")? {
+                    for document in key_documents(&listing)? {
+                        if let Some(key) = self.get(&document)? {
+                            raw.insert(&document, key);
+                        }
+                    }
+                    raw.insert("public-keys", listing);
+                }
+            }
+        }
+        // as when it's read, meta_data.json isn't worth failing over
+        match self.meta_data_document() {
+            Ok(Some(meta_data)) => raw.insert(META_DATA_DOCUMENT, meta_data),
+            Ok(None) => debug!("no openstack meta_data.json"),
+            Err(e) => warn!("failed to fetch openstack meta_data.json: {}", e),
+        }
+        Ok(())
+    }
+
+    /// parse makes a provider that reads the documents saved by fetch_raw
+    pub fn parse(raw: &RawMetadata) -> Result<OpenstackProvider> {
+        raw.check(PROVIDER)?;
+        OpenstackProvider::from_documents(raw)
+    }
+
+    /// from_documents is parse for a provider built on this one, whose
+    /// documents are saved under its own name
+    pub(crate) fn from_documents(raw: &RawMetadata) -> Result<OpenstackProvider> {
+        Ok(OpenstackProvider {
+            client: retry::Client::new()?,
+            retry: retry::Retry::new(),
+            source: Source::Saved(raw.clone()),
+        })
+    }
+
+    /// race tries the ipv4 metadata service, the ipv6 link-local metadata
+    /// service on every interface that could reach it, and the config drive
+    /// all at once, and returns the first one that answers. ipv6-only tenant
+    /// networks can't reach the ipv4 address, and the config drive is the
+    /// only option when there's no metadata service at all.
+    fn race(with_config_drive: bool) -> Result<Source> {
+        // the losers give up before their next attempt once there's a winner
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut probes: Vec<Box<Fn() -> Result<Source> + Send>> = Vec::new();
+        let mut candidates = vec![Source::Http(URL.to_owned())];
+        candidates.extend(LinkLocalEndpoint::candidates().into_iter().map(Source::LinkLocal));
+        for source in candidates {
+            let stop = stop.clone();
+            probes.push(Box::new(move || {
+                let probe = OpenstackProvider {
+                    client: retry::Client::new()?.max_attempts(PROBE_ATTEMPTS).refused_timeout(refused_timeout())
+                        .stop(stop.clone()),
+                    retry: retry::Retry::new().max_attempts(PROBE_ATTEMPTS).refused_timeout(refused_timeout())
+                        .stop(stop.clone()),
+                    source: source.clone(),
+                };
+                probe.get("instance-id")?;
+                Ok(source.clone())
+            }));
+        }
+
+        let mut count = probes.len();
+        let (tx, rx) = mpsc::channel();
+        for probe in probes {
+            let tx = tx.clone();
+            thread::spawn(move || {
+                let _ = tx.send(probe());
+            });
+        }
+        // the config drive is mounted while it's read, so it's waited for
+        // even when it loses, rather than left mounted
+        let config_drive = if with_config_drive {
+            count += 1;
+            let tx = tx.clone();
+            Some(thread::spawn(move || {
+                let _ = tx.send(configdrive::read().map(Source::ConfigDrive));
+            }))
+        } else {
+            None
+        };
+
+        let mut errors = Vec::new();
+        let mut winner = None;
+        for res in rx.iter().take(count) {
+            match res {
+                Ok(source) => {
+                    winner = Some(source);
+                    break;
+                }
+                Err(e) => {
+                    debug!("openstack metadata source failed: {}", e);
+                    errors.push(e.to_string());
+                }
+            }
+        }
+        stop.store(true, Ordering::SeqCst);
+        if let Some(config_drive) = config_drive {
+            let _ = config_drive.join();
+        }
+        match winner {
+            Some(source) => {
+                info!("using openstack metadata from {}", source.describe());
+                Ok(source)
+            }
+            None => Err(format!("no openstack metadata source could be reached: {}", errors.join("; ")).into()),
+        }
+    }
+
+    /// fetch_document fetches a path from the root of the metadata service,
+    /// e.g. `/openstack/latest/meta_data.json`, as it's given, from whichever
+    /// of its addresses answers first. the config drive isn't tried.
+    pub fn fetch_document(config: &ProviderConfig, path: &str) -> Result<Option<Vec<u8>>> {
+        let source = match config.endpoint_override() {
+            Some(endpoint) => Source::Http(endpoint),
+            None => OpenstackProvider::race(false)?,
+        };
+        match source {
+            Source::Http(ref base_url) => retry::Client::new()?
+                .refused_timeout(refused_timeout())
+                .get(retry::Raw, document_url(base_url, path)?)
+                .send_bytes(),
+            Source::LinkLocal(ref endpoint) => Ok(endpoint.get_path(retry::Retry::new().refused_timeout(refused_timeout()), path)?
+                .map(String::into_bytes)),
+            // race only picks the config drive when it's asked to
+            _ => Err("the config drive has no documents to fetch".into()),
+        }
+    }
+
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        match self.source {
+            Source::Http(ref base_url) => self.client
+                .get(retry::Raw, format!("{}/{}", base_url, key))
+                .send(),
+            Source::LinkLocal(ref endpoint) => endpoint.get(self.retry.clone(), key),
+            Source::ConfigDrive(ref metadata) => Ok(metadata.get(key)),
+            Source::Saved(ref raw) => Ok(raw.text(key)?.map(String::from)),
+        }
+    }
+
+    /// get_path fetches a path from the root of the metadata service
+    fn get_path(&self, path: &str) -> Result<Option<String>> {
+        match self.source {
+            Source::Http(ref base_url) => {
+                let root = base_url.trim_right_matches(EC2_PATH);
+                self.client.get(retry::Raw, format!("{}{}", root, path)).send()
+            }
+            Source::LinkLocal(ref endpoint) => endpoint.get_path(self.retry.clone(), path),
+            Source::ConfigDrive(_) => Err("the config drive has no documents to fetch".into()),
+            Source::Saved(_) => Err(format!("{} wasn't saved", path).into()),
+        }
+    }
+
+    /// version picks the openstack metadata version to read from the ones
+    /// the metadata service lists. a listing that can't be fetched isn't
+    /// worth failing over, since `latest` is always there.
+    fn version(&self) -> &'static str {
+        let listing = match self.get_path(&format!("{}/", OPENSTACK_PATH)) {
+            Ok(Some(listing)) => listing,
+            Ok(None) => {
+                debug!("the metadata service doesn't list openstack metadata versions");
+                return version::LATEST;
+            }
+            Err(e) => {
+                warn!("failed to list openstack metadata versions, using {}: {}", version::LATEST, e);
+                return version::LATEST;
+            }
+        };
+        let version = version::pick(listing.lines());
+        info!("using openstack metadata version {}", version);
+        version
+    }
+
+    /// meta_data returns openstack's own meta_data.json, which has more in it
+    /// than the ec2-compatible metadata (e.g. the instance's `meta` map)
+    pub fn meta_data(&self) -> Result<Option<serde_json::Value>> {
+        if let Source::ConfigDrive(ref metadata) = self.source {
+            return Ok(Some(metadata.meta_data.clone()));
+        }
+        match self.meta_data_document()? {
+            Some(data) => Ok(Some(json::from_slice(data.as_bytes())
+                .chain_err(|| "failed to parse openstack meta_data.json")?)),
+            None => Ok(None),
+        }
+    }
+
+    /// meta_data_document returns meta_data.json as it was served
+    fn meta_data_document(&self) -> Result<Option<String>> {
+        match self.source {
+            Source::ConfigDrive(ref metadata) => Ok(Some(metadata.meta_data.to_string())),
+            Source::Saved(ref raw) => Ok(raw.text(META_DATA_DOCUMENT)?.map(String::from)),
+            _ => self.get_path(&format!("{}/{}/meta_data.json", OPENSTACK_PATH, self.version())),
+        }
     }
 
     fn fetch_keys(&self) -> Result<Vec<String>> {
-        let keys_list: Option<String> = self.client
-            .get(retry::Raw, OpenstackProvider::endpoint_for("public-keys"))
-            .send()?;
+        if let Source::ConfigDrive(ref metadata) = self.source {
+            return Ok(metadata.public_keys.clone());
+        }
+
+        let keys_list: Option<String> = self.get("public-keys")?;
         let mut keys = Vec::new();
         if let Some(keys_list) = keys_list {
-            for l in keys_list.lines() {
-                let tokens: Vec<&str> = l.split('=').collect();
-                if tokens.len() != 2 {
-                    return Err("error parsing keyID".into());
-                }
-                let key: String = self.client
-                    .get(retry::Raw, OpenstackProvider::endpoint_for(&format!("public-keys/{}/openssh-key", tokens[0])))
-                    .send()?
+            for document in key_documents(&keys_list)? {
+                let key: String = self.get(&document)?
                     .ok_or("missing ssh key")?;
                 keys.push(key);
             }
@@ -49,12 +320,44 @@ impl OpenstackProvider {
     }
 }
 
+/// key_documents returns the documents with the keys in the listing of
+/// `public-keys`
+fn key_documents(keys_list: &str) -> Result<Vec<String>> {
+    let mut documents = Vec::new();
+    for l in keys_list.lines() {
+        let tokens: Vec<&str> = l.split('=').collect();
+        if tokens.len() != 2 {
+            return Err("error parsing keyID".into());
+        }
+        documents.push(format!("public-keys/{}/openssh-key", tokens[0]));
+    }
+    Ok(documents)
+}
+
+impl Source {
+    fn describe(&self) -> String {
+        match *self {
+            Source::Http(ref url) => url.clone(),
+            Source::LinkLocal(ref endpoint) => format!("fe80::a9fe:a9fe on {}", endpoint.interface()),
+            Source::ConfigDrive(_) => String::from("the config drive"),
+            Source::Saved(_) => String::from("saved documents"),
+        }
+    }
+}
+
 impl MetadataProvider for OpenstackProvider {
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            network: false,
+            ..Capabilities::all()
+        }
+    }
+
     fn attributes(&self) -> Result<HashMap<String, String>> {
         let mut out = HashMap::with_capacity(4);
 
         let add_value = |map: &mut HashMap<_, _>, key: &str, name| -> Result<()> {
-            let value = self.client.get(retry::Raw, OpenstackProvider::endpoint_for(name)).send()?;
+            let value = self.get(name)?;
             if let Some(value) = value {
                 map.insert(key.to_string(), value);
             }
@@ -70,15 +373,30 @@ impl MetadataProvider for OpenstackProvider {
     }
 
     fn hostname(&self) -> Result<Option<String>> {
-        self.client.get(retry::Raw, OpenstackProvider::endpoint_for("hostname")).send()
+        self.get("hostname")
+    }
+
+    fn instance_id(&self) -> Result<Option<String>> {
+        self.get("instance-id")
+    }
+
+    /// both addresses of the metadata service, even when the config drive
+    /// was used, since the service may be there as well
+    fn metadata_endpoints(&self) -> Vec<MetadataEndpoint> {
+        let mut endpoints = match self.source {
+            Source::Http(ref base_url) => endpoints_of(&[base_url.as_str(), URL]),
+            _ => endpoints_of(&[URL]),
+        };
+        endpoints.push(MetadataEndpoint { address: IpAddr::V6(linklocal::metadata_address()), port: 80 });
+        endpoints
     }
 
-    fn ssh_keys(&self) -> Result<Vec<AuthorizedKeyEntry>> {
+    fn ssh_keys(&self) -> Result<Vec<PublicKey>> {
         let mut out = Vec::new();
 
         for key in &self.fetch_keys()? {
             let key = PublicKey::parse(&key)?;
-            out.push(AuthorizedKeyEntry::Valid{key});
+            out.push(key);
         }
 
         Ok(out)
@@ -0,0 +1,174 @@
+//! openstack-based public clouds
+//!
+//! some public clouds are plain openstack as far as the metadata goes, except
+//! for their own keys in the instance's `meta` map (e.g. the vpc or the
+//! billing mode on open telekom cloud). their providers are the openstack
+//! one, with attributes named after the cloud and those keys added.
+
+use std::collections::HashMap;
+
+use openssh_keys::PublicKey;
+use serde_json;
+
+use errors::*;
+use network;
+use providers::{Capabilities, MetadataEndpoint, MetadataProvider, ProviderConfig};
+use providers::openstack::network::OpenstackProvider;
+use providers::raw::RawMetadata;
+use util::attributes::AttributeNamer;
+
+const OPENSTACK_PREFIX: &str = "OPENSTACK_";
+
+/// rename_attributes renames the openstack provider's attributes for a
+/// cloud built on it, e.g. OPENSTACK_HOSTNAME to OTC_HOSTNAME
+pub fn rename_attributes(attrs: HashMap<String, String>, prefix: &str) -> HashMap<String, String> {
+    attrs.into_iter()
+        .map(|(k, v)| if k.starts_with(OPENSTACK_PREFIX) {
+            (format!("{}_{}", prefix, &k[OPENSTACK_PREFIX.len()..]), v)
+        } else {
+            (k, v)
+        })
+        .collect()
+}
+
+/// meta_data_attributes picks the attributes out of openstack's
+/// meta_data.json that the ec2-compatible metadata doesn't have
+fn meta_data_attributes(meta_data: &serde_json::Value, prefix: &str) -> Vec<(String, String)> {
+    let mut attrs = Vec::new();
+    for &(key, name) in &[("availability_zone", "AVAILABILITY_ZONE"), ("project_id", "PROJECT_ID")] {
+        if let Some(value) = meta_data.get(key).and_then(serde_json::Value::as_str) {
+            attrs.push((format!("{}_{}", prefix, name), value.to_owned()));
+        }
+    }
+    if let Some(meta) = meta_data.get("meta").and_then(serde_json::Value::as_object) {
+        let mut namer = AttributeNamer::new(&format!("{}_META_", prefix));
+        // sorted, so that colliding names are numbered the same every time
+        let mut keys: Vec<&String> = meta.keys().collect();
+        keys.sort();
+        for key in keys {
+            let value = match meta[key] {
+                serde_json::Value::String(ref s) => s.clone(),
+                ref v => v.to_string(),
+            };
+            attrs.push((namer.name_for(key), value));
+        }
+    }
+    attrs
+}
+
+#[derive(Clone, Debug)]
+pub struct OpenstackVariant {
+    openstack: OpenstackProvider,
+    prefix: &'static str,
+}
+
+impl OpenstackVariant {
+    /// new fetches the openstack metadata for the cloud whose attributes are
+    /// prefixed with prefix
+    pub fn new(config: &ProviderConfig, prefix: &'static str) -> Result<OpenstackVariant> {
+        Ok(OpenstackVariant {
+            openstack: OpenstackProvider::new(config)?,
+            prefix,
+        })
+    }
+
+    /// fetch_raw fetches the openstack documents for the provider named
+    /// provider
+    pub fn fetch_raw(config: &ProviderConfig, provider: &str) -> Result<RawMetadata> {
+        OpenstackProvider::fetch_raw_as(config, provider)
+    }
+
+    /// parse makes a provider that reads the documents saved by fetch_raw
+    pub fn parse(raw: &RawMetadata, provider: &str, prefix: &'static str) -> Result<OpenstackVariant> {
+        raw.check(provider)?;
+        Ok(OpenstackVariant {
+            openstack: OpenstackProvider::from_documents(raw)?,
+            prefix,
+        })
+    }
+}
+
+impl MetadataProvider for OpenstackVariant {
+    fn capabilities(&self) -> Capabilities {
+        self.openstack.capabilities()
+    }
+
+    fn attributes(&self) -> Result<HashMap<String, String>> {
+        let mut out = rename_attributes(self.openstack.attributes()?, self.prefix);
+        // the extensions are nice to have, but not worth failing over
+        match self.openstack.meta_data() {
+            Ok(Some(ref meta_data)) => out.extend(meta_data_attributes(meta_data, self.prefix)),
+            Ok(None) => debug!("no openstack meta_data.json"),
+            Err(e) => warn!("failed to fetch openstack meta_data.json: {}", e),
+        }
+        Ok(out)
+    }
+
+    fn hostname(&self) -> Result<Option<String>> {
+        self.openstack.hostname()
+    }
+
+    fn instance_id(&self) -> Result<Option<String>> {
+        self.openstack.instance_id()
+    }
+
+    fn metadata_endpoints(&self) -> Vec<MetadataEndpoint> {
+        self.openstack.metadata_endpoints()
+    }
+
+    fn ssh_keys(&self) -> Result<Vec<PublicKey>> {
+        self.openstack.ssh_keys()
+    }
+
+    fn networks(&self) -> Result<Vec<network::Interface>> {
+        self.openstack.networks()
+    }
+
+    fn network_devices(&self) -> Result<Vec<network::Device>> {
+        self.openstack.network_devices()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn meta_data_attributes_test() {
+        let meta_data: serde_json::Value = serde_json::from_str(r#"{
+            "availability_zone": "eu-de-01",
+            "hostname": "web1",
+            "meta": {"vpc_id": "0c8d", "metering.image_id": "7a1f", "charging_mode": 0}
+        }"#).unwrap();
+        let attrs: HashMap<String, String> = meta_data_attributes(&meta_data, "OTC").into_iter().collect();
+        assert_eq!(attrs["OTC_AVAILABILITY_ZONE"], "eu-de-01");
+        assert_eq!(attrs["OTC_META_VPC_ID"], "0c8d");
+        assert_eq!(attrs["OTC_META_METERING_IMAGE_ID"], "7a1f");
+        assert_eq!(attrs["OTC_META_CHARGING_MODE"], "0");
+        assert_eq!(attrs.len(), 4);
+    }
+
+    #[test]
+    fn parse_test() {
+        let mut raw = RawMetadata::new("otc");
+        raw.insert("hostname", "web1");
+        raw.insert("instance-id", "3b7f");
+        raw.insert("openstack/meta_data.json", r#"{"availability_zone": "eu-de-01", "meta": {"vpc_id": "0c8d"}}"#);
+        let provider = OpenstackVariant::parse(&raw, "otc", "OTC").unwrap();
+        let attrs = provider.attributes().unwrap();
+        assert_eq!(attrs["OTC_HOSTNAME"], "web1");
+        assert_eq!(attrs["OTC_INSTANCE_ID"], "3b7f");
+        assert_eq!(attrs["OTC_AVAILABILITY_ZONE"], "eu-de-01");
+        assert_eq!(attrs["OTC_META_VPC_ID"], "0c8d");
+        assert!(provider.ssh_keys().unwrap().is_empty());
+        assert!(OpenstackVariant::parse(&raw, "ovhcloud", "OVHCLOUD").is_err());
+    }
+
+    #[test]
+    fn rename_attributes_test() {
+        let mut attrs = HashMap::new();
+        attrs.insert("OPENSTACK_HOSTNAME".to_owned(), "web1".to_owned());
+        let attrs = rename_attributes(attrs, "OVHCLOUD");
+        assert_eq!(attrs["OVHCLOUD_HOSTNAME"], "web1");
+    }
+}
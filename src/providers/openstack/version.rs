@@ -0,0 +1,54 @@
+//! openstack metadata versions
+//!
+//! nova serves its own metadata under `/openstack/<version>/`, with a
+//! directory for every release that changed it, listed at `/openstack/`, and
+//! `latest`, which is whatever the cloud runs. the newest version that's
+//! known here is used instead of `latest`, so that a layout nova hasn't
+//! released yet doesn't get read as one it has. clouds that don't list
+//! their versions, or only ones older than any known here, get `latest`.
+
+/// the metadata versions nova has released, oldest first
+const VERSIONS: [&str; 8] = [
+    // folsom
+    "2012-08-10",
+    // grizzly
+    "2013-04-04",
+    // havana
+    "2013-10-17",
+    // liberty
+    "2015-10-15",
+    // newton
+    "2016-06-30",
+    "2016-10-06",
+    // ocata
+    "2017-02-22",
+    // rocky
+    "2018-08-27",
+];
+
+pub const LATEST: &str = "latest";
+
+/// pick returns the newest known version in the listing, one per line, or
+/// `latest` if there isn't one
+pub fn pick<'a, I: IntoIterator<Item = &'a str>>(listing: I) -> &'static str {
+    let listed: Vec<&str> = listing.into_iter().map(str::trim).collect();
+    VERSIONS.iter().rev()
+        .find(|v| listed.contains(v))
+        .cloned()
+        .unwrap_or(LATEST)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pick_test() {
+        // newer than anything known
+        assert_eq!(pick("2012-08-10\n2013-04-04\n2017-02-22\n2020-10-14\nlatest\n".lines()), "2017-02-22");
+        // mitaka, which stops at liberty's
+        assert_eq!(pick("2012-08-10\n2013-04-04\n2013-10-17\n2015-10-15\nlatest".lines()), "2015-10-15");
+        assert_eq!(pick("latest\n".lines()), LATEST);
+        assert_eq!(pick("".lines()), LATEST);
+    }
+}
@@ -21,16 +21,21 @@ use std::str::FromStr;
 
 use openssh_keys::PublicKey;
 use pnet::util::MacAddr;
-use update_ssh_keys::AuthorizedKeyEntry;
+use serde_json;
 
 use errors::*;
 use network::{self, Interface, Device, Section, NetworkRoute};
-use providers::MetadataProvider;
+use providers::{document_url, MetadataProvider, ProviderConfig, SshHostKey};
+use providers::raw::RawMetadata;
 use retry;
 use util;
 
 use ipnetwork::{self, IpNetwork, Ipv4Network, Ipv6Network};
 
+const URL: &str = "http://metadata.packet.net";
+const PROVIDER: &str = "packet";
+const METADATA_DOCUMENT: &str = "metadata";
+
 #[derive(Clone, Debug, Deserialize)]
 struct PacketData {
     id: String,
@@ -42,6 +47,11 @@ struct PacketData {
     ssh_keys: Vec<String>,
     network: PacketNetworkInfo,
 
+    #[serde(default)]
+    bgp_neighbors: Vec<PacketBGPNeighborInfo>,
+    #[serde(default)]
+    customdata: serde_json::Value,
+
     error: Option<String>,
     phone_home_url: String,
 }
@@ -76,21 +86,58 @@ struct PacketAddressInfo {
     gateway: IpAddr,
 }
 
+#[derive(Clone, Debug, Deserialize)]
+struct PacketBGPNeighborInfo {
+    address_family: i32,
+    customer_as: u32,
+    customer_ip: IpAddr,
+    md5_enabled: bool,
+    multihop: bool,
+    peer_as: u32,
+    peer_ips: Vec<IpAddr>,
+}
+
 #[derive(Clone, Debug)]
 pub struct PacketProvider {
     data: PacketData,
 }
 
 impl PacketProvider {
-    pub fn new() -> Result<PacketProvider> {
-        let client = retry::Client::new()?;
+    pub fn new(config: &ProviderConfig) -> Result<PacketProvider> {
+        PacketProvider::parse(&PacketProvider::fetch_raw(config)?)
+    }
+
+    /// fetch_raw fetches the metadata document
+    pub fn fetch_raw(config: &ProviderConfig) -> Result<RawMetadata> {
+        let client = retry::Client::new()?
+            .dns_timeout(config.dns_timeout_or_default());
 
-        let data: PacketData = client
-            .get(retry::Json, "http://metadata.packet.net/metadata".to_string())
+        let body: String = client
+            .get(retry::Raw, format!("{}/{}", config.endpoint_or(URL), METADATA_DOCUMENT))
             .send()?
             .ok_or("not found")?;
 
-        Ok(PacketProvider{ data })
+        let mut raw = RawMetadata::new(PROVIDER);
+        raw.insert(METADATA_DOCUMENT, body);
+        Ok(raw)
+    }
+
+    /// fetch_document fetches a path from the root of the metadata service,
+    /// e.g. `/metadata`
+    pub fn fetch_document(config: &ProviderConfig, path: &str) -> Result<Option<Vec<u8>>> {
+        retry::Client::new()?
+            .dns_timeout(config.dns_timeout_or_default())
+            .return_on_404(true)
+            .get(retry::Raw, document_url(&config.endpoint_or(URL), path)?)
+            .send_bytes()
+    }
+
+    /// parse parses the metadata document. the dns servers aren't in it, so
+    /// they're still read from networkd's state when the networks are asked
+    /// for.
+    pub fn parse(raw: &RawMetadata) -> Result<PacketProvider> {
+        raw.check(PROVIDER)?;
+        Ok(PacketProvider{ data: raw.json(METADATA_DOCUMENT)? })
     }
 
     fn get_attrs(&self) -> Result<Vec<(String,String)>> {
@@ -119,11 +166,78 @@ impl PacketProvider {
                 }
             }
         }
+        // addresses that aren't management addresses are elastic ip blocks
+        // that have been assigned to this device
+        let mut v4_elastic_counter = 0;
+        let mut v6_elastic_counter = 0;
+        for a in self.data.network.addresses.iter().filter(|a| !a.management) {
+            let prefix = ipnetwork::ip_mask_to_prefix(a.netmask)
+                .chain_err(|| "invalid network mask")?;
+            let block = IpNetwork::new(a.address, prefix)
+                .chain_err(|| "invalid IP address or prefix")?;
+            match block {
+                IpNetwork::V4(_) => {
+                    attrs.push((format!("PACKET_IPV4_ELASTIC_{}", v4_elastic_counter), format!("{}", block)));
+                    v4_elastic_counter += 1;
+                }
+                IpNetwork::V6(_) => {
+                    attrs.push((format!("PACKET_IPV6_ELASTIC_{}", v6_elastic_counter), format!("{}", block)));
+                    v6_elastic_counter += 1;
+                }
+            }
+        }
+        // bgp sessions have to be enabled per-project and per-device, so most
+        // devices won't have any neighbors. the md5 password is deliberately
+        // not exposed, since the attributes file is world-readable.
+        for (i, n) in self.data.bgp_neighbors.iter().enumerate() {
+            attrs.push((format!("PACKET_BGP_NEIGHBOR_{}_ADDRESS_FAMILY", i), format!("{}", n.address_family)));
+            attrs.push((format!("PACKET_BGP_NEIGHBOR_{}_CUSTOMER_AS", i), format!("{}", n.customer_as)));
+            attrs.push((format!("PACKET_BGP_NEIGHBOR_{}_CUSTOMER_IP", i), format!("{}", n.customer_ip)));
+            attrs.push((format!("PACKET_BGP_NEIGHBOR_{}_MD5_ENABLED", i), format!("{}", n.md5_enabled)));
+            attrs.push((format!("PACKET_BGP_NEIGHBOR_{}_MULTIHOP", i), format!("{}", n.multihop)));
+            attrs.push((format!("PACKET_BGP_NEIGHBOR_{}_PEER_AS", i), format!("{}", n.peer_as)));
+            for (j, peer_ip) in n.peer_ips.iter().enumerate() {
+                attrs.push((format!("PACKET_BGP_NEIGHBOR_{}_PEER_IP_{}", i, j), format!("{}", peer_ip)));
+            }
+        }
+        attrs.push(("PACKET_FACILITY".to_owned(), self.data.facility.clone()));
         attrs.push(("PACKET_HOSTNAME".to_owned(), self.data.hostname.clone()));
+        attrs.push(("PACKET_PLAN".to_owned(), self.data.plan.clone()));
         attrs.push(("PACKET_PHONE_HOME_URL".to_owned(), self.data.phone_home_url.clone()));
         Ok(attrs)
     }
 
+    fn get_ssh_host_keys(&self) -> Result<Vec<SshHostKey>> {
+        // host keys can be passed in the user-supplied customdata, using the
+        // same layout as the cloud-config `ssh_keys` section, e.g.
+        // `{"ssh_keys": {"rsa_private": "...", "rsa_public": "..."}}`
+        let keys = match self.data.customdata.get("ssh_keys").and_then(|k| k.as_object()) {
+            Some(keys) => keys,
+            None => return Ok(vec![]),
+        };
+
+        let get = |name: String| -> Result<Option<String>> {
+            match keys.get(&name) {
+                Some(v) => v.as_str()
+                    .map(|v| Some(v.to_owned()))
+                    .ok_or_else(|| format!("ssh host key entry '{}' is not a string", name).into()),
+                None => Ok(None),
+            }
+        };
+
+        let mut host_keys = Vec::new();
+        for name in keys.keys().filter(|k| k.ends_with("_private")) {
+            let key_type = name.trim_right_matches("_private").to_owned();
+            host_keys.push(SshHostKey {
+                private_key: get(name.clone())?.unwrap_or_default(),
+                public_key: get(format!("{}_public", key_type))?,
+                certificate: get(format!("{}_certificate", key_type))?,
+                key_type,
+            });
+        }
+        Ok(host_keys)
+    }
+
     fn get_dns_servers() -> Result<Vec<IpAddr>> {
         let f = File::open("/run/systemd/netif/state")
             .chain_err(|| "failed to open /run/systemd/netif/state")?;
@@ -153,14 +267,10 @@ impl PacketProvider {
             interfaces.push(Interface {
                 mac_address: Some(mac),
                 bond: i.bond.clone(),
-                name: None,
-                priority: None,
-                nameservers: Vec::new(),
-                ip_addresses: Vec::new(),
-                routes: Vec::new(),
                 // the interface should be unmanaged if it doesn't have a bond
                 // section
                 unmanaged: i.bond.is_none(),
+                ..Default::default()
             });
 
             // if there is a bond key, make sure we have a bond device for it
@@ -169,11 +279,7 @@ impl PacketProvider {
                     name: Some(bond_name.clone()),
                     priority: Some(5),
                     nameservers: dns_servers.clone(),
-                    mac_address: None,
-                    bond: None,
-                    ip_addresses: Vec::new(),
-                    routes: Vec::new(),
-                    unmanaged: false,
+                    ..Default::default()
                 };
                 if !bonds.iter().any(|&(_, ref b): &(MacAddr, Interface)| &bond == b) {
                     bonds.push((mac, bond));
@@ -200,6 +306,13 @@ impl PacketProvider {
                 .chain_err(|| "invalid network mask")?;
             first_bond.ip_addresses.push(IpNetwork::new(a.address, prefix)
                                     .chain_err(|| "invalid IP address or prefix")?);
+            // elastic ip blocks are routed to the device by packet, and the
+            // "gateway" they come with is just the first address in the
+            // block. adding a route for them would clobber the real default
+            // route.
+            if !a.management {
+                continue;
+            }
             let dest = match (a.public,a.address) {
                 (false,IpAddr::V4(_)) =>
                     IpNetwork::V4(Ipv4Network::new(Ipv4Addr::new(10,0,0,0),8).unwrap()),
@@ -258,17 +371,25 @@ impl MetadataProvider for PacketProvider {
         Ok(Some(self.data.hostname.clone()))
     }
 
-    fn ssh_keys(&self) -> Result<Vec<AuthorizedKeyEntry>> {
+    fn instance_id(&self) -> Result<Option<String>> {
+        Ok(Some(self.data.id.clone()))
+    }
+
+    fn ssh_keys(&self) -> Result<Vec<PublicKey>> {
         let mut out = Vec::new();
 
         for key in &self.data.ssh_keys {
             let key = PublicKey::parse(&key)?;
-            out.push(AuthorizedKeyEntry::Valid{key});
+            out.push(key);
         }
 
         Ok(out)
     }
 
+    fn ssh_host_keys(&self) -> Result<Vec<SshHostKey>> {
+        self.get_ssh_host_keys()
+    }
+
     fn networks(&self) -> Result<Vec<network::Interface>> {
         let (interfaces, _devices) = self.parse_network()?;
 
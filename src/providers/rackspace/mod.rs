@@ -0,0 +1,202 @@
+// Copyright 2017 CoreOS, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! rackspace metadata fetcher
+//!
+//! rackspace is openstack underneath, so the metadata comes from the
+//! openstack metadata service or config drive where there is one. rackspace
+//! classic servers have neither a metadata service nor dhcp, though; their
+//! static network configuration is only in xenstore, where the nova agent
+//! would read it from.
+
+mod xenstore;
+
+use std::collections::HashMap;
+
+use openssh_keys::PublicKey;
+
+use errors::*;
+use network;
+use providers::openstack::network::OpenstackProvider;
+use providers::openstack::variant;
+use providers::{Capabilities, MetadataProvider, ProviderConfig};
+use providers::raw::RawMetadata;
+use util::attributes;
+
+const PROVIDER: &str = "rackspace";
+
+const HOSTNAME_PATH: &str = "vm-data/hostname";
+
+#[derive(Clone, Debug)]
+pub struct RackspaceProvider {
+    openstack: Option<OpenstackProvider>,
+    networks: Vec<xenstore::XenNetwork>,
+    /// the documents, when they were saved by fetch_raw rather than read
+    /// as they're needed
+    raw: Option<RawMetadata>,
+}
+
+impl RackspaceProvider {
+    pub fn new(config: &ProviderConfig) -> Result<RackspaceProvider> {
+        let openstack = OpenstackProvider::new(config)
+            .map_err(|e| warn!("no openstack metadata, falling back to xenstore: {}", e))
+            .ok();
+        let networks = xenstore::networks()
+            .unwrap_or_else(|e| {
+                debug!("no network configuration in xenstore: {}", e);
+                Vec::new()
+            });
+        if openstack.is_none() && networks.is_empty() {
+            return Err("found neither openstack metadata nor a xenstore network configuration".into());
+        }
+        Ok(RackspaceProvider { openstack, networks, raw: None })
+    }
+
+    /// fetch_raw fetches the openstack documents, if there's openstack
+    /// metadata, and reads the hostname and network configuration in
+    /// xenstore, as documents named by their xenstore paths
+    pub fn fetch_raw(config: &ProviderConfig) -> Result<RawMetadata> {
+        let mut raw = RawMetadata::new(PROVIDER);
+        match OpenstackProvider::new(config) {
+            Ok(openstack) => openstack.fetch_documents(&mut raw)?,
+            Err(e) => warn!("no openstack metadata, falling back to xenstore: {}", e),
+        }
+        let networks = xenstore::read_networks()
+            .unwrap_or_else(|e| {
+                debug!("no network configuration in xenstore: {}", e);
+                Vec::new()
+            });
+        if raw.documents.is_empty() && networks.is_empty() {
+            return Err("found neither openstack metadata nor a xenstore network configuration".into());
+        }
+        for (path, value) in networks {
+            raw.insert(&path, value);
+        }
+        if let Ok(hostname) = xenstore::read(HOSTNAME_PATH) {
+            raw.insert(HOSTNAME_PATH, hostname);
+        }
+        Ok(raw)
+    }
+
+    /// parse makes a provider that reads the documents saved by fetch_raw.
+    /// the openstack metadata is taken to have been there if its
+    /// instance-id was saved.
+    pub fn parse(raw: &RawMetadata) -> Result<RackspaceProvider> {
+        raw.check(PROVIDER)?;
+        let openstack = match raw.get("instance-id") {
+            Some(_) => Some(OpenstackProvider::from_documents(raw)?),
+            None => None,
+        };
+        let mut networks = Vec::new();
+        for path in raw.documents.keys() {
+            if xenstore::is_network_path(path) {
+                networks.push((path.as_str(), raw.require_text(path)?));
+            }
+        }
+        Ok(RackspaceProvider {
+            openstack,
+            networks: xenstore::parse_networks(networks)?,
+            raw: Some(raw.clone()),
+        })
+    }
+
+    fn parse_attrs(&self) -> Vec<(String, String)> {
+        // attributes are numbered per network label and address family, e.g.
+        // RACKSPACE_IPV4_PUBLIC_0
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        let mut attrs = Vec::new();
+        for n in &self.networks {
+            for ip in n.enabled_ips() {
+                let family = if ip.ip.is_ipv4() { "IPV4" } else { "IPV6" };
+                let name = format!("RACKSPACE_{}_{}", family, attributes::sanitize(&n.label));
+                let count = counts.entry(name.clone()).or_insert(0);
+                attrs.push((format!("{}_{}", name, count), ip.ip.to_string()));
+                *count += 1;
+            }
+        }
+        attrs
+    }
+}
+
+impl MetadataProvider for RackspaceProvider {
+    /// keys only come from the config drive
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            ssh_keys: self.openstack.is_some(),
+            ..Capabilities::all()
+        }
+    }
+
+    fn attributes(&self) -> Result<HashMap<String, String>> {
+        let mut out = match self.openstack {
+            Some(ref openstack) => variant::rename_attributes(openstack.attributes()?, "RACKSPACE"),
+            None => HashMap::new(),
+        };
+        out.extend(self.parse_attrs());
+        Ok(out)
+    }
+
+    fn hostname(&self) -> Result<Option<String>> {
+        match self.openstack {
+            Some(ref openstack) => openstack.hostname(),
+            None => match self.raw {
+                Some(ref raw) => Ok(raw.text(HOSTNAME_PATH)?.map(String::from)),
+                None => Ok(xenstore::read(HOSTNAME_PATH).ok()),
+            },
+        }
+    }
+
+    fn instance_id(&self) -> Result<Option<String>> {
+        match self.openstack {
+            Some(ref openstack) => openstack.instance_id(),
+            None => Ok(None),
+        }
+    }
+
+    fn ssh_keys(&self) -> Result<Vec<PublicKey>> {
+        match self.openstack {
+            Some(ref openstack) => openstack.ssh_keys(),
+            None => Ok(vec![]),
+        }
+    }
+
+    fn networks(&self) -> Result<Vec<network::Interface>> {
+        self.networks.iter().map(xenstore::XenNetwork::to_interface).collect()
+    }
+
+    fn network_devices(&self) -> Result<Vec<network::Device>> {
+        Ok(vec![])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_xenstore_test() {
+        let mut raw = RawMetadata::new(PROVIDER);
+        raw.insert(HOSTNAME_PATH, "web1");
+        raw.insert("vm-data/networking/BC764E20110A", r#"{
+            "label": "private", "mac": "BC:76:4E:20:11:0A",
+            "ips": [{"ip": "10.176.2.3", "netmask": "255.255.224.0", "enabled": "1"}]
+        }"#);
+        let provider = RackspaceProvider::parse(&raw).unwrap();
+        // without an instance-id, there was no openstack metadata
+        assert!(!provider.capabilities().ssh_keys);
+        assert_eq!(provider.hostname().unwrap(), Some("web1".to_owned()));
+        assert_eq!(provider.attributes().unwrap()["RACKSPACE_IPV4_PRIVATE_0"], "10.176.2.3");
+        assert_eq!(provider.networks().unwrap().len(), 1);
+    }
+}
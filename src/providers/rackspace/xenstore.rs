@@ -0,0 +1,215 @@
+// Copyright 2017 CoreOS, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! xenstore access through the xen tools
+//!
+//! on rackspace classic the nova agent leaves the network configuration in
+//! the guest's part of xenstore, under `vm-data/networking/<mac>`, as one
+//! json document per interface.
+
+use std::net::IpAddr;
+use std::process::Command;
+use std::str::FromStr;
+
+use ipnetwork;
+use ipnetwork::IpNetwork;
+use pnet::util::MacAddr;
+use serde_json;
+
+use errors::*;
+use network;
+
+const NETWORKING_PATH: &str = "vm-data/networking";
+
+fn run(tool: &str, path: &str) -> Result<String> {
+    let output = Command::new(tool)
+        .arg(path)
+        .output()
+        .chain_err(|| format!("failed to run {}", tool))?;
+    if !output.status.success() {
+        return Err(format!("{} {} failed: {}", tool, path, String::from_utf8_lossy(&output.stderr).trim()).into());
+    }
+    String::from_utf8(output.stdout)
+        .chain_err(|| format!("{} {} returned invalid UTF-8", tool, path))
+}
+
+/// read returns the value at the given xenstore path
+pub fn read(path: &str) -> Result<String> {
+    run("xenstore-read", path).map(|v| v.trim_right_matches('\n').to_owned())
+}
+
+/// list returns the names of the children of the given xenstore path
+pub fn list(path: &str) -> Result<Vec<String>> {
+    Ok(run("xenstore-list", path)?.lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .map(String::from)
+        .collect())
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct XenIp {
+    pub ip: IpAddr,
+    /// a dotted netmask for ipv4, and a prefix length for ipv6
+    pub netmask: serde_json::Value,
+    #[serde(default)]
+    pub enabled: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct XenRoute {
+    pub route: IpAddr,
+    pub netmask: IpAddr,
+    pub gateway: IpAddr,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct XenNetwork {
+    pub label: String,
+    pub mac: String,
+    #[serde(default)]
+    pub ips: Vec<XenIp>,
+    #[serde(default)]
+    pub ip6s: Vec<XenIp>,
+    #[serde(default)]
+    pub dns: Vec<IpAddr>,
+    #[serde(default)]
+    pub routes: Vec<XenRoute>,
+    pub gateway: Option<IpAddr>,
+    pub gateway_v6: Option<IpAddr>,
+}
+
+/// read_networks reads the configuration of every interface from xenstore,
+/// by its path
+pub fn read_networks() -> Result<Vec<(String, String)>> {
+    let mut out = Vec::new();
+    for key in list(NETWORKING_PATH)? {
+        let path = format!("{}/{}", NETWORKING_PATH, key);
+        let value = read(&path)?;
+        out.push((path, value));
+    }
+    Ok(out)
+}
+
+/// is_network_path returns whether the xenstore path is one read_networks
+/// reads
+pub fn is_network_path(path: &str) -> bool {
+    path.starts_with(NETWORKING_PATH) && path[NETWORKING_PATH.len()..].starts_with('/')
+}
+
+/// networks reads and parses the configuration of every interface
+pub fn networks() -> Result<Vec<XenNetwork>> {
+    let networks = read_networks()?;
+    parse_networks(networks.iter().map(|&(ref path, ref value)| (path.as_str(), value.as_str())))
+}
+
+/// parse_networks parses the configurations read by read_networks
+pub fn parse_networks<'a, I>(networks: I) -> Result<Vec<XenNetwork>>
+    where I: IntoIterator<Item = (&'a str, &'a str)>
+{
+    let mut out = Vec::new();
+    for (path, value) in networks {
+        out.push(serde_json::from_str(value)
+            .chain_err(|| format!("failed to parse xenstore network {}", path))?);
+    }
+    // the keys are mac addresses, which say nothing about the order; sort by
+    // label so public comes before private
+    out.sort_by(|a: &XenNetwork, b| b.label.cmp(&a.label));
+    Ok(out)
+}
+
+fn parse_network(ip: &XenIp) -> Result<IpNetwork> {
+    let prefix = match ip.netmask {
+        serde_json::Value::Number(ref n) => n.as_u64().map(|n| n as u8)
+            .ok_or_else(|| format!("invalid prefix length {}", n))?,
+        serde_json::Value::String(ref s) => match IpAddr::from_str(s) {
+            Ok(mask) => ipnetwork::ip_mask_to_prefix(mask)
+                .chain_err(|| format!("invalid network mask {}", s))?,
+            Err(_) => s.parse().chain_err(|| format!("invalid network mask {}", s))?,
+        },
+        ref v => return Err(format!("invalid network mask {}", v).into()),
+    };
+    IpNetwork::new(ip.ip, prefix)
+        .chain_err(|| format!("invalid address {}/{}", ip.ip, prefix))
+}
+
+impl XenNetwork {
+    /// enabled_ips are the ipv4 and ipv6 addresses the agent would configure
+    pub fn enabled_ips(&self) -> Vec<&XenIp> {
+        self.ips.iter().chain(self.ip6s.iter())
+            // older agents don't set enabled at all
+            .filter(|ip| ip.enabled.is_empty() || ip.enabled == "1")
+            .collect()
+    }
+
+    pub fn to_interface(&self) -> Result<network::Interface> {
+        let mac = MacAddr::from_str(&self.mac)
+            .map_err(|e| Error::from(format!("{:?}", e)))
+            .chain_err(|| format!("failed to parse mac address: '{}'", self.mac))?;
+
+        let mut routes = Vec::new();
+        for gateway in self.gateway.iter().chain(self.gateway_v6.iter()) {
+            let destination = match *gateway {
+                IpAddr::V4(_) => IpNetwork::from_str("0.0.0.0/0"),
+                IpAddr::V6(_) => IpNetwork::from_str("::/0"),
+            }.map_err(|e| Error::from(format!("{:?}", e)))?;
+            routes.push(network::NetworkRoute { destination, gateway: *gateway });
+        }
+        for r in &self.routes {
+            let prefix = ipnetwork::ip_mask_to_prefix(r.netmask)
+                .chain_err(|| format!("invalid network mask {}", r.netmask))?;
+            routes.push(network::NetworkRoute {
+                destination: IpNetwork::new(r.route, prefix)
+                    .chain_err(|| format!("invalid route {}/{}", r.route, prefix))?,
+                gateway: r.gateway,
+            });
+        }
+
+        Ok(network::Interface {
+            mac_address: Some(mac),
+            nameservers: self.dns.clone(),
+            ip_addresses: self.enabled_ips().into_iter()
+                .map(parse_network)
+                .collect::<Result<Vec<_>>>()?,
+            routes,
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn to_interface_test() {
+        let n: XenNetwork = serde_json::from_str(r#"{
+            "label": "private", "mac": "BC:76:4E:20:11:0A",
+            "ips": [{"ip": "10.176.2.3", "netmask": "255.255.224.0", "enabled": "1"}],
+            "ip6s": [{"ip": "2001:db8::2", "netmask": 64, "enabled": "1"}],
+            "dns": ["72.3.128.240"],
+            "routes": [{"route": "10.208.0.0", "netmask": "255.240.0.0", "gateway": "10.176.0.1"}],
+            "gateway_v6": "fe80::def"
+        }"#).unwrap();
+        let i = n.to_interface().unwrap();
+        assert_eq!(i.mac_address, Some(MacAddr(0xbc, 0x76, 0x4e, 0x20, 0x11, 0x0a)));
+        assert_eq!(i.ip_addresses, vec![
+            IpNetwork::from_str("10.176.2.3/19").unwrap(),
+            IpNetwork::from_str("2001:db8::2/64").unwrap(),
+        ]);
+        assert_eq!(i.routes.len(), 2);
+        assert_eq!(i.routes[0].destination, IpNetwork::from_str("::/0").unwrap());
+        assert_eq!(i.routes[1].destination, IpNetwork::from_str("10.208.0.0/12").unwrap());
+    }
+}
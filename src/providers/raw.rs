@@ -0,0 +1,167 @@
+// Copyright 2017 CoreOS, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! raw metadata is the documents a provider parses its metadata from, as the
+//! platform served them. unlike a snapshot, it can be parsed again by a
+//! later version of this crate, e.g. from a support bundle, without network
+//! access.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::Path;
+use std::str;
+
+use base64;
+use serde::de::DeserializeOwned;
+use serde_json;
+
+use errors::*;
+use providers::write_file_with_mode;
+use util::json;
+
+/// Document is how a document is saved: as is, if it's text, and base64
+/// encoded otherwise
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum Document {
+    Text(String),
+    Binary { base64: String },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Saved {
+    provider: String,
+    documents: BTreeMap<String, Document>,
+}
+
+/// RawMetadata holds the documents a provider fetched, by their path under
+/// the metadata service or config drive
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RawMetadata {
+    pub provider: String,
+    pub documents: BTreeMap<String, Vec<u8>>,
+}
+
+impl RawMetadata {
+    pub fn new(provider: &str) -> RawMetadata {
+        RawMetadata {
+            provider: provider.to_owned(),
+            documents: BTreeMap::new(),
+        }
+    }
+
+    pub fn insert<T: Into<Vec<u8>>>(&mut self, path: &str, contents: T) {
+        self.documents.insert(path.to_owned(), contents.into());
+    }
+
+    pub fn get(&self, path: &str) -> Option<&[u8]> {
+        self.documents.get(path).map(|d| d.as_slice())
+    }
+
+    /// text returns the document, if it was fetched, failing if it isn't text
+    pub fn text(&self, path: &str) -> Result<Option<&str>> {
+        match self.get(path) {
+            Some(d) => Ok(Some(str::from_utf8(d)
+                .chain_err(|| ErrorKind::Parse(ParseError::Malformed(path.to_owned())))?)),
+            None => Ok(None),
+        }
+    }
+
+    /// require_text returns the document, failing if it wasn't fetched
+    pub fn require_text(&self, path: &str) -> Result<&str> {
+        self.text(path)?
+            .ok_or_else(|| format!("missing document {}", path).into())
+    }
+
+    /// json parses the document, failing if it wasn't fetched
+    pub fn json<T: DeserializeOwned>(&self, path: &str) -> Result<T> {
+        json::from_slice(self.require_text(path)?.as_bytes())
+            .chain_err(|| ErrorKind::Parse(ParseError::Malformed(path.to_owned())))
+    }
+
+    /// optional_json parses the document, if it was fetched
+    pub fn optional_json<T: DeserializeOwned>(&self, path: &str) -> Result<Option<T>> {
+        match self.get(path) {
+            Some(_) => self.json(path).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// check fails unless the documents were fetched by the provider
+    pub fn check(&self, provider: &str) -> Result<()> {
+        if self.provider != provider {
+            bail!("documents were fetched by {}, not {}", self.provider, provider);
+        }
+        Ok(())
+    }
+
+    /// write_to saves the documents. user data can hold secrets, so it's only
+    /// readable by the owner.
+    pub fn write_to(&self, path: &str) -> Result<()> {
+        let saved = Saved {
+            provider: self.provider.clone(),
+            documents: self.documents.iter().map(|(k, v)| {
+                let document = match str::from_utf8(v) {
+                    Ok(s) => Document::Text(s.to_owned()),
+                    Err(_) => Document::Binary { base64: base64::encode(v) },
+                };
+                (k.clone(), document)
+            }).collect(),
+        };
+        let contents = serde_json::to_string_pretty(&saved)
+            .chain_err(|| "failed to serialize metadata documents")?;
+        write_file_with_mode(Path::new(path), 0o600, &contents)
+    }
+
+    pub fn read_from(path: &str) -> Result<RawMetadata> {
+        let file = File::open(path)
+            .chain_err(|| format!("failed to open metadata documents {:?}", path))?;
+        let saved: Saved = serde_json::from_reader(file)
+            .chain_err(|| format!("failed to parse metadata documents {:?}", path))?;
+        let mut raw = RawMetadata::new(&saved.provider);
+        for (k, v) in saved.documents {
+            let contents = match v {
+                Document::Text(s) => s.into_bytes(),
+                Document::Binary { base64: ref s } => base64::decode(s)
+                    .chain_err(|| format!("failed to decode document {}", k))?,
+            };
+            raw.documents.insert(k, contents);
+        }
+        Ok(raw)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let mut raw = RawMetadata::new("nutanix");
+        raw.insert("meta_data.json", "{}");
+        raw.insert("user_data", vec![0x1f, 0x8b, 0x08, 0x00]);
+
+        let dir = TempDir::new("coreos-metadata-raw").unwrap();
+        let path = dir.path().join("raw.json");
+        let path = path.to_str().unwrap();
+        raw.write_to(path).unwrap();
+        assert_eq!(RawMetadata::read_from(path).unwrap(), raw);
+
+        assert_eq!(raw.require_text("meta_data.json").unwrap(), "{}");
+        raw.text("user_data").unwrap_err();
+        raw.require_text("network_data.json").unwrap_err();
+    }
+}
@@ -0,0 +1,438 @@
+// Copyright 2017 CoreOS, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! snapshot is a serializable copy of everything a provider knows, so that
+//! fetching metadata and writing it out can happen in different processes
+//! (e.g. before and after the switch to the real root filesystem).
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+
+use base64;
+use ipnetwork::IpNetwork;
+use openssh_keys::PublicKey;
+use pnet::util::MacAddr;
+use serde_json;
+
+use errors::*;
+use network;
+use providers::{write_file_with_mode, Capabilities, MetadataEndpoint, MetadataProvider, Password, SshHostKey};
+use util::attributes::{self, Provenance};
+use util::ssh_cert::SshCertificate;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RouteSnapshot {
+    pub destination: String,
+    pub gateway: IpAddr,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct InterfaceSnapshot {
+    pub name: Option<String>,
+    pub mac_address: Option<String>,
+    pub priority: Option<u32>,
+    pub nameservers: Vec<IpAddr>,
+    pub ip_addresses: Vec<String>,
+    pub routes: Vec<RouteSnapshot>,
+    pub bond: Option<String>,
+    pub unmanaged: bool,
+    pub driver: Option<String>,
+    pub mtu: Option<u32>,
+    pub local_routes: Vec<String>,
+    pub route_table: Option<u32>,
+    pub match_permanent_mac: bool,
+}
+
+/// UserKeySnapshot is a key or certificate and the account it's meant for
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UserKeySnapshot {
+    pub user: Option<String>,
+    pub key: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SectionSnapshot {
+    pub name: String,
+    pub attributes: Vec<(String, String)>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DeviceSnapshot {
+    pub name: String,
+    pub kind: String,
+    pub mac_address: String,
+    pub priority: Option<u32>,
+    pub sections: Vec<SectionSnapshot>,
+}
+
+/// Snapshot holds the result of each of the provider's methods. a field is
+/// None if fetching it failed, in which case writing that output from the
+/// snapshot fails too. that's distinct from a provider having nothing to
+/// give, hence the nested options.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "cargo-clippy", allow(option_option))]
+pub struct Snapshot {
+    pub provider: String,
+    pub attributes: Option<HashMap<String, String>>,
+    pub hostname: Option<Option<String>>,
+    pub pretty_hostname: Option<Option<String>>,
+    pub instance_id: Option<Option<String>>,
+    pub search_domains: Option<Vec<String>>,
+    pub labels: Option<BTreeMap<String, String>>,
+    /// what became of each attribute
+    pub attribute_provenance: Option<BTreeMap<String, Provenance>>,
+    pub ssh_keys: Option<Vec<String>>,
+    pub ssh_keys_by_user: Option<Vec<UserKeySnapshot>>,
+    pub ssh_certificates_by_user: Option<Vec<UserKeySnapshot>>,
+    pub ssh_host_keys: Option<Vec<SshHostKey>>,
+    pub networks: Option<Vec<InterfaceSnapshot>>,
+    pub network_devices: Option<Vec<DeviceSnapshot>>,
+    /// base64 encoded
+    pub custom_data: Option<Option<String>>,
+    pub capabilities: Capabilities,
+    pub metadata_endpoints: Vec<MetadataEndpoint>,
+}
+
+fn parse_mac(mac: &str) -> Result<MacAddr> {
+    MacAddr::from_str(mac)
+        .map_err(|e| Error::from(format!("{:?}", e)))
+        .chain_err(|| format!("failed to parse mac address: '{}'", mac))
+}
+
+fn parse_network(net: &str) -> Result<IpNetwork> {
+    IpNetwork::from_str(net)
+        .map_err(|e| Error::from(format!("{:?}", e)))
+        .chain_err(|| format!("failed to parse network: '{}'", net))
+}
+
+impl<'a> From<&'a network::Interface> for InterfaceSnapshot {
+    fn from(i: &network::Interface) -> Self {
+        InterfaceSnapshot {
+            name: i.name.clone(),
+            mac_address: i.mac_address.map(|m| m.to_string()),
+            priority: i.priority,
+            nameservers: i.nameservers.clone(),
+            ip_addresses: i.ip_addresses.iter().map(|a| a.to_string()).collect(),
+            routes: i.routes.iter().map(|r| RouteSnapshot {
+                destination: r.destination.to_string(),
+                gateway: r.gateway,
+            }).collect(),
+            bond: i.bond.clone(),
+            unmanaged: i.unmanaged,
+            driver: i.driver.clone(),
+            mtu: i.mtu,
+            local_routes: i.local_routes.iter().map(|r| r.to_string()).collect(),
+            route_table: i.route_table,
+            match_permanent_mac: i.match_permanent_mac,
+        }
+    }
+}
+
+impl InterfaceSnapshot {
+    pub fn to_interface(&self) -> Result<network::Interface> {
+        Ok(network::Interface {
+            name: self.name.clone(),
+            mac_address: match self.mac_address {
+                Some(ref m) => Some(parse_mac(m)?),
+                None => None,
+            },
+            priority: self.priority,
+            nameservers: self.nameservers.clone(),
+            ip_addresses: self.ip_addresses.iter()
+                .map(|a| parse_network(a))
+                .collect::<Result<Vec<_>>>()?,
+            routes: self.routes.iter()
+                .map(|r| Ok(network::NetworkRoute {
+                    destination: parse_network(&r.destination)?,
+                    gateway: r.gateway,
+                }))
+                .collect::<Result<Vec<_>>>()?,
+            bond: self.bond.clone(),
+            unmanaged: self.unmanaged,
+            driver: self.driver.clone(),
+            mtu: self.mtu,
+            local_routes: self.local_routes.iter()
+                .map(|r| parse_network(r))
+                .collect::<Result<Vec<_>>>()?,
+            route_table: self.route_table,
+            match_permanent_mac: self.match_permanent_mac,
+        })
+    }
+}
+
+impl<'a> From<&'a network::Device> for DeviceSnapshot {
+    fn from(d: &network::Device) -> Self {
+        DeviceSnapshot {
+            name: d.name.clone(),
+            kind: d.kind.clone(),
+            mac_address: d.mac_address.to_string(),
+            priority: d.priority,
+            sections: d.sections.iter().map(|s| SectionSnapshot {
+                name: s.name.clone(),
+                attributes: s.attributes.clone(),
+            }).collect(),
+        }
+    }
+}
+
+impl DeviceSnapshot {
+    pub fn to_device(&self) -> Result<network::Device> {
+        Ok(network::Device {
+            name: self.name.clone(),
+            kind: self.kind.clone(),
+            mac_address: parse_mac(&self.mac_address)?,
+            priority: self.priority,
+            sections: self.sections.iter().map(|s| network::Section {
+                name: s.name.clone(),
+                attributes: s.attributes.clone(),
+            }).collect(),
+        })
+    }
+}
+
+/// capture logs the error and returns None if fetching the value failed
+fn capture<T>(what: &str, res: Result<T>) -> Option<T> {
+    match res {
+        Ok(v) => Some(v),
+        Err(e) => {
+            warn!("failed to fetch {}: {}", what, e);
+            None
+        }
+    }
+}
+
+fn missing(what: &str) -> Error {
+    format!("{} could not be fetched when the snapshot was taken", what).into()
+}
+
+impl Snapshot {
+    /// from_provider calls every method on the provider and records the
+    /// results
+    pub fn from_provider(provider: &str, metadata: &MetadataProvider) -> Snapshot {
+        let attributes = capture("attributes", metadata.attribute_provenance());
+        Snapshot {
+            provider: provider.to_owned(),
+            attributes: attributes.as_ref().map(|&(ref a, _)| a.clone()),
+            attribute_provenance: attributes.map(|(_, p)| p),
+            hostname: capture("hostname", metadata.hostname()),
+            pretty_hostname: capture("pretty hostname", metadata.pretty_hostname()),
+            instance_id: capture("instance id", metadata.instance_id()),
+            search_domains: capture("search domains", metadata.search_domains()),
+            labels: capture("labels", metadata.labels()),
+            ssh_keys: capture("ssh keys", metadata.ssh_keys().map(|keys| {
+                keys.iter().map(PublicKey::to_key_format).collect()
+            })),
+            ssh_keys_by_user: capture("ssh keys by user", metadata.ssh_keys_by_user().map(|keys| {
+                keys.iter().map(|&(ref user, ref key)| UserKeySnapshot {
+                    user: user.clone(),
+                    key: key.to_key_format(),
+                }).collect()
+            })),
+            ssh_certificates_by_user: capture("ssh certificates", metadata.ssh_certificates_by_user().map(|certs| {
+                certs.iter().map(|&(ref user, ref cert)| UserKeySnapshot {
+                    user: user.clone(),
+                    key: cert.to_key_format(),
+                }).collect()
+            })),
+            ssh_host_keys: capture("ssh host keys", metadata.ssh_host_keys()),
+            networks: capture("network interfaces", metadata.networks()
+                .map(|n| n.iter().map(InterfaceSnapshot::from).collect())),
+            network_devices: capture("network devices", metadata.network_devices()
+                .map(|d| d.iter().map(DeviceSnapshot::from).collect())),
+            custom_data: capture("custom data", metadata.custom_data()
+                .map(|d| d.map(|d| base64::encode(&d)))),
+            capabilities: metadata.capabilities(),
+            metadata_endpoints: metadata.metadata_endpoints(),
+        }
+    }
+
+    /// write_to saves the snapshot. it can contain private keys, so it's only
+    /// readable by the owner.
+    pub fn write_to(&self, path: &str) -> Result<()> {
+        let contents = serde_json::to_string_pretty(self)
+            .chain_err(|| "failed to serialize snapshot")?;
+        write_file_with_mode(Path::new(path), 0o600, &contents)
+    }
+
+    pub fn read_from(path: &str) -> Result<Snapshot> {
+        let file = File::open(path)
+            .chain_err(|| format!("failed to open snapshot {:?}", path))?;
+        serde_json::from_reader(file)
+            .chain_err(|| format!("failed to parse snapshot {:?}", path))
+    }
+}
+
+impl MetadataProvider for Snapshot {
+    /// those of the provider the snapshot was taken from
+    fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    fn metadata_endpoints(&self) -> Vec<MetadataEndpoint> {
+        self.metadata_endpoints.clone()
+    }
+
+    fn attributes(&self) -> Result<HashMap<String, String>> {
+        let (attributes, provenance) = self.attribute_provenance()?;
+        attributes::check_required(&attributes, &provenance, None)?;
+        Ok(attributes)
+    }
+
+    fn attribute_provenance(&self) -> Result<(HashMap<String, String>, BTreeMap<String, Provenance>)> {
+        let attributes = self.attributes.clone().ok_or_else(|| missing("attributes"))?;
+        let provenance = self.attribute_provenance.clone().ok_or_else(|| missing("attributes"))?;
+        Ok((attributes, provenance))
+    }
+
+    fn hostname(&self) -> Result<Option<String>> {
+        self.hostname.clone().ok_or_else(|| missing("hostname"))
+    }
+
+    fn pretty_hostname(&self) -> Result<Option<String>> {
+        self.pretty_hostname.clone().ok_or_else(|| missing("pretty hostname"))
+    }
+
+    fn instance_id(&self) -> Result<Option<String>> {
+        self.instance_id.clone().ok_or_else(|| missing("instance id"))
+    }
+
+    fn search_domains(&self) -> Result<Vec<String>> {
+        self.search_domains.clone().ok_or_else(|| missing("search domains"))
+    }
+
+    fn labels(&self) -> Result<BTreeMap<String, String>> {
+        self.labels.clone().ok_or_else(|| missing("labels"))
+    }
+
+    fn ssh_keys(&self) -> Result<Vec<PublicKey>> {
+        let keys = self.ssh_keys.as_ref().ok_or_else(|| missing("ssh keys"))?;
+        let mut out = Vec::new();
+        for key in keys {
+            out.push(PublicKey::parse(key)?);
+        }
+        Ok(out)
+    }
+
+    fn ssh_keys_by_user(&self) -> Result<Vec<(Option<String>, PublicKey)>> {
+        let keys = self.ssh_keys_by_user.as_ref().ok_or_else(|| missing("ssh keys by user"))?;
+        let mut out = Vec::new();
+        for key in keys {
+            out.push((key.user.clone(), PublicKey::parse(&key.key)?));
+        }
+        Ok(out)
+    }
+
+    fn ssh_certificates_by_user(&self) -> Result<Vec<(Option<String>, SshCertificate)>> {
+        let certs = self.ssh_certificates_by_user.as_ref().ok_or_else(|| missing("ssh certificates"))?;
+        let mut out = Vec::new();
+        for cert in certs {
+            out.push((cert.user.clone(), SshCertificate::parse(&cert.key)?));
+        }
+        Ok(out)
+    }
+
+    /// the password isn't kept, since it has to be acknowledged to the
+    /// platform it came from
+    fn password(&self) -> Result<Option<Password>> {
+        Err("the password can't be set from a snapshot".into())
+    }
+
+    fn acknowledge_password(&self) -> Result<()> {
+        Err("the password can't be acknowledged from a snapshot".into())
+    }
+
+    fn publish_ssh_host_keys(&self, _keys: &[PublicKey]) -> Result<()> {
+        Err("ssh host keys can't be published from a snapshot".into())
+    }
+
+    fn networks(&self) -> Result<Vec<network::Interface>> {
+        self.networks.as_ref().ok_or_else(|| missing("network interfaces"))?
+            .iter()
+            .map(InterfaceSnapshot::to_interface)
+            .collect()
+    }
+
+    fn network_devices(&self) -> Result<Vec<network::Device>> {
+        self.network_devices.as_ref().ok_or_else(|| missing("network devices"))?
+            .iter()
+            .map(DeviceSnapshot::to_device)
+            .collect()
+    }
+
+    fn custom_data(&self) -> Result<Option<Vec<u8>>> {
+        match *self.custom_data.as_ref().ok_or_else(|| missing("custom data"))? {
+            Some(ref data) => Ok(Some(base64::decode(data)
+                .chain_err(|| "failed to decode custom data from snapshot")?)),
+            None => Ok(None),
+        }
+    }
+
+    fn ssh_host_keys(&self) -> Result<Vec<SshHostKey>> {
+        self.ssh_host_keys.clone().ok_or_else(|| missing("ssh host keys"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interface_roundtrip() {
+        let i = network::Interface {
+            name: Some(String::from("bond0")),
+            mac_address: Some(MacAddr(0xf4,0x00,0x34,0x09,0x73,0xee)),
+            priority: Some(5),
+            nameservers: vec![IpAddr::from_str("8.8.8.8").unwrap()],
+            ip_addresses: vec![IpNetwork::from_str("10.0.0.2/24").unwrap()],
+            routes: vec![network::NetworkRoute {
+                destination: IpNetwork::from_str("0.0.0.0/0").unwrap(),
+                gateway: IpAddr::from_str("10.0.0.1").unwrap(),
+            }],
+            mtu: Some(9000),
+            local_routes: vec![IpNetwork::from_str("10.4.0.0/24").unwrap()],
+            ..Default::default()
+        };
+        let json = serde_json::to_string(&InterfaceSnapshot::from(&i)).unwrap();
+        let snapshot: InterfaceSnapshot = serde_json::from_str(&json).unwrap();
+        assert_eq!(snapshot.to_interface().unwrap(), i);
+    }
+
+    #[test]
+    fn missing_fields_error() {
+        let snapshot = Snapshot::default();
+        snapshot.attributes().unwrap_err();
+        snapshot.networks().unwrap_err();
+        snapshot.ssh_keys_by_user().unwrap_err();
+    }
+
+    #[test]
+    fn ssh_keys_by_user_roundtrip() {
+        let key = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIGhRWdT6bvpgIX5H4PUTmNM1BVAtbpyCydz6F5XtQdRg core@example";
+        let snapshot = Snapshot {
+            ssh_keys_by_user: Some(vec![
+                UserKeySnapshot { user: Some("alice".to_owned()), key: key.to_owned() },
+                UserKeySnapshot { user: None, key: key.to_owned() },
+            ]),
+            ..Snapshot::default()
+        };
+        let json = serde_json::to_string(&snapshot).unwrap();
+        let snapshot: Snapshot = serde_json::from_str(&json).unwrap();
+        let users: Vec<_> = snapshot.ssh_keys_by_user().unwrap()
+            .into_iter().map(|(user, _)| user).collect();
+        assert_eq!(users, vec![Some("alice".to_owned()), None]);
+    }
+}
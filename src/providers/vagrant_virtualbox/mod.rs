@@ -16,23 +16,61 @@
 
 use std::collections::HashMap;
 use std::net::IpAddr;
-use std::thread;
 use std::time::Duration;
 
 use hostname;
+use openssh_keys::PublicKey;
 use pnet;
-use update_ssh_keys::AuthorizedKeyEntry;
 
+use cancel;
 use errors::*;
 use network;
-use providers::MetadataProvider;
+use providers::{Capabilities, MetadataProvider};
+use providers::raw::RawMetadata;
 
-#[derive(Clone, Copy, Debug)]
-pub struct VagrantVirtualboxProvider;
+const PROVIDER: &str = "vagrant-virtualbox";
+
+#[derive(Clone, Debug)]
+pub struct VagrantVirtualboxProvider {
+    /// the hostname and address, when they were saved by fetch_raw rather
+    /// than looked up as they're needed
+    raw: Option<RawMetadata>,
+}
 
 impl VagrantVirtualboxProvider {
     pub fn new() -> Result<VagrantVirtualboxProvider> {
-        Ok(VagrantVirtualboxProvider)
+        Ok(VagrantVirtualboxProvider { raw: None })
+    }
+
+    /// fetch_raw looks up the hostname, and waits for the address of eth1.
+    /// they're saved as the documents `hostname` and `private-ipv4`.
+    pub fn fetch_raw() -> Result<RawMetadata> {
+        let mut raw = RawMetadata::new(PROVIDER);
+        if let Some(hostname) = hostname::get_hostname() {
+            raw.insert("hostname", hostname);
+        }
+        raw.insert("private-ipv4", VagrantVirtualboxProvider::get_ip()?);
+        Ok(raw)
+    }
+
+    /// parse makes a provider that reads the documents saved by fetch_raw
+    pub fn parse(raw: &RawMetadata) -> Result<VagrantVirtualboxProvider> {
+        raw.check(PROVIDER)?;
+        Ok(VagrantVirtualboxProvider { raw: Some(raw.clone()) })
+    }
+
+    fn get_hostname(&self) -> Result<Option<String>> {
+        match self.raw {
+            Some(ref raw) => Ok(raw.text("hostname")?.map(String::from)),
+            None => Ok(hostname::get_hostname()),
+        }
+    }
+
+    fn get_private_ip(&self) -> Result<String> {
+        match self.raw {
+            Some(ref raw) => Ok(raw.require_text("private-ipv4")?.to_owned()),
+            None => VagrantVirtualboxProvider::get_ip(),
+        }
     }
 
     fn get_ip() -> Result<String> {
@@ -47,7 +85,7 @@ impl VagrantVirtualboxProvider {
                 }
             }
             info!("eth1 not found or is lacking an ipv4 address; waiting 2 seconds");
-            thread::sleep(Duration::from_secs(2));
+            cancel::sleep(Duration::from_secs(2))?;
         }
         Err("eth1 was not found!".into())
     }
@@ -64,11 +102,19 @@ impl VagrantVirtualboxProvider {
 }
 
 impl MetadataProvider for VagrantVirtualboxProvider {
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            ssh_keys: false,
+            network: false,
+            ..Capabilities::all()
+        }
+    }
+
     fn attributes(&self) -> Result<HashMap<String, String>> {
         let mut out = HashMap::with_capacity(2);
 
-        let hostname = hostname::get_hostname().ok_or("unable to get hostname")?;
-        let ip = VagrantVirtualboxProvider::get_ip()?;
+        let hostname = self.get_hostname()?.ok_or("unable to get hostname")?;
+        let ip = self.get_private_ip()?;
 
         out.insert("VAGRANT_VIRTUALBOX_HOSTNAME".to_string(), hostname);
         out.insert("VAGRANT_VIRTUALBOX_PRIVATE_IPV4".to_string(), ip);
@@ -77,10 +123,10 @@ impl MetadataProvider for VagrantVirtualboxProvider {
     }
 
     fn hostname(&self) -> Result<Option<String>> {
-        Ok(hostname::get_hostname())
+        self.get_hostname()
     }
 
-    fn ssh_keys(&self) -> Result<Vec<AuthorizedKeyEntry>> {
+    fn ssh_keys(&self) -> Result<Vec<PublicKey>> {
         Ok(vec![])
     }
 
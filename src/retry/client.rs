@@ -14,37 +14,64 @@
 
 //! retry
 //!
-//! this is an abstraction over the regular http get request. it allows you to
+//! this is an abstraction over regular http requests. it allows you to
 //! have a request retry until it succeeds, with a configurable number of
 //! of attempts and a backoff strategy. It also takes care of automatically
-//! deserializing responses and handles headers in a sane way.
+//! deserializing responses and handles headers in a sane way. requests can
+//! be GETs, or POSTs and PUTs with a body, which is resent on every attempt.
 
-use std::io::Read;
-use std::time::Duration;
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::io::{self, Read};
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::time::{Duration, Instant};
 
+use hyper;
 use reqwest;
 use reqwest::header;
 use reqwest::header::ContentType;
-use reqwest::{Method,Request};
+use reqwest::{Body,Method,Request};
+use mime;
 
 use serde;
+#[cfg(feature = "azure")]
 use serde_xml_rs;
-use serde_json;
 
 use retry::Retry;
 use errors::*;
 
+use retry::limit;
+use retry::missing;
+use retry::observer::{self, CountingReader};
+use retry::pool;
 use retry::raw_deserializer;
+use util::{dns, json, redact};
 
 pub trait Deserializer {
     fn deserialize<T, R>(&self, R) -> Result<T>
         where T: for<'de> serde::Deserialize<'de>, R: Read;
     fn content_type(&self) -> ContentType;
+
+    /// accepts is whether a response with the given content type can be
+    /// deserialized. metadata services are sloppy about content types (ec2
+    /// serves json as text/plain), so by default everything is accepted.
+    fn accepts(&self, _content_type: &mime::Mime) -> bool {
+        true
+    }
 }
 
+/// is_html is for structured deserializers to turn down the error pages of
+/// proxies and captive portals early, instead of failing to parse them
+fn is_html(content_type: &mime::Mime) -> bool {
+    content_type.type_() == mime::TEXT && content_type.subtype() == mime::HTML
+}
+
+#[cfg(feature = "azure")]
 #[derive(Debug, Clone, Copy)]
 pub struct Xml;
 
+#[cfg(feature = "azure")]
 impl Deserializer for Xml {
     fn deserialize<T, R>(&self, r: R) -> Result<T>
         where T: for<'de> serde::Deserialize<'de>, R: Read
@@ -55,21 +82,30 @@ impl Deserializer for Xml {
     fn content_type(&self) -> ContentType {
         ContentType("text/xml; charset=utf-8".parse().unwrap())
     }
+    fn accepts(&self, content_type: &mime::Mime) -> bool {
+        !is_html(content_type)
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
 pub struct Json;
 
 impl Deserializer for Json {
-    fn deserialize<T, R>(&self, r: R) -> Result<T>
+    fn deserialize<T, R>(&self, mut r: R) -> Result<T>
         where T: serde::de::DeserializeOwned, R: Read
     {
-        serde_json::from_reader(r)
+        let mut doc = Vec::new();
+        r.read_to_end(&mut doc)
+            .chain_err(|| "failed to read response")?;
+        json::from_slice(&doc)
             .chain_err(|| "failed json deserialization")
     }
     fn content_type(&self) -> ContentType {
         ContentType("text/json; charset=utf-8".parse().unwrap())
     }
+    fn accepts(&self, content_type: &mime::Mime) -> bool {
+        !is_html(content_type)
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -87,26 +123,77 @@ impl Deserializer for Raw {
     }
 }
 
+/// status_is_retryable is whether a request that failed with the given status
+/// might succeed if it's made again: timeouts, throttling, and server errors.
+/// anything else (403, 404, ...) is going to fail the same way every time.
+pub fn status_is_retryable(status: u16) -> bool {
+    match status {
+        408 | 429 => true,
+        500..=599 => true,
+        _ => false,
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Client {
     client: reqwest::Client,
     headers: header::Headers,
     retry: Retry,
     return_on_404: bool,
+    remember_404: bool,
+    retry_on: Vec<u16>,
+    timeout: Duration,
+    max_response_size: u64,
+    dns_timeout: Option<Duration>,
+    request_id_header: Option<&'static str>,
 }
 
+/// how long an attempt may take by default, from sending the request to
+/// reading the last byte of the response
+pub const DEFAULT_TIMEOUT_SECS: u64 = 30;
+/// the largest response body that is read. user data is the largest thing
+/// that's fetched, and no platform allows more than a few megabytes of it.
+pub const DEFAULT_MAX_RESPONSE_SIZE: u64 = 16 * 1024 * 1024;
+
 impl Client {
     pub fn new() -> Result<Self> {
-        let client = reqwest::Client::new()
-            .chain_err(|| "failed to initialize client")?;
         Ok(Client{
-            client,
+            client: pool::client(Duration::from_secs(DEFAULT_TIMEOUT_SECS))?,
             headers: header::Headers::new(),
             retry: Retry::new(),
             return_on_404: false,
+            remember_404: false,
+            retry_on: Vec::new(),
+            timeout: Duration::from_secs(DEFAULT_TIMEOUT_SECS),
+            max_response_size: DEFAULT_MAX_RESPONSE_SIZE,
+            dns_timeout: None,
+            request_id_header: None,
         })
     }
 
+    /// timeout limits how long each attempt can take. the socket timeout is
+    /// set to the same, so a server that stops sending is caught as well as
+    /// one that never stops.
+    pub fn timeout(mut self, timeout: Duration) -> Result<Self> {
+        self.client = pool::client(timeout)?;
+        self.timeout = timeout;
+        Ok(self)
+    }
+
+    /// max_response_size limits the size of response bodies
+    #[cfg(test)]
+    pub fn max_response_size(mut self, max_response_size: u64) -> Self {
+        self.max_response_size = max_response_size;
+        self
+    }
+
+    /// dns_timeout limits how long the host name of the url is looked up
+    /// for, on every attempt. urls with ip addresses aren't looked up.
+    pub fn dns_timeout(mut self, dns_timeout: Duration) -> Self {
+        self.dns_timeout = Some(dns_timeout);
+        self
+    }
+
     pub fn header<H>(mut self, h: H) -> Self
         where H: header::Header
     {
@@ -114,6 +201,14 @@ impl Client {
         self
     }
 
+    /// request_id_header names the response header in which the platform
+    /// identifies each request, for its support. it's logged with every
+    /// response, and errors for failed requests give it.
+    pub fn request_id_header(mut self, name: &'static str) -> Self {
+        self.request_id_header = Some(name);
+        self
+    }
+
     pub fn initial_backoff(mut self, initial_backoff: Duration) -> Self {
         self.retry = self.retry.initial_backoff(initial_backoff);
         self
@@ -130,21 +225,97 @@ impl Client {
         self
     }
 
+    /// refused_timeout waits for up to this long for a metadata service that
+    /// refuses connections, for platforms where it starts after the guest
+    pub fn refused_timeout(mut self, refused_timeout: Duration) -> Self {
+        self.retry = self.retry.refused_timeout(refused_timeout);
+        self
+    }
+
+    /// stop gives up before the next attempt once the flag is set
+    pub fn stop(mut self, stop: Arc<AtomicBool>) -> Self {
+        self.retry = self.retry.stop(stop);
+        self
+    }
+
     pub fn return_on_404(mut self, return_on_404: bool) -> Self {
         self.return_on_404 = return_on_404;
         self
     }
 
+    /// remember_404 makes a resource that returned a 404 be taken as missing
+    /// for the rest of the run, without asking again. it's for metadata
+    /// that an instance either has or doesn't; resources that can appear
+    /// later, like notices, should opt out with `RequestBuilder::remember_404`.
+    pub fn remember_404(mut self, remember_404: bool) -> Self {
+        self.remember_404 = remember_404;
+        self
+    }
+
+    /// retry_on_status makes requests that fail with the given status be
+    /// retried, for platforms where it's only a temporary condition
+    pub fn retry_on_status(mut self, status: u16) -> Self {
+        self.retry_on.push(status);
+        self
+    }
+
     pub fn get<D>(&self, d: D, url: String) -> RequestBuilder<D>
         where D: Deserializer
+    {
+        self.request(Method::Get, d, url)
+    }
+
+    /// get_json fetches and deserializes a json document. use `get` with
+    /// `Json` to set headers on the request.
+    pub fn get_json<T>(&self, url: String) -> Result<Option<T>>
+        where T: for<'de> serde::Deserialize<'de>
+    {
+        self.get(Json, url).send()
+    }
+
+    /// get_xml fetches and deserializes an xml document. use `get` with `Xml`
+    /// to set headers on the request.
+    #[cfg(feature = "azure")]
+    pub fn get_xml<T>(&self, url: String) -> Result<Option<T>>
+        where T: for<'de> serde::Deserialize<'de>
+    {
+        self.get(Xml, url).send()
+    }
+
+    /// post sends the body given with `RequestBuilder::body`, and
+    /// deserializes the response with d
+    pub fn post<D>(&self, d: D, url: String) -> RequestBuilder<D>
+        where D: Deserializer
+    {
+        self.request(Method::Post, d, url)
+    }
+
+    /// put sends the body given with `RequestBuilder::body`, and deserializes
+    /// the response with d
+    pub fn put<D>(&self, d: D, url: String) -> RequestBuilder<D>
+        where D: Deserializer
+    {
+        self.request(Method::Put, d, url)
+    }
+
+    fn request<D>(&self, method: Method, d: D, url: String) -> RequestBuilder<D>
+        where D: Deserializer
     {
         RequestBuilder{
             url,
+            method,
+            body: None,
             d,
             client: self.client.clone(),
             headers: self.headers.clone(),
             retry: self.retry.clone(),
             return_on_404: self.return_on_404,
+            remember_404: self.remember_404,
+            retry_on: self.retry_on.clone(),
+            timeout: self.timeout,
+            max_response_size: self.max_response_size,
+            dns_timeout: self.dns_timeout,
+            request_id_header: self.request_id_header,
         }
     }
 }
@@ -153,11 +324,19 @@ pub struct RequestBuilder<D>
     where D: Deserializer
 {
     url: String,
+    method: Method,
+    body: Option<Vec<u8>>,
     d: D,
     client: reqwest::Client,
     headers: header::Headers,
     retry: Retry,
     return_on_404: bool,
+    remember_404: bool,
+    retry_on: Vec<u16>,
+    timeout: Duration,
+    max_response_size: u64,
+    dns_timeout: Option<Duration>,
+    request_id_header: Option<&'static str>,
 }
 
 impl<D> RequestBuilder<D>
@@ -171,54 +350,405 @@ impl<D> RequestBuilder<D>
         self
     }
 
+    /// raw_header sets a header that doesn't have a typed representation
+    pub fn raw_header<K>(mut self, name: K, value: String) -> Self
+        where K: Into<Cow<'static, str>>
+    {
+        self.headers.set_raw(name, value);
+        self
+    }
+
+    /// remember_404 overrides the client's setting for this request
+    pub fn remember_404(mut self, remember_404: bool) -> Self {
+        self.remember_404 = remember_404;
+        self
+    }
+
+    /// body sets the request body. its content type is the one of the
+    /// deserializer.
+    pub fn body<B>(mut self, body: B) -> Self
+        where B: Into<Vec<u8>>
+    {
+        self.body = Some(body.into());
+        self
+    }
+
     pub fn send<T>(self) -> Result<Option<T>>
         where T: for<'de> serde::Deserialize<'de>
+    {
+        self.send_with_headers().map(|(body, _)| body)
+    }
+
+    /// send_with_headers also returns the headers of the response, for
+    /// providers that take signals from them. a missing resource has the
+    /// headers of the 404.
+    pub fn send_with_headers<T>(self) -> Result<(Option<T>, header::Headers)>
+        where T: for<'de> serde::Deserialize<'de>
+    {
+        self.send_with(|d, body| d.deserialize(body))
+    }
+
+    /// send_bytes returns the response body as it is, for binary payloads
+    /// like compressed user data. the deserializer only sets the content
+    /// type.
+    pub fn send_bytes(self) -> Result<Option<Vec<u8>>> {
+        self.send_with(|_, body| Ok(body.to_vec())).map(|(body, _)| body)
+    }
+
+    fn send_with<T, F>(self, parse: F) -> Result<(Option<T>, header::Headers)>
+        where F: Fn(&D, &[u8]) -> Result<T>
     {
         let url = reqwest::Url::parse(self.url.as_str())
             .chain_err(|| "failed to parse uri")?;
-        let mut req = Request::new(Method::Get, url);
+        let mut req = Request::new(self.method.clone(), url);
         req.headers_mut().extend(self.headers.iter());
         req.headers_mut().set(self.d.content_type());
 
-        self.retry.clone().retry(|attempt| {
-            info!("Fetching {}: Attempt #{}", req.url(), attempt + 1);
-            self.dispatch_request(&req)
-        })
+        let url = self.redacted_url();
+        let remember = self.return_on_404 && self.remember_404 && self.method == Method::Get;
+        if remember && missing::is_missing(&self.url) {
+            info!("{} {}: not found before, not fetching again", self.describe(), url);
+            return Ok((None, header::Headers::new()));
+        }
+        let bytes = Cell::new(0);
+        let attempts = Cell::new(0);
+        // once the connection was refused, the retry says so once rather
+        // than every attempt logging it
+        let refused = Cell::new(false);
+        let res = self.retry.clone().retry(|attempt| {
+            limit::wait(req.url())?;
+            if refused.get() {
+                debug!("{} {}: Attempt #{}", self.describe(), url, attempt + 1);
+            } else {
+                info!("{} {}: Attempt #{}", self.describe(), url, attempt + 1);
+            }
+            attempts.set(attempt + 1);
+            observer::notify(|o| o.attempt_started(&url, attempt + 1));
+            let res = self.dispatch_request(&req, &bytes, &parse);
+            if let Err(ref e) = res {
+                observer::notify(|o| o.attempt_failed(&url, attempt + 1, e));
+            }
+            refused.set(match res {
+                Err(ref e) => match *e.kind() {
+                    ErrorKind::Fetch(FetchError::ConnectionRefused(_)) => true,
+                    _ => false,
+                },
+                Ok(_) => false,
+            });
+            res
+        });
+        if let Ok((ref body, _)) = res {
+            observer::notify(|o| o.succeeded(&url, attempts.get(), bytes.get()));
+            if body.is_none() && remember {
+                missing::remember(&self.url);
+            }
+        }
+        res
     }
 
-    fn dispatch_request<T>(&self, req: &Request) -> Result<Option<T>>
-        where T: for<'de> serde::Deserialize<'de>
+    /// redacted_url is the url for error messages, which can end up in logs
+    fn redacted_url(&self) -> String {
+        redact::redact_text(&self.url)
+    }
+
+    /// read_body reads the response body, as long as it's no larger than
+    /// the maximum size and arrives before the deadline
+    fn read_body<R: Read>(&self, mut r: R, deadline: Instant) -> Result<Vec<u8>> {
+        let mut body = Vec::new();
+        let mut buf = [0; 8192];
+        loop {
+            if Instant::now() > deadline {
+                return Err(ErrorKind::Fetch(FetchError::TimedOut(self.redacted_url())).into());
+            }
+            let n = r.read(&mut buf)
+                .chain_err(|| "failed to read response body")?;
+            if n == 0 {
+                return Ok(body);
+            }
+            if body.len() as u64 + n as u64 > self.max_response_size {
+                return Err(ErrorKind::Parse(ParseError::TooLarge(self.redacted_url(), self.max_response_size)).into());
+            }
+            body.extend_from_slice(&buf[..n]);
+        }
+    }
+
+    /// request_id returns the platform's id for the request, if it gives one
+    fn request_id(&self, headers: &header::Headers) -> Option<String> {
+        self.request_id_header
+            .and_then(|name| headers.get_raw(name))
+            .and_then(|raw| raw.one())
+            .and_then(|value| ::std::str::from_utf8(value).ok())
+            .map(|value| value.trim().to_owned())
+    }
+
+    /// request_error makes an error of the kind, with the request id as the
+    /// cause if there is one, so that it shows up wherever the error does
+    fn request_error(&self, headers: &header::Headers, cause: Option<Error>, kind: ErrorKind) -> Error {
+        let cause = match (cause, self.request_id(headers)) {
+            (Some(cause), Some(id)) => Some(Error::with_chain(cause, format!("request id {}", id))),
+            (None, Some(id)) => Some(Error::from(format!("request id {}", id))),
+            (cause, None) => cause,
+        };
+        match cause {
+            Some(cause) => Error::with_chain(cause, kind),
+            None => kind.into(),
+        }
+    }
+
+    fn describe(&self) -> &'static str {
+        match self.method {
+            Method::Post => "Posting to",
+            Method::Put => "Putting",
+            _ => "Fetching",
+        }
+    }
+
+    /// dispatch_request makes one attempt at the request. the size of the
+    /// response body is counted in bytes.
+    fn dispatch_request<T, F>(&self, req: &Request, bytes: &Cell<u64>, parse: &F) -> Result<(Option<T>, header::Headers)>
+        where F: Fn(&D, &[u8]) -> Result<T>
     {
-        match self.client.execute(clone_request(req)) {
+        if let Some(dns_timeout) = self.dns_timeout {
+            if let Err(e) = dns::resolve_url(req.url(), dns_timeout) {
+                info!("Failed to fetch: {}", e);
+                return Err(Error::with_chain(e, ErrorKind::Fetch(FetchError::Unreachable(self.redacted_url()))));
+            }
+        }
+        let deadline = Instant::now() + self.timeout;
+        let mut req = clone_request(req);
+        // bodies can't be cloned, so a new one is made for every attempt
+        if let Some(ref body) = self.body {
+            *req.body_mut() = Some(Body::from(body.clone()));
+        }
+        match self.client.execute(req) {
             Ok(resp) => {
+                let headers = resp.headers().clone();
+                if let Some(id) = self.request_id(&headers) {
+                    info!("Request id: {}", id);
+                }
                 match (resp.status(), self.return_on_404) {
                     (reqwest::StatusCode::Ok,_) => {
                         info!("Fetch successful");
-                        self.d.deserialize(resp)
-                            .map(Some)
-                            .chain_err(|| "failed to deserialize data")
+                        if let Some(&ContentType(ref content_type)) = headers.get::<ContentType>() {
+                            if !self.d.accepts(content_type) {
+                                let cause = Error::from(format!("unexpected content type {}", content_type));
+                                return Err(self.request_error(&headers, Some(cause), ErrorKind::Parse(ParseError::Malformed(self.redacted_url()))));
+                            }
+                        }
+                        bytes.set(0);
+                        let body = self.read_body(CountingReader::new(resp, bytes), deadline)?;
+                        match parse(&self.d, &body) {
+                            Ok(body) => Ok((Some(body), headers)),
+                            Err(e) => Err(self.request_error(&headers, Some(e), ErrorKind::Parse(ParseError::Malformed(self.redacted_url())))),
+                        }
                     }
                     (reqwest::StatusCode::NotFound,true) => {
                         info!("Fetch failed with 404: resource not found");
-                        Ok(None)
+                        Ok((None, headers))
                     }
-                    (s,_) => {
+                    (s,_) if status_is_retryable(s.as_u16()) || self.retry_on.contains(&s.as_u16()) => {
                         info!("Failed to fetch: {}", s);
-                        Err(format!("failed to fetch: {}", s).into())
+                        let retry_after = retry_after(&headers);
+                        Err(self.request_error(&headers, None, ErrorKind::Fetch(FetchError::RetryableStatus(s.as_u16(), retry_after))))
+                    }
+                    (s,_) => {
+                        info!("Failed to fetch: {}, not retrying", s);
+                        Err(self.request_error(&headers, None, ErrorKind::Fetch(FetchError::FatalStatus(s.as_u16()))))
                     }
                 }
             }
             Err(e) => {
-                info!("Failed to fetch: {}", e);
-                Err(Error::with_chain(e, "failed to fetch"))
+                if is_refused(&e) {
+                    debug!("Failed to fetch: connection refused");
+                    return Err(Error::with_chain(e, ErrorKind::Fetch(FetchError::ConnectionRefused(self.redacted_url()))));
+                }
+                info!("Failed to fetch: {}", redact::redact_text(&e.to_string()));
+                Err(Error::with_chain(e, ErrorKind::Fetch(FetchError::Unreachable(self.redacted_url()))))
             }
         }
     }
 }
 
+/// is_refused is whether the request failed because nothing was listening,
+/// as opposed to timing out or the network being unreachable
+fn is_refused(e: &reqwest::Error) -> bool {
+    let inner = match e.get_ref() {
+        Some(inner) => inner,
+        None => return false,
+    };
+    let io = match inner.downcast_ref::<hyper::Error>() {
+        Some(&hyper::Error::Io(ref io)) => Some(io),
+        _ => inner.downcast_ref::<io::Error>(),
+    };
+    io.map_or(false, |io| io.kind() == io::ErrorKind::ConnectionRefused)
+}
+
+/// retry_after returns the number of seconds in a Retry-After header, if there
+/// is one. the http-date form isn't used by any metadata service, so it's
+/// ignored.
+fn retry_after(headers: &header::Headers) -> Option<u64> {
+    headers.get_raw("Retry-After")
+        .and_then(|raw| raw.one())
+        .and_then(|value| ::std::str::from_utf8(value).ok())
+        .and_then(|value| value.trim().parse().ok())
+}
+
 /// Reqwests Request struct doesn't implement copy, so we have to do it here
 fn clone_request(req: &Request) -> Request {
     let mut newreq = Request::new(req.method().clone(), req.url().clone());
     newreq.headers_mut().extend(req.headers().iter());
     newreq
 }
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use mockito;
+
+    use super::*;
+
+    #[test]
+    fn status_is_retryable_test() {
+        for s in &[408, 429, 500, 502, 503, 504] {
+            assert!(status_is_retryable(*s), "{}", s);
+        }
+        for s in &[400, 401, 403, 404, 410] {
+            assert!(!status_is_retryable(*s), "{}", s);
+        }
+    }
+
+    #[test]
+    fn accepts_test() {
+        let html: mime::Mime = "text/html; charset=utf-8".parse().unwrap();
+        let plain: mime::Mime = "text/plain".parse().unwrap();
+        assert!(!Json.accepts(&html));
+        assert!(Json.accepts(&plain));
+        assert!(Json.accepts(&mime::APPLICATION_JSON));
+        assert!(Raw.accepts(&html));
+    }
+
+    #[test]
+    fn retry_after_test() {
+        let mut headers = header::Headers::new();
+        assert_eq!(retry_after(&headers), None);
+        headers.set_raw("Retry-After", "120");
+        assert_eq!(retry_after(&headers), Some(120));
+        headers.set_raw("Retry-After", "Wed, 21 Oct 2015 07:28:00 GMT");
+        assert_eq!(retry_after(&headers), None);
+    }
+
+    #[test]
+    fn read_body_test() {
+        let req = Client::new().unwrap()
+            .max_response_size(8)
+            .get(Raw, "http://169.254.169.254/latest/user-data".to_owned());
+        let deadline = Instant::now() + Duration::from_secs(60);
+        assert_eq!(req.read_body(&b"#!/bin/sh"[..8], deadline).unwrap(), b"#!/bin/s");
+        match *req.read_body(&b"#!/bin/sh"[..], deadline).unwrap_err().kind() {
+            ErrorKind::Parse(ParseError::TooLarge(_, 8)) => {}
+            ref e => panic!("unexpected error {:?}", e),
+        }
+        let past = Instant::now() - Duration::from_secs(1);
+        match *req.read_body(&b""[..], past).unwrap_err().kind() {
+            ErrorKind::Fetch(FetchError::TimedOut(_)) => {}
+            ref e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn refused_test() {
+        // nothing listens on the tcpmux port
+        let res: Result<Option<String>> = Client::new().unwrap()
+            .max_attempts(1)
+            .get(Raw, "http://127.0.0.1:1/".to_owned())
+            .send();
+        let e = res.unwrap_err();
+        assert_eq!(e.class(), ErrorClass::Unreachable);
+        assert!(e.iter().any(|c| c.to_string() == "http://127.0.0.1:1/ refused the connection"),
+                "unexpected error {:?}", e);
+    }
+
+    #[test]
+    fn error_class_test() {
+        let e: Error = ErrorKind::Fetch(FetchError::FatalStatus(403)).into();
+        assert_eq!(e.class(), ErrorClass::HttpStatus);
+        let e = Error::with_chain(Error::from("connection refused"), ErrorKind::Fetch(FetchError::Unreachable("http://169.254.169.254".to_owned())));
+        assert_eq!(e.class(), ErrorClass::Unreachable);
+        // the innermost class wins
+        let e = Error::with_chain(e, "failed to fetch hostname");
+        assert_eq!(e.class(), ErrorClass::Unreachable);
+        assert_eq!(e.fetch_error(), Some(&FetchError::Unreachable("http://169.254.169.254".to_owned())));
+        let e = Error::with_chain(Error::from("unexpected eof"), ErrorKind::Parse(ParseError::Malformed("http://169.254.169.254".to_owned())));
+        let e = Error::with_chain(e, ErrorKind::Write(WriteError::Failed("/run/metadata/x".to_owned())));
+        assert_eq!(e.class(), ErrorClass::Malformed);
+        assert_eq!(e.parse_error(), Some(&ParseError::Malformed("http://169.254.169.254".to_owned())));
+        assert_eq!(e.write_error(), Some(&WriteError::Failed("/run/metadata/x".to_owned())));
+        assert_eq!(e.fetch_error(), None);
+        assert_eq!(Error::from("oops").class(), ErrorClass::Other);
+        let e = Error::from(ErrorKind::SandboxUnsupported("s390x".to_owned()));
+        assert_eq!(e.class(), ErrorClass::Other);
+        assert_eq!(e.to_string(), "there's no syscall filter for s390x to sandbox the fetch with");
+        // a message that reads like a kind's description isn't that kind
+        assert_eq!(Error::from("cancelled").class(), ErrorClass::Other);
+    }
+
+    #[test]
+    fn remember_404_test() {
+        let path = "/retry/remember-404";
+        let url = format!("{}{}", mockito::SERVER_URL, path);
+        let client = Client::new().unwrap()
+            .max_attempts(1)
+            .return_on_404(true)
+            .remember_404(true);
+
+        let _m = mockito::mock("GET", path)
+            .with_status(404)
+            .create();
+        let body: Option<String> = client.get(Raw, url.clone()).send().unwrap();
+        assert_eq!(body, None);
+
+        // it isn't asked for again, on any thread
+        let _m = mockito::mock("GET", path)
+            .with_status(200)
+            .with_body("203.0.113.7")
+            .create();
+        let body: Option<String> = client.get(Raw, url.clone()).send().unwrap();
+        assert_eq!(body, None);
+        let (other_client, other_url) = (client.clone(), url.clone());
+        let body: Option<String> = thread::spawn(move || other_client.get(Raw, other_url).send().unwrap())
+            .join().unwrap();
+        assert_eq!(body, None);
+        let body: Option<String> = client.get(Raw, url).remember_404(false).send().unwrap();
+        assert_eq!(body, Some("203.0.113.7".to_owned()));
+    }
+
+    #[test]
+    fn request_id_test() {
+        let path = "/retry/request-id";
+        let url = format!("{}{}", mockito::SERVER_URL, path);
+        let client = Client::new().unwrap()
+            .max_attempts(1)
+            .request_id_header("x-request-id");
+
+        let _m = mockito::mock("GET", path)
+            .with_status(403)
+            .with_header("x-request-id", "4a8d-77")
+            .create();
+        let e = client.get(Raw, url.clone()).send::<String>().unwrap_err();
+        match *e.kind() {
+            ErrorKind::Fetch(FetchError::FatalStatus(403)) => {}
+            ref k => panic!("unexpected error {:?}", k),
+        }
+        assert!(e.iter().any(|cause| cause.to_string() == "request id 4a8d-77"));
+
+        let _m = mockito::mock("GET", path)
+            .with_status(200)
+            .with_header("x-request-id", "4a8d-78")
+            .with_body("i-1234")
+            .create();
+        let (body, headers) = client.get(Raw, url).send_with_headers::<String>().unwrap();
+        assert_eq!(body, Some("i-1234".to_owned()));
+        assert_eq!(headers.get_raw("x-request-id").and_then(|raw| raw.one()), Some(&b"4a8d-78"[..]));
+    }
+}
@@ -0,0 +1,136 @@
+// Copyright 2017 CoreOS, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! request rate limiting
+//!
+//! when a large fleet reboots at once, every instance asks the metadata
+//! service for everything at the same moment, and services like ec2's
+//! throttle them. with a limit set, requests to each metadata service are
+//! spaced out to at most that many a second, plus a random courtesy delay of
+//! up to half the spacing, like the jitter between retries, so that
+//! instances don't stay in lockstep. retried attempts count too. the limit is
+//! process-wide, since some providers probe their endpoints from threads of
+//! their own.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rand;
+use reqwest::Url;
+
+use cancel;
+use errors::*;
+
+struct Limiter {
+    /// the least time between requests to the same host
+    interval: Duration,
+    /// when the next request to each host may be made
+    next: HashMap<String, Instant>,
+}
+
+lazy_static! {
+    static ref LIMITER: Mutex<Option<Limiter>> = Mutex::new(None);
+}
+
+/// set_max_requests_per_second limits the requests made to each host, or
+/// lifts the limit with None
+pub fn set_max_requests_per_second(rate: Option<u32>) {
+    let limiter = match rate {
+        Some(rate) if rate > 0 => Some(Limiter {
+            interval: Duration::from_secs(1) / rate,
+            next: HashMap::new(),
+        }),
+        _ => None,
+    };
+    *LIMITER.lock().unwrap() = limiter;
+}
+
+/// courtesy picks a delay of up to half the interval, with r in [0, 1)
+fn courtesy(interval: Duration, r: f64) -> Duration {
+    let nanos = interval.as_secs() * 1_000_000_000 + u64::from(interval.subsec_nanos());
+    let extra = (nanos as f64 * r / 2.0) as u64;
+    Duration::new(extra / 1_000_000_000, (extra % 1_000_000_000) as u32)
+}
+
+/// wait waits until a request may be made to the url's host, and takes its
+/// turn
+pub(crate) fn wait(url: &Url) -> Result<()> {
+    wait_for_host(url.host_str().unwrap_or(""))
+}
+
+/// wait_for_host is wait for requests that aren't made with a url, by the
+/// host as it would be in one, e.g. `[fe80::a9fe:a9fe]`
+pub(crate) fn wait_for_host(host: &str) -> Result<()> {
+    // the turn is taken with the lock held, but waited for without it
+    let delay = match *LIMITER.lock().unwrap() {
+        Some(ref mut limiter) => {
+            let now = Instant::now();
+            let at = match limiter.next.get(host) {
+                Some(&next) if next > now => next,
+                _ => now,
+            };
+            let spacing = limiter.interval + courtesy(limiter.interval, rand::random());
+            limiter.next.insert(host.to_owned(), at + spacing);
+            Some(at - now)
+        }
+        None => None,
+    };
+    match delay {
+        Some(delay) if delay > Duration::from_secs(0) => {
+            debug!("waiting {}ms before the next request to {}", delay.as_secs() * 1000 + u64::from(delay.subsec_nanos()) / 1_000_000, host);
+            cancel::sleep(delay)
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    // the limit is process-wide, so both cases are in one test, to keep
+    // tests running in parallel from changing it under each other
+    #[test]
+    fn wait_test() {
+        let url = Url::parse("http://169.254.169.254/latest/meta-data").unwrap();
+        set_max_requests_per_second(Some(20));
+        let start = Instant::now();
+        for _ in 0..3 {
+            wait(&url).unwrap();
+        }
+        // the first request goes right away, the others at least 50ms apart
+        assert!(start.elapsed() >= Duration::from_millis(100));
+
+        // requests made on other threads wait their turn too
+        let start = Instant::now();
+        let other = url.clone();
+        thread::spawn(move || {
+            for _ in 0..2 {
+                wait(&other).unwrap();
+            }
+        }).join().unwrap();
+        wait(&url).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(100));
+
+        set_max_requests_per_second(None);
+        let start = Instant::now();
+        for _ in 0..3 {
+            wait(&url).unwrap();
+        }
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,39 @@
+// Copyright 2017 CoreOS, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! endpoints that are known to be missing
+//!
+//! some metadata is simply not there on some instances, e.g. there's no
+//! public-ipv4 on an ec2 instance without a public address, and asking
+//! again gets the same 404. clients that opt in remember those urls, so that
+//! fetching the attributes for several outputs, or on every pass of `watch`,
+//! doesn't ask again. they're kept for the whole process, so that what the
+//! threads some providers probe their endpoints from find out is shared.
+
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+lazy_static! {
+    static ref MISSING: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+}
+
+/// is_missing is whether the url returned a 404 before
+pub(crate) fn is_missing(url: &str) -> bool {
+    MISSING.lock().unwrap().contains(url)
+}
+
+/// remember records that the url returned a 404
+pub(crate) fn remember(url: &str) {
+    MISSING.lock().unwrap().insert(url.to_owned());
+}
@@ -13,20 +13,54 @@
 // limitations under the License.
 
 //! retry is a generic function that retrys functions until they succeed.
+//!
+//! the delay between attempts doubles every time, up to the maximum backoff,
+//! and is randomized so that a fleet of machines booted at the same time
+//! doesn't hit the metadata service in lockstep. when a server says how long
+//! to wait with `Retry-After`, that's used instead.
+//!
+//! some metadata services refuse connections for a while at boot, until
+//! whatever serves them has started. a retry can be given a separate budget
+//! for that: refused connections are then retried every second until it
+//! runs out, without using up attempts, and only the first one is logged.
 
+use cancel;
 use errors::*;
-use std::time::Duration;
-use std::thread;
+use rand;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
 pub mod raw_deserializer;
 mod client;
+// the openstack link-local endpoint can't go through the client, and uses
+// the same limit, 404 memory and observer directly
+pub(crate) mod limit;
+pub(crate) mod missing;
+pub(crate) mod observer;
+mod pool;
 pub use self::client::*;
+pub use self::limit::set_max_requests_per_second;
+pub use self::observer::{FetchObserver, set_fetch_observer};
+
+/// the longest a Retry-After header is allowed to hold up boot
+const MAX_RETRY_AFTER_SECS: u64 = 60;
+
+/// how long a metadata service that refuses connections is waited for, for
+/// the clients that wait at all
+pub const DEFAULT_REFUSED_TIMEOUT_SECS: u64 = 120;
+
+/// how often a refused connection is retried. a refusal is immediate, so
+/// there's no need to back off.
+const REFUSED_INTERVAL_SECS: u64 = 1;
 
 #[derive(Clone, Debug)]
 pub struct Retry {
     initial_backoff: Duration,
     max_backoff: Duration,
     max_attempts: u32,
+    refused_timeout: Option<Duration>,
+    stop: Option<Arc<AtomicBool>>,
 }
 
 impl ::std::default::Default for Retry {
@@ -35,6 +69,8 @@ impl ::std::default::Default for Retry {
             initial_backoff: Duration::new(1,0),
             max_backoff: Duration::new(5,0),
             max_attempts: 10,
+            refused_timeout: None,
+            stop: None,
         }
     }
 }
@@ -59,27 +95,94 @@ impl Retry {
         self
     }
 
+    /// refused_timeout waits for up to this long for a service that refuses
+    /// connections to start listening, on top of the attempts
+    pub fn refused_timeout(mut self, refused_timeout: Duration) -> Self {
+        self.refused_timeout = Some(refused_timeout);
+        self
+    }
+
+    /// stop gives up before the next attempt once the flag is set, e.g. when
+    /// something else has already answered
+    pub fn stop(mut self, stop: Arc<AtomicBool>) -> Self {
+        self.stop = Some(stop);
+        self
+    }
+
+    fn stopped(&self) -> bool {
+        self.stop.as_ref().map_or(false, |stop| stop.load(Ordering::SeqCst))
+    }
+
+    /// retry calls try until it succeeds, with the number of tries so far
     pub fn retry<F, R>(self, try: F) -> Result<R>
         where F: Fn(u32) -> Result<R>
     {
         let mut delay = self.initial_backoff;
         let mut attempts = 0;
+        let mut tries = 0;
+        let mut first_refused: Option<Instant> = None;
 
         loop {
-            let res = try(attempts);
+            cancel::check()?;
+            if self.stopped() {
+                return Err("stopped retrying".into());
+            }
+            let res = try(tries);
+            tries += 1;
 
             // if the result is ok, we don't need to try again
             if res.is_ok() {
                 break res;
             }
 
+            // and if it failed in a way that won't change, there's no point
+            let (fatal, retry_after, refused) = match res {
+                // a request that failed after the signal isn't retried
+                Err(_) if cancel::is_cancelled() => return Err(ErrorKind::Cancelled.into()),
+                Err(ref e) => match *e.kind() {
+                    ErrorKind::Fetch(FetchError::FatalStatus(_)) => (true, None, false),
+                    // it's going to be just as large the next time
+                    ErrorKind::Parse(ParseError::TooLarge(..)) => (true, None, false),
+                    ErrorKind::Fetch(FetchError::RetryableStatus(_, retry_after)) => (false, retry_after, false),
+                    ErrorKind::Fetch(FetchError::ConnectionRefused(_)) => (false, None, true),
+                    _ => (false, None, false),
+                },
+                Ok(_) => (false, None, false),
+            };
+            if fatal {
+                break res;
+            }
+
+            // a service that isn't listening yet is waited for separately
+            if let (true, Some(timeout)) = (refused, self.refused_timeout) {
+                let since = match first_refused {
+                    Some(since) => since,
+                    None => {
+                        if let Err(ref e) = res {
+                            info!("{}, waiting up to {} seconds for it to start listening", e, timeout.as_secs());
+                        }
+                        let now = Instant::now();
+                        first_refused = Some(now);
+                        now
+                    }
+                };
+                if since.elapsed() >= timeout {
+                    break res.map_err(|e| Error::with_chain(e, "timed out waiting for the connection to be accepted"));
+                }
+                cancel::sleep(Duration::from_secs(REFUSED_INTERVAL_SECS))?;
+                continue;
+            }
+
             // otherwise, perform the retry-backoff logic
             attempts += 1;
             if attempts == self.max_attempts {
                 break res.map_err(|e| Error::with_chain(e, "timed out"));
             }
 
-            thread::sleep(delay);
+            match retry_after {
+                Some(secs) => cancel::sleep(Duration::from_secs(secs.min(MAX_RETRY_AFTER_SECS)))?,
+                None => cancel::sleep(jitter(delay, rand::random()))?,
+            }
 
             delay = if self.max_backoff != Duration::new(0,0) && delay * 2 > self.max_backoff {
                 self.max_backoff
@@ -89,3 +192,71 @@ impl Retry {
         }
     }
 }
+
+/// jitter picks a delay between half of the given one and all of it, with r
+/// in [0, 1)
+fn jitter(delay: Duration, r: f64) -> Duration {
+    let millis = delay.as_secs() * 1000 + u64::from(delay.subsec_nanos()) / 1_000_000;
+    let half = millis / 2;
+    Duration::from_millis(half + ((millis - half) as f64 * r) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn retry_stops_on_fatal_status() {
+        let attempts = Cell::new(0);
+        let retry = Retry::new().initial_backoff(Duration::from_millis(1)).max_attempts(5);
+        let res: Result<()> = retry.clone().retry(|_| {
+            attempts.set(attempts.get() + 1);
+            Err(ErrorKind::Fetch(FetchError::FatalStatus(403)).into())
+        });
+        res.unwrap_err();
+        assert_eq!(attempts.get(), 1);
+
+        attempts.set(0);
+        let res: Result<()> = retry.retry(|_| {
+            attempts.set(attempts.get() + 1);
+            Err("failed to fetch: 503".into())
+        });
+        res.unwrap_err();
+        assert_eq!(attempts.get(), 5);
+    }
+
+    #[test]
+    fn retry_waits_for_refused_connections() {
+        let tries = Cell::new(0);
+        let refused = || -> Result<()> { Err(ErrorKind::Fetch(FetchError::ConnectionRefused("http://169.254.169.254".to_owned())).into()) };
+        // refusals don't use up the attempts
+        let res: Result<()> = Retry::new().max_attempts(1).refused_timeout(Duration::from_secs(10)).retry(|_| {
+            tries.set(tries.get() + 1);
+            if tries.get() < 3 {
+                refused()
+            } else {
+                Ok(())
+            }
+        });
+        res.unwrap();
+        assert_eq!(tries.get(), 3);
+
+        // until they run out of time of their own
+        tries.set(0);
+        let res: Result<()> = Retry::new().max_attempts(5).refused_timeout(Duration::from_secs(0)).retry(|_| {
+            tries.set(tries.get() + 1);
+            refused()
+        });
+        res.unwrap_err();
+        assert_eq!(tries.get(), 1);
+    }
+
+    #[test]
+    fn jitter_test() {
+        let delay = Duration::from_secs(4);
+        assert_eq!(jitter(delay, 0.0), Duration::from_secs(2));
+        assert_eq!(jitter(delay, 0.5), Duration::from_secs(3));
+        assert!(jitter(delay, 0.999) < delay);
+    }
+}
@@ -0,0 +1,129 @@
+// Copyright 2017 CoreOS, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! fetch progress
+//!
+//! programs embedding the library can follow the requests made to the
+//! metadata service, e.g. to show progress or keep metrics, by setting a
+//! FetchObserver. the observer is process-wide, so that it also sees the
+//! requests some providers make from threads of their own, e.g. to probe
+//! their endpoints, and is only called by one thread at a time. urls are
+//! redacted the same way they are in the logs.
+
+use std::cell::Cell;
+use std::io::{self, Read};
+use std::sync::Mutex;
+
+use errors::*;
+
+pub trait FetchObserver {
+    /// attempt_started is called before every attempt, counting from 1
+    fn attempt_started(&self, _url: &str, _attempt: u32) {}
+
+    /// attempt_failed is called for every failed attempt, whether or not it's
+    /// going to be retried
+    fn attempt_failed(&self, _url: &str, _attempt: u32, _error: &Error) {}
+
+    /// succeeded is called when a request succeeds, with the number of
+    /// attempts it took and the size of the response body. a resource that
+    /// the provider allows to be missing succeeds with no body.
+    fn succeeded(&self, _url: &str, _attempts: u32, _bytes: u64) {}
+}
+
+lazy_static! {
+    static ref OBSERVER: Mutex<Option<Box<FetchObserver + Send>>> = Mutex::new(None);
+}
+
+/// set_fetch_observer sets the observer for requests made on any thread, or
+/// unsets it with None
+pub fn set_fetch_observer(observer: Option<Box<FetchObserver + Send>>) {
+    *OBSERVER.lock().unwrap() = observer;
+}
+
+/// notify calls f with the observer, if there is one
+pub(crate) fn notify<F>(f: F)
+    where F: FnOnce(&FetchObserver)
+{
+    if let Some(ref observer) = *OBSERVER.lock().unwrap() {
+        f(&**observer);
+    }
+}
+
+/// CountingReader counts the bytes read through it
+pub(crate) struct CountingReader<'a, R> {
+    inner: R,
+    count: &'a Cell<u64>,
+}
+
+impl<'a, R: Read> CountingReader<'a, R> {
+    pub fn new(inner: R, count: &'a Cell<u64>) -> Self {
+        CountingReader { inner, count }
+    }
+}
+
+impl<'a, R: Read> Read for CountingReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count.set(self.count.get() + n as u64);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    /// Recorder records the events for the test's urls, since the observer
+    /// also sees the requests of tests running at the same time
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    impl FetchObserver for Recorder {
+        fn attempt_started(&self, url: &str, attempt: u32) {
+            if url.starts_with("http://observer-test") {
+                self.0.lock().unwrap().push(format!("start {} {}", url, attempt));
+            }
+        }
+        fn succeeded(&self, url: &str, attempts: u32, bytes: u64) {
+            if url.starts_with("http://observer-test") {
+                self.0.lock().unwrap().push(format!("done {} {} {}", url, attempts, bytes));
+            }
+        }
+    }
+
+    #[test]
+    fn observer_test() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        notify(|o| o.attempt_started("http://observer-test/a", 1));
+        set_fetch_observer(Some(Box::new(Recorder(events.clone()))));
+
+        let count = Cell::new(0);
+        let mut body = String::new();
+        CountingReader::new(&b"hostname"[..], &count).read_to_string(&mut body).unwrap();
+        notify(|o| o.attempt_started("http://observer-test/b", 1));
+        notify(|o| o.attempt_failed("http://observer-test/b", 1, &"oops".into()));
+        notify(|o| o.succeeded("http://observer-test/b", 2, count.get()));
+        // requests on other threads are seen too
+        thread::spawn(|| notify(|o| o.attempt_started("http://observer-test/c", 1))).join().unwrap();
+
+        set_fetch_observer(None);
+        notify(|o| o.attempt_started("http://observer-test/d", 1));
+        assert_eq!(*events.lock().unwrap(), vec![
+            "start http://observer-test/b 1",
+            "done http://observer-test/b 2 8",
+            "start http://observer-test/c 1",
+        ]);
+    }
+}
@@ -0,0 +1,55 @@
+// Copyright 2017 CoreOS, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! connection reuse
+//!
+//! every reqwest client has an event loop and a connection pool of its own,
+//! so building one per retry::Client meant a thread and a fresh connection
+//! for every handful of requests. the clients are shared instead, one per
+//! timeout, so that the many small requests some providers make (ec2 makes
+//! one per attribute) go over the same keep-alive connection. they're shared
+//! by the whole process, including the threads some providers probe their
+//! endpoints from. clones of a reqwest client share its pool.
+//!
+//! reqwest (through hyper 0.11) only speaks http/1.1, which is all the
+//! metadata services do anyway.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use reqwest;
+
+use errors::*;
+
+lazy_static! {
+    static ref CLIENTS: Mutex<HashMap<Duration, reqwest::Client>> = Mutex::new(HashMap::new());
+}
+
+fn build_client(timeout: Duration) -> Result<reqwest::Client> {
+    reqwest::ClientBuilder::new()
+        .and_then(|mut builder| builder.timeout(timeout).build())
+        .chain_err(|| "failed to initialize client")
+}
+
+/// client returns the client for the timeout, building it the first time
+pub(crate) fn client(timeout: Duration) -> Result<reqwest::Client> {
+    let mut clients = CLIENTS.lock().unwrap();
+    if let Some(client) = clients.get(&timeout) {
+        return Ok(client.clone());
+    }
+    let client = build_client(timeout)?;
+    clients.insert(timeout, client.clone());
+    Ok(client)
+}
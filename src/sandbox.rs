@@ -0,0 +1,376 @@
+// Copyright 2017 CoreOS, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! sandboxing of the fetch
+//!
+//! fetching is the part of a run that parses what the network hands it, and
+//! it doesn't need root for most providers. with the sandbox, the metadata
+//! is fetched by a child process (this program's `fetch`), which drops to an
+//! unprivileged user and installs a seccomp filter before it starts. the
+//! filter refuses the syscalls that would let a compromised parser reach
+//! beyond the process: mounting, loading modules, tracing, and the like. the
+//! child hands back a snapshot, and the outputs are written from it by the
+//! parent, which kept its privileges.
+//!
+//! the child also gets a mount namespace of its own, in which every mount is
+//! read-only except the directory the snapshot is written to. that's set up
+//! while it's still root, and the filter keeps it from being undone. it can
+//! still read whatever its user can, which the fetch needs (e.g.
+//! `/etc/resolv.conf` and the ca certificates).
+//!
+//! providers that mount a config drive or read root-only files can't fetch
+//! in the sandbox, and nothing is sent back to the platform from a snapshot
+//! (e.g. acknowledging a password).
+
+use std::env;
+use std::ffi::CString;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::Command;
+use std::ptr;
+
+use nix::libc;
+use nix::unistd::{chown, Gid, Uid};
+use tempdir::TempDir;
+
+use errors::*;
+use providers::snapshot::Snapshot;
+use util::useradd;
+
+/// the user the fetch runs as by default
+pub const DEFAULT_USER: &str = "nobody";
+
+const PR_SET_NO_NEW_PRIVS: libc::c_int = 38;
+const PR_SET_SECCOMP: libc::c_int = 22;
+const SECCOMP_MODE_FILTER: libc::c_ulong = 2;
+
+const SECCOMP_RET_KILL: u32 = 0;
+const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+
+/// BPF_LD | BPF_W | BPF_ABS
+const BPF_LD_W_ABS: u16 = 0x20;
+/// BPF_JMP | BPF_JEQ | BPF_K
+const BPF_JMP_JEQ_K: u16 = 0x15;
+/// BPF_JMP | BPF_JGE | BPF_K
+const BPF_JMP_JGE_K: u16 = 0x35;
+/// BPF_RET | BPF_K
+const BPF_RET_K: u16 = 0x06;
+
+/// offsets into struct seccomp_data
+const SECCOMP_DATA_NR: u32 = 0;
+const SECCOMP_DATA_ARCH: u32 = 4;
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_003e);
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_00b7);
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const AUDIT_ARCH: Option<u32> = None;
+
+/// x32 syscalls on x86_64 have this bit set, and would get around a filter
+/// that only knows the numbers of the native ones
+#[cfg(target_arch = "x86_64")]
+const X32_SYSCALL_BIT: Option<u32> = Some(0x4000_0000);
+#[cfg(not(target_arch = "x86_64"))]
+const X32_SYSCALL_BIT: Option<u32> = None;
+
+/// the io_uring syscalls, which libc doesn't have yet. they're numbered the
+/// same on every architecture.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+const SYS_IO_URING_SETUP: libc::c_long = 425;
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+const SYS_IO_URING_ENTER: libc::c_long = 426;
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+const SYS_IO_URING_REGISTER: libc::c_long = 427;
+
+/// the syscalls the fetch is refused. most of them fail without root anyway;
+/// the filter is for when a kernel bug would let them succeed. io_uring is
+/// refused because its operations aren't syscalls the filter sees. they're
+/// only looked up where there's a filter, since libc doesn't have all of
+/// them on every architecture.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+const DENIED_SYSCALLS: [libc::c_long; 28] = [
+    libc::SYS_mount,
+    libc::SYS_umount2,
+    libc::SYS_pivot_root,
+    libc::SYS_chroot,
+    libc::SYS_ptrace,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_kexec_load,
+    libc::SYS_init_module,
+    libc::SYS_finit_module,
+    libc::SYS_delete_module,
+    libc::SYS_reboot,
+    libc::SYS_swapon,
+    libc::SYS_swapoff,
+    libc::SYS_bpf,
+    libc::SYS_perf_event_open,
+    libc::SYS_keyctl,
+    libc::SYS_add_key,
+    libc::SYS_request_key,
+    libc::SYS_unshare,
+    libc::SYS_setns,
+    libc::SYS_acct,
+    libc::SYS_settimeofday,
+    libc::SYS_sethostname,
+    libc::SYS_setdomainname,
+    SYS_IO_URING_SETUP,
+    SYS_IO_URING_ENTER,
+    SYS_IO_URING_REGISTER,
+];
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const DENIED_SYSCALLS: [libc::c_long; 0] = [];
+
+/// the mount options that are kept when a mount is made read-only
+const KEPT_MOUNT_FLAGS: [(&str, libc::c_ulong); 3] = [
+    ("nosuid", libc::MS_NOSUID),
+    ("nodev", libc::MS_NODEV),
+    ("noexec", libc::MS_NOEXEC),
+];
+
+/// parse_mountinfo returns the mount points in the contents of
+/// `/proc/self/mountinfo`, with the flags to keep for each
+fn parse_mountinfo(contents: &str) -> Result<Vec<(String, libc::c_ulong)>> {
+    let mut mounts = Vec::new();
+    for line in contents.lines().filter(|l| !l.is_empty()) {
+        // id, parent id, major:minor, root, mount point, mount options, ...
+        let fields: Vec<&str> = line.split(' ').collect();
+        if fields.len() < 6 {
+            bail!("invalid mountinfo line {:?}", line);
+        }
+        let flags = fields[5].split(',')
+            .filter_map(|o| KEPT_MOUNT_FLAGS.iter().find(|&&(name, _)| name == o))
+            .fold(0, |flags, &(_, flag)| flags | flag);
+        mounts.push((unescape_mount_point(fields[4])?, flags));
+    }
+    Ok(mounts)
+}
+
+/// unescape_mount_point undoes the octal escapes mountinfo uses for spaces,
+/// tabs, newlines, and backslashes in mount points
+fn unescape_mount_point(escaped: &str) -> Result<String> {
+    let bytes = escaped.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 3 < bytes.len() && bytes[i + 1..i + 4].iter().all(|b| b'0' <= *b && *b <= b'7') {
+            let digits = &escaped[i + 1..i + 4];
+            out.push(u8::from_str_radix(digits, 8)
+                .chain_err(|| format!("invalid escape in mount point {:?}", escaped))?);
+            i += 4;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out)
+        .chain_err(|| format!("invalid mount point {:?}", escaped))
+}
+
+/// Confinement is what the child needs to make its mounts read-only. it's
+/// put together before the fork, since the child can't allocate.
+struct Confinement {
+    mounts: Vec<(CString, libc::c_ulong)>,
+    writable: CString,
+}
+
+impl Confinement {
+    fn new(writable: &Path) -> Result<Confinement> {
+        let mut contents = String::new();
+        File::open("/proc/self/mountinfo")
+            .and_then(|mut f| f.read_to_string(&mut contents))
+            .chain_err(|| "failed to read /proc/self/mountinfo")?;
+        let mut mounts = Vec::new();
+        for (point, flags) in parse_mountinfo(&contents)? {
+            mounts.push((CString::new(point).chain_err(|| "invalid mount point")?, flags));
+        }
+        Ok(Confinement {
+            mounts,
+            writable: CString::new(writable.as_os_str().as_bytes())
+                .chain_err(|| format!("invalid path {:?}", writable))?,
+        })
+    }
+
+    /// enter unshares the mount namespace, and makes every mount in it
+    /// read-only except for a bind mount of the writable directory. the
+    /// mounts are made private first, so that none of this propagates back.
+    fn enter(&self) -> io::Result<()> {
+        let root = b"/\0";
+        let check = |res: libc::c_int| if res == 0 { Ok(()) } else { Err(io::Error::last_os_error()) };
+        unsafe {
+            check(libc::unshare(libc::CLONE_NEWNS))?;
+            check(libc::mount(ptr::null(), root.as_ptr() as *const libc::c_char, ptr::null(),
+                              libc::MS_REC | libc::MS_PRIVATE, ptr::null()))?;
+            // a bind mount starts out read-only if what it binds is, so
+            // it's made before the rest
+            check(libc::mount(self.writable.as_ptr(), self.writable.as_ptr(), ptr::null(),
+                              libc::MS_BIND, ptr::null()))?;
+            for &(ref point, flags) in &self.mounts {
+                check(libc::mount(ptr::null(), point.as_ptr(), ptr::null(),
+                                  libc::MS_REMOUNT | libc::MS_BIND | libc::MS_RDONLY | flags, ptr::null()))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// SockFilter is struct sock_filter, one bpf instruction
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct SockFilter {
+    code: u16,
+    jt: u8,
+    jf: u8,
+    k: u32,
+}
+
+/// SockFprog is struct sock_fprog
+#[repr(C)]
+struct SockFprog {
+    len: u16,
+    filter: *const SockFilter,
+}
+
+fn statement(code: u16, k: u32) -> SockFilter {
+    SockFilter { code, jt: 0, jf: 0, k }
+}
+
+fn jump(code: u16, k: u32, jt: u8, jf: u8) -> SockFilter {
+    SockFilter { code, jt, jf, k }
+}
+
+/// filter is the seccomp program that refuses the denied syscalls with
+/// EPERM, and kills the process if it makes syscalls of another
+/// architecture. there's none for architectures it doesn't know.
+fn filter() -> Option<Vec<SockFilter>> {
+    let arch = AUDIT_ARCH?;
+    let eperm = SECCOMP_RET_ERRNO | libc::EPERM as u32;
+    let mut program = vec![
+        statement(BPF_LD_W_ABS, SECCOMP_DATA_ARCH),
+        jump(BPF_JMP_JEQ_K, arch, 1, 0),
+        statement(BPF_RET_K, SECCOMP_RET_KILL),
+        statement(BPF_LD_W_ABS, SECCOMP_DATA_NR),
+    ];
+    if let Some(bit) = X32_SYSCALL_BIT {
+        program.push(jump(BPF_JMP_JGE_K, bit, 0, 1));
+        program.push(statement(BPF_RET_K, eperm));
+    }
+    for &nr in DENIED_SYSCALLS.iter() {
+        program.push(jump(BPF_JMP_JEQ_K, nr as u32, 0, 1));
+        program.push(statement(BPF_RET_K, eperm));
+    }
+    program.push(statement(BPF_RET_K, SECCOMP_RET_ALLOW));
+    Some(program)
+}
+
+/// enter confines the mounts, drops to the user, and installs the filter.
+/// it runs in the child between fork and exec, so it only makes syscalls.
+fn enter(uid: libc::uid_t, gid: libc::gid_t, confinement: &Confinement, program: &[SockFilter]) -> io::Result<()> {
+    let (on, unused): (libc::c_ulong, libc::c_ulong) = (1, 0);
+    confinement.enter()?;
+    unsafe {
+        if libc::setgroups(0, ptr::null()) != 0
+            || libc::setgid(gid) != 0
+            || libc::setuid(uid) != 0
+            || libc::prctl(PR_SET_NO_NEW_PRIVS, on, unused, unused, unused) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let prog = SockFprog {
+            len: program.len() as u16,
+            filter: program.as_ptr(),
+        };
+        if libc::prctl(PR_SET_SECCOMP, SECCOMP_MODE_FILTER, &prog as *const SockFprog as libc::c_ulong) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// fetch runs the command, which is expected to be this program's `fetch`,
+/// in the sandbox as the user, and returns the snapshot it took. the
+/// command is given the `--out` to write it to.
+pub fn fetch(mut command: Command, user: &str) -> Result<Snapshot> {
+    let entry = useradd::lookup_user(Path::new("/"), user)?
+        .ok_or_else(|| format!("no user {} to fetch as", user))?;
+    if entry.uid == 0 {
+        bail!("refusing to sandbox the fetch as {}, which is root", user);
+    }
+    let program = filter()
+        .ok_or_else(|| ErrorKind::SandboxUnsupported(env::consts::ARCH.to_owned()))?;
+
+    // the snapshot can hold secrets, so only the user can get at it
+    let dir = TempDir::new("coreos-metadata-sandbox")
+        .chain_err(|| "failed to create a directory for the sandboxed fetch")?;
+    fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o700))
+        .chain_err(|| format!("failed to set permissions on {:?}", dir.path()))?;
+    chown(dir.path(), Some(Uid::from_raw(entry.uid)), Some(Gid::from_raw(entry.gid)))
+        .chain_err(|| format!("failed to set owner of {:?}", dir.path()))?;
+    let out = dir.path().join("snapshot.json");
+    let confinement = Confinement::new(dir.path())?;
+
+    let (uid, gid) = (entry.uid, entry.gid);
+    command.arg("--out").arg(&out);
+    command.before_exec(move || enter(uid, gid, &confinement, &program));
+    info!("fetching metadata in a sandbox as {}", user);
+    let status = command.status()
+        .chain_err(|| "failed to run the sandboxed fetch")?;
+    if !status.success() {
+        bail!("sandboxed fetch failed: {}", status);
+    }
+    Snapshot::read_from(&out.to_string_lossy())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    fn filter_test() {
+        let program = filter().unwrap();
+        // every denied syscall is a jump and a return, and the program ends
+        // by allowing everything else
+        let denied = program.iter()
+            .filter(|i| i.code == BPF_RET_K && i.k == SECCOMP_RET_ERRNO | libc::EPERM as u32)
+            .count();
+        assert_eq!(denied, DENIED_SYSCALLS.len() + X32_SYSCALL_BIT.map_or(0, |_| 1));
+        assert_eq!(program.last(), Some(&statement(BPF_RET_K, SECCOMP_RET_ALLOW)));
+        for &nr in &[libc::SYS_process_vm_readv, SYS_IO_URING_SETUP] {
+            assert!(program.contains(&jump(BPF_JMP_JEQ_K, nr as u32, 0, 1)));
+        }
+        assert!(program.len() < 4096);
+    }
+
+    #[test]
+    fn parse_mountinfo_test() {
+        let mounts = parse_mountinfo("\
+22 1 0:21 / / rw,relatime shared:1 - ext4 /dev/sda9 rw,seclabel
+23 22 0:5 / /dev rw,nosuid shared:2 - devtmpfs devtmpfs rw,size=4096k
+24 22 0:22 / /run/media/core/my\\040disk rw,nosuid,nodev,noexec,relatime shared:3 - vfat /dev/sdb1 rw
+").unwrap();
+        assert_eq!(mounts, vec![
+            ("/".to_owned(), 0),
+            ("/dev".to_owned(), libc::MS_NOSUID),
+            ("/run/media/core/my disk".to_owned(), libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC),
+        ]);
+        assert!(parse_mountinfo("22 1 0:21 /").is_err());
+    }
+}
@@ -0,0 +1,189 @@
+// Copyright 2017 CoreOS, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! stamp records which outputs have been successfully written, so that a
+//! restarted unit doesn't redo work (or clobber changes made since). each
+//! output is recorded separately, so after a partial failure only the outputs
+//! that failed are retried. outputs are recorded with where they were written
+//! (or whatever else they were written for, e.g. the user of the ssh keys),
+//! so an output that's pointed somewhere else is written again.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, ErrorKind as IoErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use audit;
+use errors::*;
+
+#[derive(Debug)]
+pub struct Stamp {
+    path: Option<PathBuf>,
+    force: bool,
+    /// the outputs that were written, and their targets
+    done: BTreeMap<String, String>,
+}
+
+impl Stamp {
+    /// load reads the stamp file at the given path, if there is one. with no
+    /// path, nothing is ever considered done and nothing is recorded.
+    pub fn load(path: Option<&str>, force: bool) -> Result<Stamp> {
+        let path = path.map(PathBuf::from);
+        let mut done = BTreeMap::new();
+
+        if let Some(ref path) = path {
+            match File::open(path) {
+                Ok(file) => {
+                    for line in BufReader::new(file).lines() {
+                        let line = line
+                            .chain_err(|| format!("failed to read stamp file {:?}", path))?;
+                        let line = line.trim();
+                        if line.is_empty() {
+                            continue;
+                        }
+                        let mut parts = line.splitn(2, '\t');
+                        match (parts.next(), parts.next()) {
+                            (Some(output), Some(target)) => {
+                                done.insert(output.to_owned(), target.to_owned());
+                            }
+                            _ => return Err(format!("invalid line in stamp file {:?}: {}", path, line).into()),
+                        }
+                    }
+                }
+                Err(ref e) if e.kind() == IoErrorKind::NotFound => {}
+                Err(e) => return Err(Error::with_chain(e, format!("failed to open stamp file {:?}", path))),
+            }
+        }
+
+        Ok(Stamp { path, force, done })
+    }
+
+    /// is_done returns true if the output was already written to the target
+    pub fn is_done(&self, output: &str, target: &str) -> bool {
+        if self.force {
+            return false;
+        }
+        self.done.get(output).map_or(false, |done| done == target)
+    }
+
+    /// all_done returns true if every one of the given outputs has already
+    /// been written to its target by a previous run
+    pub fn all_done(&self, outputs: &[(&str, String)]) -> bool {
+        self.path.is_some() && outputs.iter().all(|&(o, ref t)| self.is_done(o, t))
+    }
+
+    /// run calls f unless the output has already been written to the target,
+    /// and records the output as written there if f succeeds
+    pub fn run<F>(&mut self, output: &str, target: &str, f: F) -> Result<()>
+        where F: FnOnce() -> Result<()>
+    {
+        if self.is_done(output, target) {
+            info!("{} already written by a previous run, skipping", output);
+            return Ok(());
+        }
+        if let Some(done) = self.done.get(output) {
+            info!("{} was written for {}, writing it for {}", output, done, target);
+        }
+        f()?;
+        self.mark_done(output, target)
+    }
+
+    /// reset forgets every output written so far, so they are all written
+    /// again, including by later runs if this one fails
+    pub fn reset(&mut self) -> Result<()> {
+        self.done.clear();
+        self.save()
+    }
+
+    fn mark_done(&mut self, output: &str, target: &str) -> Result<()> {
+        self.done.insert(output.to_owned(), target.to_owned());
+        self.save()
+    }
+
+    fn save(&self) -> Result<()> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+
+        // write the new stamp next to the old one and rename it into place,
+        // so a crash never leaves a stamp that lists outputs that weren't
+        // written
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .chain_err(|| format!("failed to create directory {:?}", dir))?;
+        }
+        let tmp_path = Path::new(&format!("{}.tmp", path.display())).to_owned();
+        {
+            let mut tmp = File::create(&tmp_path)
+                .chain_err(|| format!("failed to create file {:?}", tmp_path))?;
+            for (o, t) in &self.done {
+                writeln!(tmp, "{}\t{}", o, t)
+                    .chain_err(|| format!("failed to write file {:?}", tmp_path))?;
+            }
+            tmp.sync_all()
+                .chain_err(|| format!("failed to sync file {:?}", tmp_path))?;
+        }
+        fs::rename(&tmp_path, path)
+            .chain_err(|| format!("failed to rename {:?} to {:?}", tmp_path, path))?;
+        audit::wrote(path);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn stamp_roundtrip() {
+        let dir = TempDir::new("coreos-metadata-test").unwrap();
+        let path = dir.path().join("sub").join("stamp");
+        let path = path.to_str().unwrap();
+
+        let attributes = ("attributes", "/run/metadata/coreos".to_owned());
+        let hostname = ("hostname", "/etc/hostname".to_owned());
+        let mut stamp = Stamp::load(Some(path), false).unwrap();
+        assert!(!stamp.all_done(&[attributes.clone()]));
+        stamp.run("attributes", &attributes.1, || Ok(())).unwrap();
+        stamp.run("hostname", &hostname.1, || Err("nope".into())).unwrap_err();
+
+        let stamp = Stamp::load(Some(path), false).unwrap();
+        assert!(stamp.is_done("attributes", "/run/metadata/coreos"));
+        assert!(!stamp.is_done("hostname", "/etc/hostname"));
+        assert!(stamp.all_done(&[attributes.clone()]));
+        assert!(!stamp.all_done(&[attributes.clone(), hostname]));
+        // moving an output writes it again
+        assert!(!stamp.is_done("attributes", "/run/metadata/attributes"));
+
+        let stamp = Stamp::load(Some(path), true).unwrap();
+        assert!(!stamp.is_done("attributes", "/run/metadata/coreos"));
+    }
+
+    #[test]
+    fn stamp_invalid_line() {
+        let dir = TempDir::new("coreos-metadata-test").unwrap();
+        let path = dir.path().join("stamp");
+        File::create(&path).unwrap().write_all(b"attributes\n").unwrap();
+        Stamp::load(path.to_str(), false).unwrap_err();
+    }
+
+    #[test]
+    fn stamp_without_path() {
+        let mut stamp = Stamp::load(None, false).unwrap();
+        stamp.run("attributes", "/run/metadata/coreos", || Ok(())).unwrap();
+        assert!(!stamp.all_done(&[("attributes", "/run/metadata/coreos".to_owned())]));
+    }
+}
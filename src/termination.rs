@@ -0,0 +1,103 @@
+// Copyright 2017 CoreOS, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! watching for the platform's notice that the instance is going away
+//!
+//! preemptible and spot instances are told shortly before they're taken
+//! away. the notice is written out as attributes, and can run a command, so
+//! that workloads get to drain before the instance is shut down.
+
+use std::path::Path;
+use std::process::Command;
+use std::time::Duration;
+
+use cancel;
+use errors::*;
+use providers::{self, MetadataProvider, TerminationNotice};
+
+/// how often the provider is asked for a notice by default
+pub const DEFAULT_POLL_INTERVAL_SECS: u64 = 5;
+
+/// watch polls the provider until it gives notice. the first poll has to
+/// succeed, so that platforms without notices fail right away; later
+/// failures are only logged, since a metadata service that's briefly
+/// unavailable doesn't mean the instance is going away.
+pub fn watch(provider: &MetadataProvider, interval: Duration) -> Result<TerminationNotice> {
+    let mut first = true;
+    loop {
+        match provider.termination_notice() {
+            Ok(Some(notice)) => return Ok(notice),
+            Ok(None) => {}
+            Err(e) => {
+                if first || cancel::is_cancelled() {
+                    return Err(e);
+                }
+                warn!("failed to check for a termination notice: {}", e);
+            }
+        }
+        first = false;
+        cancel::sleep(interval)?;
+    }
+}
+
+/// attributes returns the notice as attributes
+pub fn attributes(notice: &TerminationNotice) -> Vec<(String, String)> {
+    let mut attributes = vec![("COREOS_TERMINATION_NOTICE".to_owned(), notice.reason.clone())];
+    if let Some(ref not_before) = notice.not_before {
+        attributes.push(("COREOS_TERMINATION_TIME".to_owned(), not_before.clone()));
+    }
+    attributes
+}
+
+/// write_notice writes the notice to the file in the same format as the
+/// attributes file
+pub fn write_notice(path: &str, notice: &TerminationNotice) -> Result<()> {
+    providers::write_attributes_file(Path::new(path), &attributes(notice))
+        .chain_err(|| format!("failed to write termination notice to {}", path))
+}
+
+/// exec runs the command with the shell, with the notice's attributes in
+/// its environment, and waits for it to finish
+pub fn exec(command: &str, notice: &TerminationNotice) -> Result<()> {
+    let status = Command::new("/bin/sh")
+        .arg("-c")
+        .arg(command)
+        .envs(attributes(notice))
+        .status()
+        .chain_err(|| format!("failed to run {:?}", command))?;
+    if !status.success() {
+        bail!("{:?} failed: {}", command, status);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attributes_test() {
+        let notice = TerminationNotice {
+            reason: "terminate".to_owned(),
+            not_before: Some("2017-09-18T08:22:00Z".to_owned()),
+        };
+        assert_eq!(attributes(&notice), vec![
+            ("COREOS_TERMINATION_NOTICE".to_owned(), "terminate".to_owned()),
+            ("COREOS_TERMINATION_TIME".to_owned(), "2017-09-18T08:22:00Z".to_owned()),
+        ]);
+
+        let notice = TerminationNotice { reason: "preempted".to_owned(), not_before: None };
+        assert_eq!(attributes(&notice).len(), 1);
+    }
+}
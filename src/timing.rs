@@ -0,0 +1,250 @@
+// Copyright 2017 CoreOS, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! where the time of a run goes
+//!
+//! a slow boot is usually one slow request, or one output waiting on
+//! something. the run records how long each phase took (finding the
+//! provider, waiting for the network, fetching, and each output), and a
+//! FetchTimer records how long each request took, retries included, so that
+//! it can all be logged as one line at the end. with status updates on, the
+//! phase that's running is also the unit's status in `systemctl status`.
+//! like the fetch observer, everything is kept for the whole process, so
+//! that the endpoint probes some providers make from threads of their own
+//! are counted too.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::env;
+use std::os::unix::net::UnixDatagram;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use errors::*;
+use retry::FetchObserver;
+
+/// how many of the slowest requests the summary names
+const SLOWEST_REQUESTS: usize = 3;
+
+static STATUS_UPDATES: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref PHASES: Mutex<Vec<(String, Duration)>> = Mutex::new(Vec::new());
+    static ref REQUESTS: Mutex<Vec<Request>> = Mutex::new(Vec::new());
+}
+
+/// Request is how long a request took, from its first attempt to its last
+#[derive(Clone, Debug, PartialEq)]
+struct Request {
+    url: String,
+    duration: Duration,
+    attempts: u32,
+    succeeded: bool,
+}
+
+/// set_status_updates turns on telling systemd which phase is running, if
+/// the unit has a notify socket
+pub fn set_status_updates(enabled: bool) {
+    STATUS_UPDATES.store(enabled, Ordering::SeqCst);
+}
+
+/// phase runs f as the named phase, and records how long it took
+pub fn phase<T, F>(name: &str, f: F) -> T
+    where F: FnOnce() -> T
+{
+    status(name);
+    let start = Instant::now();
+    let res = f();
+    let elapsed = start.elapsed();
+    debug!("{} took {}", name, seconds(elapsed));
+    PHASES.lock().unwrap().push((name.to_owned(), elapsed));
+    res
+}
+
+/// FetchTimer is a FetchObserver that records how long each request takes.
+/// a request that's still failing is recorded as of its last attempt.
+#[derive(Debug, Default)]
+pub struct FetchTimer {
+    /// the requests in progress, with when they started and where they are
+    /// recorded once an attempt has finished
+    started: RefCell<HashMap<String, (Instant, Option<usize>)>>,
+}
+
+impl FetchTimer {
+    fn finished(&self, url: &str, attempts: u32, succeeded: bool) {
+        let mut started = self.started.borrow_mut();
+        let (start, index) = match started.get(url) {
+            Some(&entry) => entry,
+            None => return,
+        };
+        let request = Request {
+            url: url.to_owned(),
+            duration: start.elapsed(),
+            attempts,
+            succeeded,
+        };
+        let index = {
+            let mut requests = REQUESTS.lock().unwrap();
+            match index {
+                Some(i) => {
+                    requests[i] = request;
+                    i
+                }
+                None => {
+                    requests.push(request);
+                    requests.len() - 1
+                }
+            }
+        };
+        if succeeded {
+            started.remove(url);
+        } else {
+            started.insert(url.to_owned(), (start, Some(index)));
+        }
+    }
+}
+
+impl FetchObserver for FetchTimer {
+    fn attempt_started(&self, url: &str, attempt: u32) {
+        if attempt == 1 {
+            self.started.borrow_mut().insert(url.to_owned(), (Instant::now(), None));
+        }
+    }
+
+    fn attempt_failed(&self, url: &str, attempt: u32, _error: &Error) {
+        self.finished(url, attempt, false);
+    }
+
+    fn succeeded(&self, url: &str, attempts: u32, _bytes: u64) {
+        self.finished(url, attempts, true);
+    }
+}
+
+/// summary is the phases so far and the slowest requests, as one line
+pub fn summary() -> String {
+    let phases = PHASES.lock().unwrap().clone();
+    let mut requests = REQUESTS.lock().unwrap().clone();
+    summarize(&phases, &mut requests)
+}
+
+fn summarize(phases: &[(String, Duration)], requests: &mut Vec<Request>) -> String {
+    let total = phases.iter().fold(Duration::from_secs(0), |total, &(_, d)| total + d);
+    let mut line = format!("took {}", seconds(total));
+    if !phases.is_empty() {
+        line.push_str(": ");
+        line.push_str(&phases.iter()
+            .map(|&(ref name, d)| format!("{} {}", name, seconds(d)))
+            .collect::<Vec<_>>()
+            .join(", "));
+    }
+    if !requests.is_empty() {
+        requests.sort_by(|a, b| b.duration.cmp(&a.duration));
+        line.push_str("; slowest requests: ");
+        line.push_str(&requests.iter()
+            .take(SLOWEST_REQUESTS)
+            .map(|r| {
+                let mut s = format!("{} {}", r.url, seconds(r.duration));
+                match (r.attempts, r.succeeded) {
+                    (1, true) => {}
+                    (n, true) => s.push_str(&format!(" ({} attempts)", n)),
+                    (n, false) => s.push_str(&format!(" (failed after {} attempts)", n)),
+                }
+                s
+            })
+            .collect::<Vec<_>>()
+            .join(", "));
+    }
+    line
+}
+
+/// status tells systemd what the run is doing, if status updates are on
+/// and there's someone to tell
+pub fn status(status: &str) {
+    if !STATUS_UPDATES.load(Ordering::SeqCst) {
+        return;
+    }
+    if let Err(e) = notify(&format!("STATUS={}", status)) {
+        debug!("failed to update the unit's status: {}", e);
+    }
+}
+
+/// notify sends the message to the notify socket the way sd_notify does.
+/// sockets in the abstract namespace aren't supported.
+fn notify(message: &str) -> Result<()> {
+    let path = match env::var("NOTIFY_SOCKET") {
+        Ok(path) => path,
+        Err(_) => return Ok(()),
+    };
+    if !path.starts_with('/') {
+        bail!("unsupported notify socket {:?}", path);
+    }
+    let socket = UnixDatagram::unbound()
+        .chain_err(|| "failed to create notify socket")?;
+    socket.send_to(message.as_bytes(), &path)
+        .chain_err(|| format!("failed to send to notify socket {:?}", path))?;
+    Ok(())
+}
+
+fn seconds(d: Duration) -> String {
+    format!("{:.3}s", d.as_secs() as f64 + f64::from(d.subsec_nanos()) / 1e9)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summarize_test() {
+        let phases = vec![
+            ("detection".to_owned(), Duration::from_millis(20)),
+            ("fetch".to_owned(), Duration::from_millis(19_500)),
+            ("attributes".to_owned(), Duration::from_millis(1)),
+        ];
+        let request = |url: &str, millis, attempts, succeeded| Request {
+            url: url.to_owned(),
+            duration: Duration::from_millis(millis),
+            attempts,
+            succeeded,
+        };
+        let mut requests = vec![
+            request("http://169.254.169.254/a", 10, 1, true),
+            request("http://169.254.169.254/b", 12_000, 4, true),
+            request("http://169.254.169.254/c", 7_000, 3, false),
+            request("http://169.254.169.254/d", 5, 1, true),
+        ];
+        assert_eq!(summarize(&phases, &mut requests),
+                   "took 19.521s: detection 0.020s, fetch 19.500s, attributes 0.001s; \
+                    slowest requests: http://169.254.169.254/b 12.000s (4 attempts), \
+                    http://169.254.169.254/c 7.000s (failed after 3 attempts), http://169.254.169.254/a 0.010s");
+        assert_eq!(summarize(&[], &mut vec![]), "took 0.000s");
+    }
+
+    #[test]
+    fn fetch_timer_test() {
+        let timer = FetchTimer::default();
+        timer.attempt_started("http://fetch-timer-test/a", 1);
+        timer.attempt_failed("http://fetch-timer-test/a", 1, &"oops".into());
+        timer.attempt_started("http://fetch-timer-test/a", 2);
+        timer.succeeded("http://fetch-timer-test/a", 2, 8);
+        timer.succeeded("http://fetch-timer-test/b", 1, 8);
+        // the log is shared with the other tests
+        let requests: Vec<_> = REQUESTS.lock().unwrap().iter()
+            .filter(|r| r.url.starts_with("http://fetch-timer-test/"))
+            .cloned()
+            .collect();
+        assert_eq!(requests.len(), 1);
+        assert_eq!((requests[0].attempts, requests[0].succeeded), (2, true));
+    }
+}
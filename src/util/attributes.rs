@@ -0,0 +1,315 @@
+// Copyright 2017 CoreOS, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! attribute name sanitization
+//!
+//! attribute names end up as environment variable names, so anything that
+//! comes from user-controlled metadata (tags, labels, custom keys) has to be
+//! converted before it can be used as part of one. the rules are:
+//!
+//!   - ascii letters are uppercased
+//!   - ascii digits and underscores are kept as-is
+//!   - every other character (including each byte of non-ascii characters)
+//!     is replaced with an underscore
+//!   - the full name (including the prefix) is truncated to
+//!     `MAX_NAME_LENGTH` characters
+//!   - if two different keys map to the same name, the later one gets a
+//!     `_2`, `_3`, ... suffix and a warning is logged
+//!
+//! attributes are written out prefixed with `COREOS_`, and optionally under
+//! the names afterburn uses as well, or renamed with a table of names.
+//!
+//! providers that fetch their attributes one at a time record what became
+//! of each one, so that an attribute that couldn't be fetched only fails
+//! the outputs when it's required.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+use errors::*;
+
+pub const MAX_NAME_LENGTH: usize = 128;
+
+/// provider prefixes that afterburn names after the platform instead
+const AFTERBURN_PREFIXES: [(&str, &str); 2] = [
+    ("EC2_", "AWS_"),
+    ("GCE_", "GCP_"),
+];
+
+/// sanitize converts a single key into a valid environment variable name
+/// fragment, following the rules in the module documentation.
+pub fn sanitize(key: &str) -> String {
+    key.bytes()
+        .map(|b| match b {
+            b'a'..=b'z' => (b - b'a' + b'A') as char,
+            b'A'..=b'Z' | b'0'..=b'9' | b'_' => b as char,
+            _ => '_',
+        })
+        .collect()
+}
+
+/// quote_value double-quotes a value for an environment.d file, escaping
+/// the characters that are special inside double quotes. note that systemd
+/// still expands `${NAME}` in environment.d values after unquoting them.
+pub fn quote_value(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' | '\\' | '`' | '$' => out.push('\\'),
+            _ => {}
+        }
+        out.push(c);
+    }
+    out.push('"');
+    out
+}
+
+/// afterburn_name is the name afterburn gives the attribute
+fn afterburn_name(name: &str) -> String {
+    for &(prefix, replacement) in &AFTERBURN_PREFIXES {
+        if name.starts_with(prefix) {
+            return format!("AFTERBURN_{}{}", replacement, &name[prefix.len()..]);
+        }
+    }
+    format!("AFTERBURN_{}", name)
+}
+
+/// AttributeNaming is the names attributes are written out with
+#[derive(Clone, Debug, Default)]
+pub struct AttributeNaming {
+    /// also write every attribute with its `AFTERBURN_` name
+    pub afterburn: bool,
+    /// full names, e.g. `COREOS_EC2_REGION`, to the names they are written
+    /// as instead
+    pub renames: HashMap<String, String>,
+}
+
+impl AttributeNaming {
+    /// read_renames reads a rename table, with a `<name>=<new name>` line per
+    /// attribute. empty lines and lines starting with `#` are skipped.
+    pub fn read_renames(path: &str) -> Result<HashMap<String, String>> {
+        let file = File::open(path)
+            .chain_err(|| format!("failed to open rename table {:?}", path))?;
+        let mut renames = HashMap::new();
+        for line in BufReader::new(file).lines() {
+            let line = line.chain_err(|| format!("failed to read rename table {:?}", path))?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(new_name)) if !new_name.trim().is_empty() => {
+                    renames.insert(name.trim().to_owned(), new_name.trim().to_owned());
+                }
+                _ => return Err(format!("invalid line in rename table {:?}: {}", path, line).into()),
+            }
+        }
+        Ok(renames)
+    }
+
+    /// apply returns the attributes under the names they are written as,
+    /// sorted so that files are the same every time
+    pub fn apply(&self, attributes: HashMap<String, String>) -> Vec<(String, String)> {
+        let mut out = Vec::with_capacity(attributes.len());
+        for (k, v) in attributes {
+            let name = format!("COREOS_{}", k);
+            if self.afterburn {
+                out.push((afterburn_name(&k), v.clone()));
+            }
+            out.push((self.renames.get(&name).cloned().unwrap_or(name), v));
+        }
+        out.sort();
+        out
+    }
+}
+
+/// AttributeNamer hands out unique attribute names for a set of keys that
+/// share a prefix, e.g. all of the tags on an instance.
+#[derive(Clone, Debug)]
+pub struct AttributeNamer {
+    prefix: String,
+    // sanitized name -> original key
+    names: HashMap<String, String>,
+}
+
+impl AttributeNamer {
+    pub fn new(prefix: &str) -> Self {
+        AttributeNamer {
+            prefix: sanitize(prefix),
+            names: HashMap::new(),
+        }
+    }
+
+    pub fn name_for(&mut self, key: &str) -> String {
+        let mut base = format!("{}{}", self.prefix, sanitize(key));
+        // names can't start with a digit
+        if base.starts_with(|c: char| c.is_ascii_digit()) {
+            base.insert(0, '_');
+        }
+        base.truncate(MAX_NAME_LENGTH);
+
+        let mut name = base.clone();
+        let mut n = 1;
+        loop {
+            match self.names.get(&name) {
+                Some(existing) if existing == key => return name,
+                Some(_) => {}
+                None => break,
+            }
+            n += 1;
+            let suffix = format!("_{}", n);
+            let mut candidate = base.clone();
+            candidate.truncate(MAX_NAME_LENGTH - suffix.len());
+            candidate.push_str(&suffix);
+            name = candidate;
+        }
+
+        if n > 1 {
+            warn!("attribute name collision for key {:?}, using {}", key, name);
+        }
+        self.names.insert(name.clone(), key.to_owned());
+        name
+    }
+}
+
+/// Provenance is what became of an attribute the provider looked for
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Provenance {
+    Fetched,
+    /// the provider doesn't supply it, e.g. a public address on an instance
+    /// without one
+    Absent,
+    /// fetching it failed, with the error
+    Failed(String),
+}
+
+/// check_required fails if a required attribute (by its full name, e.g.
+/// `COREOS_EC2_IPV4_LOCAL`) isn't in the attributes, and logs the ones that
+/// couldn't be fetched. without a list of required attributes, every
+/// attribute that couldn't be fetched is required, as it has always been.
+pub fn check_required(attributes: &HashMap<String, String>, provenance: &BTreeMap<String, Provenance>,
+                      required: Option<&[String]>) -> Result<()> {
+    for (key, p) in provenance {
+        if let Provenance::Failed(ref e) = *p {
+            if required.is_none() {
+                return Err(format!("failed to fetch attribute COREOS_{}: {}", key, e).into());
+            }
+            warn!("failed to fetch attribute COREOS_{}, leaving it out: {}", key, e);
+        }
+    }
+    for name in required.unwrap_or(&[]) {
+        let key = name.trim_left_matches("COREOS_");
+        if attributes.contains_key(key) {
+            continue;
+        }
+        return Err(match provenance.get(key) {
+            Some(&Provenance::Failed(ref e)) => format!("failed to fetch required attribute {}: {}", name, e),
+            Some(_) => format!("required attribute {} isn't supplied by the provider", name),
+            None => format!("required attribute {} isn't an attribute of the provider", name),
+        }.into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitize_test() {
+        let tests = vec![
+            ("foo", "FOO"),
+            ("Foo_Bar9", "FOO_BAR9"),
+            ("kubernetes.io/role", "KUBERNETES_IO_ROLE"),
+            ("with space", "WITH_SPACE"),
+            ("ü", "__"),
+            ("", ""),
+        ];
+        for (key, expected) in tests {
+            assert_eq!(sanitize(key), expected);
+        }
+    }
+
+    #[test]
+    fn quote_value_test() {
+        assert_eq!(quote_value("us-east-1"), r#""us-east-1""#);
+        assert_eq!(quote_value(r#"a "b" $c \d"#), r#""a \"b\" \$c \\d""#);
+    }
+
+    #[test]
+    fn naming_test() {
+        let attributes: HashMap<String, String> = vec![
+            ("EC2_REGION".to_owned(), "us-east-1".to_owned()),
+            ("AZURE_VMSIZE".to_owned(), "Standard_B1s".to_owned()),
+        ].into_iter().collect();
+        let naming = AttributeNaming {
+            afterburn: true,
+            renames: vec![("COREOS_AZURE_VMSIZE".to_owned(), "VM_SIZE".to_owned())].into_iter().collect(),
+        };
+        assert_eq!(naming.apply(attributes), vec![
+            ("AFTERBURN_AWS_REGION".to_owned(), "us-east-1".to_owned()),
+            ("AFTERBURN_AZURE_VMSIZE".to_owned(), "Standard_B1s".to_owned()),
+            ("COREOS_EC2_REGION".to_owned(), "us-east-1".to_owned()),
+            ("VM_SIZE".to_owned(), "Standard_B1s".to_owned()),
+        ]);
+    }
+
+    #[test]
+    fn namer_collisions() {
+        let mut namer = AttributeNamer::new("azure_tag_");
+        assert_eq!(namer.name_for("env"), "AZURE_TAG_ENV");
+        assert_eq!(namer.name_for("Env"), "AZURE_TAG_ENV_2");
+        assert_eq!(namer.name_for("e.n.v"), "AZURE_TAG_E_N_V");
+        assert_eq!(namer.name_for("ENV"), "AZURE_TAG_ENV_3");
+        // the same key always gets the same name
+        assert_eq!(namer.name_for("Env"), "AZURE_TAG_ENV_2");
+    }
+
+    #[test]
+    fn namer_leading_digit() {
+        let mut namer = AttributeNamer::new("");
+        assert_eq!(namer.name_for("1st"), "_1ST");
+    }
+
+    #[test]
+    fn namer_length_limit() {
+        let mut namer = AttributeNamer::new("TAG_");
+        let long = "x".repeat(2 * MAX_NAME_LENGTH);
+        let first = namer.name_for(&long);
+        assert_eq!(first.len(), MAX_NAME_LENGTH);
+        let second = namer.name_for(&format!("{}y", long));
+        assert_eq!(second.len(), MAX_NAME_LENGTH);
+        assert!(second.ends_with("_2"));
+    }
+
+    #[test]
+    fn check_required_test() {
+        let mut attributes = HashMap::new();
+        attributes.insert("EC2_IPV4_LOCAL".to_owned(), "10.0.0.5".to_owned());
+        let mut provenance = BTreeMap::new();
+        provenance.insert("EC2_IPV4_LOCAL".to_owned(), Provenance::Fetched);
+        provenance.insert("EC2_IPV4_PUBLIC".to_owned(), Provenance::Absent);
+        provenance.insert("EC2_HOSTNAME".to_owned(), Provenance::Failed("500 Internal Server Error".to_owned()));
+
+        check_required(&attributes, &provenance, None).unwrap_err();
+        check_required(&attributes, &provenance, Some(&[])).unwrap();
+        check_required(&attributes, &provenance, Some(&["COREOS_EC2_IPV4_LOCAL".to_owned()])).unwrap();
+        check_required(&attributes, &provenance, Some(&["COREOS_EC2_IPV4_PUBLIC".to_owned()])).unwrap_err();
+        check_required(&attributes, &provenance, Some(&["COREOS_EC2_HOSTNAME".to_owned()])).unwrap_err();
+        check_required(&attributes, &provenance, Some(&["COREOS_GCE_ZONE".to_owned()])).unwrap_err();
+    }
+}
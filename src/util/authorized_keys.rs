@@ -0,0 +1,277 @@
+// Copyright 2017 CoreOS, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! all-or-nothing updates of a user's authorized keys
+//!
+//! update-ssh-keys rewrites our fragment in `authorized_keys.d` and then
+//! `authorized_keys` itself in place, so an update that fails halfway (on a
+//! full disk, say) can leave a truncated file behind and the user locked out.
+//! the files are staged first: copied to backups next to them, which fails
+//! before anything was touched if there's no room. a failed update is then
+//! undone by renaming the backups back, which doesn't need any room.
+//! the ssh directory belongs to the user, who can put symlinks anywhere in
+//! it, so nothing is followed: only regular files the user owns are backed
+//! up, and the backups are always new files.
+//!
+//! update-ssh-keys also waits for its lock for as long as it takes, so it's
+//! taken on a thread of its own and given up on after a timeout.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind as IoErrorKind};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use nix::libc;
+use update_ssh_keys::AuthorizedKeys;
+use users::User;
+
+use cancel;
+use errors::*;
+
+/// how long to wait for update-ssh-keys' lock by default
+pub const DEFAULT_LOCK_TIMEOUT_SECS: u64 = 30;
+
+/// how often waiting for the lock checks whether it was cancelled
+const POLL_INTERVAL_MILLIS: u64 = 100;
+
+/// the name of the fragment in `authorized_keys.d` that keys are added as
+pub const FRAGMENT_NAME: &str = "coreos-metadata";
+
+/// open opens the user's authorized keys directory, giving up if the lock
+/// isn't free within the timeout. the directory is opened on a thread that
+/// keeps waiting after we gave up, and drops it (and the lock) once it gets it.
+pub fn open(user: User, ssh_dir: &Path, timeout: Duration) -> Result<AuthorizedKeys> {
+    let (tx, rx) = mpsc::channel();
+    let dir = ssh_dir.to_path_buf();
+    thread::spawn(move || {
+        let res = AuthorizedKeys::open(user, true, Some(dir));
+        // nobody is listening anymore if the lock took too long
+        let _ = tx.send(res);
+    });
+
+    let start = Instant::now();
+    loop {
+        cancel::check()?;
+        let elapsed = start.elapsed();
+        if elapsed >= timeout {
+            bail!("timed out after {}s waiting for the lock on {:?}", timeout.as_secs(), ssh_dir);
+        }
+        match rx.recv_timeout((timeout - elapsed).min(Duration::from_millis(POLL_INTERVAL_MILLIS))) {
+            Ok(res) => return res.chain_err(|| format!("failed to open authorized keys directory {:?}", ssh_dir)),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => bail!("failed to open authorized keys directory {:?}", ssh_dir),
+        }
+    }
+}
+
+/// Staged holds the backups of the files an update can touch
+#[derive(Debug)]
+pub struct Staged {
+    /// each file, and its backup if it existed
+    files: Vec<(PathBuf, Option<PathBuf>)>,
+}
+
+impl Staged {
+    /// stage backs up the files under the ssh directory that an update can
+    /// touch: `authorized_keys` and our fragment. the backups are kept in
+    /// the ssh directory itself, so they're on the same filesystem, but not
+    /// in `authorized_keys.d`, where they would be taken for fragments. the
+    /// files have to be regular files owned by the user with the given uid.
+    pub fn stage(ssh_dir: &Path, uid: u32) -> Result<Staged> {
+        for dir in &[ssh_dir.to_owned(), ssh_dir.join("authorized_keys.d")] {
+            if let Ok(metadata) = fs::symlink_metadata(dir) {
+                if !metadata.is_dir() {
+                    bail!("refusing to back up authorized keys: {:?} isn't a directory", dir);
+                }
+            }
+        }
+        let paths = vec![
+            ssh_dir.join("authorized_keys"),
+            ssh_dir.join("authorized_keys.d").join(FRAGMENT_NAME),
+        ];
+        let mut files = Vec::new();
+        for path in paths {
+            let source = match open_nofollow(&path) {
+                Ok(source) => source,
+                Err(ref e) if e.kind() == IoErrorKind::NotFound => {
+                    files.push((path, None));
+                    continue;
+                }
+                Err(e) => return Err(e).chain_err(|| format!("failed to open {:?}, which has to be a regular file", path)),
+            };
+            let metadata = source.metadata()
+                .chain_err(|| format!("failed to stat {:?}", path))?;
+            if !metadata.is_file() || metadata.uid() != uid {
+                bail!("refusing to back up {:?}, which isn't a regular file owned by uid {}", path, uid);
+            }
+            let name = path.strip_prefix(ssh_dir)
+                .chain_err(|| format!("{:?} isn't under {:?}", path, ssh_dir))?
+                .to_string_lossy()
+                .replace('/', "-");
+            let backup = ssh_dir.join(format!(".{}.coreos-metadata-backup", name));
+            copy(source, &backup, &metadata)?;
+            files.push((path, Some(backup)));
+        }
+        Ok(Staged { files })
+    }
+
+    /// commit removes the backups once the update went through
+    pub fn commit(self) -> Result<()> {
+        for (_, backup) in self.files {
+            if let Some(backup) = backup {
+                fs::remove_file(&backup)
+                    .chain_err(|| format!("failed to remove backup {:?}", backup))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// rollback puts the files back the way they were, renaming the backups
+    /// over whatever the failed update left, and removing files it created
+    pub fn rollback(self) -> Result<()> {
+        let mut failed = Vec::new();
+        for (path, backup) in self.files {
+            let res = match backup {
+                Some(ref backup) => fs::rename(backup, &path),
+                None if path.exists() => fs::remove_file(&path),
+                None => Ok(()),
+            };
+            if let Err(e) = res {
+                warn!("failed to restore {:?}: {}", path, e);
+                failed.push(path.display().to_string());
+            }
+        }
+        if !failed.is_empty() {
+            bail!("failed to restore {}", failed.join(", "));
+        }
+        Ok(())
+    }
+}
+
+/// open_nofollow opens the file for reading, failing if it's a symlink
+fn open_nofollow(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK)
+        .open(path)
+}
+
+/// copy copies the opened file to a new file with its owner and
+/// permissions, and syncs the copy, so that it can be renamed back in place
+/// of the file. a leftover backup is removed first, without following it if
+/// it's a symlink, and the copy is only ever created, never opened.
+fn copy(mut from: File, to: &Path, metadata: &fs::Metadata) -> Result<()> {
+    match fs::remove_file(to) {
+        Err(ref e) if e.kind() != IoErrorKind::NotFound =>
+            bail!("failed to remove leftover backup {:?}: {}", to, e),
+        _ => {}
+    }
+    let mut backup = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(to)
+        .chain_err(|| format!("failed to create backup {:?}", to))?;
+    io::copy(&mut from, &mut backup)
+        .chain_err(|| format!("failed to back up to {:?}", to))?;
+    let res = unsafe { libc::fchown(backup.as_raw_fd(), metadata.uid(), metadata.gid()) };
+    if res != 0 {
+        return Err(io::Error::last_os_error()).chain_err(|| format!("failed to set owner of {:?}", to));
+    }
+    backup.set_permissions(metadata.permissions())
+        .chain_err(|| format!("failed to set permissions of {:?}", to))?;
+    backup.sync_all()
+        .chain_err(|| format!("failed to sync {:?}", to))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::os::unix::fs::symlink;
+    use users;
+    use tempdir::TempDir;
+
+    fn uid() -> u32 {
+        users::get_current_uid()
+    }
+
+    fn read(path: &Path) -> String {
+        let mut contents = String::new();
+        File::open(path).unwrap().read_to_string(&mut contents).unwrap();
+        contents
+    }
+
+    #[test]
+    fn rollback_test() {
+        let dir = TempDir::new("coreos-metadata-test").unwrap();
+        let authorized_keys = dir.path().join("authorized_keys");
+        File::create(&authorized_keys).unwrap().write_all(b"ssh-ed25519 AAAA old\n").unwrap();
+
+        let staged = Staged::stage(dir.path(), uid()).unwrap();
+        // a failed update truncated the file and created the fragment
+        File::create(&authorized_keys).unwrap();
+        let fragment = dir.path().join("authorized_keys.d").join(FRAGMENT_NAME);
+        fs::create_dir_all(fragment.parent().unwrap()).unwrap();
+        File::create(&fragment).unwrap();
+        staged.rollback().unwrap();
+
+        assert_eq!(read(&authorized_keys), "ssh-ed25519 AAAA old\n");
+        assert!(!fragment.exists());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn commit_test() {
+        let dir = TempDir::new("coreos-metadata-test").unwrap();
+        let authorized_keys = dir.path().join("authorized_keys");
+        File::create(&authorized_keys).unwrap().write_all(b"ssh-ed25519 AAAA old\n").unwrap();
+
+        let staged = Staged::stage(dir.path(), uid()).unwrap();
+        File::create(&authorized_keys).unwrap().write_all(b"ssh-ed25519 AAAA new\n").unwrap();
+        staged.commit().unwrap();
+
+        assert_eq!(read(&authorized_keys), "ssh-ed25519 AAAA new\n");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn symlink_test() {
+        let dir = TempDir::new("coreos-metadata-test").unwrap();
+        let secret = dir.path().join("secret");
+        File::create(&secret).unwrap().write_all(b"root:hash\n").unwrap();
+
+        // authorized_keys is a symlink
+        symlink(&secret, dir.path().join("authorized_keys")).unwrap();
+        assert!(Staged::stage(dir.path(), uid()).is_err());
+        fs::remove_file(dir.path().join("authorized_keys")).unwrap();
+
+        // a backup planted as a symlink is replaced, not written through
+        File::create(dir.path().join("authorized_keys")).unwrap().write_all(b"ssh-ed25519 AAAA old\n").unwrap();
+        let backup = dir.path().join(".authorized_keys.coreos-metadata-backup");
+        symlink(&secret, &backup).unwrap();
+        let staged = Staged::stage(dir.path(), uid()).unwrap();
+        assert_eq!(read(&secret), "root:hash\n");
+        assert_eq!(read(&backup), "ssh-ed25519 AAAA old\n");
+        staged.commit().unwrap();
+
+        // files someone else owns aren't backed up
+        assert!(Staged::stage(dir.path(), uid() + 1).is_err());
+    }
+}
@@ -0,0 +1,205 @@
+// Copyright 2017 CoreOS, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! config drive discovery, mounting, and integrity checks
+//!
+//! a config drive that was truncated (e.g. a partially written iso) still
+//! mounts, but files come back short or padded with zeroes, which then parse
+//! into garbage. if the drive has a `SHA256SUMS` manifest (in the format
+//! written by `sha256sum`) every file in it is verified, and in any case
+//! text files that are obviously damaged are rejected with a
+//! `ParseError::ConfigDriveCorrupt` error instead of being used. user data
+//! can be binary (gzipped, say), so it's only checked against the manifest.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+use tempdir::TempDir;
+
+use errors::*;
+use util::mount;
+
+const FSTYPES: [&str; 2] = ["iso9660", "vfat"];
+
+const MANIFEST: &str = "SHA256SUMS";
+
+fn corrupt<P: AsRef<Path>>(path: P, reason: &str) -> Error {
+    ErrorKind::Parse(ParseError::ConfigDriveCorrupt(path.as_ref().display().to_string(), reason.to_owned())).into()
+}
+
+/// find_device resolves a config drive specification (a device path,
+/// `LABEL=<label>`, or `UUID=<uuid>`) to a device. with no specification,
+/// the first of the given labels that exists is used.
+pub fn find_device(spec: Option<&str>, default_labels: &[&str]) -> Result<PathBuf> {
+    let candidates = match spec {
+        Some(spec) if spec.starts_with("LABEL=") =>
+            vec![Path::new("/dev/disk/by-label").join(&spec["LABEL=".len()..])],
+        Some(spec) if spec.starts_with("UUID=") =>
+            vec![Path::new("/dev/disk/by-uuid").join(&spec["UUID=".len()..])],
+        Some(spec) => vec![PathBuf::from(spec)],
+        None => default_labels.iter()
+            .map(|l| Path::new("/dev/disk/by-label").join(l))
+            .collect(),
+    };
+    candidates.iter()
+        .find(|p| p.exists())
+        .cloned()
+        .ok_or_else(|| format!("no config drive found (tried {:?})", candidates).into())
+}
+
+/// MountedDrive is a config drive mounted read-only on a temporary directory
+/// for as long as it's alive
+#[derive(Debug)]
+pub struct MountedDrive {
+    dir: TempDir,
+}
+
+impl MountedDrive {
+    pub fn mount(device: &Path) -> Result<MountedDrive> {
+        let dir = TempDir::new("coreos-metadata")
+            .chain_err(|| "failed to create temporary directory")?;
+        mount::mount_ro(device, dir.path(), FSTYPES[0])
+            .or_else(|_| mount::mount_ro(device, dir.path(), FSTYPES[1]))?;
+        debug!("mounted config drive {:?} on {:?}", device, dir.path());
+        Ok(MountedDrive { dir })
+    }
+
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+}
+
+impl ::std::ops::Drop for MountedDrive {
+    fn drop(&mut self) {
+        // the temporary directory can't be removed while the drive is still
+        // mounted on it, but there's nothing else to be done about it here
+        if let Err(e) = mount::unmount(self.dir.path()) {
+            warn!("failed to unmount config drive: {}", e);
+        }
+    }
+}
+
+/// read_bytes reads a file from the config drive as it is, for files that
+/// can be binary
+pub fn read_bytes(path: &Path) -> Result<Vec<u8>> {
+    let mut contents = Vec::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_end(&mut contents))
+        .chain_err(|| format!("failed to read file {:?}", path))?;
+    Ok(contents)
+}
+
+/// read_text reads a text file (e.g. json or yaml) from the config drive,
+/// rejecting it if it's padded with zeroes
+pub fn read_text(path: &Path) -> Result<String> {
+    let contents = read_bytes(path)?;
+    if contents.contains(&0) {
+        return Err(corrupt(path, "file contains NUL bytes, the drive is probably truncated"));
+    }
+    String::from_utf8(contents)
+        .map_err(|_| corrupt(path, "file is not valid UTF-8"))
+}
+
+/// verify checks every file listed in the drive's checksum manifest, if it
+/// has one. it returns whether there was a manifest to check.
+pub fn verify(root: &Path) -> Result<bool> {
+    let manifest_path = root.join(MANIFEST);
+    if !manifest_path.exists() {
+        debug!("no checksum manifest on config drive {:?}", root);
+        return Ok(false);
+    }
+
+    let manifest = read_text(&manifest_path)?;
+    for line in manifest.lines().filter(|l| !l.trim().is_empty()) {
+        let (expected, name) = parse_manifest_line(line)
+            .ok_or_else(|| corrupt(&manifest_path, &format!("malformed line {:?}", line)))?;
+        if name.starts_with('/') || name.split('/').any(|c| c == "..") {
+            return Err(corrupt(&manifest_path, &format!("path {:?} is outside the drive", name)));
+        }
+
+        let path = root.join(name);
+        if !path.exists() {
+            return Err(corrupt(&path, "file listed in the checksum manifest is missing"));
+        }
+        let mut contents = Vec::new();
+        File::open(&path)
+            .and_then(|mut f| f.read_to_end(&mut contents))
+            .chain_err(|| format!("failed to read file {:?}", path))?;
+        let actual = format!("{:x}", Sha256::digest(&contents));
+        if actual != expected.to_lowercase() {
+            return Err(corrupt(&path, &format!("sha256 is {}, expected {}", actual, expected)));
+        }
+    }
+    info!("verified config drive contents against {:?}", manifest_path);
+    Ok(true)
+}
+
+/// parse_manifest_line splits a `sha256sum` output line into the checksum and
+/// the file name (which is marked with a `*` in binary mode)
+fn parse_manifest_line(line: &str) -> Option<(&str, &str)> {
+    let i = line.find(char::is_whitespace)?;
+    let (sum, rest) = line.split_at(i);
+    let name = rest.trim_left();
+    let name = if name.starts_with('*') { &name[1..] } else { name };
+    if sum.len() != 64 || !sum.chars().all(|c| c.is_ascii_hexdigit()) || name.is_empty() {
+        return None;
+    }
+    Some((sum, name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempdir::TempDir;
+
+    const HELLO_SHA256: &str = "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03";
+
+    #[test]
+    fn parse_manifest_line_test() {
+        let line = format!("{}  meta/hello.txt", HELLO_SHA256);
+        assert_eq!(parse_manifest_line(&line), Some((HELLO_SHA256, "meta/hello.txt")));
+        let line = format!("{} *hello.txt", HELLO_SHA256);
+        assert_eq!(parse_manifest_line(&line), Some((HELLO_SHA256, "hello.txt")));
+        assert_eq!(parse_manifest_line("abc  hello.txt"), None);
+    }
+
+    #[test]
+    fn verify_test() {
+        let dir = TempDir::new("coreos-metadata-test").unwrap();
+        assert!(!verify(dir.path()).unwrap());
+
+        File::create(dir.path().join("hello.txt")).unwrap().write_all(b"hello\n").unwrap();
+        File::create(dir.path().join(MANIFEST)).unwrap()
+            .write_all(format!("{}  hello.txt\n", HELLO_SHA256).as_bytes()).unwrap();
+        assert!(verify(dir.path()).unwrap());
+
+        File::create(dir.path().join("hello.txt")).unwrap().write_all(b"hell").unwrap();
+        match *verify(dir.path()).unwrap_err().kind() {
+            ErrorKind::Parse(ParseError::ConfigDriveCorrupt(..)) => {}
+            ref e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn read_text_rejects_padding() {
+        let dir = TempDir::new("coreos-metadata-test").unwrap();
+        let path = dir.path().join("vm_id.txt");
+        File::create(&path).unwrap().write_all(b"abc\0\0\0").unwrap();
+        read_text(&path).unwrap_err();
+        assert_eq!(read_bytes(&path).unwrap(), b"abc\0\0\0");
+    }
+}
@@ -0,0 +1,103 @@
+// Copyright 2017 CoreOS, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! payload decoding
+//!
+//! user data is often gzipped, base64-encoded, or both, to fit the platform's
+//! size limit or a text-only field. decoded payloads are limited to
+//! `MAX_DECODED_SIZE`, so that a small compressed payload can't take all of
+//! the memory of the machine.
+
+use std::io::Read;
+
+use base64;
+use flate2::read::GzDecoder;
+
+use errors::*;
+
+pub const MAX_DECODED_SIZE: usize = 16 * 1024 * 1024;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// decode_base64 decodes base64, ignoring whitespace (line breaks in
+/// particular)
+pub fn decode_base64(data: &[u8]) -> Result<Vec<u8>> {
+    let data: Vec<u8> = data.iter()
+        .cloned()
+        .filter(|b| !(*b as char).is_ascii_whitespace())
+        .collect();
+    Ok(base64::decode(&data)
+        .chain_err(|| "failed to decode base64")?)
+}
+
+/// is_gzip is whether the data starts like a gzip stream does
+pub fn is_gzip(data: &[u8]) -> bool {
+    data.starts_with(&GZIP_MAGIC)
+}
+
+/// gunzip decompresses gzipped data, up to `MAX_DECODED_SIZE` bytes
+pub fn gunzip(data: &[u8]) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    GzDecoder::new(data)
+        .take(MAX_DECODED_SIZE as u64 + 1)
+        .read_to_end(&mut out)
+        .chain_err(|| "failed to decompress gzip data")?;
+    if out.len() > MAX_DECODED_SIZE {
+        return Err(format!("decompressed data is larger than {} bytes", MAX_DECODED_SIZE).into());
+    }
+    Ok(out)
+}
+
+/// maybe_gunzip decompresses the data if it's gzipped, and leaves it as it is
+/// otherwise
+pub fn maybe_gunzip(data: Vec<u8>) -> Result<Vec<u8>> {
+    if is_gzip(&data) {
+        debug!("decompressing gzipped data");
+        gunzip(&data)
+    } else {
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn decode_test() {
+        let encoded = base64::encode(&gzip(b"#cloud-config\n"));
+        let (first, rest) = encoded.split_at(10);
+        let wrapped = format!("{}\n{}\n", first, rest);
+        let decoded = decode_base64(wrapped.as_bytes()).and_then(maybe_gunzip).unwrap();
+        assert_eq!(decoded, b"#cloud-config\n");
+        assert_eq!(maybe_gunzip(b"plain".to_vec()).unwrap(), b"plain");
+    }
+
+    #[test]
+    fn gunzip_limit_test() {
+        let zeros = vec![0; MAX_DECODED_SIZE + 1];
+        let bomb = gzip(&zeros);
+        assert!(bomb.len() < 64 * 1024);
+        gunzip(&bomb).unwrap_err();
+    }
+}
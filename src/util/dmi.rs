@@ -0,0 +1,109 @@
+// Copyright 2017 CoreOS, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! helpers for reading SMBIOS/DMI fields exported by the kernel, and telling
+//! the platform from them
+
+use std::fs::File;
+use std::io::{ErrorKind as IoErrorKind, Read};
+use std::path::Path;
+
+use errors::*;
+
+const DMI_PATH: &str = "/sys/class/dmi/id";
+
+/// the dmi field and the text in it that gives a platform away, and the
+/// provider for the platform. the first match wins.
+const PLATFORMS: [(&str, &str, &str); 13] = [
+    ("sys_vendor", "Amazon EC2", "ec2"),
+    // xen-based instance types predate the Amazon EC2 vendor string
+    ("bios_version", "amazon", "ec2"),
+    ("product_name", "Google Compute Engine", "gce"),
+    // the asset tag of every azure vm
+    ("chassis_asset_tag", "7783-7084-3265-9085-8269-3286-77", "azure"),
+    ("sys_vendor", "DigitalOcean", "digitalocean"),
+    ("sys_vendor", "Linode", "linode"),
+    ("sys_vendor", "Nutanix", "nutanix"),
+    ("product_name", "KubeVirt", "kubevirt"),
+    ("chassis_asset_tag", "OpenTelekomCloud", "otc"),
+    ("sys_vendor", "OpenStack Foundation", "openstack-metadata"),
+    ("product_name", "OpenStack Nova", "openstack-metadata"),
+    ("product_name", "OpenStack Compute", "openstack-metadata"),
+    ("product_name", "CloudStack KVM Hypervisor", "cloudstack-metadata"),
+];
+
+/// read_field returns the trimmed contents of the given dmi field, or None if
+/// the field isn't exported or is empty. some fields (like the serial number)
+/// are only readable by root.
+pub fn read_field(name: &str) -> Result<Option<String>> {
+    let path = Path::new(DMI_PATH).join(name);
+    let mut file = match File::open(&path) {
+        Ok(f) => f,
+        Err(ref e) if e.kind() == IoErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(Error::with_chain(e, format!("failed to open dmi field '{:?}'", path))),
+    };
+
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)
+        .chain_err(|| format!("failed to read dmi field '{:?}'", path))?;
+
+    let contents = contents.trim();
+    if contents.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(contents.to_owned()))
+    }
+}
+
+/// detect_provider guesses the provider from the dmi fields
+pub fn detect_provider() -> Result<Option<&'static str>> {
+    match_platform(read_field)
+}
+
+fn match_platform<F>(read: F) -> Result<Option<&'static str>>
+    where F: Fn(&str) -> Result<Option<String>>
+{
+    for &(field, text, provider) in &PLATFORMS {
+        if let Some(value) = read(field)? {
+            if value.contains(text) {
+                debug!("dmi field {} is {:?}, guessing provider {}", field, value, provider);
+                return Ok(Some(provider));
+            }
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn match_platform_test() {
+        let fields: HashMap<&str, &str> = vec![
+            ("sys_vendor", "Xen"),
+            ("bios_version", "4.2.amazon"),
+        ].into_iter().collect();
+        let read = |f: &str| Ok(fields.get(f).map(|v| v.to_string()));
+        assert_eq!(match_platform(read).unwrap(), Some("ec2"));
+
+        let fields: HashMap<&str, &str> = vec![
+            ("sys_vendor", "QEMU"),
+            ("product_name", "Standard PC (Q35 + ICH9, 2009)"),
+        ].into_iter().collect();
+        let read = |f: &str| Ok(fields.get(f).map(|v| v.to_string()));
+        assert_eq!(match_platform(read).unwrap(), None);
+    }
+}
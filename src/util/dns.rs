@@ -0,0 +1,105 @@
+// Copyright 2017 CoreOS, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! name resolution with a time limit
+//!
+//! early in boot the resolver is often pointed at servers that aren't
+//! reachable yet, and getaddrinfo waits out every one of them, several times,
+//! before it gives up. lookups here run on a thread of their own and are
+//! abandoned after the timeout. ip literals are never looked up.
+
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use reqwest::Url;
+
+use cancel;
+use errors::*;
+
+/// how long a lookup may take by default
+pub const DEFAULT_TIMEOUT_SECS: u64 = 2;
+
+/// how often a lookup in progress checks whether it was cancelled
+const POLL_INTERVAL_MILLIS: u64 = 100;
+
+/// literal returns the address if the host is an ip literal. ipv6 literals
+/// may be in brackets, the way they are in urls.
+pub fn literal(host: &str) -> Option<IpAddr> {
+    host.trim_matches(|c| c == '[' || c == ']').parse().ok()
+}
+
+/// resolve looks up the addresses of the host, giving up after the timeout.
+/// the addresses of ip literals are returned without a lookup.
+pub fn resolve(host: &str, port: u16, timeout: Duration) -> Result<Vec<SocketAddr>> {
+    if let Some(ip) = literal(host) {
+        return Ok(vec![SocketAddr::new(ip, port)]);
+    }
+
+    let (tx, rx) = mpsc::channel();
+    let name = host.to_owned();
+    thread::spawn(move || {
+        let addrs = (name.as_str(), port).to_socket_addrs()
+            .map(|addrs| addrs.collect::<Vec<_>>());
+        // nobody is listening anymore if the lookup took too long
+        let _ = tx.send(addrs);
+    });
+
+    let start = Instant::now();
+    loop {
+        cancel::check()?;
+        let elapsed = start.elapsed();
+        if elapsed >= timeout {
+            bail!("timed out resolving {} after {}ms", host,
+                  timeout.as_secs() * 1000 + u64::from(timeout.subsec_nanos()) / 1_000_000);
+        }
+        match rx.recv_timeout((timeout - elapsed).min(Duration::from_millis(POLL_INTERVAL_MILLIS))) {
+            Ok(Ok(addrs)) => {
+                if addrs.is_empty() {
+                    bail!("{} has no addresses", host);
+                }
+                return Ok(addrs);
+            }
+            Ok(Err(e)) => return Err(Error::with_chain(e, format!("failed to resolve {}", host))),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => bail!("failed to resolve {}", host),
+        }
+    }
+}
+
+/// resolve_url looks up the host of the url, if it has a name rather than an
+/// address
+pub fn resolve_url(url: &Url, timeout: Duration) -> Result<()> {
+    match (url.host_str(), url.port_or_known_default()) {
+        (Some(host), Some(port)) => resolve(host, port, timeout).map(|_| ()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn literal_test() {
+        // a zero timeout would fail any real lookup
+        let timeout = Duration::from_secs(0);
+        assert_eq!(resolve("169.254.169.254", 80, timeout).unwrap(),
+                   vec!["169.254.169.254:80".parse().unwrap()]);
+        assert_eq!(resolve("[fd00:ec2::254]", 80, timeout).unwrap(),
+                   vec!["[fd00:ec2::254]:80".parse().unwrap()]);
+        assert!(resolve("metadata.google.internal", 80, timeout).is_err());
+    }
+}
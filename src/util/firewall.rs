@@ -0,0 +1,203 @@
+// Copyright 2017 CoreOS, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! firewall rules that keep the metadata service to root
+//!
+//! anything on the instance can ask the metadata service for the instance's
+//! credentials (an iam role on ec2, the service account on gce), including
+//! the pods of a kubernetes node. the rules only let root and the given
+//! groups connect to the endpoints the provider uses, and reject everyone
+//! else. the owner match only sees the primary group of the process that
+//! opened the socket, not its supplementary groups. forwarded traffic, e.g.
+//! from pods with a network namespace of their own, has no owner, and is
+//! rejected altogether.
+
+use std::net::IpAddr;
+
+use errors::*;
+use providers::MetadataEndpoint;
+
+/// the nftables table the rules are in
+const TABLE: &str = "coreos_metadata";
+
+/// the iptables chains the rules are in
+const OUTPUT_CHAIN: &str = "COREOS-METADATA";
+const FORWARD_CHAIN: &str = "COREOS-METADATA-FWD";
+
+/// FirewallFormat is the firewall the rules are written for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FirewallFormat {
+    /// a table for `nft -f`, covering ipv4 and ipv6
+    Nftables,
+    /// rules for `iptables-restore --noflush`, which only cover ipv4
+    Iptables,
+}
+
+impl Default for FirewallFormat {
+    fn default() -> Self {
+        FirewallFormat::Nftables
+    }
+}
+
+/// FirewallOptions are the user-supplied settings of the firewall rules
+#[derive(Clone, Debug, Default)]
+pub struct FirewallOptions {
+    pub format: FirewallFormat,
+    /// groups whose processes may reach the metadata service besides root's
+    pub groups: Vec<String>,
+}
+
+/// check_group makes sure the group name can't break out of the rules
+fn check_group(group: &str) -> Result<()> {
+    let valid = !group.is_empty() && !group.starts_with('-')
+        && group.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.');
+    if !valid {
+        bail!("invalid group name {:?}", group);
+    }
+    Ok(())
+}
+
+/// rules returns the firewall rules for the endpoints
+pub fn rules(endpoints: &[MetadataEndpoint], options: &FirewallOptions) -> Result<String> {
+    for group in &options.groups {
+        check_group(group)?;
+    }
+    Ok(match options.format {
+        FirewallFormat::Nftables => nftables(endpoints, &options.groups),
+        FirewallFormat::Iptables => iptables(endpoints, &options.groups),
+    })
+}
+
+fn nftables(endpoints: &[MetadataEndpoint], groups: &[String]) -> String {
+    let matches: Vec<String> = endpoints.iter()
+        .map(|e| format!("{} daddr {} tcp dport {}", match e.address {
+            IpAddr::V4(_) => "ip",
+            IpAddr::V6(_) => "ip6",
+        }, e.address, e.port))
+        .collect();
+
+    // declaring and deleting the table first makes loading the rules again
+    // replace them, instead of failing or adding to them
+    let mut out = format!("# written by coreos-metadata, load with nft -f\n\
+                           table inet {table}\n\
+                           delete table inet {table}\n\
+                           table inet {table} {{\n\
+                           \tchain output {{\n\
+                           \t\ttype filter hook output priority 0; policy accept;\n", table = TABLE);
+    for m in &matches {
+        out.push_str(&format!("\t\t{} meta skuid 0 accept\n", m));
+        for group in groups {
+            out.push_str(&format!("\t\t{} meta skgid {} accept\n", m, group));
+        }
+        out.push_str(&format!("\t\t{} reject\n", m));
+    }
+    out.push_str("\t}\n\
+                  \tchain forward {\n\
+                  \t\ttype filter hook forward priority 0; policy accept;\n");
+    for m in &matches {
+        out.push_str(&format!("\t\t{} reject\n", m));
+    }
+    out.push_str("\t}\n}\n");
+    out
+}
+
+fn iptables(endpoints: &[MetadataEndpoint], groups: &[String]) -> String {
+    let mut out = format!("# written by coreos-metadata, load with iptables-restore --noflush\n\
+                           *filter\n\
+                           :{} - [0:0]\n\
+                           :{} - [0:0]\n", OUTPUT_CHAIN, FORWARD_CHAIN);
+    for e in endpoints {
+        let m = match e.address {
+            IpAddr::V4(address) => format!("-d {}/32 -p tcp --dport {}", address, e.port),
+            IpAddr::V6(address) => {
+                warn!("the metadata endpoint {} port {} needs ip6tables, it isn't covered by the iptables rules", address, e.port);
+                out.push_str(&format!("# {} port {} isn't covered, it needs ip6tables\n", address, e.port));
+                continue;
+            }
+        };
+        out.push_str(&format!("-A {} {} -m owner --uid-owner 0 -j ACCEPT\n", OUTPUT_CHAIN, m));
+        for group in groups {
+            out.push_str(&format!("-A {} {} -m owner --gid-owner {} -j ACCEPT\n", OUTPUT_CHAIN, m, group));
+        }
+        out.push_str(&format!("-A {} {} -j REJECT\n", OUTPUT_CHAIN, m));
+        out.push_str(&format!("-A {} {} -j REJECT\n", FORWARD_CHAIN, m));
+    }
+    out.push_str(&format!("-A OUTPUT -j {}\n\
+                           -A FORWARD -j {}\n\
+                           COMMIT\n", OUTPUT_CHAIN, FORWARD_CHAIN));
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use super::*;
+
+    fn endpoints() -> Vec<MetadataEndpoint> {
+        vec![
+            MetadataEndpoint { address: IpAddr::from_str("169.254.169.254").unwrap(), port: 80 },
+            MetadataEndpoint { address: IpAddr::from_str("fd00:ec2::254").unwrap(), port: 80 },
+        ]
+    }
+
+    #[test]
+    fn nftables_test() {
+        let options = FirewallOptions { format: FirewallFormat::Nftables, groups: vec!["metadata".to_owned()] };
+        assert_eq!(rules(&endpoints(), &options).unwrap(), "\
+# written by coreos-metadata, load with nft -f
+table inet coreos_metadata
+delete table inet coreos_metadata
+table inet coreos_metadata {
+\tchain output {
+\t\ttype filter hook output priority 0; policy accept;
+\t\tip daddr 169.254.169.254 tcp dport 80 meta skuid 0 accept
+\t\tip daddr 169.254.169.254 tcp dport 80 meta skgid metadata accept
+\t\tip daddr 169.254.169.254 tcp dport 80 reject
+\t\tip6 daddr fd00:ec2::254 tcp dport 80 meta skuid 0 accept
+\t\tip6 daddr fd00:ec2::254 tcp dport 80 meta skgid metadata accept
+\t\tip6 daddr fd00:ec2::254 tcp dport 80 reject
+\t}
+\tchain forward {
+\t\ttype filter hook forward priority 0; policy accept;
+\t\tip daddr 169.254.169.254 tcp dport 80 reject
+\t\tip6 daddr fd00:ec2::254 tcp dport 80 reject
+\t}
+}
+");
+    }
+
+    #[test]
+    fn iptables_test() {
+        let options = FirewallOptions { format: FirewallFormat::Iptables, groups: vec![] };
+        assert_eq!(rules(&endpoints(), &options).unwrap(), "\
+# written by coreos-metadata, load with iptables-restore --noflush
+*filter
+:COREOS-METADATA - [0:0]
+:COREOS-METADATA-FWD - [0:0]
+-A COREOS-METADATA -d 169.254.169.254/32 -p tcp --dport 80 -m owner --uid-owner 0 -j ACCEPT
+-A COREOS-METADATA -d 169.254.169.254/32 -p tcp --dport 80 -j REJECT
+-A COREOS-METADATA-FWD -d 169.254.169.254/32 -p tcp --dport 80 -j REJECT
+# fd00:ec2::254 port 80 isn't covered, it needs ip6tables
+-A OUTPUT -j COREOS-METADATA
+-A FORWARD -j COREOS-METADATA-FWD
+COMMIT
+");
+    }
+
+    #[test]
+    fn invalid_group() {
+        let options = FirewallOptions { format: FirewallFormat::Nftables, groups: vec!["a b".to_owned()] };
+        rules(&endpoints(), &options).unwrap_err();
+    }
+}
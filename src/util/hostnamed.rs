@@ -0,0 +1,161 @@
+// Copyright 2017 CoreOS, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! setting the hostname through systemd-hostnamed
+//!
+//! hostnamed is asked over dbus when it's available, so that it notifies
+//! everything that watches the hostname. in the initramfs, or in builds
+//! without the `hostnamed` feature, the transient hostname is set through
+//! procfs and the pretty hostname is written to /etc/machine-info, which is
+//! where hostnamed reads it from when it starts. under a root filesystem
+//! that's being staged, neither hostnamed nor procfs are its own, so the
+//! hostname is written to its /etc/hostname instead.
+
+use std::fs::{self, File};
+use std::io::{ErrorKind as IoErrorKind, Read, Write};
+use std::path::Path;
+
+#[cfg(feature = "hostnamed")]
+use dbus::{BusType, Connection, Message};
+
+use audit;
+use errors::*;
+use util::{self, attributes};
+
+const KERNEL_HOSTNAME: &str = "/proc/sys/kernel/hostname";
+const MACHINE_INFO: &str = "/etc/machine-info";
+const STATIC_HOSTNAME: &str = "/etc/hostname";
+
+#[cfg(feature = "hostnamed")]
+const DBUS_TIMEOUT_MS: i32 = 5000;
+
+/// set_transient sets the hostname the kernel reports
+pub fn set_transient(hostname: &str) -> Result<()> {
+    call("SetHostname", hostname)
+        .or_else(|e| {
+            debug!("failed to set hostname through hostnamed, falling back to procfs: {}", e);
+            let mut file = File::create(KERNEL_HOSTNAME)
+                .chain_err(|| format!("failed to open {}", KERNEL_HOSTNAME))?;
+            file.write_all(hostname.as_bytes())
+                .chain_err(|| format!("failed to write {}", KERNEL_HOSTNAME))
+        })
+}
+
+/// set_pretty sets the free-form, human readable hostname
+pub fn set_pretty(pretty: &str) -> Result<()> {
+    call("SetPrettyHostname", pretty)
+        .or_else(|e| {
+            debug!("failed to set pretty hostname through hostnamed, falling back to {}: {}", MACHINE_INFO, e);
+            write_machine_info(Path::new(MACHINE_INFO), pretty)
+        })
+}
+
+/// set_static_in writes the hostname to /etc/hostname under the root, which
+/// hostnamed sets the transient hostname from when the root is booted
+pub fn set_static_in(root: &Path, hostname: &str) -> Result<()> {
+    let path = util::rooted(Some(root), STATIC_HOSTNAME);
+    replace_file(&path, &format!("{}\n", hostname))
+}
+
+/// set_pretty_in writes the pretty hostname to /etc/machine-info under the
+/// root
+pub fn set_pretty_in(root: &Path, pretty: &str) -> Result<()> {
+    write_machine_info(&util::rooted(Some(root), MACHINE_INFO), pretty)
+}
+
+#[cfg(feature = "hostnamed")]
+fn call(method: &str, value: &str) -> Result<()> {
+    let conn = Connection::get_private(BusType::System)
+        .chain_err(|| "failed to connect to the system bus")?;
+    let msg = Message::new_method_call("org.freedesktop.hostname1", "/org/freedesktop/hostname1",
+                                       "org.freedesktop.hostname1", method)
+        .map_err(Error::from)?
+        // the second argument is whether polkit may prompt
+        .append2(value, false);
+    conn.send_with_reply_and_block(msg, DBUS_TIMEOUT_MS)
+        .chain_err(|| format!("hostnamed {} call failed", method))?;
+    Ok(())
+}
+
+#[cfg(not(feature = "hostnamed"))]
+fn call(_method: &str, _value: &str) -> Result<()> {
+    Err("built without the hostnamed feature".into())
+}
+
+/// write_machine_info replaces PRETTY_HOSTNAME in the machine-info file,
+/// keeping the rest of the file
+fn write_machine_info(path: &Path, pretty: &str) -> Result<()> {
+    let mut contents = String::new();
+    match File::open(path) {
+        Ok(mut file) => {
+            file.read_to_string(&mut contents)
+                .chain_err(|| format!("failed to read {:?}", path))?;
+        }
+        Err(ref e) if e.kind() == IoErrorKind::NotFound => {}
+        Err(e) => return Err(Error::with_chain(e, format!("failed to open {:?}", path))),
+    }
+    replace_file(path, &replace_pretty_hostname(&contents, pretty))
+}
+
+fn replace_file(path: &Path, contents: &str) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)
+            .chain_err(|| format!("failed to create directory {:?}", dir))?;
+    }
+    let tmp_path = Path::new(&format!("{}.tmp", path.display())).to_owned();
+    File::create(&tmp_path)
+        .and_then(|mut f| f.write_all(contents.as_bytes()))
+        .chain_err(|| format!("failed to write {:?}", tmp_path))?;
+    fs::rename(&tmp_path, path)
+        .chain_err(|| format!("failed to rename {:?} to {:?}", tmp_path, path))?;
+    audit::wrote(path);
+    Ok(())
+}
+
+fn replace_pretty_hostname(contents: &str, pretty: &str) -> String {
+    let mut out: String = contents.lines()
+        .filter(|l| !l.starts_with("PRETTY_HOSTNAME="))
+        .map(|l| format!("{}\n", l))
+        .collect();
+    out.push_str(&format!("PRETTY_HOSTNAME={}\n", attributes::quote_value(pretty)));
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replace_pretty_hostname_test() {
+        assert_eq!(replace_pretty_hostname("", "web 1"), "PRETTY_HOSTNAME=\"web 1\"\n");
+        let contents = "CHASSIS=vm\nPRETTY_HOSTNAME=old\n";
+        assert_eq!(replace_pretty_hostname(contents, "new"), "CHASSIS=vm\nPRETTY_HOSTNAME=\"new\"\n");
+    }
+
+    #[test]
+    fn set_in_root_test() {
+        use tempdir::TempDir;
+
+        let root = TempDir::new("coreos-metadata-test").unwrap();
+        set_static_in(root.path(), "web-1").unwrap();
+        set_pretty_in(root.path(), "web 1").unwrap();
+        let read = |path: &str| {
+            let mut contents = String::new();
+            File::open(root.path().join(path)).unwrap().read_to_string(&mut contents).unwrap();
+            contents
+        };
+        assert_eq!(read("etc/hostname"), "web-1\n");
+        assert_eq!(read("etc/machine-info"), "PRETTY_HOSTNAME=\"web 1\"\n");
+    }
+}
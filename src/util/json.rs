@@ -0,0 +1,225 @@
+// Copyright 2017 CoreOS, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! json documents with errors that say where they went wrong
+//!
+//! serde only gives the line and column of an error, which doesn't mean much
+//! for a document that's served on a single line. the document is scanned up
+//! to there to find the json pointer (rfc 6901) of the value that didn't
+//! fit, and the error names it, along with the text around it, redacted.
+//! a missing field is reported at the object it's missing from.
+
+use serde::de::DeserializeOwned;
+use serde_json;
+
+use errors::*;
+use util::redact;
+
+/// how much of the document is shown on either side of the error
+const SNIPPET_CONTEXT: usize = 24;
+
+/// from_slice deserializes the document, and says where in it any error is
+pub fn from_slice<T: DeserializeOwned>(doc: &[u8]) -> Result<T> {
+    serde_json::from_slice(doc).map_err(|e| {
+        let offset = offset_of(doc, e.line(), e.column());
+        let path = path_at(doc, offset, e.to_string().starts_with("missing field"));
+        let location = format!("at {}, near `{}`", pointer(&path), snippet(doc, offset, &path));
+        Error::with_chain(e, location)
+    })
+}
+
+/// describe returns the error along with its causes on one line, for errors
+/// that carry their cause as a string
+pub fn describe(e: &Error) -> String {
+    e.iter().map(|e| e.to_string()).collect::<Vec<_>>().join(": ")
+}
+
+/// offset_of turns serde's line and column, which count from 1, into a byte
+/// offset into the document
+fn offset_of(doc: &[u8], line: usize, column: usize) -> usize {
+    let mut start = 0;
+    for _ in 1..line {
+        match doc[start..].iter().position(|&b| b == b'\n') {
+            Some(i) => start += i + 1,
+            None => break,
+        }
+    }
+    (start + column).min(doc.len())
+}
+
+enum Frame {
+    Object { key: Option<String>, expecting_key: bool },
+    Array { index: usize },
+}
+
+/// Segment is a step in a json pointer
+#[derive(Debug, PartialEq)]
+enum Segment {
+    Key(String),
+    Index(usize),
+}
+
+/// path_at returns the path to the value the document is in the middle of
+/// at the offset, or to the object it's in if the error is about the object
+/// as a whole
+fn path_at(doc: &[u8], offset: usize, object_level: bool) -> Vec<Segment> {
+    let mut stack: Vec<Frame> = Vec::new();
+    let mut i = 0;
+    while i < offset {
+        match doc[i] {
+            b'{' => stack.push(Frame::Object { key: None, expecting_key: true }),
+            b'[' => stack.push(Frame::Array { index: 0 }),
+            b'}' | b']' => { stack.pop(); }
+            b',' => match stack.last_mut() {
+                Some(&mut Frame::Object { ref mut expecting_key, .. }) => *expecting_key = true,
+                Some(&mut Frame::Array { ref mut index }) => *index += 1,
+                None => {}
+            },
+            b':' => if let Some(&mut Frame::Object { ref mut expecting_key, .. }) = stack.last_mut() {
+                *expecting_key = false;
+            },
+            b'"' => {
+                let start = i + 1;
+                i = start;
+                while i < doc.len() && doc[i] != b'"' {
+                    i += if doc[i] == b'\\' { 2 } else { 1 };
+                }
+                if let Some(&mut Frame::Object { ref mut key, expecting_key: true }) = stack.last_mut() {
+                    let end = i.min(doc.len());
+                    *key = Some(unescape(&doc[start..end]));
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    if object_level {
+        if let Some(&mut Frame::Object { ref mut key, .. }) = stack.last_mut() {
+            *key = None;
+        }
+    }
+    stack.into_iter().filter_map(|frame| match frame {
+        Frame::Object { key: Some(key), .. } => Some(Segment::Key(key)),
+        Frame::Object { key: None, .. } => None,
+        Frame::Array { index } => Some(Segment::Index(index)),
+    }).collect()
+}
+
+/// unescape decodes a key, as far as json's escapes go. unicode escapes are
+/// kept as they are.
+fn unescape(raw: &[u8]) -> String {
+    let raw = String::from_utf8_lossy(raw);
+    let mut out = String::new();
+    let mut chars = raw.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('t') => out.push('\t'),
+            Some('r') => out.push('\r'),
+            Some('u') => out.push_str("\\u"),
+            Some(c) => out.push(c),
+            None => {}
+        }
+    }
+    out
+}
+
+/// pointer formats the path as a json pointer
+fn pointer(path: &[Segment]) -> String {
+    if path.is_empty() {
+        return "the top level".to_owned();
+    }
+    path.iter().map(|segment| match *segment {
+        Segment::Key(ref key) => format!("/{}", key.replace('~', "~0").replace('/', "~1")),
+        Segment::Index(index) => format!("/{}", index),
+    }).collect()
+}
+
+/// snippet returns the text around the offset on one line, with anything
+/// sensitive masked. values under a sensitive key aren't shown at all.
+fn snippet(doc: &[u8], offset: usize, path: &[Segment]) -> String {
+    let sensitive = path.iter().any(|segment| match *segment {
+        Segment::Key(ref key) => redact::is_sensitive_key(key),
+        Segment::Index(_) => false,
+    });
+    if sensitive {
+        return "<redacted>".to_owned();
+    }
+    let start = offset.saturating_sub(SNIPPET_CONTEXT);
+    let end = (offset + SNIPPET_CONTEXT).min(doc.len());
+    let text = String::from_utf8_lossy(&doc[start..end]);
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    redact::redact_text(&text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Deserialize)]
+    #[allow(dead_code)]
+    struct Address {
+        ip_address: String,
+        netmask: String,
+    }
+
+    #[derive(Debug, Deserialize)]
+    #[allow(dead_code)]
+    struct Interfaces {
+        public: Vec<Address>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    #[allow(dead_code)]
+    struct Droplet {
+        droplet_id: u64,
+        interfaces: Interfaces,
+    }
+
+    fn location(doc: &str) -> String {
+        let e = from_slice::<Droplet>(doc.as_bytes()).unwrap_err();
+        e.to_string()
+    }
+
+    #[test]
+    fn location_test() {
+        let doc = r#"{"droplet_id": "2756294", "interfaces": {}}"#;
+        assert!(location(doc).starts_with("at /droplet_id, near `"), "{}", location(doc));
+
+        let doc = r#"{"droplet_id": 2756294, "interfaces": {"public": [
+            {"ip_address": "104.131.20.105", "netmask": "255.255.192.0"},
+            {"ip_address": "104.131.20.106"}
+        ]}}"#;
+        assert!(location(doc).starts_with("at /interfaces/public/1, near `"), "{}", location(doc));
+
+        assert!(location(r#""droplet""#).starts_with("at the top level"), "{}", location(r#""droplet""#));
+    }
+
+    #[test]
+    fn pointer_test() {
+        assert_eq!(pointer(&[Segment::Key("a/b~c".to_owned()), Segment::Index(3)]), "/a~1b~0c/3");
+    }
+
+    #[test]
+    fn snippet_redaction_test() {
+        let doc = br#"{"admin_pass": 12345}"#;
+        let path = path_at(doc, doc.len() - 2, false);
+        assert_eq!(path, vec![Segment::Key("admin_pass".to_owned())]);
+        assert_eq!(snippet(doc, doc.len() - 2, &path), "<redacted>");
+    }
+}
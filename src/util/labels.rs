@@ -0,0 +1,118 @@
+// Copyright 2017 CoreOS, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! kubernetes node label conversion
+//!
+//! provider tags and labels are far more permissive than kubernetes labels,
+//! so they're converted before kubelet sees them. a label key is an optional
+//! dns subdomain prefix and a `/`, followed by a name; names and values are
+//! at most 63 characters of letters, digits, `-`, `_`, and `.`, beginning
+//! and ending with a letter or digit. the rules are:
+//!
+//!   - every other character is replaced with a `-`
+//!   - names and values are truncated, then trimmed to start and end with a
+//!     letter or digit
+//!   - prefixes are lowercased, and dropped if nothing is left of them
+//!   - tags whose name is empty after conversion are skipped, and if two
+//!     tags convert to the same key, the first one (by tag name) is kept
+
+use std::collections::BTreeMap;
+
+pub const MAX_NAME_LENGTH: usize = 63;
+pub const MAX_PREFIX_LENGTH: usize = 253;
+
+/// convert replaces the characters `keep` rejects with a `-`, truncates, and
+/// trims the result to start and end with a letter or digit
+fn convert<F>(s: &str, max: usize, keep: F) -> String
+    where F: Fn(char) -> bool
+{
+    let converted: String = s.chars()
+        .map(|c| if c.is_ascii() && keep(c) { c } else { '-' })
+        .take(max)
+        .collect();
+    converted.trim_matches(|c: char| !c.is_ascii_alphanumeric()).to_owned()
+}
+
+fn label_name(name: &str) -> String {
+    convert(name, MAX_NAME_LENGTH, |c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+fn label_prefix(prefix: &str) -> String {
+    convert(&prefix.to_lowercase(), MAX_PREFIX_LENGTH, |c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+}
+
+/// label_key converts a tag name to a label key, if anything is left of it
+pub fn label_key(key: &str) -> Option<String> {
+    let (prefix, name) = match key.rfind('/') {
+        Some(i) => (label_prefix(&key[..i]), label_name(&key[i + 1..])),
+        None => (String::new(), label_name(key)),
+    };
+    if name.is_empty() {
+        None
+    } else if prefix.is_empty() {
+        Some(name)
+    } else {
+        Some(format!("{}/{}", prefix, name))
+    }
+}
+
+/// label_value converts a tag value to a label value, which may be empty
+pub fn label_value(value: &str) -> String {
+    label_name(value)
+}
+
+/// node_labels converts the provider's tags to node labels
+pub fn node_labels(tags: &BTreeMap<String, String>) -> BTreeMap<String, String> {
+    let mut labels = BTreeMap::new();
+    for (k, v) in tags {
+        let key = match label_key(k) {
+            Some(key) => key,
+            None => {
+                warn!("skipping tag {:?}, which has no characters that are valid in a label", k);
+                continue;
+            }
+        };
+        if labels.contains_key(&key) {
+            warn!("skipping tag {:?}, which is also label {:?}", k, key);
+            continue;
+        }
+        labels.insert(key, label_value(v));
+    }
+    labels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn node_labels_test() {
+        let mut tags = BTreeMap::new();
+        tags.insert("Name".to_owned(), "web server #1".to_owned());
+        tags.insert("Team/Owner".to_owned(), "ops".to_owned());
+        tags.insert("aws:autoscaling:groupName".to_owned(), "-workers-".to_owned());
+        tags.insert("k8s.io/role/node".to_owned(), "".to_owned());
+        tags.insert("Name!".to_owned(), "other".to_owned());
+        tags.insert("***".to_owned(), "x".to_owned());
+        tags.insert("long".to_owned(), "a".repeat(70));
+
+        let labels = node_labels(&tags);
+        assert_eq!(labels.len(), 5);
+        assert_eq!(labels["Name"], "web-server--1");
+        assert_eq!(labels["team/Owner"], "ops");
+        assert_eq!(labels["aws-autoscaling-groupName"], "workers");
+        assert_eq!(labels["k8s.io-role/node"], "");
+        assert_eq!(labels["long"].len(), MAX_NAME_LENGTH);
+    }
+}
@@ -14,14 +14,48 @@
 
 //! utility functions
 
+pub mod attributes;
+#[cfg(feature = "ssh-keys")]
+pub mod authorized_keys;
+#[cfg(any(feature = "cloudstack", feature = "ibmcloud-classic", feature = "kubevirt", feature = "nutanix", feature = "openstack"))]
+pub mod configdrive;
+pub mod decode;
+pub mod dmi;
+pub mod dns;
+pub mod firewall;
+pub mod hostnamed;
+pub mod json;
+pub mod labels;
+#[cfg(any(feature = "azure", feature = "cloudstack", feature = "ibmcloud-classic", feature = "kubevirt", feature = "nutanix", feature = "openstack"))]
+pub mod mount;
+#[cfg(feature = "kubevirt")]
+pub mod network_config;
+#[cfg(any(feature = "ibmcloud-classic", feature = "kubevirt", feature = "nutanix"))]
+pub mod network_data;
+pub mod redact;
+pub mod secret;
+pub mod ssh_cert;
+pub mod template;
+pub mod topology;
+pub mod useradd;
+
 use pnet;
 use std::io::{Read, BufRead, BufReader};
 use std::fs::File;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 use errors::*;
 use retry;
 
+/// rooted returns the path under the given root directory, for writing to a
+/// staged root filesystem instead of the running system
+pub fn rooted(root: Option<&Path>, path: &str) -> PathBuf {
+    match root {
+        Some(root) => root.join(path.trim_left_matches('/')),
+        None => PathBuf::from(path),
+    }
+}
+
 fn key_lookup_line(delim: char, key: &str, line: &str) -> Option<String> {
     match line.find(delim) {
         Some(index) => {
@@ -77,10 +111,49 @@ pub fn dns_lease_key_lookup(key: &str) -> Result<String> {
         })
 }
 
+/// dhcp_leases returns the name and the contents of the lease file of every
+/// interface that has one, once there is at least one
+pub fn dhcp_leases() -> Result<Vec<(String, String)>> {
+    let mut interfaces = pnet::datalink::interfaces();
+    interfaces.retain(|i| !i.is_loopback());
+    interfaces.sort_by_key(|i| i.index);
+
+    retry::Retry::new()
+        .initial_backoff(Duration::from_millis(50))
+        .max_backoff(Duration::from_millis(500))
+        .max_attempts(60)
+        .retry(|_| {
+            let mut leases = Vec::new();
+            for interface in &interfaces {
+                let lease_path = format!("/run/systemd/netif/leases/{}", interface.index);
+                let lease_path = Path::new(&lease_path);
+                if !lease_path.exists() {
+                    continue;
+                }
+                debug!("found lease file {:?} for {}", lease_path, interface.name);
+                let mut contents = String::new();
+                File::open(&lease_path)
+                    .and_then(|mut f| f.read_to_string(&mut contents))
+                    .chain_err(|| format!("failed to read lease file ({:?})", lease_path))?;
+                leases.push((interface.name.clone(), contents));
+            }
+            if leases.is_empty() {
+                return Err("no dhcp lease found".into());
+            }
+            Ok(leases)
+        })
+}
+
 #[cfg(test)]
 mod tests {
     use std::io::Cursor;
     use super::*;
+    #[test]
+    fn rooted_test() {
+        assert_eq!(rooted(None, "/etc/hostname"), Path::new("/etc/hostname"));
+        assert_eq!(rooted(Some(Path::new("/tmp/root")), "/etc/hostname"), Path::new("/tmp/root/etc/hostname"));
+    }
+
     #[test]
     fn key_lookup_test() {
         let tests = vec![
@@ -0,0 +1,31 @@
+// Copyright 2017 CoreOS, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! helpers for temporarily mounting provider-supplied block devices
+
+use std::path::Path;
+
+use nix::mount;
+
+use errors::*;
+
+pub fn mount_ro(source: &Path, target: &Path, fstype: &str) -> Result<()> {
+    mount::mount(Some(source), target, Some(fstype), mount::MS_RDONLY, None::<&str>)
+        .chain_err(|| format!("failed to read-only mount source '{:?}' to target '{:?}' with filetype '{}'", source, target, fstype))
+}
+
+pub fn unmount(target: &Path) -> Result<()> {
+    mount::umount(target)
+        .chain_err(|| format!("failed to unmount target '{:?}'", target))
+}
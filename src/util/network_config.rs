@@ -0,0 +1,419 @@
+// Copyright 2017 CoreOS, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! cloud-init network-config
+//!
+//! drives in the nocloud layout describe the network in `network-config`,
+//! in either of cloud-init's formats: version 1, a list of typed entries
+//! (physical interfaces with their subnets, nameservers, and routes), or
+//! version 2, which is netplan's. either may be wrapped in a `network` key.
+//! only interfaces with static addresses become network configuration; dhcp
+//! ones are left to the default network configuration, and bonds, bridges,
+//! and vlans aren't supported.
+
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use ipnetwork;
+use ipnetwork::IpNetwork;
+use pnet::util::MacAddr;
+use serde_yaml;
+
+use errors::*;
+use network;
+
+/// NetworkConfig is a network-config document of either version
+#[derive(Clone, Debug, Deserialize)]
+pub struct NetworkConfig {
+    network: Option<Box<NetworkConfig>>,
+    version: Option<u32>,
+    /// the entries of version 1
+    #[serde(default)]
+    config: Vec<Entry>,
+    /// the interfaces of version 2
+    #[serde(default)]
+    ethernets: BTreeMap<String, Ethernet>,
+    #[serde(default)]
+    bonds: BTreeMap<String, serde_yaml::Value>,
+    #[serde(default)]
+    bridges: BTreeMap<String, serde_yaml::Value>,
+    #[serde(default)]
+    vlans: BTreeMap<String, serde_yaml::Value>,
+}
+
+/// OneOrMany is a value that can be given on its own or as a list
+#[derive(Clone, Debug, Deserialize)]
+#[serde(untagged)]
+enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+impl<T: Clone> OneOrMany<T> {
+    fn to_vec(&self) -> Vec<T> {
+        match *self {
+            OneOrMany::One(ref v) => vec![v.clone()],
+            OneOrMany::Many(ref v) => v.clone(),
+        }
+    }
+}
+
+/// Entry is a version 1 entry. only the fields of the types that are
+/// supported are read: `physical`, `nameserver`, and `route`.
+#[derive(Clone, Debug, Deserialize)]
+struct Entry {
+    #[serde(rename = "type")]
+    kind: String,
+    name: Option<String>,
+    mac_address: Option<String>,
+    mtu: Option<u32>,
+    #[serde(default)]
+    subnets: Vec<Subnet>,
+    /// the nameservers of a nameserver entry
+    address: Option<OneOrMany<IpAddr>>,
+    destination: Option<String>,
+    network: Option<IpAddr>,
+    netmask: Option<IpAddr>,
+    gateway: Option<IpAddr>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct Subnet {
+    #[serde(rename = "type")]
+    kind: String,
+    /// either with a prefix, or with a netmask of its own
+    address: Option<String>,
+    netmask: Option<IpAddr>,
+    gateway: Option<IpAddr>,
+    dns_nameservers: Option<OneOrMany<IpAddr>>,
+    #[serde(default)]
+    routes: Vec<SubnetRoute>,
+}
+
+/// SubnetRoute is a version 1 route, with either a destination or a network
+/// and netmask
+#[derive(Clone, Debug, Deserialize)]
+struct SubnetRoute {
+    destination: Option<String>,
+    network: Option<IpAddr>,
+    netmask: Option<IpAddr>,
+    gateway: IpAddr,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+struct Match {
+    name: Option<String>,
+    macaddress: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+struct Nameservers {
+    #[serde(default)]
+    addresses: Vec<IpAddr>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct NetplanRoute {
+    to: String,
+    via: IpAddr,
+}
+
+/// Ethernet is a version 2 ethernet
+#[derive(Clone, Debug, Deserialize)]
+struct Ethernet {
+    #[serde(rename = "match", default)]
+    match_: Match,
+    #[serde(default)]
+    addresses: Vec<String>,
+    gateway4: Option<IpAddr>,
+    gateway6: Option<IpAddr>,
+    #[serde(default)]
+    nameservers: Nameservers,
+    #[serde(default)]
+    routes: Vec<NetplanRoute>,
+    mtu: Option<u32>,
+}
+
+/// parse parses a network-config document
+pub fn parse(contents: &[u8]) -> ::std::result::Result<NetworkConfig, serde_yaml::Error> {
+    serde_yaml::from_slice(contents)
+}
+
+fn parse_mac(mac: &str) -> Result<MacAddr> {
+    MacAddr::from_str(mac)
+        .map_err(|e| Error::from(format!("{:?}", e)))
+        .chain_err(|| format!("failed to parse mac address: '{}'", mac))
+}
+
+/// parse_destination parses a route destination, where `default` means the
+/// default route of the gateway's address family
+fn parse_destination(to: &str, via: IpAddr) -> Result<IpNetwork> {
+    match (to, via) {
+        ("default", IpAddr::V4(_)) => Ok(IpNetwork::from_str("0.0.0.0/0").unwrap()),
+        ("default", IpAddr::V6(_)) => Ok(IpNetwork::from_str("::/0").unwrap()),
+        _ => IpNetwork::from_str(to).chain_err(|| format!("invalid route destination {:?}", to)),
+    }
+}
+
+/// with_netmask is the network of the address and netmask, or of just the
+/// address if there's no netmask
+fn with_netmask(address: IpAddr, netmask: Option<IpAddr>) -> Result<IpNetwork> {
+    let prefix = match (netmask, address) {
+        (Some(mask), _) => ipnetwork::ip_mask_to_prefix(mask)
+            .chain_err(|| format!("invalid network mask {}", mask))?,
+        (None, IpAddr::V4(_)) => 32,
+        (None, IpAddr::V6(_)) => 128,
+    };
+    IpNetwork::new(address, prefix)
+        .chain_err(|| format!("invalid address {}/{}", address, prefix))
+}
+
+/// route_destination is the destination of a version 1 route
+fn route_destination(destination: &Option<String>, network: Option<IpAddr>, netmask: Option<IpAddr>, gateway: IpAddr) -> Result<IpNetwork> {
+    match (destination, network) {
+        (&Some(ref destination), _) => parse_destination(destination, gateway),
+        (&None, Some(network)) => with_netmask(network, netmask),
+        (&None, None) => Err(format!("route via {} has no destination", gateway).into()),
+    }
+}
+
+/// contains is whether the ip is in the network
+fn contains(network: &IpNetwork, ip: IpAddr) -> bool {
+    match (*network, ip) {
+        (IpNetwork::V4(net), IpAddr::V4(ip)) => net.contains(ip),
+        (IpNetwork::V6(net), IpAddr::V6(ip)) => net.contains(ip),
+        _ => false,
+    }
+}
+
+fn static_interface(name: Option<String>, mac_address: Option<MacAddr>, nameservers: Vec<IpAddr>,
+                    ip_addresses: Vec<IpNetwork>, routes: Vec<network::NetworkRoute>, mtu: Option<u32>) -> network::Interface {
+    network::Interface {
+        name,
+        mac_address,
+        nameservers,
+        ip_addresses,
+        routes,
+        mtu,
+        ..Default::default()
+    }
+}
+
+impl NetworkConfig {
+    /// interfaces returns the interfaces with static addresses, matched by
+    /// mac address if they have one and by name otherwise
+    pub fn interfaces(&self) -> Result<Vec<network::Interface>> {
+        if let Some(ref inner) = self.network {
+            return inner.interfaces();
+        }
+        match self.version {
+            Some(1) => self.v1_interfaces(),
+            Some(2) => self.v2_interfaces(),
+            version => {
+                warn!("ignoring network-config with unsupported version {:?}, only versions 1 and 2 are supported", version);
+                Ok(vec![])
+            }
+        }
+    }
+
+    fn v1_interfaces(&self) -> Result<Vec<network::Interface>> {
+        // nameserver entries apply to every interface
+        let global_nameservers: Vec<IpAddr> = self.config.iter()
+            .filter(|e| e.kind == "nameserver")
+            .flat_map(|e| e.address.as_ref().map_or_else(Vec::new, OneOrMany::to_vec))
+            .collect();
+
+        let mut interfaces = Vec::new();
+        for entry in &self.config {
+            match entry.kind.as_str() {
+                "physical" => {}
+                "nameserver" | "route" => continue,
+                kind => {
+                    warn!("ignoring network-config {} {}, only physical interfaces are supported",
+                          kind, entry.name.as_ref().map_or("", String::as_str));
+                    continue;
+                }
+            }
+
+            let mut ip_addresses = Vec::new();
+            let mut routes = Vec::new();
+            let mut nameservers = Vec::new();
+            for subnet in entry.subnets.iter().filter(|s| s.kind == "static" || s.kind == "static6") {
+                let address = subnet.address.as_ref()
+                    .ok_or_else(|| format!("static subnet of {} has no address", entry.name.as_ref().map_or("", String::as_str)))?;
+                ip_addresses.push(if address.contains('/') {
+                    IpNetwork::from_str(address).chain_err(|| format!("invalid address {:?}", address))?
+                } else {
+                    let ip = IpAddr::from_str(address).chain_err(|| format!("invalid address {:?}", address))?;
+                    with_netmask(ip, subnet.netmask)?
+                });
+                if let Some(gateway) = subnet.gateway {
+                    routes.push(network::NetworkRoute { destination: parse_destination("default", gateway)?, gateway });
+                }
+                for r in &subnet.routes {
+                    routes.push(network::NetworkRoute {
+                        destination: route_destination(&r.destination, r.network, r.netmask, r.gateway)?,
+                        gateway: r.gateway,
+                    });
+                }
+                nameservers.extend(subnet.dns_nameservers.as_ref().map_or_else(Vec::new, OneOrMany::to_vec));
+            }
+            if ip_addresses.is_empty() {
+                continue;
+            }
+            nameservers.extend(global_nameservers.iter().cloned());
+
+            // the names are the ones cloud-init would have given the
+            // interfaces, so the mac address is matched on when there is one
+            let mac_address = match entry.mac_address {
+                Some(ref mac) => Some(parse_mac(mac)?),
+                None => None,
+            };
+            let name = match mac_address {
+                Some(_) => None,
+                None => Some(entry.name.clone().ok_or("physical interface has neither a name nor a mac address")?),
+            };
+            interfaces.push(static_interface(name, mac_address, nameservers, ip_addresses, routes, entry.mtu));
+        }
+
+        // route entries go on the interface that can reach their gateway
+        for entry in self.config.iter().filter(|e| e.kind == "route") {
+            let gateway = entry.gateway.ok_or("route has no gateway")?;
+            let route = network::NetworkRoute {
+                destination: route_destination(&entry.destination, entry.network, entry.netmask, gateway)?,
+                gateway,
+            };
+            match interfaces.iter_mut().find(|i| i.ip_addresses.iter().any(|a| contains(a, gateway))) {
+                Some(interface) => interface.routes.push(route),
+                None => warn!("ignoring network-config route via {}, which isn't on a statically configured interface", gateway),
+            }
+        }
+        Ok(interfaces)
+    }
+
+    fn v2_interfaces(&self) -> Result<Vec<network::Interface>> {
+        for &(kind, names) in &[("bonds", &self.bonds), ("bridges", &self.bridges), ("vlans", &self.vlans)] {
+            if !names.is_empty() {
+                warn!("ignoring network-config {} {}, only ethernets are supported",
+                      kind, names.keys().cloned().collect::<Vec<_>>().join(", "));
+            }
+        }
+
+        let mut interfaces = Vec::new();
+        for (id, eth) in &self.ethernets {
+            if eth.addresses.is_empty() {
+                continue;
+            }
+            let mac_address = match eth.match_.macaddress {
+                Some(ref mac) => Some(parse_mac(mac)?),
+                None => None,
+            };
+            // without a match the id is the interface name
+            let name = match (&eth.match_.name, &mac_address) {
+                (&Some(ref name), _) => Some(name.clone()),
+                (&None, &None) => Some(id.clone()),
+                (&None, &Some(_)) => None,
+            };
+
+            let mut ip_addresses = Vec::new();
+            for a in &eth.addresses {
+                ip_addresses.push(IpNetwork::from_str(a)
+                    .chain_err(|| format!("invalid address {:?}", a))?);
+            }
+            let mut routes = Vec::new();
+            if let Some(gateway) = eth.gateway4 {
+                routes.push(network::NetworkRoute { destination: parse_destination("default", gateway)?, gateway });
+            }
+            if let Some(gateway) = eth.gateway6 {
+                routes.push(network::NetworkRoute { destination: parse_destination("default", gateway)?, gateway });
+            }
+            for r in &eth.routes {
+                routes.push(network::NetworkRoute { destination: parse_destination(&r.to, r.via)?, gateway: r.via });
+            }
+
+            interfaces.push(static_interface(name, mac_address, eth.nameservers.addresses.clone(), ip_addresses, routes, eth.mtu));
+        }
+        Ok(interfaces)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn v1_test() {
+        let config = parse(b"network:
+  version: 1
+  config:
+    - type: physical
+      name: eth0
+      mac_address: '02:00:00:0a:0b:0c'
+      mtu: 1450
+      subnets:
+        - type: static
+          address: 192.168.10.5
+          netmask: 255.255.255.0
+          gateway: 192.168.10.1
+          dns_nameservers: 192.168.10.1
+        - type: static6
+          address: fd00::5/64
+    - type: physical
+      name: eth1
+      subnets:
+        - type: dhcp
+    - type: bond
+      name: bond0
+    - type: nameserver
+      address: [1.1.1.1]
+    - type: route
+      destination: 10.0.0.0/8
+      gateway: 192.168.10.254
+").unwrap();
+        let interfaces = config.interfaces().unwrap();
+        assert_eq!(interfaces.len(), 1);
+        let eth0 = &interfaces[0];
+        assert_eq!(eth0.name, None);
+        assert_eq!(eth0.mac_address, Some(MacAddr::from_str("02:00:00:0a:0b:0c").unwrap()));
+        assert_eq!(eth0.mtu, Some(1450));
+        assert_eq!(eth0.ip_addresses, vec![
+            IpNetwork::from_str("192.168.10.5/24").unwrap(),
+            IpNetwork::from_str("fd00::5/64").unwrap(),
+        ]);
+        assert_eq!(eth0.nameservers, vec![IpAddr::from_str("192.168.10.1").unwrap(), IpAddr::from_str("1.1.1.1").unwrap()]);
+        let destinations: Vec<IpNetwork> = eth0.routes.iter().map(|r| r.destination).collect();
+        assert_eq!(destinations, vec![IpNetwork::from_str("0.0.0.0/0").unwrap(), IpNetwork::from_str("10.0.0.0/8").unwrap()]);
+    }
+
+    #[test]
+    fn v2_test() {
+        let config = parse(b"version: 2
+ethernets:
+  eth0:
+    dhcp4: true
+  ens3:
+    addresses: [192.168.10.5/24]
+    routes:
+      - to: 10.0.0.0/8
+        via: 192.168.10.254
+").unwrap();
+        let interfaces = config.interfaces().unwrap();
+        assert_eq!(interfaces.len(), 1);
+        assert_eq!(interfaces[0].name, Some("ens3".to_owned()));
+        assert_eq!(interfaces[0].routes[0].destination, IpNetwork::from_str("10.0.0.0/8").unwrap());
+
+        assert!(parse(b"version: 3").unwrap().interfaces().unwrap().is_empty());
+    }
+}
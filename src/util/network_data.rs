@@ -0,0 +1,117 @@
+// Copyright 2017 CoreOS, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! openstack network_data.json
+//!
+//! config drives in the openstack layout describe the network in
+//! `network_data.json`: the links (interfaces), the networks (addresses) on
+//! them, and the services (nameservers). only statically configured networks
+//! carry an address; dhcp ones are left to the default network configuration.
+
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use ipnetwork;
+use ipnetwork::IpNetwork;
+use pnet::util::MacAddr;
+
+use errors::*;
+use network;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Link {
+    pub id: String,
+    pub ethernet_mac_address: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Route {
+    pub network: IpAddr,
+    pub netmask: IpAddr,
+    pub gateway: IpAddr,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Network {
+    pub link: String,
+    pub ip_address: Option<IpAddr>,
+    pub netmask: Option<IpAddr>,
+    #[serde(default)]
+    pub routes: Vec<Route>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Service {
+    #[serde(rename = "type")]
+    pub type_name: String,
+    pub address: IpAddr,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct NetworkData {
+    #[serde(default)]
+    pub links: Vec<Link>,
+    #[serde(default)]
+    pub networks: Vec<Network>,
+    #[serde(default)]
+    pub services: Vec<Service>,
+}
+
+impl NetworkData {
+    /// interfaces returns an interface, matched by mac address, for every
+    /// link. with `static_only`, links without a static address are skipped.
+    pub fn interfaces(&self, static_only: bool) -> Result<Vec<network::Interface>> {
+        let nameservers: Vec<IpAddr> = self.services.iter()
+            .filter(|s| s.type_name == "dns")
+            .map(|s| s.address)
+            .collect();
+
+        let mut interfaces = Vec::new();
+        for link in &self.links {
+            let mac = MacAddr::from_str(&link.ethernet_mac_address)
+                .map_err(|e| Error::from(format!("{:?}", e)))
+                .chain_err(|| format!("failed to parse mac address: '{}'", link.ethernet_mac_address))?;
+            let mut ip_addresses = Vec::new();
+            let mut routes = Vec::new();
+            for n in self.networks.iter().filter(|n| n.link == link.id) {
+                if let (Some(ip), Some(mask)) = (n.ip_address, n.netmask) {
+                    let prefix = ipnetwork::ip_mask_to_prefix(mask)
+                        .chain_err(|| format!("invalid network mask {}", mask))?;
+                    ip_addresses.push(IpNetwork::new(ip, prefix)
+                        .chain_err(|| format!("invalid address {}/{}", ip, prefix))?);
+                }
+                for r in &n.routes {
+                    let prefix = ipnetwork::ip_mask_to_prefix(r.netmask)
+                        .chain_err(|| format!("invalid network mask {}", r.netmask))?;
+                    routes.push(network::NetworkRoute {
+                        destination: IpNetwork::new(r.network, prefix)
+                            .chain_err(|| format!("invalid route {}/{}", r.network, prefix))?,
+                        gateway: r.gateway,
+                    });
+                }
+            }
+            if static_only && ip_addresses.is_empty() {
+                continue;
+            }
+            interfaces.push(network::Interface {
+                mac_address: Some(mac),
+                nameservers: nameservers.clone(),
+                ip_addresses,
+                routes,
+                ..Default::default()
+            });
+        }
+        Ok(interfaces)
+    }
+}
@@ -0,0 +1,93 @@
+// Copyright 2017 CoreOS, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! secrets that are wiped from memory when they're dropped
+//!
+//! passwords, metadata tokens and decrypted key material are kept in a
+//! Secret as soon as they're read, so that they don't linger in freed memory
+//! (or a core dump) after the run is done with them. only the Secret's own
+//! buffer is wiped: copies made before it was wrapped, e.g. by the http
+//! client, or handed to something else, e.g. as a header, aren't.
+
+use std::fmt;
+use std::ptr;
+use std::str;
+use std::sync::atomic::{self, Ordering};
+
+use errors::*;
+
+/// Secret is a buffer that is zeroed when it's dropped, and never printed
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret {
+    bytes: Vec<u8>,
+}
+
+impl Secret {
+    pub fn new<T: Into<Vec<u8>>>(secret: T) -> Secret {
+        Secret { bytes: secret.into() }
+    }
+
+    pub fn expose(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// expose_str is the secret as text, for the ones that are
+    pub fn expose_str(&self) -> Result<&str> {
+        str::from_utf8(&self.bytes).chain_err(|| "secret is not valid utf-8")
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Secret(..)")
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        zero(&mut self.bytes);
+    }
+}
+
+/// zero overwrites the whole allocation, including what's past the length,
+/// in a way the compiler can't leave out because the buffer is about to be
+/// freed
+fn zero(bytes: &mut Vec<u8>) {
+    let ptr = bytes.as_mut_ptr();
+    for i in 0..bytes.capacity() {
+        unsafe { ptr::write_volatile(ptr.add(i), 0) };
+    }
+    atomic::compiler_fence(Ordering::SeqCst);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_test() {
+        let mut bytes = Vec::with_capacity(16);
+        bytes.extend_from_slice(b"hunter2");
+        zero(&mut bytes);
+        assert_eq!(bytes, vec![0; 7]);
+    }
+
+    #[test]
+    fn secret_test() {
+        let secret = Secret::new("hunter2".to_owned());
+        assert_eq!(secret.expose_str().unwrap(), "hunter2");
+        assert_eq!(format!("{:?}", secret), "Secret(..)");
+        Secret::new(vec![0xff]).expose_str().unwrap_err();
+    }
+}
@@ -0,0 +1,238 @@
+// Copyright 2017 CoreOS, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! openssh certificates
+//!
+//! openssh_keys only parses plain public keys, so certificates are parsed
+//! here, as far as it takes to tell what they're for and when they're valid.
+//! the signature isn't checked; that's up to sshd and the certificate
+//! authority it trusts.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64;
+
+use errors::*;
+
+/// the suffix of the key types of certificates
+pub const CERT_SUFFIX: &str = "-cert-v01@openssh.com";
+
+/// the type of certificates for users, as opposed to hosts
+const USER_CERT: u32 = 1;
+
+/// SshCertificate is an openssh certificate for a user
+#[derive(Clone, Debug, PartialEq)]
+pub struct SshCertificate {
+    /// e.g. `ssh-ed25519-cert-v01@openssh.com`
+    pub key_type: String,
+    /// the certificate, base64 encoded as it was given
+    pub data: String,
+    pub comment: Option<String>,
+    pub key_id: String,
+    /// the users the certificate is for; none means any user
+    pub principals: Vec<String>,
+    /// seconds since the epoch
+    pub valid_after: u64,
+    /// seconds since the epoch
+    pub valid_before: u64,
+}
+
+/// is_certificate returns true if the line holds a certificate rather than
+/// a plain public key
+pub fn is_certificate(line: &str) -> bool {
+    line.split_whitespace().next().map_or(false, |t| t.ends_with(CERT_SUFFIX))
+}
+
+/// Reader reads the fields of the ssh wire format
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.data.len() < n {
+            bail!("certificate is truncated");
+        }
+        let (head, tail) = self.data.split_at(n);
+        self.data = tail;
+        Ok(head)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(self.bytes(4)?.iter().fold(0, |n, &b| n << 8 | u32::from(b)))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(self.bytes(8)?.iter().fold(0, |n, &b| n << 8 | u64::from(b)))
+    }
+
+    fn string(&mut self) -> Result<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.bytes(len)
+    }
+
+    fn text(&mut self) -> Result<String> {
+        String::from_utf8(self.string()?.to_vec())
+            .chain_err(|| "certificate has a field that isn't text")
+    }
+}
+
+/// key_fields returns how many fields the public key in a certificate of the
+/// type has
+fn key_fields(key_type: &str) -> Result<usize> {
+    Ok(match key_type {
+        "ssh-rsa-cert-v01@openssh.com" => 2,
+        "ssh-dss-cert-v01@openssh.com" => 4,
+        "ecdsa-sha2-nistp256-cert-v01@openssh.com"
+            | "ecdsa-sha2-nistp384-cert-v01@openssh.com"
+            | "ecdsa-sha2-nistp521-cert-v01@openssh.com" => 2,
+        "ssh-ed25519-cert-v01@openssh.com" => 1,
+        "sk-ecdsa-sha2-nistp256-cert-v01@openssh.com" => 3,
+        "sk-ssh-ed25519-cert-v01@openssh.com" => 2,
+        _ => bail!("unsupported certificate type {}", key_type),
+    })
+}
+
+impl SshCertificate {
+    /// parse parses a certificate in the authorized keys format, e.g.
+    /// `ssh-ed25519-cert-v01@openssh.com AAAA... comment`
+    pub fn parse(line: &str) -> Result<SshCertificate> {
+        let mut fields = line.split_whitespace();
+        let (key_type, data) = match (fields.next(), fields.next()) {
+            (Some(t), Some(d)) => (t, d),
+            _ => bail!("certificate has no data"),
+        };
+        let comment: Vec<&str> = fields.collect();
+        let comment = if comment.is_empty() { None } else { Some(comment.join(" ")) };
+
+        let blob = base64::decode(data)
+            .chain_err(|| "failed to decode certificate")?;
+        let mut r = Reader { data: &blob };
+        let embedded_type = r.text()?;
+        if embedded_type != key_type {
+            bail!("certificate of type {} is labelled {}", embedded_type, key_type);
+        }
+        // the nonce, then the public key
+        for _ in 0..=key_fields(key_type)? {
+            r.string()?;
+        }
+        let _serial = r.u64()?;
+        if r.u32()? != USER_CERT {
+            bail!("certificate is not a user certificate");
+        }
+        let key_id = r.text()?;
+        let mut principals = Vec::new();
+        let mut packed = Reader { data: r.string()? };
+        while !packed.data.is_empty() {
+            principals.push(packed.text()?);
+        }
+        let valid_after = r.u64()?;
+        let valid_before = r.u64()?;
+
+        Ok(SshCertificate {
+            key_type: key_type.to_owned(),
+            data: data.to_owned(),
+            comment,
+            key_id,
+            principals,
+            valid_after,
+            valid_before,
+        })
+    }
+
+    /// check_valid fails if the certificate isn't valid at the time, in
+    /// seconds since the epoch
+    pub fn check_valid(&self, now: u64) -> Result<()> {
+        if now >= self.valid_before {
+            bail!("certificate {:?} expired", self.key_id);
+        }
+        if now < self.valid_after {
+            bail!("certificate {:?} isn't valid yet", self.key_id);
+        }
+        Ok(())
+    }
+
+    /// check_valid_now fails if the certificate isn't valid at the moment
+    pub fn check_valid_now(&self) -> Result<()> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)
+            .chain_err(|| "clock is before the epoch")?;
+        self.check_valid(now.as_secs())
+    }
+
+    /// to_key_format returns the certificate in the authorized keys format
+    pub fn to_key_format(&self) -> String {
+        match self.comment {
+            Some(ref comment) => format!("{} {} {}", self.key_type, self.data, comment),
+            None => format!("{} {}", self.key_type, self.data),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn put_string(out: &mut Vec<u8>, s: &[u8]) {
+        out.extend_from_slice(&[(s.len() >> 24) as u8, (s.len() >> 16) as u8, (s.len() >> 8) as u8, s.len() as u8]);
+        out.extend_from_slice(s);
+    }
+
+    fn put_u64(out: &mut Vec<u8>, n: u64) {
+        for i in (0..8).rev() {
+            out.push((n >> (i * 8)) as u8);
+        }
+    }
+
+    fn certificate(cert_type: u32) -> String {
+        let mut blob = Vec::new();
+        put_string(&mut blob, b"ssh-ed25519-cert-v01@openssh.com");
+        put_string(&mut blob, &[0; 32]);
+        put_string(&mut blob, &[1; 32]);
+        put_u64(&mut blob, 7);
+        blob.extend_from_slice(&[0, 0, 0, cert_type as u8]);
+        put_string(&mut blob, b"alice@example.com");
+        let mut principals = Vec::new();
+        put_string(&mut principals, b"alice");
+        put_string(&mut principals, b"core");
+        put_string(&mut blob, &principals);
+        put_u64(&mut blob, 1_500_000_000);
+        put_u64(&mut blob, 1_600_000_000);
+        for _ in 0..5 {
+            put_string(&mut blob, b"");
+        }
+        format!("ssh-ed25519-cert-v01@openssh.com {} alice's laptop", base64::encode(&blob))
+    }
+
+    #[test]
+    fn parse_test() {
+        let line = certificate(USER_CERT);
+        assert!(is_certificate(&line));
+        assert!(!is_certificate("ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIHaHNO0 core"));
+
+        let cert = SshCertificate::parse(&line).unwrap();
+        assert_eq!(cert.key_id, "alice@example.com");
+        assert_eq!(cert.principals, vec!["alice".to_owned(), "core".to_owned()]);
+        assert_eq!(cert.comment, Some("alice's laptop".to_owned()));
+        assert_eq!(cert.to_key_format(), line);
+
+        cert.check_valid(1_550_000_000).unwrap();
+        cert.check_valid(1_400_000_000).unwrap_err();
+        cert.check_valid(1_600_000_000).unwrap_err();
+
+        // host certificates aren't for authorized keys
+        SshCertificate::parse(&certificate(2)).unwrap_err();
+        // nor is a certificate that doesn't match its label
+        SshCertificate::parse(&line.replace("ssh-ed25519-cert", "ssh-rsa-cert")).unwrap_err();
+    }
+}
@@ -0,0 +1,97 @@
+// Copyright 2017 CoreOS, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! attribute templates
+//!
+//! templates are rendered by replacing every `{{ NAME }}` with the value of
+//! the attribute called `NAME`, under the name it has in the attributes file
+//! (e.g. `COREOS_EC2_IPV4_LOCAL`). a filter escapes the value for where it
+//! is used:
+//!
+//!   - `{{ NAME | shell }}` single-quotes it for a shell
+//!   - `{{ NAME | json }}` makes it a json string, quotes included
+//!
+//! attribute values come from metadata that users of the instance can often
+//! change, so values that are substituted without a filter may not contain
+//! control characters (e.g. a newline that would start a line of its own in
+//! the rendered config file). a missing attribute is an error rather than
+//! an empty string.
+
+use std::collections::HashMap;
+
+use serde_json;
+
+use errors::*;
+
+/// render renders the template with the given attributes
+pub fn render(template: &str, attributes: &HashMap<String, String>) -> Result<String> {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        let end = rest[start..].find("}}")
+            .ok_or_else(|| format!("unterminated placeholder: {}", &rest[start..]))?;
+        let placeholder = &rest[start + 2..start + end];
+        out.push_str(&substitute(placeholder, attributes)?);
+        rest = &rest[start + end + 2..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// substitute returns the value for the inside of a placeholder
+fn substitute(placeholder: &str, attributes: &HashMap<String, String>) -> Result<String> {
+    let mut parts = placeholder.splitn(2, '|');
+    let name = parts.next().unwrap_or("").trim();
+    let filter = parts.next().map(str::trim);
+    let value = attributes.get(name)
+        .ok_or_else(|| format!("no attribute {:?} for the template", name))?;
+    match filter {
+        None => {
+            if value.chars().any(char::is_control) {
+                return Err(format!("attribute {} contains control characters, use a filter to escape it", name).into());
+            }
+            Ok(value.clone())
+        }
+        Some("shell") => Ok(format!("'{}'", value.replace('\'', "'\\''"))),
+        Some("json") => serde_json::to_string(value)
+            .chain_err(|| format!("failed to encode attribute {}", name)),
+        Some(filter) => Err(format!("unknown template filter {:?}", filter).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_test() {
+        let mut attributes = HashMap::new();
+        attributes.insert("COREOS_EC2_IPV4_LOCAL".to_owned(), "10.0.0.5".to_owned());
+        attributes.insert("COREOS_EC2_TAG_NAME".to_owned(), "it's\nhere".to_owned());
+
+        assert_eq!(render("--node-ip={{ COREOS_EC2_IPV4_LOCAL }} --x={{COREOS_EC2_IPV4_LOCAL}}\n", &attributes).unwrap(),
+                   "--node-ip=10.0.0.5 --x=10.0.0.5\n");
+        assert_eq!(render("NAME={{ COREOS_EC2_TAG_NAME | shell }}", &attributes).unwrap(),
+                   "NAME='it'\\''s\nhere'");
+        assert_eq!(render("{\"name\": {{ COREOS_EC2_TAG_NAME | json }}}", &attributes).unwrap(),
+                   "{\"name\": \"it's\\nhere\"}");
+        assert_eq!(render("no placeholders", &attributes).unwrap(), "no placeholders");
+
+        render("{{ COREOS_EC2_TAG_NAME }}", &attributes).unwrap_err();
+        render("{{ COREOS_EC2_REGION }}", &attributes).unwrap_err();
+        render("{{ COREOS_EC2_IPV4_LOCAL | upper }}", &attributes).unwrap_err();
+        render("{{ COREOS_EC2_IPV4_LOCAL", &attributes).unwrap_err();
+    }
+}
@@ -0,0 +1,109 @@
+// Copyright 2017 CoreOS, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! normalized topology attributes
+//!
+//! every provider names its attributes after the platform, which leaves
+//! units that run on more than one platform with a conditional per cloud.
+//! the `TOPOLOGY_*` attributes are the same few facts under the same names
+//! everywhere, copied from the provider's own attributes. a provider that
+//! doesn't know one of them just doesn't have it.
+
+use std::collections::HashMap;
+
+/// provider attributes to the topology attribute they are copied to. the
+/// first one that's there wins.
+const TOPOLOGY_ATTRIBUTES: [(&str, &str); 44] = [
+    ("AZURE_IPV4_DYNAMIC", "TOPOLOGY_PRIVATE_IPV4"),
+    ("AZURE_IPV4_VIRTUAL", "TOPOLOGY_PUBLIC_IPV4"),
+    ("AZURE_VM_SIZE", "TOPOLOGY_INSTANCE_TYPE"),
+    ("CLOUDSTACK_AVAILABILITY_ZONE", "TOPOLOGY_ZONE"),
+    ("CLOUDSTACK_IPV4_LOCAL", "TOPOLOGY_PRIVATE_IPV4"),
+    ("CLOUDSTACK_IPV4_PUBLIC", "TOPOLOGY_PUBLIC_IPV4"),
+    ("CLOUDSTACK_SERVICE_OFFERING", "TOPOLOGY_INSTANCE_TYPE"),
+    ("DHCP_IPV4", "TOPOLOGY_PRIVATE_IPV4"),
+    ("DIGITALOCEAN_IPV4_PRIVATE_0", "TOPOLOGY_PRIVATE_IPV4"),
+    ("DIGITALOCEAN_IPV4_PUBLIC_0", "TOPOLOGY_PUBLIC_IPV4"),
+    ("DIGITALOCEAN_REGION", "TOPOLOGY_REGION"),
+    ("EC2_AVAILABILITY_ZONE", "TOPOLOGY_ZONE"),
+    ("EC2_INSTANCE_TYPE", "TOPOLOGY_INSTANCE_TYPE"),
+    ("EC2_IPV4_LOCAL", "TOPOLOGY_PRIVATE_IPV4"),
+    ("EC2_IPV4_PUBLIC", "TOPOLOGY_PUBLIC_IPV4"),
+    ("EC2_REGION", "TOPOLOGY_REGION"),
+    ("GCE_IP_EXTERNAL_0", "TOPOLOGY_PUBLIC_IPV4"),
+    ("GCE_IP_LOCAL_0", "TOPOLOGY_PRIVATE_IPV4"),
+    ("GCE_MACHINE_TYPE", "TOPOLOGY_INSTANCE_TYPE"),
+    ("GCE_REGION", "TOPOLOGY_REGION"),
+    ("GCE_ZONE", "TOPOLOGY_ZONE"),
+    ("IBMCLOUD_CLASSIC_DATACENTER", "TOPOLOGY_ZONE"),
+    ("IBMCLOUD_CLASSIC_IPV4_PRIVATE_0", "TOPOLOGY_PRIVATE_IPV4"),
+    ("IBMCLOUD_CLASSIC_IPV4_PUBLIC_0", "TOPOLOGY_PUBLIC_IPV4"),
+    ("KUBEVIRT_INSTANCE_TYPE", "TOPOLOGY_INSTANCE_TYPE"),
+    ("LINODE_IPV4_PRIVATE_0", "TOPOLOGY_PRIVATE_IPV4"),
+    ("LINODE_IPV4_PUBLIC_0", "TOPOLOGY_PUBLIC_IPV4"),
+    ("LINODE_REGION", "TOPOLOGY_REGION"),
+    ("LINODE_TYPE", "TOPOLOGY_INSTANCE_TYPE"),
+    ("OPENSTACK_IPV4_LOCAL", "TOPOLOGY_PRIVATE_IPV4"),
+    ("OPENSTACK_IPV4_PUBLIC", "TOPOLOGY_PUBLIC_IPV4"),
+    ("OTC_AVAILABILITY_ZONE", "TOPOLOGY_ZONE"),
+    ("OTC_IPV4_LOCAL", "TOPOLOGY_PRIVATE_IPV4"),
+    ("OTC_IPV4_PUBLIC", "TOPOLOGY_PUBLIC_IPV4"),
+    ("OVHCLOUD_AVAILABILITY_ZONE", "TOPOLOGY_ZONE"),
+    ("OVHCLOUD_IPV4_LOCAL", "TOPOLOGY_PRIVATE_IPV4"),
+    ("OVHCLOUD_IPV4_PUBLIC", "TOPOLOGY_PUBLIC_IPV4"),
+    ("PACKET_FACILITY", "TOPOLOGY_ZONE"),
+    ("PACKET_IPV4_PRIVATE_0", "TOPOLOGY_PRIVATE_IPV4"),
+    ("PACKET_IPV4_PUBLIC_0", "TOPOLOGY_PUBLIC_IPV4"),
+    ("PACKET_PLAN", "TOPOLOGY_INSTANCE_TYPE"),
+    ("RACKSPACE_IPV4_LOCAL", "TOPOLOGY_PRIVATE_IPV4"),
+    ("RACKSPACE_IPV4_PUBLIC", "TOPOLOGY_PUBLIC_IPV4"),
+    ("VAGRANT_VIRTUALBOX_PRIVATE_IPV4", "TOPOLOGY_PRIVATE_IPV4"),
+];
+
+/// add_topology adds the topology attributes for the provider's attributes
+pub fn add_topology(attributes: &mut HashMap<String, String>) {
+    for &(native, topology) in TOPOLOGY_ATTRIBUTES.iter() {
+        if attributes.contains_key(topology) {
+            continue;
+        }
+        if let Some(value) = attributes.get(native).cloned() {
+            attributes.insert(topology.to_owned(), value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn add_topology_test() {
+        let mut attributes = HashMap::new();
+        attributes.insert("EC2_REGION".to_owned(), "eu-west-1".to_owned());
+        attributes.insert("EC2_AVAILABILITY_ZONE".to_owned(), "eu-west-1b".to_owned());
+        attributes.insert("EC2_IPV4_LOCAL".to_owned(), "10.0.0.5".to_owned());
+        add_topology(&mut attributes);
+        assert_eq!(attributes.len(), 6);
+        assert_eq!(attributes["TOPOLOGY_REGION"], "eu-west-1");
+        assert_eq!(attributes["TOPOLOGY_ZONE"], "eu-west-1b");
+        assert_eq!(attributes["TOPOLOGY_PRIVATE_IPV4"], "10.0.0.5");
+
+        // the first one in the table wins
+        let mut attributes = HashMap::new();
+        attributes.insert("DHCP_IPV4".to_owned(), "10.0.2.15".to_owned());
+        attributes.insert("VAGRANT_VIRTUALBOX_PRIVATE_IPV4".to_owned(), "192.168.33.10".to_owned());
+        add_topology(&mut attributes);
+        assert_eq!(attributes["TOPOLOGY_PRIVATE_IPV4"], "10.0.2.15");
+    }
+}
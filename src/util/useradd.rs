@@ -0,0 +1,194 @@
+// Copyright 2017 CoreOS, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! local users
+//!
+//! the users ssh keys are written for are usually created by ignition, and
+//! when coreos-metadata runs first (or there is no ignition config), they
+//! don't exist yet. such users can be created with useradd, which takes care
+//! of locking and updating passwd, shadow, and group. passwords are set with
+//! chpasswd for the same reason. both work on a staged root filesystem with
+//! `--root`, in which case users are looked up in its passwd file rather
+//! than through nss.
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+#[cfg(feature = "ssh-keys")]
+use users;
+
+use errors::*;
+use providers::Password;
+use util;
+use util::secret::Secret;
+
+/// PasswdEntry is the part of a passwd entry that's needed to write a user's
+/// files
+#[derive(Clone, Debug, PartialEq)]
+pub struct PasswdEntry {
+    pub uid: u32,
+    pub gid: u32,
+    pub home: String,
+}
+
+/// parse_passwd returns the entry for the user in the contents of a passwd
+/// file
+fn parse_passwd(contents: &str, name: &str) -> Option<PasswdEntry> {
+    contents.lines()
+        .map(|l| l.split(':').collect::<Vec<_>>())
+        .filter(|fields| fields.len() >= 6 && fields[0] == name)
+        .filter_map(|fields| Some(PasswdEntry {
+            uid: fields[2].parse().ok()?,
+            gid: fields[3].parse().ok()?,
+            home: fields[5].to_owned(),
+        }))
+        .next()
+}
+
+/// lookup_user returns the user's passwd entry, from the passwd file of the
+/// given root
+pub fn lookup_user(root: &Path, name: &str) -> Result<Option<PasswdEntry>> {
+    let path = util::rooted(Some(root), "/etc/passwd");
+    let mut contents = String::new();
+    File::open(&path)
+        .and_then(|mut f| f.read_to_string(&mut contents))
+        .chain_err(|| format!("failed to read {:?}", path))?;
+    Ok(parse_passwd(&contents, name))
+}
+
+/// root_args are the arguments that make the shadow tools work on the given
+/// root
+fn root_args(root: Option<&Path>) -> Vec<String> {
+    match root {
+        Some(root) => vec!["--root".to_owned(), root.display().to_string()],
+        None => vec![],
+    }
+}
+
+/// NewUser is how users that don't exist are created
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct NewUser {
+    /// supplementary groups, which have to exist
+    pub groups: Vec<String>,
+    pub shell: Option<String>,
+    /// the range the uid is picked from, instead of the one in login.defs
+    pub uid_range: Option<(u32, u32)>,
+}
+
+impl NewUser {
+    #[cfg_attr(not(feature = "ssh-keys"), allow(dead_code))]
+    fn useradd_args(&self, name: &str) -> Vec<String> {
+        let mut args = vec!["--create-home".to_owned()];
+        if !self.groups.is_empty() {
+            args.push("--groups".to_owned());
+            args.push(self.groups.join(","));
+        }
+        if let Some(ref shell) = self.shell {
+            args.push("--shell".to_owned());
+            args.push(shell.clone());
+        }
+        if let Some((min, max)) = self.uid_range {
+            args.push("-K".to_owned());
+            args.push(format!("UID_MIN={}", min));
+            args.push("-K".to_owned());
+            args.push(format!("UID_MAX={}", max));
+        }
+        args.push(name.to_owned());
+        args
+    }
+}
+
+/// ensure_user creates the user if it doesn't exist
+#[cfg(feature = "ssh-keys")]
+pub fn ensure_user(name: &str, new_user: &NewUser, root: Option<&Path>) -> Result<()> {
+    let exists = match root {
+        Some(root) => lookup_user(root, name)?.is_some(),
+        None => users::get_user_by_name(name).is_some(),
+    };
+    if exists {
+        return Ok(());
+    }
+    info!("creating user {}", name);
+    let output = Command::new("useradd")
+        .args(&root_args(root))
+        .args(&new_user.useradd_args(name))
+        .output()
+        .chain_err(|| "failed to run useradd")?;
+    if !output.status.success() {
+        return Err(format!("failed to create user {}: {}", name, String::from_utf8_lossy(&output.stderr).trim()).into());
+    }
+    Ok(())
+}
+
+/// set_password sets the password of an existing user. plain passwords are
+/// hashed by chpasswd as configured in login.defs.
+pub fn set_password(name: &str, password: &Password, root: Option<&Path>) -> Result<()> {
+    let (line, args): (Secret, &[&str]) = match *password {
+        Password::Plain(ref p) => (Secret::new(format!("{}:{}\n", name, p.expose_str()?)), &[]),
+        Password::Hash(ref h) => (Secret::new(format!("{}:{}\n", name, h.expose_str()?)), &["--encrypted"]),
+    };
+    // the line's own newline is the only one it may have
+    if line.expose().iter().position(|&b| b == b'\n') != Some(line.expose().len() - 1) {
+        return Err("password contains a newline".into());
+    }
+    let mut child = Command::new("chpasswd")
+        .args(&root_args(root))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .chain_err(|| "failed to run chpasswd")?;
+    {
+        // the password is only ever written to chpasswd's stdin, never to
+        // its command line
+        let stdin = child.stdin.as_mut().ok_or("failed to open chpasswd stdin")?;
+        stdin.write_all(line.expose())
+            .chain_err(|| "failed to write to chpasswd")?;
+    }
+    let output = child.wait_with_output()
+        .chain_err(|| "failed to run chpasswd")?;
+    if !output.status.success() {
+        return Err(format!("failed to set password for user {}: {}", name, String::from_utf8_lossy(&output.stderr).trim()).into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_passwd_test() {
+        let passwd = "root:x:0:0:root:/root:/bin/bash\ncore:x:500:500:CoreOS Admin:/home/core:/bin/bash\nbroken:x\n";
+        assert_eq!(parse_passwd(passwd, "core"), Some(PasswdEntry { uid: 500, gid: 500, home: "/home/core".to_owned() }));
+        assert_eq!(parse_passwd(passwd, "broken"), None);
+        assert_eq!(parse_passwd(passwd, "alice"), None);
+    }
+
+    #[test]
+    fn useradd_args_test() {
+        assert_eq!(NewUser::default().useradd_args("core"), vec!["--create-home", "core"]);
+        let new_user = NewUser {
+            groups: vec!["sudo".to_owned(), "docker".to_owned()],
+            shell: Some("/bin/bash".to_owned()),
+            uid_range: Some((2000, 2999)),
+        };
+        assert_eq!(new_user.useradd_args("alice"),
+                   vec!["--create-home", "--groups", "sudo,docker", "--shell", "/bin/bash",
+                        "-K", "UID_MIN=2000", "-K", "UID_MAX=2999", "alice"]);
+    }
+}